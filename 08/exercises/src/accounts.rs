@@ -0,0 +1,146 @@
+use crate::jsonl;
+use crate::Accounts;
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::time::{Duration, Instant};
+
+/// Reasons why a user cannot register or log in.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AccountError {
+    NotEnabled,
+    /// The name belongs to an account (or to the admin) and the user has not logged in.
+    Reserved,
+    /// Anonymous users are not allowed and the user has not logged in.
+    LoginRequired,
+    /// The password is wrong or the account does not exist.
+    InvalidCredentials,
+    TooManyFailedLogins,
+    AlreadyRegistered,
+    /// An anonymous user with the name is connected.
+    UsernameTaken,
+    EmptyPassword,
+}
+
+impl Display for AccountError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccountError::NotEnabled => f.write_str("Accounts are not enabled"),
+            AccountError::Reserved => f.write_str("Username is reserved"),
+            AccountError::LoginRequired => f.write_str("You have to log in"),
+            AccountError::InvalidCredentials => f.write_str("Invalid username or password"),
+            AccountError::TooManyFailedLogins => {
+                f.write_str("Too many failed logins, try again later")
+            }
+            AccountError::AlreadyRegistered => f.write_str("Username already registered"),
+            AccountError::UsernameTaken => f.write_str("Username already taken"),
+            AccountError::EmptyPassword => f.write_str("Password cannot be empty"),
+        }
+    }
+}
+
+impl std::error::Error for AccountError {}
+
+/// A single line of the account database.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Record {
+    name: String,
+    /// Argon2 hash of the password in the PHC string format, which includes its salt.
+    password_hash: String,
+}
+
+/// Failed logins to a single account since the last successful one.
+struct FailedLogins {
+    count: u32,
+    last: Instant,
+}
+
+/// Registered accounts, stored in a file with a JSON line for every account.
+pub struct AccountDb {
+    file: File,
+    /// Password hashes of the accounts, indexed by their username.
+    password_hashes: HashMap<String, String>,
+    failed_logins: HashMap<String, FailedLogins>,
+    max_failed_logins: u32,
+    lockout: Duration,
+}
+
+impl AccountDb {
+    pub fn open(opts: &Accounts) -> anyhow::Result<Self> {
+        let (file, records) = jsonl::open::<Record>(&opts.database, "account database")?;
+        Ok(Self {
+            file,
+            password_hashes: records
+                .into_iter()
+                .map(|record| (record.name, record.password_hash))
+                .collect(),
+            failed_logins: HashMap::new(),
+            max_failed_logins: opts.max_failed_logins,
+            lockout: opts.lockout,
+        })
+    }
+
+    pub fn password_hash(&self, name: &str) -> Option<&str> {
+        self.password_hashes.get(name).map(String::as_str)
+    }
+
+    pub fn register(&mut self, name: &str, password_hash: String) -> anyhow::Result<()> {
+        if self.password_hashes.contains_key(name) {
+            return Err(AccountError::AlreadyRegistered.into());
+        }
+        let record = Record {
+            name: name.to_string(),
+            password_hash,
+        };
+        jsonl::append(&mut self.file, &record)?;
+        self.password_hashes
+            .insert(record.name, record.password_hash);
+        Ok(())
+    }
+
+    /// Counts a login attempt to the account, unless it is locked after too many failed logins.
+    /// The attempt counts as failed until [`AccountDb::login_succeeded`] is called, so that
+    /// concurrent attempts cannot get around the limit.
+    pub fn start_login(&mut self, name: &str, now: Instant) -> Result<(), AccountError> {
+        let failed = self
+            .failed_logins
+            .entry(name.to_string())
+            .or_insert(FailedLogins {
+                count: 0,
+                last: now,
+            });
+        if now.saturating_duration_since(failed.last) >= self.lockout {
+            failed.count = 0;
+        }
+        if failed.count >= self.max_failed_logins {
+            return Err(AccountError::TooManyFailedLogins);
+        }
+        failed.count += 1;
+        failed.last = now;
+        Ok(())
+    }
+
+    pub fn login_succeeded(&mut self, name: &str) {
+        self.failed_logins.remove(name);
+    }
+}
+
+/// Hashes the password with a random salt.
+pub fn hash_password(password: &str) -> anyhow::Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|error| anyhow::anyhow!("Cannot hash password: {error}"))?;
+    Ok(hash.to_string())
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
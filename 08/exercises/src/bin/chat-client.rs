@@ -0,0 +1,594 @@
+//! Interactive client for the chat server.
+//!
+//! Usage: `chat-client [--format json|cbor|messagepack] [--secret <secret>]
+//! [--password [--register]] <address> <username>`
+//!
+//! The admin of the server joins with its `--secret`. A user with an account logs in with
+//! `--password`, together with `--register` the account is created first. The password is taken
+//! from the `CHAT_PASSWORD` environment variable, or read from the terminal if it is not set, so
+//! that it does not show up in the list of processes or in the shell history.
+//!
+//! Lines typed by the user are broadcasted to everybody, unless they start with a command:
+//! - `/dm <user> <message>` sends a direct message
+//! - `/list` lists the connected users
+//! - `/ping` checks that the connection is OK
+//! - `/kick <user>`, `/ban <user> <seconds>` and `/mute <user>` moderate other users (admin only)
+//! - `/send <user> <path>` offers a file, `/accept <id>` receives an offered file into the current
+//!   directory and `/cancel <id>` rejects an offer or stops a transfer
+//! - `/quit` disconnects from the server
+//!
+//! Messages received from the server are printed as soon as they arrive.
+
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, Read, Write};
+use std::net::{Shutdown, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use week08::codec::WireFormat;
+use week08::messages::{ClientToServerMsg, ServerToClientMsg};
+use week08::reader::MessageReader;
+use week08::writer::MessageWriter;
+
+type Reader = MessageReader<ServerToClientMsg, TcpStream, WireFormat>;
+type Writer = MessageWriter<ClientToServerMsg, TcpStream, WireFormat>;
+type SharedWriter = Arc<Mutex<Writer>>;
+type SharedFiles = Arc<Mutex<Files>>;
+
+/// Space left in every chunk for the rest of the message.
+const CHUNK_OVERHEAD: usize = 256;
+
+const USAGE: &str = "Usage: chat-client [--format json|cbor|messagepack] [--secret <secret>] \
+    [--password [--register]] <address> <username>";
+
+/// Environment variable with the password of the user, see `--password`.
+const PASSWORD_VAR: &str = "CHAT_PASSWORD";
+
+struct Args {
+    address: String,
+    name: String,
+    secret: Option<String>,
+    password: Option<String>,
+    register: bool,
+    format: WireFormat,
+}
+
+/// What should be done with a line typed by the user.
+enum Command {
+    Send(ClientToServerMsg),
+    SendFile { to: String, path: PathBuf },
+    AcceptFile(u64),
+    CancelFile(u64),
+    Help,
+    Quit,
+}
+
+/// File transfers of this client, shared by the thread that reads user input and the thread
+/// that handles messages from the server.
+struct Files {
+    /// Size of the chunks in which files are sent.
+    chunk_size: usize,
+    /// Files offered by this client that the server has not confirmed yet.
+    offering: Vec<OutgoingFile>,
+    /// Files offered by this client, by their transfer ID. They are removed when the transfer
+    /// is cancelled, which stops sending them.
+    outgoing: HashMap<u64, OutgoingFile>,
+    /// Files offered to this client that it has not accepted yet.
+    offers: HashMap<u64, Offer>,
+    /// Files that are being received.
+    incoming: HashMap<u64, IncomingFile>,
+}
+
+struct OutgoingFile {
+    to: String,
+    name: String,
+    path: PathBuf,
+}
+
+struct Offer {
+    name: String,
+    size: u64,
+    sha256: String,
+}
+
+struct IncomingFile {
+    offer: Offer,
+    path: PathBuf,
+    file: File,
+    hasher: Sha256,
+    received: u64,
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("{error}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    match run(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: Args) -> anyhow::Result<()> {
+    let stream = TcpStream::connect(&args.address)
+        .map_err(|error| anyhow::anyhow!("Cannot connect to {}: {error}", args.address))?;
+    let mut reader = Reader::with_codec(stream.try_clone()?, args.format);
+    let mut writer = Writer::with_codec(stream.try_clone()?, args.format);
+
+    // The server might tell us that it is full before we even send `Join`, so a failed write
+    // is only reported after we check what the server has sent us.
+    let join = match (&args.password, args.register) {
+        (Some(password), true) => ClientToServerMsg::Register {
+            name: args.name.clone(),
+            password: password.clone(),
+        },
+        _ => ClientToServerMsg::Join {
+            name: args.name.clone(),
+            secret: args.secret.clone(),
+            password: args.password.clone(),
+        },
+    };
+    let joined = writer.write(join);
+    let max_message_size = match reader.read() {
+        Some(Ok(ServerToClientMsg::Welcome { max_message_size })) => {
+            println!(
+                "Joined {} as {} (maximum message size: {max_message_size} bytes)",
+                args.address, args.name
+            );
+            println!("Type /help to list the available commands");
+            max_message_size
+        }
+        Some(Ok(ServerToClientMsg::Error(error))) => anyhow::bail!("Cannot join: {error}"),
+        Some(Ok(msg)) => anyhow::bail!("Unexpected response to Join: {msg:?}"),
+        Some(Err(error)) => return Err(error),
+        None => {
+            joined?;
+            anyhow::bail!("The server closed the connection");
+        }
+    };
+
+    // JSON encodes every byte of a chunk as up to four characters
+    let files = Arc::new(Mutex::new(Files {
+        chunk_size: (max_message_size.saturating_sub(CHUNK_OVERHEAD) / 4).max(1),
+        offering: vec![],
+        outgoing: HashMap::new(),
+        offers: HashMap::new(),
+        incoming: HashMap::new(),
+    }));
+    let writer = Arc::new(Mutex::new(writer));
+    let printer = std::thread::spawn({
+        let (writer, files) = (writer.clone(), files.clone());
+        move || print_messages(reader, &writer, &files)
+    });
+
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let msg = match parse_command(&line) {
+            Ok(Command::Send(msg)) => Some(msg),
+            Ok(Command::SendFile { to, path }) => match offer_file(&files, to, path) {
+                Ok(msg) => Some(msg),
+                Err(error) => {
+                    eprintln!("{error}");
+                    None
+                }
+            },
+            Ok(Command::AcceptFile(id)) => match accept_file(&files, id) {
+                Ok(msg) => Some(msg),
+                Err(error) => {
+                    eprintln!("{error}");
+                    None
+                }
+            },
+            Ok(Command::CancelFile(id)) => {
+                files.lock().unwrap().cancel(id);
+                Some(ClientToServerMsg::CancelFile { id })
+            }
+            Ok(Command::Help) => {
+                print_help();
+                None
+            }
+            Ok(Command::Quit) => break,
+            Err(error) => {
+                eprintln!("{error}");
+                None
+            }
+        };
+        if let Some(msg) = msg {
+            if writer.lock().unwrap().write(msg).is_err() {
+                // The printer thread tells the user what happened
+                break;
+            }
+        }
+    }
+
+    // Wake up the printer thread, which is waiting for messages from the server
+    let _ = stream.shutdown(Shutdown::Both);
+    let _ = printer.join();
+    Ok(())
+}
+
+/// Hashes the file and returns the offer for it.
+fn offer_file(files: &SharedFiles, to: String, path: PathBuf) -> anyhow::Result<ClientToServerMsg> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| anyhow::anyhow!("{} is not a file", path.display()))?
+        .to_string_lossy()
+        .to_string();
+    let mut file = File::open(&path)
+        .map_err(|error| anyhow::anyhow!("Cannot open {}: {error}", path.display()))?;
+    let mut hasher = Sha256::new();
+    let size = std::io::copy(&mut file, &mut hasher)?;
+    let msg = ClientToServerMsg::OfferFile {
+        to: to.clone(),
+        name: name.clone(),
+        size,
+        sha256: format!("{:x}", hasher.finalize()),
+    };
+    files
+        .lock()
+        .unwrap()
+        .offering
+        .push(OutgoingFile { to, name, path });
+    Ok(msg)
+}
+
+/// Creates the file for an offer in the current directory and returns the acceptance.
+fn accept_file(files: &SharedFiles, id: u64) -> anyhow::Result<ClientToServerMsg> {
+    let mut files = files.lock().unwrap();
+    let offer = files
+        .offers
+        .remove(&id)
+        .ok_or_else(|| anyhow::anyhow!("No file with ID {id} was offered"))?;
+    // The name comes from the other user, so it must not point to another directory
+    let path = Path::new(&offer.name)
+        .file_name()
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow::anyhow!("Invalid file name {}", offer.name))?;
+    let file = File::create_new(&path)
+        .map_err(|error| anyhow::anyhow!("Cannot create {}: {error}", path.display()))?;
+    println!("Receiving {} into {}", offer.name, path.display());
+    let incoming = IncomingFile {
+        offer,
+        path,
+        file,
+        hasher: Sha256::new(),
+        received: 0,
+    };
+    // An empty file is complete without any chunks
+    if incoming.offer.size == 0 {
+        incoming.finish_if_complete();
+    } else {
+        files.incoming.insert(id, incoming);
+    }
+    Ok(ClientToServerMsg::AcceptFile { id })
+}
+
+impl Files {
+    /// Forgets about a transfer, a partially received file is deleted.
+    fn cancel(&mut self, id: u64) {
+        self.outgoing.remove(&id);
+        self.offers.remove(&id);
+        if let Some(incoming) = self.incoming.remove(&id) {
+            let _ = std::fs::remove_file(incoming.path);
+        }
+    }
+
+    /// Keeps track of the transfers from a message of the server, other messages are ignored.
+    fn handle(&mut self, msg: &ServerToClientMsg, writer: &SharedWriter, files: &SharedFiles) {
+        match msg {
+            ServerToClientMsg::FileOffered { id, to, name } => {
+                let position = self
+                    .offering
+                    .iter()
+                    .position(|file| file.to == *to && file.name == *name);
+                if let Some(position) = position {
+                    let file = self.offering.remove(position);
+                    self.outgoing.insert(*id, file);
+                }
+                println!("Offered {name} to {to}, waiting until they accept it (transfer {id})");
+            }
+            ServerToClientMsg::FileOffer {
+                id,
+                from,
+                name,
+                size,
+                sha256,
+            } => {
+                println!("{from} offers {name} ({size} bytes), type /accept {id} to receive it");
+                self.offers.insert(
+                    *id,
+                    Offer {
+                        name: name.clone(),
+                        size: *size,
+                        sha256: sha256.clone(),
+                    },
+                );
+            }
+            ServerToClientMsg::FileAccepted { id } => {
+                let Some(file) = self.outgoing.get(id) else {
+                    return;
+                };
+                println!("{} accepted {}, sending it", file.to, file.name);
+                let (path, chunk_size, id) = (file.path.clone(), self.chunk_size, *id);
+                let (writer, files) = (writer.clone(), files.clone());
+                std::thread::spawn(move || {
+                    if let Err(error) = send_file(&path, chunk_size, id, &writer, &files) {
+                        eprintln!("Cannot send {}: {error}", path.display());
+                    }
+                });
+            }
+            ServerToClientMsg::FileChunk { id, data, .. } => {
+                let Some(incoming) = self.incoming.get_mut(id) else {
+                    return;
+                };
+                incoming.received += data.len() as u64;
+                incoming.hasher.update(data);
+                if let Err(error) = incoming.file.write_all(data) {
+                    eprintln!("Cannot write {}: {error}", incoming.path.display());
+                }
+                if incoming.received >= incoming.offer.size {
+                    if let Some(incoming) = self.incoming.remove(id) {
+                        incoming.finish_if_complete();
+                    }
+                }
+            }
+            ServerToClientMsg::FileCancelled { id, reason } => {
+                println!("Transfer {id} was cancelled: {reason}");
+                self.cancel(*id);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl IncomingFile {
+    /// Checks the hash of the file once it has been received completely, a corrupted file is
+    /// deleted.
+    fn finish_if_complete(&self) {
+        if self.received < self.offer.size {
+            return;
+        }
+        let sha256 = format!("{:x}", self.hasher.clone().finalize());
+        if sha256 == self.offer.sha256 {
+            println!("Received {}", self.path.display());
+        } else {
+            println!("{} is corrupted, deleting it", self.path.display());
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Sends the file in chunks, until it is sent or the transfer is cancelled. The writer is locked
+/// only for a single chunk, so that the messages typed by the user are sent in between.
+fn send_file(
+    path: &Path,
+    chunk_size: usize,
+    id: u64,
+    writer: &SharedWriter,
+    files: &SharedFiles,
+) -> anyhow::Result<()> {
+    let mut file = File::open(path)?;
+    let mut data = vec![0; chunk_size];
+    for index in 0.. {
+        let read = file.read(&mut data)?;
+        if read == 0 {
+            break;
+        }
+        if !files.lock().unwrap().outgoing.contains_key(&id) {
+            return Ok(());
+        }
+        writer.lock().unwrap().write(ClientToServerMsg::FileChunk {
+            id,
+            index,
+            data: data[..read].to_vec(),
+        })?;
+    }
+    files.lock().unwrap().outgoing.remove(&id);
+    println!("Sent {}", path.display());
+    Ok(())
+}
+
+/// Prints messages from the server until it disconnects.
+/// The whole client exits when the server disconnects, even if the user is in the middle of
+/// typing a line.
+fn print_messages(reader: Reader, writer: &SharedWriter, files: &SharedFiles) {
+    for msg in reader {
+        match msg {
+            Ok(msg) => {
+                files.lock().unwrap().handle(&msg, writer, files);
+                print_message(msg);
+            }
+            Err(error) => {
+                eprintln!("Connection error: {error}");
+                break;
+            }
+        }
+    }
+    println!("Disconnected from the server");
+    std::process::exit(0);
+}
+
+fn print_message(msg: ServerToClientMsg) {
+    match msg {
+        ServerToClientMsg::Welcome { .. } => println!("Unexpected welcome message"),
+        ServerToClientMsg::Pong => println!("Pong"),
+        ServerToClientMsg::UserList { mut users } => {
+            users.sort();
+            println!("Connected users: {}", users.join(", "));
+        }
+        ServerToClientMsg::Message { from, message } => println!("<{from}> {message}"),
+        ServerToClientMsg::RoomJoined { room } => println!("Joined room {room}"),
+        ServerToClientMsg::RoomLeft { room } => println!("Left room {room}"),
+        ServerToClientMsg::RoomList { mut rooms } => {
+            rooms.sort();
+            println!("Rooms: {}", rooms.join(", "));
+        }
+        ServerToClientMsg::RoomMessage {
+            room,
+            from,
+            message,
+        } => println!("[{room}] <{from}> {message}"),
+        ServerToClientMsg::History { messages } => {
+            for msg in messages {
+                match msg.to {
+                    Some(to) => println!("#{} <{} -> {to}> {}", msg.id, msg.from, msg.message),
+                    None => println!("#{} <{}> {}", msg.id, msg.from, msg.message),
+                }
+            }
+        }
+        ServerToClientMsg::Error(error) => println!("Error: {error}"),
+        // Printed by `Files::handle`
+        ServerToClientMsg::FileOffered { .. }
+        | ServerToClientMsg::FileOffer { .. }
+        | ServerToClientMsg::FileAccepted { .. }
+        | ServerToClientMsg::FileChunk { .. }
+        | ServerToClientMsg::FileCancelled { .. } => {}
+    }
+}
+
+fn parse_command(line: &str) -> Result<Command, String> {
+    let Some(command) = line.strip_prefix('/') else {
+        return Ok(Command::Send(ClientToServerMsg::Broadcast {
+            message: line.to_string(),
+        }));
+    };
+    let (command, rest) = command.split_once(' ').unwrap_or((command, ""));
+    let rest = rest.trim();
+    let msg = match command {
+        "dm" => {
+            let Some((to, message)) = rest.split_once(' ') else {
+                return Err("Usage: /dm <user> <message>".to_string());
+            };
+            ClientToServerMsg::SendDM {
+                to: to.to_string(),
+                message: message.trim().to_string(),
+            }
+        }
+        "list" => ClientToServerMsg::ListUsers,
+        "ping" => ClientToServerMsg::Ping,
+        "kick" if !rest.is_empty() => ClientToServerMsg::Kick {
+            name: rest.to_string(),
+        },
+        "kick" => return Err("Usage: /kick <user>".to_string()),
+        "ban" => {
+            let duration = rest
+                .split_once(' ')
+                .and_then(|(name, duration)| Some((name, duration.trim().parse().ok()?)));
+            let Some((name, duration)) = duration else {
+                return Err("Usage: /ban <user> <seconds>".to_string());
+            };
+            ClientToServerMsg::Ban {
+                name: name.to_string(),
+                duration,
+            }
+        }
+        "mute" if !rest.is_empty() => ClientToServerMsg::Mute {
+            name: rest.to_string(),
+        },
+        "mute" => return Err("Usage: /mute <user>".to_string()),
+        "send" => {
+            let Some((to, path)) = rest.split_once(' ') else {
+                return Err("Usage: /send <user> <path>".to_string());
+            };
+            return Ok(Command::SendFile {
+                to: to.to_string(),
+                path: PathBuf::from(path.trim()),
+            });
+        }
+        "accept" | "cancel" => {
+            let Ok(id) = rest.parse() else {
+                return Err(format!("Usage: /{command} <id>"));
+            };
+            return Ok(match command {
+                "accept" => Command::AcceptFile(id),
+                _ => Command::CancelFile(id),
+            });
+        }
+        "help" => return Ok(Command::Help),
+        "quit" => return Ok(Command::Quit),
+        _ => return Err(format!("Unknown command /{command}, type /help for help")),
+    };
+    Ok(Command::Send(msg))
+}
+
+fn print_help() {
+    println!("/dm <user> <message>  send a direct message");
+    println!("/list                 list connected users");
+    println!("/ping                 check the connection");
+    println!("/kick <user>          disconnect a user (admin only)");
+    println!("/ban <user> <seconds> disconnect a user and refuse them to join (admin only)");
+    println!("/mute <user>          stop a user from sending messages (admin only)");
+    println!("/send <user> <path>   offer a file to a user");
+    println!("/accept <id>          receive an offered file into the current directory");
+    println!("/cancel <id>          reject an offered file or stop a transfer");
+    println!("/quit                 disconnect");
+    println!("Anything else is broadcasted to all connected users.");
+}
+
+/// Takes the password from [`PASSWORD_VAR`], or asks the user for it.
+fn read_password() -> anyhow::Result<String> {
+    if let Ok(password) = std::env::var(PASSWORD_VAR) {
+        return Ok(password);
+    }
+    print!("Password: ");
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    if std::io::stdin().read_line(&mut line)? == 0 {
+        anyhow::bail!("Missing password, set {PASSWORD_VAR} or type it");
+    }
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> anyhow::Result<Args> {
+    let mut format = WireFormat::Json;
+    let mut secret = None;
+    let mut with_password = false;
+    let mut register = false;
+    let mut positional = vec![];
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--format" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("Missing value of --format"))?;
+                format = value.parse()?;
+            }
+            "--secret" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("Missing value of --secret"))?;
+                secret = Some(value);
+            }
+            "--password" => with_password = true,
+            "--register" => register = true,
+            option if option.starts_with('-') => anyhow::bail!("Unknown option {option}"),
+            _ => positional.push(arg),
+        }
+    }
+    let [address, name] = <[String; 2]>::try_from(positional)
+        .map_err(|_| anyhow::anyhow!("Expected an address and a username"))?;
+    if register && !with_password {
+        anyhow::bail!("--register needs a --password");
+    }
+    let password = with_password.then(read_password).transpose()?;
+    Ok(Args {
+        address,
+        name,
+        secret,
+        password,
+        register,
+        format,
+    })
+}
@@ -1,5 +1,208 @@
-//! You can use this file for experiments.
+//! Runs the chat server until it receives Ctrl-C.
+//!
+//! Every option can be passed as a command line flag (e.g. `--max-clients 10`), as an environment
+//! variable (e.g. `CHAT_MAX_CLIENTS=10`) or in a TOML file passed with `--config`
+//! (e.g. `max_clients = 10`), in this order of precedence.
+//! Keys that this server does not know are ignored, so that the same file can be shared with
+//! the servers from the other weeks.
 
-fn main() {
-    println!("Hello Rust!");
+use clap::Parser;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use week08::clock::SystemClock;
+use week08::codec::WireFormat;
+use week08::net::ListenAddr;
+use week08::reader::DEFAULT_MAX_MESSAGE_SIZE;
+use week08::{run_server, Accounts, Admin, RateLimit, ServerOpts};
+
+#[derive(Parser, Deserialize, Default)]
+#[serde(default)]
+struct Config {
+    /// TOML file with the configuration
+    #[arg(long, env = "CHAT_CONFIG")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    /// Address on which the server listens [default: 127.0.0.1]
+    #[arg(long, env = "CHAT_ADDRESS")]
+    address: Option<IpAddr>,
+    /// Port on which the server listens, 0 lets the operating system choose one [default: 0]
+    #[arg(long, env = "CHAT_PORT")]
+    port: Option<u16>,
+    /// Path of a Unix socket on which the server listens in addition to TCP/IP
+    #[arg(long, env = "CHAT_UNIX_SOCKET")]
+    unix_socket: Option<PathBuf>,
+    /// Maximum number of clients connected at once [default: 100]
+    #[arg(long, env = "CHAT_MAX_CLIENTS")]
+    max_clients: Option<usize>,
+    /// Maximum size of a single message in bytes [default: 65536]
+    #[arg(long, env = "CHAT_MAX_MESSAGE_SIZE")]
+    max_message_size: Option<usize>,
+    /// File where the message history is stored, the history is disabled if not set
+    #[arg(long, env = "CHAT_HISTORY")]
+    history: Option<PathBuf>,
+    /// Serialization format of messages: json, cbor or messagepack [default: json]
+    #[arg(long, env = "CHAT_WIRE_FORMAT")]
+    wire_format: Option<WireFormat>,
+    /// Username of the admin that can kick, ban and mute users, moderation is disabled if not set
+    #[arg(long, env = "CHAT_ADMIN_NAME")]
+    admin_name: Option<String>,
+    /// Secret that the admin sends when joining
+    #[arg(long, env = "CHAT_ADMIN_SECRET", hide_env_values = true)]
+    admin_secret: Option<String>,
+    /// Messages per second that a single client can send, clients are not limited if not set
+    #[arg(long, env = "CHAT_RATE_LIMIT")]
+    rate_limit: Option<f64>,
+    /// Messages that a client can send at once after staying quiet [default: 20]
+    #[arg(long, env = "CHAT_RATE_LIMIT_BURST")]
+    rate_limit_burst: Option<u32>,
+    /// Rate limited messages after which a client is disconnected [default: 10]
+    #[arg(long, env = "CHAT_RATE_LIMIT_VIOLATIONS")]
+    rate_limit_violations: Option<u32>,
+    /// File where user accounts are stored, accounts are disabled if not set
+    #[arg(long, env = "CHAT_ACCOUNTS")]
+    accounts: Option<PathBuf>,
+    /// Whether users without an account can join when accounts are enabled [default: true]
+    #[arg(long, env = "CHAT_ALLOW_ANONYMOUS")]
+    allow_anonymous: Option<bool>,
+    /// Failed logins in a row after which an account is locked [default: 5]
+    #[arg(long, env = "CHAT_MAX_FAILED_LOGINS")]
+    max_failed_logins: Option<u32>,
+    /// Seconds for which an account is locked after too many failed logins [default: 60]
+    #[arg(long, env = "CHAT_LOGIN_LOCKOUT")]
+    login_lockout: Option<u64>,
+    /// Port of an HTTP endpoint on localhost that serves Prometheus metrics at `/metrics`,
+    /// the endpoint is disabled if not set
+    #[arg(long, env = "CHAT_METRICS_PORT")]
+    metrics_port: Option<u16>,
+    /// File where the server records its clients for debugging, the recording is disabled if
+    /// not set
+    #[arg(long, env = "CHAT_RECORD")]
+    record: Option<PathBuf>,
+    /// Log level: off, error, warn, info, debug or trace [default: info]
+    #[arg(long, env = "CHAT_LOG_LEVEL")]
+    log_level: Option<LevelFilter>,
+}
+
+impl Config {
+    fn load() -> anyhow::Result<Self> {
+        let args = Config::parse();
+        let file = match &args.config {
+            Some(path) => {
+                let content = std::fs::read_to_string(path).map_err(|error| {
+                    anyhow::anyhow!("Cannot read config file {}: {error}", path.display())
+                })?;
+                toml::from_str(&content).map_err(|error| {
+                    anyhow::anyhow!("Cannot parse config file {}: {error}", path.display())
+                })?
+            }
+            None => Config::default(),
+        };
+        Ok(args.or(file))
+    }
+
+    /// Fills the values that are missing in `self` with values from `other`.
+    fn or(self, other: Config) -> Config {
+        Config {
+            config: self.config,
+            address: self.address.or(other.address),
+            port: self.port.or(other.port),
+            unix_socket: self.unix_socket.or(other.unix_socket),
+            max_clients: self.max_clients.or(other.max_clients),
+            max_message_size: self.max_message_size.or(other.max_message_size),
+            history: self.history.or(other.history),
+            wire_format: self.wire_format.or(other.wire_format),
+            admin_name: self.admin_name.or(other.admin_name),
+            admin_secret: self.admin_secret.or(other.admin_secret),
+            rate_limit: self.rate_limit.or(other.rate_limit),
+            rate_limit_burst: self.rate_limit_burst.or(other.rate_limit_burst),
+            rate_limit_violations: self.rate_limit_violations.or(other.rate_limit_violations),
+            accounts: self.accounts.or(other.accounts),
+            allow_anonymous: self.allow_anonymous.or(other.allow_anonymous),
+            max_failed_logins: self.max_failed_logins.or(other.max_failed_logins),
+            login_lockout: self.login_lockout.or(other.login_lockout),
+            metrics_port: self.metrics_port.or(other.metrics_port),
+            record: self.record.or(other.record),
+            log_level: self.log_level.or(other.log_level),
+        }
+    }
+
+    fn listen(&self) -> Vec<ListenAddr> {
+        let tcp = SocketAddr::new(
+            self.address.unwrap_or(Ipv4Addr::LOCALHOST.into()),
+            self.port.unwrap_or(0),
+        );
+        let mut listen = vec![ListenAddr::Tcp(tcp)];
+        listen.extend(self.unix_socket.clone().map(ListenAddr::Unix));
+        listen
+    }
+
+    fn server_opts(&self) -> anyhow::Result<ServerOpts> {
+        let admin = match (&self.admin_name, &self.admin_secret) {
+            (Some(name), Some(secret)) => Some(Admin {
+                name: name.clone(),
+                secret: secret.clone(),
+            }),
+            (Some(_), None) => anyhow::bail!("The admin needs a secret"),
+            (None, _) => None,
+        };
+        if let Some(rate_limit) = self.rate_limit {
+            if !(rate_limit > 0.0 && rate_limit.is_finite()) || self.rate_limit_burst == Some(0) {
+                anyhow::bail!("The rate limit and its burst must be positive");
+            }
+        }
+        Ok(ServerOpts {
+            listen: self.listen(),
+            metrics: self
+                .metrics_port
+                .map(|port| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)),
+            max_clients: self.max_clients.unwrap_or(100),
+            history: self.history.clone(),
+            wire_format: self.wire_format.unwrap_or_default(),
+            max_message_size: self.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
+            admin,
+            rate_limit: self.rate_limit.map(|messages_per_second| RateLimit {
+                messages_per_second,
+                burst: self.rate_limit_burst.unwrap_or(20),
+                max_violations: self.rate_limit_violations.unwrap_or(10),
+            }),
+            accounts: self.accounts.clone().map(|database| Accounts {
+                database,
+                allow_anonymous: self.allow_anonymous.unwrap_or(true),
+                max_failed_logins: self.max_failed_logins.unwrap_or(5),
+                lockout: Duration::from_secs(self.login_lockout.unwrap_or(60)),
+            }),
+            clock: Arc::new(SystemClock),
+            record: self.record.clone(),
+        })
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::load()?;
+    env_logger::Builder::new()
+        .filter_level(config.log_level.unwrap_or(LevelFilter::Info))
+        .init();
+
+    let (stop_tx, stop_rx) = std::sync::mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = stop_tx.send(());
+    })?;
+
+    let server = run_server(config.server_opts()?)?;
+    for address in server.addresses() {
+        log::info!("Listening on {address}");
+    }
+    if let Some(address) = server.metrics_address() {
+        log::info!("Serving metrics on http://{address}/metrics");
+    }
+
+    let _ = stop_rx.recv();
+    log::info!("Shutting down");
+    drop(server);
+    log::info!("Server stopped");
+    Ok(())
 }
@@ -0,0 +1,506 @@
+use crate::accounts::AccountError;
+use crate::codec::WireFormat;
+use crate::messages::{ClientToServerMsg, ServerToClientMsg};
+use crate::metrics::{Counted, Metrics};
+use crate::net::Stream;
+use crate::rate_limit::{RateDecision, TokenBucket};
+use crate::reader::{MessageReader, MessageTooLarge};
+use crate::state::{DmRoute, JoinOutcome, ServerState, SharedWriter};
+use crate::trace::{ConnectionTrace, Event};
+use crate::transfers::TransferError;
+use crate::writer::MessageWriter;
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Reads messages from a client, keeps the metrics up to date and records the messages.
+pub struct ClientReader {
+    reader: MessageReader<ClientToServerMsg, Counted<Stream>, WireFormat>,
+    metrics: Arc<Metrics>,
+    trace: ConnectionTrace,
+    /// When was the last message returned. The time until the next message is requested is spent
+    /// handling it.
+    handling_since: Option<Instant>,
+}
+
+impl ClientReader {
+    pub fn new(stream: Stream, state: &ServerState, trace: ConnectionTrace) -> Self {
+        let stream = Counted::new(stream, state.metrics.clone());
+        Self {
+            reader: MessageReader::with_codec(stream, state.opts.wire_format)
+                .with_max_message_size(state.opts.max_message_size),
+            metrics: state.metrics.clone(),
+            trace,
+            handling_since: None,
+        }
+    }
+
+    fn finish_handling(&mut self) {
+        if let Some(since) = self.handling_since.take() {
+            self.metrics.add_busy_time(since.elapsed());
+        }
+    }
+}
+
+impl Iterator for ClientReader {
+    type Item = anyhow::Result<ClientToServerMsg>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.finish_handling();
+        let msg = self.reader.read();
+        match &msg {
+            Some(Ok(msg)) => {
+                self.metrics.message_received(msg);
+                self.handling_since = Some(Instant::now());
+                self.trace.record(|client| Event::Received {
+                    client,
+                    msg: msg.clone(),
+                });
+            }
+            Some(Err(error)) if error.is::<MessageTooLarge>() => {
+                self.trace.record(|client| Event::TooLarge { client });
+            }
+            Some(Err(error)) => self.trace.record(|client| Event::Lost {
+                client,
+                error: error.to_string(),
+            }),
+            None => self.trace.record(|client| Event::Closed { client }),
+        }
+        msg
+    }
+}
+
+impl Drop for ClientReader {
+    fn drop(&mut self) {
+        self.finish_handling();
+    }
+}
+
+/// Writes messages to a client, keeps the metrics up to date and records the messages.
+pub struct ClientWriter {
+    writer: MessageWriter<ServerToClientMsg, Counted<Stream>, WireFormat>,
+    metrics: Arc<Metrics>,
+    trace: ConnectionTrace,
+}
+
+impl ClientWriter {
+    pub fn new(stream: Stream, state: &ServerState, trace: ConnectionTrace) -> Self {
+        let stream = Counted::new(stream, state.metrics.clone());
+        Self {
+            writer: MessageWriter::with_codec(stream, state.opts.wire_format),
+            metrics: state.metrics.clone(),
+            trace,
+        }
+    }
+
+    pub fn write(&mut self, msg: ServerToClientMsg) -> anyhow::Result<()> {
+        self.metrics.message_sent(&msg);
+        self.trace.record(|client| Event::Sent {
+            client,
+            msg: msg.clone(),
+        });
+        self.writer.write(msg)
+    }
+
+    pub fn inner(&self) -> &Stream {
+        self.writer.inner().get_ref()
+    }
+}
+
+/// Serves a single connected client until it disconnects, misbehaves or the server shuts down.
+pub fn handle_client(
+    state: &ServerState,
+    stream: Stream,
+    trace: ConnectionTrace,
+) -> anyhow::Result<()> {
+    let max_message_size = state.opts.max_message_size;
+    let mut reader = ClientReader::new(stream.try_clone()?, state, trace.clone());
+    let writer: SharedWriter = Arc::new(Mutex::new(ClientWriter::new(stream, state, trace)));
+
+    let (name, secret, password, register) = loop {
+        match reader.next() {
+            Some(Ok(ClientToServerMsg::Join {
+                name,
+                secret,
+                password,
+            })) => break (name, secret, password, false),
+            Some(Ok(ClientToServerMsg::Register { name, password })) => {
+                break (name, None, Some(password), true)
+            }
+            Some(Ok(_)) => return send(&writer, unexpected_message()),
+            Some(Err(error)) if error.is::<MessageTooLarge>() => {
+                send(&writer, message_too_large())?
+            }
+            Some(Err(error)) => return Err(error),
+            None => return Ok(()),
+        }
+    };
+
+    let is_admin = match &state.opts.admin {
+        Some(admin) if admin.name == name => {
+            if secret.as_ref() != Some(&admin.secret) {
+                return send(
+                    &writer,
+                    ServerToClientMsg::Error("Invalid secret".to_string()),
+                );
+            }
+            true
+        }
+        _ => false,
+    };
+    // The admin is authenticated by its secret, it has no account
+    if !is_admin {
+        let login = match register {
+            true => state.register(&name, password.as_deref().unwrap_or_default()),
+            false => state.login(&name, password.as_deref()),
+        };
+        match login {
+            Ok(()) => {}
+            Err(error) if error.is::<AccountError>() => {
+                return send(&writer, ServerToClientMsg::Error(error.to_string()))
+            }
+            Err(error) => return Err(error),
+        }
+    }
+
+    // Hold the writer while joining, so that nobody can send a message to the new user
+    // before it receives the welcome message and the DMs that it has missed.
+    let mut guard = writer.lock().unwrap();
+    let missed = match state.add_user(&name, writer.clone())? {
+        JoinOutcome::Joined(missed) => missed,
+        JoinOutcome::UsernameTaken => {
+            return guard.write(ServerToClientMsg::Error(
+                "Username already taken".to_string(),
+            ))
+        }
+        JoinOutcome::Banned => {
+            return guard.write(ServerToClientMsg::Error("You are banned".to_string()))
+        }
+    };
+    let result = guard
+        .write(ServerToClientMsg::Welcome { max_message_size })
+        .and_then(|_| {
+            missed.into_iter().try_for_each(|msg| {
+                guard.write(ServerToClientMsg::Message {
+                    from: msg.from,
+                    message: msg.message,
+                })
+            })
+        });
+    drop(guard);
+    log::debug!("User {name} joined");
+
+    let result = result
+        .and_then(|_| state.missed_dms_delivered(&name))
+        .and_then(|_| serve_user(state, &name, is_admin, &mut reader, &writer));
+    for (id, other) in state.remove_user(&name) {
+        let _ = send(
+            &other,
+            ServerToClientMsg::FileCancelled {
+                id,
+                reason: format!("{name} disconnected"),
+            },
+        );
+    }
+    log::debug!("User {name} left");
+    result
+}
+
+fn serve_user(
+    state: &ServerState,
+    name: &str,
+    is_admin: bool,
+    reader: &mut ClientReader,
+    writer: &SharedWriter,
+) -> anyhow::Result<()> {
+    let mut bucket = state
+        .opts
+        .rate_limit
+        .clone()
+        .map(|limit| TokenBucket::new(limit, state.opts.clock.now()));
+    for msg in reader {
+        let msg = match msg {
+            Ok(msg) => msg,
+            Err(error) if error.is::<MessageTooLarge>() => {
+                send(writer, message_too_large())?;
+                continue;
+            }
+            Err(error) => return Err(error),
+        };
+        // Chunks of a file are limited by its size, which the recipient has accepted
+        let is_chunk = matches!(msg, ClientToServerMsg::FileChunk { .. });
+        if let Some(bucket) = bucket.as_mut().filter(|_| !is_chunk) {
+            match bucket.take(state.opts.clock.now()) {
+                RateDecision::Allow => {}
+                RateDecision::Reject => {
+                    send(writer, rate_limited())?;
+                    continue;
+                }
+                RateDecision::Disconnect => {
+                    log::info!("Disconnecting user {name}, it keeps sending messages too fast");
+                    return send(writer, rate_limited());
+                }
+            }
+        }
+        let is_message = matches!(
+            msg,
+            ClientToServerMsg::SendDM { .. }
+                | ClientToServerMsg::Broadcast { .. }
+                | ClientToServerMsg::RoomMessage { .. }
+                | ClientToServerMsg::OfferFile { .. }
+        );
+        if is_message && state.is_muted(name) {
+            send(
+                writer,
+                ServerToClientMsg::Error("You are muted".to_string()),
+            )?;
+            continue;
+        }
+        match msg {
+            ClientToServerMsg::Join { .. } | ClientToServerMsg::Register { .. } => {
+                return send(writer, unexpected_message())
+            }
+            ClientToServerMsg::Ping => send(writer, ServerToClientMsg::Pong)?,
+            ClientToServerMsg::ListUsers => send(
+                writer,
+                ServerToClientMsg::UserList {
+                    users: state.usernames(),
+                },
+            )?,
+            ClientToServerMsg::SendDM { to, message } => {
+                if to == name {
+                    send(
+                        writer,
+                        ServerToClientMsg::Error("Cannot send a DM to yourself".to_string()),
+                    )?;
+                    continue;
+                }
+                match state.route_dm(name, &to, &message)? {
+                    DmRoute::Online(recipient) => {
+                        // The recipient might have disconnected in the meantime, which is not
+                        // an error of the sender.
+                        let _ = send(
+                            &recipient,
+                            ServerToClientMsg::Message {
+                                from: name.to_string(),
+                                message,
+                            },
+                        );
+                    }
+                    DmRoute::Stored => {}
+                    DmRoute::UnknownUser => send(
+                        writer,
+                        ServerToClientMsg::Error(format!("User {to} does not exist")),
+                    )?,
+                }
+            }
+            ClientToServerMsg::Broadcast { message } => {
+                for recipient in state.broadcast(name, &message)? {
+                    let _ = send(
+                        &recipient,
+                        ServerToClientMsg::Message {
+                            from: name.to_string(),
+                            message: message.clone(),
+                        },
+                    );
+                }
+            }
+            ClientToServerMsg::CreateRoom { room } => match state.create_room(&room, name) {
+                Ok(()) => send(writer, ServerToClientMsg::RoomJoined { room })?,
+                Err(error) => send(writer, ServerToClientMsg::Error(error.to_string()))?,
+            },
+            ClientToServerMsg::JoinRoom { room } => match state.join_room(&room, name) {
+                Ok(()) => send(writer, ServerToClientMsg::RoomJoined { room })?,
+                Err(error) => send(writer, ServerToClientMsg::Error(error.to_string()))?,
+            },
+            ClientToServerMsg::LeaveRoom { room } => match state.leave_room(&room, name) {
+                Ok(()) => send(writer, ServerToClientMsg::RoomLeft { room })?,
+                Err(error) => send(writer, ServerToClientMsg::Error(error.to_string()))?,
+            },
+            ClientToServerMsg::ListRooms => send(
+                writer,
+                ServerToClientMsg::RoomList {
+                    rooms: state.room_names(),
+                },
+            )?,
+            ClientToServerMsg::RoomMessage { room, message } => {
+                match state.room_recipients(&room, name) {
+                    Ok(recipients) => {
+                        for recipient in recipients {
+                            let _ = send(
+                                &recipient,
+                                ServerToClientMsg::RoomMessage {
+                                    room: room.clone(),
+                                    from: name.to_string(),
+                                    message: message.clone(),
+                                },
+                            );
+                        }
+                    }
+                    Err(error) => send(writer, ServerToClientMsg::Error(error.to_string()))?,
+                }
+            }
+            ClientToServerMsg::History { since, limit } => {
+                match state.history_page(name, since, limit) {
+                    Some(messages) => send(writer, ServerToClientMsg::History { messages })?,
+                    None => send(
+                        writer,
+                        ServerToClientMsg::Error("History is not enabled".to_string()),
+                    )?,
+                }
+            }
+            ClientToServerMsg::Kick { name: user } => match can_moderate(name, is_admin, &user) {
+                Ok(()) => match state.user_writer(&user) {
+                    Some(recipient) => {
+                        disconnect(&recipient, "You have been kicked");
+                        log::info!("User {user} was kicked");
+                    }
+                    None => send(
+                        writer,
+                        ServerToClientMsg::Error(format!("User {user} does not exist")),
+                    )?,
+                },
+                Err(error) => send(writer, error)?,
+            },
+            ClientToServerMsg::Ban {
+                name: user,
+                duration,
+            } => match can_moderate(name, is_admin, &user) {
+                Ok(()) => {
+                    if let Some(recipient) = state.ban(&user, Duration::from_secs(duration)) {
+                        disconnect(&recipient, "You are banned");
+                    }
+                    log::info!("User {user} was banned for {duration}s");
+                }
+                Err(error) => send(writer, error)?,
+            },
+            ClientToServerMsg::Mute { name: user } => match can_moderate(name, is_admin, &user) {
+                Ok(()) => {
+                    state.mute(&user);
+                    log::info!("User {user} was muted");
+                }
+                Err(error) => send(writer, error)?,
+            },
+            ClientToServerMsg::OfferFile {
+                to,
+                name: file,
+                size,
+                sha256,
+            } => {
+                if to == name {
+                    send(
+                        writer,
+                        ServerToClientMsg::Error("Cannot send a file to yourself".to_string()),
+                    )?;
+                    continue;
+                }
+                let Some((id, recipient)) = state.offer_file(name, &to, size) else {
+                    send(
+                        writer,
+                        ServerToClientMsg::Error(format!("User {to} does not exist")),
+                    )?;
+                    continue;
+                };
+                send(
+                    writer,
+                    ServerToClientMsg::FileOffered {
+                        id,
+                        to,
+                        name: file.clone(),
+                    },
+                )?;
+                let _ = send(
+                    &recipient,
+                    ServerToClientMsg::FileOffer {
+                        id,
+                        from: name.to_string(),
+                        name: file,
+                        size,
+                        sha256,
+                    },
+                );
+            }
+            ClientToServerMsg::AcceptFile { id } => match state.accept_file(id, name) {
+                Ok(sender) => {
+                    if let Some(sender) = sender {
+                        let _ = send(&sender, ServerToClientMsg::FileAccepted { id });
+                    }
+                }
+                Err(error) => send(writer, ServerToClientMsg::Error(error.to_string()))?,
+            },
+            ClientToServerMsg::FileChunk { id, index, data } => {
+                match state.file_chunk(id, name, index, data.len()) {
+                    Ok(recipient) => {
+                        if let Some(recipient) = recipient {
+                            let _ =
+                                send(&recipient, ServerToClientMsg::FileChunk { id, index, data });
+                        }
+                    }
+                    // The transfer was cancelled, both sides are told
+                    Err((error @ TransferError::UnexpectedChunk(..), recipient)) => {
+                        let cancelled = || ServerToClientMsg::FileCancelled {
+                            id,
+                            reason: error.to_string(),
+                        };
+                        if let Some(recipient) = recipient {
+                            let _ = send(&recipient, cancelled());
+                        }
+                        send(writer, cancelled())?;
+                    }
+                    Err((error, _)) => send(writer, ServerToClientMsg::Error(error.to_string()))?,
+                }
+            }
+            ClientToServerMsg::CancelFile { id } => match state.cancel_file(id, name) {
+                Ok(other) => {
+                    if let Some(other) = other {
+                        let _ = send(
+                            &other,
+                            ServerToClientMsg::FileCancelled {
+                                id,
+                                reason: format!("Cancelled by {name}"),
+                            },
+                        );
+                    }
+                }
+                Err(error) => send(writer, ServerToClientMsg::Error(error.to_string()))?,
+            },
+        }
+    }
+    Ok(())
+}
+
+/// Checks that `name` is allowed to kick, ban or mute `user`.
+fn can_moderate(name: &str, is_admin: bool, user: &str) -> Result<(), ServerToClientMsg> {
+    if !is_admin {
+        return Err(ServerToClientMsg::Error("You are not an admin".to_string()));
+    }
+    if user == name {
+        return Err(ServerToClientMsg::Error(
+            "Cannot moderate yourself".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Sends a last error to a user and closes its connection, which also ends its thread.
+fn disconnect(writer: &SharedWriter, error: &str) {
+    let mut writer = writer.lock().unwrap();
+    let _ = writer.write(ServerToClientMsg::Error(error.to_string()));
+    let _ = writer.inner().shutdown(Shutdown::Both);
+}
+
+fn unexpected_message() -> ServerToClientMsg {
+    ServerToClientMsg::Error("Unexpected message received".to_string())
+}
+
+fn message_too_large() -> ServerToClientMsg {
+    ServerToClientMsg::Error("Message too large".to_string())
+}
+
+fn rate_limited() -> ServerToClientMsg {
+    ServerToClientMsg::Error("Rate limited".to_string())
+}
+
+pub fn send(writer: &SharedWriter, msg: ServerToClientMsg) -> anyhow::Result<()> {
+    writer.lock().unwrap().write(msg)
+}
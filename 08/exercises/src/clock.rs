@@ -0,0 +1,19 @@
+use std::time::Instant;
+
+/// Source of time of the server.
+///
+/// Everything time-based (rate limits, bans) asks the clock instead of calling
+/// [`Instant::now`] directly, so that tests can control the time.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The real time of the operating system.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
@@ -0,0 +1,103 @@
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::str::FromStr;
+
+/// Serialization format of a single message inside a frame.
+///
+/// [`MessageReader`](crate::reader::MessageReader) and
+/// [`MessageWriter`](crate::writer::MessageWriter) take care of the framing, so a codec only has
+/// to turn a message into bytes and back.
+pub trait Codec {
+    fn encode<T: Serialize>(&self, message: &T) -> anyhow::Result<Vec<u8>>;
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> anyhow::Result<T>;
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct JsonCodec;
+
+impl Codec for JsonCodec {
+    fn encode<T: Serialize>(&self, message: &T) -> anyhow::Result<Vec<u8>> {
+        Ok(serde_json::to_vec(message)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> anyhow::Result<T> {
+        Ok(serde_json::from_slice(data)?)
+    }
+}
+
+/// [CBOR](https://cbor.io), a compact binary format.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CborCodec;
+
+impl Codec for CborCodec {
+    fn encode<T: Serialize>(&self, message: &T) -> anyhow::Result<Vec<u8>> {
+        let mut data = vec![];
+        ciborium::into_writer(message, &mut data)?;
+        Ok(data)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> anyhow::Result<T> {
+        Ok(ciborium::from_reader(data)?)
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct MessagePackCodec;
+
+impl Codec for MessagePackCodec {
+    fn encode<T: Serialize>(&self, message: &T) -> anyhow::Result<Vec<u8>> {
+        // Encode structs as maps, so that fields with a default value can be omitted
+        Ok(rmp_serde::to_vec_named(message)?)
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> anyhow::Result<T> {
+        Ok(rmp_serde::from_slice(data)?)
+    }
+}
+
+/// Codec selected at runtime, e.g. through [`ServerOpts`](crate::ServerOpts).
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    #[default]
+    Json,
+    Cbor,
+    MessagePack,
+}
+
+impl WireFormat {
+    pub const ALL: [WireFormat; 3] = [WireFormat::Json, WireFormat::Cbor, WireFormat::MessagePack];
+}
+
+impl FromStr for WireFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(format: &str) -> anyhow::Result<Self> {
+        match format {
+            "json" => Ok(WireFormat::Json),
+            "cbor" => Ok(WireFormat::Cbor),
+            "messagepack" | "msgpack" => Ok(WireFormat::MessagePack),
+            _ => Err(anyhow::anyhow!(
+                "Unknown wire format {format}, expected json, cbor or messagepack"
+            )),
+        }
+    }
+}
+
+impl Codec for WireFormat {
+    fn encode<T: Serialize>(&self, message: &T) -> anyhow::Result<Vec<u8>> {
+        match self {
+            WireFormat::Json => JsonCodec.encode(message),
+            WireFormat::Cbor => CborCodec.encode(message),
+            WireFormat::MessagePack => MessagePackCodec.encode(message),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(&self, data: &[u8]) -> anyhow::Result<T> {
+        match self {
+            WireFormat::Json => JsonCodec.decode(data),
+            WireFormat::Cbor => CborCodec.decode(data),
+            WireFormat::MessagePack => MessagePackCodec.decode(data),
+        }
+    }
+}
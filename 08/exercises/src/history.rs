@@ -0,0 +1,155 @@
+use crate::jsonl;
+use crate::messages::HistoryMessage;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::path::Path;
+
+/// Maximum number of messages returned in a single history page.
+pub const MAX_HISTORY_PAGE: usize = 100;
+
+/// A single line of the history log.
+#[derive(serde::Serialize, serde::Deserialize)]
+enum Record {
+    /// A user with this name has joined the server for the first time.
+    Joined { name: String },
+    /// A broadcast or a DM was accepted by the server.
+    Message {
+        message: HistoryMessage,
+        delivered: bool,
+    },
+    /// All DMs sent to `to` so far have been delivered.
+    Delivered { to: String },
+}
+
+/// Persistent log of broadcasts and DMs.
+///
+/// Every change is appended as a JSON line to a file, which is replayed when the history is
+/// opened again, so that the history survives server restarts.
+pub struct History {
+    file: File,
+    messages: Vec<HistoryMessage>,
+    /// Users that have joined the server at least once.
+    known_users: HashSet<String>,
+    /// IDs of DMs that were sent to an offline user and not delivered yet.
+    undelivered: HashMap<String, Vec<u64>>,
+}
+
+impl History {
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let (file, records) = jsonl::open(path, "history file")?;
+        let mut history = Self {
+            file,
+            messages: vec![],
+            known_users: Default::default(),
+            undelivered: Default::default(),
+        };
+        for record in records {
+            history.apply(record);
+        }
+        Ok(history)
+    }
+
+    pub fn is_known_user(&self, name: &str) -> bool {
+        self.known_users.contains(name)
+    }
+
+    /// Remembers that `name` has joined and returns the DMs that it has missed while it was
+    /// offline. They stay undelivered until [`History::mark_delivered`] is called, so that they
+    /// are not lost if sending them to the user fails.
+    pub fn user_joined(&mut self, name: &str) -> anyhow::Result<Vec<HistoryMessage>> {
+        if !self.is_known_user(name) {
+            self.append(Record::Joined {
+                name: name.to_string(),
+            })?;
+        }
+        let Some(ids) = self.undelivered.get(name) else {
+            return Ok(vec![]);
+        };
+        Ok(ids
+            .iter()
+            .map(|id| self.messages[*id as usize - 1].clone())
+            .collect())
+    }
+
+    /// Marks all DMs sent to `name` so far as delivered.
+    pub fn mark_delivered(&mut self, name: &str) -> anyhow::Result<()> {
+        if !self.undelivered.contains_key(name) {
+            return Ok(());
+        }
+        self.append(Record::Delivered {
+            to: name.to_string(),
+        })
+    }
+
+    pub fn record_broadcast(&mut self, from: &str, message: &str) -> anyhow::Result<()> {
+        self.record(from, None, message, true)
+    }
+
+    /// Stores a DM. If it was not `delivered`, it will be returned from [`History::user_joined`]
+    /// the next time the recipient joins.
+    pub fn record_dm(
+        &mut self,
+        from: &str,
+        to: &str,
+        message: &str,
+        delivered: bool,
+    ) -> anyhow::Result<()> {
+        self.record(from, Some(to), message, delivered)
+    }
+
+    /// Returns up to `limit` messages visible to `user` with an ID larger than `since`.
+    /// A user can see all broadcasts and the DMs that it has sent or received.
+    pub fn page(&self, user: &str, since: u64, limit: usize) -> Vec<HistoryMessage> {
+        self.messages
+            .iter()
+            .skip(since as usize)
+            .filter(|msg| msg.to.is_none() || msg.from == user || msg.to.as_deref() == Some(user))
+            .take(limit.min(MAX_HISTORY_PAGE))
+            .cloned()
+            .collect()
+    }
+
+    fn record(
+        &mut self,
+        from: &str,
+        to: Option<&str>,
+        message: &str,
+        delivered: bool,
+    ) -> anyhow::Result<()> {
+        self.append(Record::Message {
+            message: HistoryMessage {
+                id: self.messages.len() as u64 + 1,
+                from: from.to_string(),
+                to: to.map(|to| to.to_string()),
+                message: message.to_string(),
+            },
+            delivered,
+        })
+    }
+
+    fn append(&mut self, record: Record) -> anyhow::Result<()> {
+        jsonl::append(&mut self.file, &record)?;
+        self.apply(record);
+        Ok(())
+    }
+
+    fn apply(&mut self, record: Record) {
+        match record {
+            Record::Joined { name } => {
+                self.known_users.insert(name);
+            }
+            Record::Message { message, delivered } => {
+                if let (Some(to), false) = (&message.to, delivered) {
+                    self.undelivered
+                        .entry(to.clone())
+                        .or_default()
+                        .push(message.id);
+                }
+                self.messages.push(message);
+            }
+            Record::Delivered { to } => {
+                self.undelivered.remove(&to);
+            }
+        }
+    }
+}
@@ -1,593 +1,1560 @@
-//! TODO: implement a simple chat server
+//! Chat server that uses threads and blocking I/O.
 //!
-//! The chat server will allow users to connect to it through TCP/IP, set their username,
-//! and then send either direct messages (DMs) or broadcasts to other connected users.
-//! The server should properly handle graceful shutdown and client disconnects, and avoid
-//! interleaving unrelated messages.
-//! It should also support concurrency and allow the connection of multiple clients at once.
+//! Users connect through TCP/IP or Unix sockets, join with a unique username and then send DMs,
+//! broadcasts and messages to chat rooms, see [`messages`] for the protocol. Optional features
+//! (history, accounts, an admin, rate limiting, metrics) are enabled through [`ServerOpts`].
 //!
-//! You do not need to implement message encoding and network communication details, as those have
-//! already been implemented for you (see `reader.rs` and `writer.rs`).
-//!
-//! **Do not use `async/await` or any external crates that deal with networking for this assignment.
-//! The existing dependencies of this crate (`anyhow`, `serde`, `serde_json`) should be enough.**
-//!
-//! Try to distribute your code across multiple files (modules), based on the responsibility of
-//! the code (code that deals with the same stuff should generally be in the same module).
-//!
-//! Hint: take a look at the [`TcpStream::shutdown`] function, which can be used to terminate
-//! a TCP/IP connection. It might be useful here :)
-//!
-//! Note: this assignment will probably get extended in the upcoming weeks, so it would be nice if
-//! you implement at least some part of it, so that you can continue improving it later.
-
-/// The following modules were prepared for you. You should not need to modify them.
-///
-/// Take a look at this file to see how should the individual messages be handled
-mod messages;
+//! Every listener and every client is served by its own thread. Messages for a client are
+//! written through its shared writer under a lock, so unrelated messages are never interleaved.
+//! On shutdown, the connections of the clients are shut down with [`std::net::TcpStream::shutdown`] to
+//! wake up their blocked threads.
+
+use crate::client::ClientWriter;
+use crate::clock::Clock;
+use crate::codec::WireFormat;
+use crate::messages::ServerToClientMsg;
+use crate::metrics::Stats;
+use crate::net::{ListenAddr, Listener};
+use crate::state::ServerState;
+use crate::trace::{ConnectionTrace, Event};
+use std::io::ErrorKind;
+use std::net::{Shutdown, SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Registered users and their passwords
+mod accounts;
+/// Handling of a single connected client
+mod client;
+/// Time source of the server
+pub mod clock;
+/// Serialization formats of messages
+pub mod codec;
+/// Persistent message history
+mod history;
+/// HTTP endpoint with the metrics
+mod http;
+/// Files with a JSON line for every record
+mod jsonl;
+/// Messages of the chat protocol, their documentation describes how the server handles them
+pub mod messages;
+/// Counters and gauges describing the running server
+pub mod metrics;
+/// Bans and mutes
+mod moderation;
+/// Listening on TCP/IP and Unix sockets
+pub mod net;
+/// Limiting how fast can clients send messages
+mod rate_limit;
 /// Message reading
-mod reader;
+pub mod reader;
+/// Chat rooms
+mod rooms;
+/// State shared between the threads of the server
+mod state;
+/// Recording of the server for debugging
+pub mod trace;
+/// File transfers between users
+mod transfers;
 /// Message writing
-mod writer;
+pub mod writer;
+
+/// How long does an accepting thread wait after an accept fails for a reason that would most
+/// likely repeat right away.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+#[derive(Clone)]
+pub struct ServerOpts {
+    /// Addresses on which the server listens, there has to be at least one.
+    pub listen: Vec<ListenAddr>,
+    /// Maximum number of clients that can be connected to the server at once, through all
+    /// listeners together.
+    pub max_clients: usize,
+    /// File where the history of broadcasts and DMs is stored.
+    /// If it is `None`, the history is disabled.
+    pub history: Option<PathBuf>,
+    /// Serialization format used for all messages sent between the server and its clients.
+    pub wire_format: WireFormat,
+    /// Maximum size of a single message (in bytes) that a client can send to the server.
+    pub max_message_size: usize,
+    /// User that can kick, ban and mute other users.
+    /// If it is `None`, moderation is disabled.
+    pub admin: Option<Admin>,
+    /// Limit of how fast a single client can send messages.
+    /// If it is `None`, clients are not limited.
+    pub rate_limit: Option<RateLimit>,
+    /// Time source used for rate limits and bans.
+    pub clock: Arc<dyn Clock>,
+    /// Address of an HTTP endpoint that serves the metrics of the server at `/metrics`, in the
+    /// Prometheus text format. The endpoint has no authentication, so it should listen on
+    /// localhost. If it is `None`, the endpoint is disabled.
+    pub metrics: Option<SocketAddr>,
+    /// Accounts that users can register and log in to.
+    /// If it is `None`, anyone can join with any name that is not taken.
+    pub accounts: Option<Accounts>,
+    /// File where the server records every connection and every message that it receives or
+    /// sends, see [`trace::read_trace`].
+    /// The file is replaced when the server starts. If it is `None`, the recording is disabled.
+    pub record: Option<PathBuf>,
+}
 
-#[derive(Copy, Clone)]
-struct ServerOpts {
-    /// Maximum number of clients that can be connected to the server at once.
-    max_clients: usize,
+/// Credentials of the admin of the server.
+#[derive(Clone, Debug)]
+pub struct Admin {
+    pub name: String,
+    /// Secret that the admin has to send in `Join`.
+    pub secret: String,
 }
 
-/// TODO: implement the following function called `run_server`
-/// It should start a chat server on a TCP/IP port assigned to it by the operating system and
-/// return a structure called `RunningServer`. This struct should have a method called `port`,
-/// which returns the port on which the server is running.
-///
-/// The server should implement the messages described in `messages.rs`, see the message comments
-/// for more details.
+/// Settings of the user accounts.
+#[derive(Clone, Debug)]
+pub struct Accounts {
+    /// File where the accounts and the hashes of their passwords are stored.
+    pub database: PathBuf,
+    /// Whether users without an account can join with a name that is not registered.
+    pub allow_anonymous: bool,
+    /// After how many failed logins in a row is an account locked.
+    pub max_failed_logins: u32,
+    /// How long is an account locked after too many failed logins.
+    pub lockout: Duration,
+}
+
+/// Token bucket limit of the messages sent by a single client.
+#[derive(Clone, Debug)]
+pub struct RateLimit {
+    /// How many messages per second can a client send in the long run.
+    pub messages_per_second: f64,
+    /// How many messages can a client send at once after staying quiet for a while.
+    pub burst: u32,
+    /// After how many messages rejected with an error "Rate limited" is the client disconnected.
+    /// The count is reset once the client stays quiet long enough to refill its whole burst.
+    pub max_violations: u32,
+}
+
+/// Representation of a running server
+pub struct RunningServer {
+    /// Addresses on which the server is running, in the order of [`ServerOpts::listen`]
+    addresses: Vec<ListenAddr>,
+    /// State shared with all threads of the server
+    state: Arc<ServerState>,
+    /// Threads that accept new connections, one for each listener
+    accept_threads: Vec<JoinHandle<()>>,
+    /// Address of the metrics endpoint, if it is enabled
+    metrics_address: Option<SocketAddr>,
+    /// Thread that serves the metrics endpoint
+    metrics_thread: Option<JoinHandle<()>>,
+}
+
+impl RunningServer {
+    /// Returns the port of the first TCP/IP listener.
+    ///
+    /// Panics if the server listens only on Unix sockets.
+    pub fn port(&self) -> u16 {
+        self.addresses
+            .iter()
+            .find_map(|address| match address {
+                ListenAddr::Tcp(address) => Some(address.port()),
+                ListenAddr::Unix(_) => None,
+            })
+            .expect("the server does not listen on TCP/IP")
+    }
+
+    /// Returns the addresses on which the server listens, including the ports chosen by the
+    /// operating system.
+    pub fn addresses(&self) -> &[ListenAddr] {
+        &self.addresses
+    }
+
+    /// Returns the address of the metrics endpoint, including the port chosen by the operating
+    /// system, or `None` if it is disabled.
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_address
+    }
+
+    /// Returns the current metrics of the server.
+    pub fn stats(&self) -> Stats {
+        self.state.stats()
+    }
+}
+
+impl Drop for RunningServer {
+    fn drop(&mut self) {
+        self.state.start_shutdown();
+        if let Some(recorder) = &self.state.recorder {
+            recorder.record(Event::Stopped);
+        }
+
+        // The accept threads are blocked in `accept`, wake them up with dummy connections
+        for address in &self.addresses {
+            net::wake_up(address);
+        }
+        if let Some(address) = self.metrics_address {
+            net::wake_up(&ListenAddr::Tcp(address));
+        }
+        for thread in self.accept_threads.drain(..) {
+            let _ = thread.join();
+        }
+        if let Some(thread) = self.metrics_thread.take() {
+            let _ = thread.join();
+        }
+        for address in &self.addresses {
+            net::remove_socket(address);
+        }
+
+        // No new clients can appear now, so we can disconnect the existing ones
+        self.state.disconnect_all();
+        self.state.join_threads();
+    }
+}
+
+/// Starts the chat server on the addresses from [`ServerOpts::listen`]; TCP/IP port 0 lets the
+/// operating system choose a free port, see [`RunningServer::port`].
 ///
 /// # Client connection
-/// When a client connects to the server, it should send a `Join` message.
-/// If it sends anything else, the server should respond with an error "Unexpected message received"
-/// and disconnect the client immediately.
-/// If the user sends a Join message (with a unique username), the server should respond with
-/// the `Welcome` message.
-/// Then it should start receiving requests from the client.
-/// If the client ever sends the `Join` message again, the server should respond with an error
-/// "Unexpected message received" and disconnect the client immediately.
+/// A client has to send `Join` (with a unique username) first, the server responds with
+/// `Welcome`. If it sends anything else first, or sends `Join` again later, the server responds
+/// with an error "Unexpected message received" and disconnects it.
 ///
 /// # Maximum number of clients
-/// When a client connects and there are already `opts.max_clients` other clients connected, the
-/// server should respond with an error "Server is full" and disconnect the client immediately.
-/// Note that if the server is full, the client should be disconnected even before it sends the
-/// `Join` message.
+/// A client that connects while `opts.max_clients` other clients are connected receives an error
+/// "Server is full" and is disconnected right away, before it sends `Join`.
 ///
 /// # Graceful shutdown
-/// When `RunningServer` is dropped, it should:
-/// 1) Stop receiving new TCP/IP connections
-/// 2) Correctly disconnect all connected users
-/// 3) Wait until all threads that it has created has completed executing
-///
-/// Graceful shutdown with threads and blocking I/O is challenging (if you don't consider
-/// `exit()` or `abort()` to be a "graceful" shutdown :) ), because it can be difficult to
-/// communicate with blocked threads.
-/// Think about how you can get around this - can you find some way to "wake" the threads up?
-///
-/// See tests for more details.
-fn run_server(opts: ServerOpts) -> anyhow::Result<RunningServer> { todo!() }
+/// When the [`RunningServer`] is dropped, the server stops accepting new connections, disconnects
+/// all users and waits until all of its threads finish.
+pub fn run_server(opts: ServerOpts) -> anyhow::Result<RunningServer> {
+    if opts.listen.is_empty() {
+        anyhow::bail!("The server has to listen on at least one address");
+    }
+    let mut listeners = vec![];
+    for address in &opts.listen {
+        let listener = Listener::bind(address)
+            .map_err(|error| anyhow::anyhow!("Cannot listen on {address}: {error}"))?;
+        listeners.push(listener);
+    }
+    let addresses = listeners
+        .iter()
+        .map(|listener| listener.local_addr())
+        .collect::<Result<Vec<_>, _>>()?;
+    let metrics_listener = opts
+        .metrics
+        .map(|address| {
+            TcpListener::bind(address)
+                .map_err(|error| anyhow::anyhow!("Cannot serve metrics on {address}: {error}"))
+        })
+        .transpose()?;
+    let metrics_address = metrics_listener
+        .as_ref()
+        .map(|listener| listener.local_addr())
+        .transpose()?;
+
+    let state = Arc::new(ServerState::new(opts)?);
+    let accept_threads = listeners
+        .into_iter()
+        .map(|listener| {
+            let state = state.clone();
+            std::thread::spawn(move || accept_loop(listener, state))
+        })
+        .collect();
+    let metrics_thread = metrics_listener.map(|listener| {
+        let state = state.clone();
+        std::thread::spawn(move || metrics_loop(listener, state))
+    });
+
+    Ok(RunningServer {
+        addresses,
+        state,
+        accept_threads,
+        metrics_address,
+        metrics_thread,
+    })
+}
 
+/// Serves the requests to the metrics endpoint one by one.
+fn metrics_loop(listener: TcpListener, state: Arc<ServerState>) {
+    for stream in listener.incoming() {
+        if state.is_shutting_down() {
+            break;
+        }
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(error) => {
+                accept_failed(error);
+                continue;
+            }
+        };
+        if let Err(error) = http::handle_request(stream, || state.stats().to_prometheus()) {
+            log::debug!("Metrics request failed: {error}");
+        }
+    }
+}
+
+/// Handles a failed accept. The connection might have been aborted by the client already, which
+/// is not an error of the server. Other errors (e.g. too many open files) would most likely
+/// happen again right away, so the accepting thread waits a bit first.
+fn accept_failed(error: std::io::Error) {
+    if !matches!(
+        error.kind(),
+        ErrorKind::Interrupted | ErrorKind::ConnectionAborted
+    ) {
+        log::warn!("Cannot accept a connection: {error}");
+        std::thread::sleep(ACCEPT_BACKOFF);
+    }
+}
+
+fn accept_loop(listener: Listener, state: Arc<ServerState>) {
+    loop {
+        let client = listener.accept();
+        if state.is_shutting_down() {
+            break;
+        }
+        let (stream, peer) = match client {
+            Ok(client) => client,
+            Err(error) => {
+                accept_failed(error);
+                continue;
+            }
+        };
+        let trace = ConnectionTrace::connected(state.recorder.as_ref());
+        let id = match state.add_connection(&stream) {
+            Ok(Some(id)) => id,
+            Ok(None) => {
+                log::info!("Rejecting client {peer}, the server is full");
+                let mut writer = ClientWriter::new(stream, &state, trace);
+                let _ = writer.write(ServerToClientMsg::Error("Server is full".to_string()));
+                let stream = writer.inner();
+                let _ = stream.shutdown(Shutdown::Both);
+                continue;
+            }
+            Err(_) => continue,
+        };
+
+        let thread = std::thread::spawn({
+            let state = state.clone();
+            move || {
+                log::debug!("Client {peer} connected");
+                if let Err(error) = client::handle_client(&state, stream, trace) {
+                    log::debug!("Client {peer} failed: {error:#}");
+                }
+                log::debug!("Client {peer} disconnected");
+                state.remove_connection(id);
+            }
+        });
+        state.add_thread(thread);
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use crate::clock::{Clock, SystemClock};
+    use crate::codec::WireFormat;
+    use crate::history::History;
     use crate::messages::{ClientToServerMsg, ServerToClientMsg};
-    use crate::reader::MessageReader;
+    use crate::net::{ListenAddr, Stream};
+    use crate::reader::{MessageReader, DEFAULT_MAX_MESSAGE_SIZE};
+    use crate::trace::{read_trace, Event, Settings};
     use crate::writer::MessageWriter;
-    use crate::{run_server, RunningServer, ServerOpts};
-    use std::io::{Read, Write};
-    use std::net::{Shutdown, TcpStream};
-    use std::sync::atomic::{AtomicUsize, Ordering};
-    use std::sync::{Arc, Barrier, Mutex};
-    use std::thread::spawn;
-    use std::time::Duration;
+    use crate::{run_server, Accounts, Admin, RateLimit, RunningServer, ServerOpts};
+    use sha2::{Digest, Sha256};
+    use std::io::{Cursor, Read, Write};
+    use std::net::{Shutdown, SocketAddr, TcpStream};
+    use std::os::unix::net::UnixStream;
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+    use tempfile::TempDir;
 
-    // If you're struggling with this test, comment it and implement the rest of the
-    // functionality first.
     #[test]
-    fn empty_server_shuts_down() {
-        run_test(opts(2), |_| Ok(()));
-    }
-
-    #[test]
-    fn max_clients() {
+    fn room_create() {
         run_test(opts(2), |server| {
-            let _client = server.client();
-            let _client2 = server.client();
-
-            let mut client3 = server.client();
-            client3.expect_error("Server is full");
-            client3.check_closed();
+            let mut client = server.client();
+            client.join("Mia");
+            client.create_room("Rustaceans");
+            assert_eq!(client.list_rooms(), vec!["Rustaceans".to_string()]);
 
             Ok(())
         });
     }
 
     #[test]
-    fn max_clients_after_client_leaves() {
+    fn room_create_existing() {
         run_test(opts(2), |server| {
-            let _client = server.client();
-            let client2 = server.client();
-            client2.close();
-
-            sleep(1000);
-
-            let mut client3 = server.client();
-            client3.join("Foo");
-
-            Ok(())
-        });
-    }
-
-    #[test]
-    fn max_clients_herd() {
-        let max_clients = 5;
-        run_test(opts(max_clients), |server| {
-            let thread_count = 50;
-
-            let server = Arc::new(server);
-            let barrier = Arc::new(Barrier::new(thread_count));
-
-            let errors = Arc::new(AtomicUsize::new(0));
-            let successes = Arc::new(AtomicUsize::new(0));
-
-            let joined_clients = Arc::new(Mutex::new(vec![]));
-            std::thread::scope(|s| {
-                for thread_id in 0..thread_count {
-                    let barrier = barrier.clone();
-                    let server = server.clone();
-                    let errors = errors.clone();
-                    let successes = successes.clone();
-                    let joined_clients = joined_clients.clone();
-                    s.spawn(move || {
-                        barrier.wait();
-                        let mut client = server.client();
-                        client.try_send(ClientToServerMsg::Join {
-                            name: format!("Thread {thread_id}"),
-                        });
-                        match client.recv() {
-                            ServerToClientMsg::Error(_) => {
-                                errors.fetch_add(1, Ordering::SeqCst);
-                            }
-                            ServerToClientMsg::Welcome => {
-                                successes.fetch_add(1, Ordering::SeqCst);
-                                // Make sure that the client doesn't disconnect
-                                joined_clients.lock().unwrap().push(client);
-                            }
-                            msg => {
-                                panic!("Unexpected message {msg:?}");
-                            }
-                        }
-                    });
-                }
+            let mut mia = server.client();
+            mia.join("Mia");
+            mia.create_room("Rustaceans");
+
+            let mut leo = server.client();
+            leo.join("Leo");
+            leo.send(ClientToServerMsg::CreateRoom {
+                room: "Rustaceans".to_string(),
             });
-            assert_eq!(errors.load(Ordering::SeqCst), thread_count - max_clients);
-            assert_eq!(successes.load(Ordering::SeqCst), max_clients);
-
-            drop(joined_clients);
+            leo.expect_error("Room Rustaceans already exists");
 
             Ok(())
         });
     }
 
     #[test]
-    fn list_users_before_join() {
+    fn room_join_nonexistent() {
         run_test(opts(2), |server| {
             let mut client = server.client();
-            client.send(ClientToServerMsg::ListUsers);
-            client.expect_error("Unexpected message received");
+            client.join("Mia");
+            client.send(ClientToServerMsg::JoinRoom {
+                room: "Gophers".to_string(),
+            });
+            client.expect_error("Room Gophers does not exist");
 
             Ok(())
         });
     }
 
     #[test]
-    fn duplicated_join() {
+    fn room_join_twice() {
         run_test(opts(2), |server| {
             let mut client = server.client();
-            client.join("Foo");
-            client.send(ClientToServerMsg::Join {
-                name: "Bar".to_string(),
+            client.join("Mia");
+            client.create_room("Rustaceans");
+            client.send(ClientToServerMsg::JoinRoom {
+                room: "Rustaceans".to_string(),
             });
-            client.expect_error("Unexpected message received");
+            client.expect_error("You are already in room Rustaceans");
 
             Ok(())
         });
     }
 
     #[test]
-    fn error_then_disconnect() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.join("Foo");
-            client.send(ClientToServerMsg::Join {
-                name: "Bar".to_string(),
-            });
-            client.close();
+    fn room_message_reaches_only_members() {
+        run_test(opts(10), |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            mia.create_room("Team A");
+
+            let mut leo = server.client();
+            leo.join("Leo");
+            leo.join_room("Team A");
+
+            let mut zoe = server.client();
+            zoe.join("Zoe");
+            zoe.create_room("Team B");
 
-            let mut client2 = server.client();
-            client2.join("Bar");
+            mia.room_message("Team A", "Standup in 5 minutes");
+            mia.ping();
+
+            leo.expect_room_message("Team A", "Mia", "Standup in 5 minutes");
+            // Zoe is not in the room, so the next thing she receives is the response to her ping
+            zoe.ping();
 
             Ok(())
         });
     }
 
     #[test]
-    fn duplicated_username() {
+    fn room_message_not_member() {
         run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.join("Foo");
+            let mut mia = server.client();
+            mia.join("Mia");
+            mia.create_room("Team A");
 
-            let mut client2 = server.client();
-            client2.send(ClientToServerMsg::Join {
-                name: "Foo".to_string(),
-            });
-            client2.expect_error("Username already taken");
+            let mut leo = server.client();
+            leo.join("Leo");
+            leo.room_message("Team A", "Let me in!");
+            leo.expect_error("You are not in room Team A");
 
             Ok(())
         });
     }
 
     #[test]
-    fn ping() {
+    fn room_leave() {
         run_test(opts(2), |server| {
-            let mut luca = server.client();
-            luca.join("Luca");
-            luca.ping();
+            let mut mia = server.client();
+            mia.join("Mia");
+            mia.create_room("Team A");
+
+            let mut leo = server.client();
+            leo.join("Leo");
+            leo.join_room("Team A");
+            leo.leave_room("Team A");
+
+            mia.room_message("Team A", "Anyone?");
+            mia.ping();
+            leo.ping();
+
+            leo.send(ClientToServerMsg::LeaveRoom {
+                room: "Team A".to_string(),
+            });
+            leo.expect_error("You are not in room Team A");
 
             Ok(())
         });
     }
 
     #[test]
-    fn ping_before_join() {
+    fn room_removed_when_empty() {
         run_test(opts(2), |server| {
             let mut client = server.client();
-            client.send(ClientToServerMsg::Ping);
-            client.expect_error("Unexpected message received");
+            client.join("Mia");
+            client.create_room("Team A");
+            client.leave_room("Team A");
+            assert!(client.list_rooms().is_empty());
 
             Ok(())
         });
     }
 
     #[test]
-    fn list_users_reconnect() {
+    fn room_membership_removed_on_disconnect() {
         run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.join("Foo");
-            client.close();
+            let mut mia = server.client();
+            mia.join("Mia");
+            mia.create_room("Team A");
 
-            let mut client = server.client();
-            client.join("Foo");
-            assert_eq!(client.list_users(), vec!["Foo".to_string()]);
+            let mut leo = server.client();
+            leo.join("Leo");
+            leo.create_room("Team B");
+            leo.join_room("Team A");
+            leo.close();
+
+            sleep(1000);
+
+            // Team B had no other members, so it should be gone
+            assert_eq!(mia.list_rooms(), vec!["Team A".to_string()]);
+
+            // Leo should not receive anything anymore, and it can join again as a new member
+            let mut leo = server.client();
+            leo.join("Leo");
+            leo.join_room("Team A");
 
             Ok(())
         });
     }
 
     #[test]
-    fn list_users_self() {
-        run_test(opts(2), |server| {
+    fn codec_round_trip() {
+        for format in WireFormat::ALL {
+            let messages = vec![
+                ClientToServerMsg::Join {
+                    name: "Mia".to_string(),
+                    secret: None,
+                    password: None,
+                },
+                ClientToServerMsg::Ping,
+                ClientToServerMsg::Ban {
+                    name: "Leo".to_string(),
+                    duration: 60,
+                },
+                ClientToServerMsg::SendDM {
+                    to: "Leo".to_string(),
+                    message: "Hi 👋".to_string(),
+                },
+                ClientToServerMsg::History {
+                    since: 5,
+                    limit: 10,
+                },
+            ];
+
+            let mut writer = MessageWriter::<ClientToServerMsg, _, _>::with_codec(vec![], format);
+            for msg in &messages {
+                writer.write(msg.clone()).unwrap();
+            }
+            let reader = MessageReader::<ClientToServerMsg, _, _>::with_codec(
+                Cursor::new(writer.into_inner()),
+                format,
+            );
+            let received = reader.collect::<anyhow::Result<Vec<_>>>().unwrap();
+            assert_eq!(received, messages, "{format:?}");
+        }
+    }
+
+    #[test]
+    fn chat_over_every_codec() {
+        for format in WireFormat::ALL {
+            let opts = ServerOpts {
+                wire_format: format,
+                ..opts(2)
+            };
+            run_test(opts, |server| {
+                let mut mia = server.client();
+                mia.join("Mia");
+                let mut leo = server.client();
+                leo.join("Leo");
+
+                mia.dm("Leo", "Hi");
+                leo.expect_message("Mia", "Hi");
+                assert_eq!(leo.list_users(), vec!["Leo".to_string(), "Mia".to_string()]);
+                leo.dm("Zoe", "Hi");
+                leo.expect_error("User Zoe does not exist");
+
+                Ok(())
+            });
+        }
+    }
+
+    #[test]
+    fn admin_invalid_secret() {
+        run_test(opts_with_admin(3), |server| {
+            let mut client = server.client();
+            client.send(ClientToServerMsg::Join {
+                name: "Mod".to_string(),
+                secret: Some("hunter3".to_string()),
+                password: None,
+            });
+            client.expect_error("Invalid secret");
+            client.check_closed();
+
             let mut client = server.client();
-            client.join("Martin");
-            assert_eq!(client.list_users(), vec!["Martin".to_string()]);
+            client.join_with_secret("Mod", None);
+            client.expect_error("Invalid secret");
+            client.check_closed();
+
+            // Other users do not need a secret
+            let mut leo = server.client();
+            leo.join("Leo");
 
             Ok(())
         });
     }
 
     #[test]
-    fn list_users_ignore_not_joined_users() {
-        run_test(opts(2), |server| {
-            let _client = server.client();
-            let mut client2 = server.client();
-            client2.join("Joe");
-            assert_eq!(client2.list_users(), vec!["Joe".to_string()]);
+    fn moderation_requires_admin() {
+        run_test(opts_with_admin(3), |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut leo = server.client();
+            leo.join("Leo");
+
+            leo.send(ClientToServerMsg::Kick {
+                name: "Mia".to_string(),
+            });
+            leo.expect_error("You are not an admin");
+            leo.send(ClientToServerMsg::Ban {
+                name: "Mia".to_string(),
+                duration: 60,
+            });
+            leo.expect_error("You are not an admin");
+            leo.send(ClientToServerMsg::Mute {
+                name: "Mia".to_string(),
+            });
+            leo.expect_error("You are not an admin");
+
+            mia.dm("Leo", "Still here");
+            leo.expect_message("Mia", "Still here");
 
             Ok(())
         });
     }
 
     #[test]
-    fn list_users_after_error() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.join("Terrence");
+    fn moderate_yourself() {
+        run_test(opts_with_admin(2), |server| {
+            let mut admin = server.admin();
+            admin.send(ClientToServerMsg::Kick {
+                name: "Mod".to_string(),
+            });
+            admin.expect_error("Cannot moderate yourself");
+            admin.send(ClientToServerMsg::Ban {
+                name: "Mod".to_string(),
+                duration: 60,
+            });
+            admin.expect_error("Cannot moderate yourself");
+            admin.ping();
 
-            let mut client2 = server.client();
-            client2.join("Joe");
+            Ok(())
+        });
+    }
 
-            client.send(ClientToServerMsg::Join {
-                name: "Barbara".to_string(),
+    #[test]
+    fn kick() {
+        run_test(opts_with_admin(3), |server| {
+            let mut admin = server.admin();
+            let mut leo = server.client();
+            leo.join("Leo");
+
+            admin.send(ClientToServerMsg::Kick {
+                name: "Leo".to_string(),
             });
+            leo.expect_error("You have been kicked");
+            leo.check_closed();
 
             sleep(1000);
 
-            assert_eq!(client2.list_users(), vec!["Joe".to_string()]);
+            assert_eq!(admin.list_users(), vec!["Mod".to_string()]);
+
+            // A kicked user can join again right away
+            let mut leo = server.client();
+            leo.join("Leo");
 
             Ok(())
         });
     }
 
     #[test]
-    fn list_users() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.join("Terrence");
-
-            let mut client2 = server.client();
-            client2.join("Joe");
-            assert_eq!(
-                client2.list_users(),
-                vec!["Joe".to_string(), "Terrence".to_string()]
-            );
-            client2.close();
+    fn kick_nonexistent_user() {
+        run_test(opts_with_admin(2), |server| {
+            let mut admin = server.admin();
+            admin.send(ClientToServerMsg::Kick {
+                name: "Zoe".to_string(),
+            });
+            admin.expect_error("User Zoe does not exist");
 
-            sleep(1000);
+            Ok(())
+        });
+    }
 
-            assert_eq!(client.list_users(), vec!["Terrence".to_string()]);
+    #[test]
+    fn ban() {
+        run_test(opts_with_admin(3), |server| {
+            let mut admin = server.admin();
+            let mut leo = server.client();
+            leo.join("Leo");
+
+            admin.send(ClientToServerMsg::Ban {
+                name: "Leo".to_string(),
+                duration: 60,
+            });
+            leo.expect_error("You are banned");
+            leo.check_closed();
+
+            let mut leo = server.client();
+            leo.send(ClientToServerMsg::Join {
+                name: "Leo".to_string(),
+                secret: None,
+                password: None,
+            });
+            leo.expect_error("You are banned");
+            leo.check_closed();
 
             Ok(())
         });
     }
 
     #[test]
-    fn dm_nonexistent_user() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.join("Mark");
-            client.dm("Fiona", "Hi");
-            client.expect_error("User Fiona does not exist");
+    fn ban_expires() {
+        let clock = ManualClock::new();
+        let opts = ServerOpts {
+            clock: Arc::new(clock.clone()),
+            ..opts_with_admin(3)
+        };
+        run_test(opts, |server| {
+            let mut admin = server.admin();
+            // Users can be banned before they ever join
+            admin.send(ClientToServerMsg::Ban {
+                name: "Zoe".to_string(),
+                duration: 1,
+            });
+            admin.ping();
+
+            let mut zoe = server.client();
+            zoe.join_with_secret("Zoe", None);
+            zoe.expect_error("You are banned");
+            zoe.check_closed();
+
+            clock.advance(Duration::from_secs(1));
+
+            let mut zoe = server.client();
+            zoe.join("Zoe");
 
             Ok(())
         });
     }
 
     #[test]
-    fn dm_self() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.join("Xal'atath");
-            client.dm("Xal'atath", "I'm so lonely :(");
-            client.expect_error("Cannot send a DM to yourself");
+    fn mute() {
+        run_test(opts_with_admin(3), |server| {
+            let mut admin = server.admin();
+            let mut leo = server.client();
+            leo.join("Leo");
+
+            admin.send(ClientToServerMsg::Mute {
+                name: "Leo".to_string(),
+            });
+            admin.ping();
+
+            leo.dm("Mod", "Why?");
+            leo.expect_error("You are muted");
+            leo.send(ClientToServerMsg::Broadcast {
+                message: "Help!".to_string(),
+            });
+            leo.expect_error("You are muted");
+            // Everything else still works
+            assert_eq!(leo.list_users(), vec!["Leo".to_string(), "Mod".to_string()]);
+
+            // The mute survives a reconnect
+            leo.close();
+            sleep(1000);
+            let mut leo = server.client();
+            leo.join("Leo");
+            leo.dm("Mod", "Hi again");
+            leo.expect_error("You are muted");
+
+            // Nothing reached the admin
+            admin.ping();
 
             Ok(())
         });
     }
 
     #[test]
-    fn dm_other() {
-        run_test(opts(2), |server| {
-            let mut terrence = server.client();
-            terrence.join("Terrence");
+    fn rate_limit_burst() {
+        let clock = ManualClock::new();
+        run_test(opts_with_rate_limit(&clock, 3, 10), |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            for _ in 0..3 {
+                mia.ping();
+            }
+            mia.send(ClientToServerMsg::Ping);
+            mia.expect_error("Rate limited");
 
-            let mut joe = server.client();
-            joe.join("Joe");
+            // One message per second
+            clock.advance(Duration::from_secs(1));
+            mia.ping();
+            mia.send(ClientToServerMsg::Ping);
+            mia.expect_error("Rate limited");
 
-            terrence.dm("Joe", "How you doin'");
-            joe.expect_message("Terrence", "How you doin'");
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn rate_limited_messages_are_dropped() {
+        let clock = ManualClock::new();
+        run_test(opts_with_rate_limit(&clock, 2, 10), |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut leo = server.client();
+            leo.join("Leo");
+
+            mia.dm("Leo", "1");
+            mia.dm("Leo", "2");
+            mia.dm("Leo", "3");
+            mia.expect_error("Rate limited");
+
+            leo.expect_message("Mia", "1");
+            leo.expect_message("Mia", "2");
+            leo.ping();
 
             Ok(())
         });
     }
 
     #[test]
-    fn dm_spam() {
-        run_test(opts(2), |server| {
+    fn rate_limit_disconnects_flooders() {
+        let clock = ManualClock::new();
+        run_test(opts_with_rate_limit(&clock, 10, 5), |server| {
             let mut diana = server.client();
             diana.join("Diana");
-
             let mut francesca = server.client();
             francesca.join("Francesca");
 
-            let barrier = Arc::new(Barrier::new(2));
-            let barrier2 = barrier.clone();
+            for _ in 0..15 {
+                diana.dm("Francesca", "Can I borrow your brush? Pleeeeeease :(((");
+            }
+            for _ in 0..5 {
+                diana.expect_error("Rate limited");
+            }
+            diana.check_closed();
+
+            for _ in 0..10 {
+                francesca.expect_message("Diana", "Can I borrow your brush? Pleeeeeease :(((");
+            }
+            francesca.ping();
 
-            let count = 100000;
+            Ok(())
+        });
+    }
 
-            // Let's say that someone is spamming you...
-            let t1 = spawn(move || {
-                barrier.wait();
+    #[test]
+    fn rate_limit_forgives_violations() {
+        let clock = ManualClock::new();
+        run_test(opts_with_rate_limit(&clock, 2, 2), |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+
+            mia.ping();
+            mia.ping();
+            mia.send(ClientToServerMsg::Ping);
+            mia.expect_error("Rate limited");
+
+            // The whole burst is refilled, so the violation is forgotten
+            clock.advance(Duration::from_secs(2));
+            mia.ping();
+            mia.ping();
+            mia.send(ClientToServerMsg::Ping);
+            mia.expect_error("Rate limited");
+
+            clock.advance(Duration::from_secs(1));
+            mia.ping();
 
-                for _ in 0..count {
-                    diana.dm("Francesca", "Can I borrow your brush? Pleeeeeease :(((");
-                }
-            });
+            Ok(())
+        });
+    }
 
-            // ...so you get angry, and start spamming them back.
-            // But you make a critical *error*, because you're sending the message
-            // to the wrong account.
-            // Can your chat server handle that?
-            let t2 = spawn(move || {
-                // Sync the threads a little bit
-                barrier2.wait();
-
-                for _ in 0..count {
-                    francesca.dm("Daina", "NO! Get your own!");
-                    match francesca.recv() {
-                        ServerToClientMsg::Message { from, message } => {
-                            assert_eq!(from, "Diana");
-                            assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
-                        }
-                        ServerToClientMsg::Error(error) => {
-                            assert_eq!(error, "User Daina does not exist");
-                        }
-                        msg => panic!("Unexpected message {msg:?}"),
-                    }
-                }
-                // Francesca should receive count * 2 messages, `count` from Diana and `count`
-                // error messages
-                for _ in 0..count {
-                    match francesca.recv() {
-                        ServerToClientMsg::Message { from, message } => {
-                            assert_eq!(from, "Diana");
-                            assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
-                        }
-                        ServerToClientMsg::Error(error) => {
-                            assert_eq!(error, "User Daina does not exist");
-                        }
-                        msg => panic!("Unexpected message {msg:?}"),
-                    }
-                }
+    #[test]
+    fn unix_socket_chat() {
+        let dir = TempDir::new().unwrap();
+        run_test(opts_with_unix_socket(2, &dir), |server| {
+            let mut mia = server.unix_client();
+            mia.join("Mia");
+            let mut leo = server.client();
+            leo.join("Leo");
+
+            leo.send(ClientToServerMsg::SendDM {
+                to: "Mia".to_string(),
+                message: "hi".to_string(),
+            });
+            mia.expect_message("Leo", "hi");
+            mia.send(ClientToServerMsg::SendDM {
+                to: "Leo".to_string(),
+                message: "hello".to_string(),
             });
-            t1.join().unwrap();
-            t2.join().unwrap();
+            leo.expect_message("Mia", "hello");
 
             Ok(())
         });
     }
 
     #[test]
-    fn dm_spam_2() {
-        // Meanwhile, in a parallel universe...
-        run_test(opts(2), |server| {
-            let mut diana = server.client();
-            diana.join("Diana");
+    fn unix_socket_shares_max_clients() {
+        let dir = TempDir::new().unwrap();
+        run_test(opts_with_unix_socket(2, &dir), |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut leo = server.unix_client();
+            leo.join("Leo");
+
+            server.unix_client().expect_error("Server is full");
+            server.client().expect_error("Server is full");
+
+            mia.close();
+            sleep(100);
+            let mut kim = server.unix_client();
+            kim.join("Kim");
 
-            let mut francesca = server.client();
-            francesca.join("Francesca");
+            Ok(())
+        });
+    }
 
-            let barrier = Arc::new(Barrier::new(2));
-            let barrier2 = barrier.clone();
+    #[test]
+    fn unix_socket_is_removed() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("chat.sock");
+        run_test(opts_with_unix_socket(2, &dir), |server| {
+            assert!(path.exists());
+            server.unix_client().join("Mia");
+            Ok(())
+        });
+        assert!(!path.exists());
+    }
 
-            let count = 100000;
+    #[test]
+    fn unix_socket_replaces_stale_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("chat.sock");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        run_test(opts_with_unix_socket(2, &dir), |server| {
+            server.unix_client().join("Mia");
+            Ok(())
+        });
+    }
 
-            // Let's say that someone is spamming you...
-            let t1 = spawn(move || {
-                barrier.wait();
+    #[test]
+    fn listen_on_no_address() {
+        let opts = ServerOpts {
+            listen: vec![],
+            ..opts(2)
+        };
+        assert!(run_server(opts).is_err());
+    }
 
-                for _ in 0..count {
-                    diana.dm("Francesca", "Can I borrow your brush? Pleeeeeease :(((");
-                }
+    #[test]
+    fn stats_count_messages() {
+        run_test(opts(2), |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut leo = server.client();
+            leo.join("Leo");
+
+            mia.ping();
+            mia.send(ClientToServerMsg::SendDM {
+                to: "Leo".to_string(),
+                message: "hi".to_string(),
             });
+            leo.expect_message("Mia", "hi");
+            for to in ["Eva", "Zoe"] {
+                mia.send(ClientToServerMsg::SendDM {
+                    to: to.to_string(),
+                    message: "hi".to_string(),
+                });
+                mia.expect_error(&format!("User {to} does not exist"));
+            }
 
-            // ...so you get angry, and start spamming them back.
-            // But you make a critical *error*, because you push the wrong button and start
-            // sending pings to the server instead.
-            // Can your chat server handle that?
-            let t2 = spawn(move || {
-                // Sync the threads a little bit
-                barrier2.wait();
-
-                for _ in 0..count {
-                    francesca.send(ClientToServerMsg::Ping);
-                    match francesca.recv() {
-                        ServerToClientMsg::Message { from, message } => {
-                            assert_eq!(from, "Diana");
-                            assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
-                        }
-                        ServerToClientMsg::Pong => {}
-                        msg => panic!("Unexpected message {msg:?}"),
-                    }
-                }
-                // Francesca should receive count * 2 messages, `count` from Diana and `count`
-                // pong messages
-                for _ in 0..count {
-                    match francesca.recv() {
-                        ServerToClientMsg::Message { from, message } => {
-                            assert_eq!(from, "Diana");
-                            assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
-                        }
-                        ServerToClientMsg::Pong => {}
-                        msg => panic!("Unexpected message {msg:?}"),
-                    }
+            let stats = server.stats();
+            assert_eq!(stats.messages_received["Join"], 2);
+            assert_eq!(stats.messages_received["Ping"], 1);
+            assert_eq!(stats.messages_received["SendDM"], 3);
+            assert_eq!(stats.messages_sent["Welcome"], 2);
+            assert_eq!(stats.messages_sent["Pong"], 1);
+            assert_eq!(stats.messages_sent["Message"], 1);
+            assert_eq!(stats.messages_sent["Error"], 2);
+            assert_eq!(stats.errors["User <name> does not exist"], 2);
+            assert!(stats.bytes_received > 0);
+            assert!(stats.bytes_sent > 0);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn stats_gauges() {
+        run_test(opts(3), |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut leo = server.client();
+            leo.join("Leo");
+            let _client = server.client();
+            sleep(100);
+
+            let stats = server.stats();
+            assert_eq!(stats.connected_clients, 3);
+            assert_eq!(stats.joined_users, 2);
+
+            leo.close();
+            sleep(100);
+            let stats = server.stats();
+            assert_eq!(stats.connected_clients, 2);
+            assert_eq!(stats.joined_users, 1);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn stats_server_full() {
+        run_test(opts(1), |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            server.client().expect_error("Server is full");
+
+            assert_eq!(server.stats().errors["Server is full"], 1);
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn metrics_endpoint() {
+        let opts = ServerOpts {
+            metrics: Some(SocketAddr::from(([127, 0, 0, 1], 0))),
+            ..opts(2)
+        };
+        run_test(opts, |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            mia.ping();
+
+            let address = server.metrics_address().expect("metrics are disabled");
+            let response = http_get(address, "/metrics");
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.contains("\nchat_joined_users 1\n"));
+            assert!(response.contains("\nchat_messages_received_total{type=\"Ping\"} 1\n"));
+            assert!(response.contains("\n# TYPE chat_busy_seconds_total counter\n"));
+
+            assert!(http_get(address, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn file_transfer() {
+        run_test(opts(3), |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut leo = server.client();
+            leo.join("Leo");
+
+            let file = b"fn main() {}\n".repeat(100);
+            let id = mia.offer_file("Leo", "main.rs", &file);
+            let sha256 = match leo.recv() {
+                ServerToClientMsg::FileOffer {
+                    id: offer_id,
+                    from,
+                    name,
+                    size,
+                    sha256,
+                } => {
+                    assert_eq!((offer_id, from.as_str()), (id, "Mia"));
+                    assert_eq!((name.as_str(), size), ("main.rs", file.len() as u64));
+                    sha256
                 }
-            });
-            t2.join().unwrap();
-            t1.join().unwrap();
+                msg => panic!("Unexpected message {msg:?}"),
+            };
+            leo.send(ClientToServerMsg::AcceptFile { id });
+            mia.expect_file_accepted(id);
+
+            for (index, chunk) in file.chunks(500).enumerate() {
+                mia.send_chunk(id, index as u64, chunk);
+            }
+            let mut received = vec![];
+            for index in 0..file.chunks(500).len() {
+                received.extend(leo.expect_chunk(id, index as u64));
+            }
+            assert_eq!(format!("{:x}", Sha256::digest(&received)), sha256);
+
+            // The transfer is finished
+            mia.send_chunk(id, 3, b"more");
+            mia.expect_error(&format!("Unknown transfer {id}"));
 
             Ok(())
         });
     }
 
     #[test]
-    fn broadcast_empty() {
-        run_test(opts(2), |server| {
-            let mut ji = server.client();
-            ji.join("Ji");
-            ji.send(ClientToServerMsg::Broadcast {
-                message: "Haaaaaai!".to_string(),
+    fn file_transfer_interleaves_with_messages() {
+        run_test(opts(3), |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut leo = server.client();
+            leo.join("Leo");
+            let mut zoe = server.client();
+            zoe.join("Zoe");
+
+            let id = mia.offer_file("Leo", "notes.txt", b"abcdef");
+            leo.expect_file_offer(id);
+            leo.send(ClientToServerMsg::AcceptFile { id });
+            mia.expect_file_accepted(id);
+
+            mia.send_chunk(id, 0, b"abc");
+            mia.ping();
+            zoe.dm("Leo", "Are you there?");
+            zoe.ping();
+            mia.send_chunk(id, 1, b"def");
+            mia.ping();
+
+            assert_eq!(leo.expect_chunk(id, 0), b"abc");
+            leo.expect_message("Zoe", "Are you there?");
+            assert_eq!(leo.expect_chunk(id, 1), b"def");
+            leo.ping();
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn file_transfer_invalid_requests() {
+        run_test(opts(3), |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut leo = server.client();
+            leo.join("Leo");
+
+            mia.send(ClientToServerMsg::OfferFile {
+                to: "Mia".to_string(),
+                name: "a.txt".to_string(),
+                size: 1,
+                sha256: String::new(),
             });
-            ji.ping();
+            mia.expect_error("Cannot send a file to yourself");
+            mia.send(ClientToServerMsg::OfferFile {
+                to: "Zoe".to_string(),
+                name: "a.txt".to_string(),
+                size: 1,
+                sha256: String::new(),
+            });
+            mia.expect_error("User Zoe does not exist");
+
+            let id = mia.offer_file("Leo", "a.txt", b"a");
+            leo.expect_file_offer(id);
+            mia.send_chunk(id, 0, b"a");
+            mia.expect_error(&format!("Transfer {id} was not accepted"));
+            // Only the recipient can accept the file
+            mia.send(ClientToServerMsg::AcceptFile { id });
+            mia.expect_error(&format!("Unknown transfer {id}"));
+            leo.send(ClientToServerMsg::AcceptFile { id: id + 1 });
+            leo.expect_error(&format!("Unknown transfer {}", id + 1));
+            // Only the sender can send chunks
+            leo.send(ClientToServerMsg::AcceptFile { id });
+            mia.expect_file_accepted(id);
+            leo.send_chunk(id, 0, b"a");
+            leo.expect_error(&format!("Unknown transfer {id}"));
 
             Ok(())
         });
     }
 
     #[test]
-    fn broadcast() {
-        run_test(opts(10), |server| {
-            let mut niko = server.client();
-            niko.join("Niko");
-
-            let users: Vec<Client> = (0..5)
-                .map(|i| {
-                    let mut client = server.client();
-                    client.join(&format!("NPC {i}"));
-                    client
-                })
-                .collect();
+    fn file_transfer_unexpected_chunk() {
+        run_test(opts(3), |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut leo = server.client();
+            leo.join("Leo");
+
+            let id = mia.offer_file("Leo", "a.txt", b"abcdef");
+            leo.expect_file_offer(id);
+            leo.send(ClientToServerMsg::AcceptFile { id });
+            mia.expect_file_accepted(id);
+
+            mia.send_chunk(id, 0, b"abc");
+            mia.send_chunk(id, 2, b"def");
+            let reason = format!("Unexpected chunk 2 of transfer {id}");
+            mia.expect_file_cancelled(id, &reason);
+            leo.expect_chunk(id, 0);
+            leo.expect_file_cancelled(id, &reason);
+
+            // A chunk that makes the file larger than announced cancels the transfer as well
+            let id = mia.offer_file("Leo", "b.txt", b"abc");
+            leo.expect_file_offer(id);
+            leo.send(ClientToServerMsg::AcceptFile { id });
+            mia.expect_file_accepted(id);
+            mia.send_chunk(id, 0, b"abcd");
+            let reason = format!("Unexpected chunk 0 of transfer {id}");
+            mia.expect_file_cancelled(id, &reason);
+            leo.expect_file_cancelled(id, &reason);
 
-            niko.send(ClientToServerMsg::Broadcast {
-                message: "Borrow this!".to_string(),
-            });
-            niko.ping();
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn file_transfer_cancel() {
+        run_test(opts(3), |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut leo = server.client();
+            leo.join("Leo");
+
+            let id = mia.offer_file("Leo", "a.txt", b"abc");
+            leo.expect_file_offer(id);
+            leo.send(ClientToServerMsg::CancelFile { id });
+            mia.expect_file_cancelled(id, "Cancelled by Leo");
+            leo.send(ClientToServerMsg::AcceptFile { id });
+            leo.expect_error(&format!("Unknown transfer {id}"));
+
+            // A disconnect cancels the transfers of the user
+            let id = mia.offer_file("Leo", "b.txt", b"abc");
+            leo.expect_file_offer(id);
+            leo.send(ClientToServerMsg::AcceptFile { id });
+            mia.expect_file_accepted(id);
+            leo.close();
+            mia.expect_file_cancelled(id, "Leo disconnected");
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn accounts_register_and_login() {
+        let dir = TempDir::new().unwrap();
+        run_test(
+            opts_with_accounts(&dir, &ManualClock::new(), true),
+            |server| {
+                let mut mia = server.client();
+                mia.register("Mia", "correct horse");
+                assert!(matches!(mia.recv(), ServerToClientMsg::Welcome { .. }));
+                mia.close();
+                sleep(100);
+
+                let mut mia = server.client();
+                mia.join_with_secret("Mia", None);
+                mia.expect_error("Username is reserved");
+                let mut mia = server.client();
+                mia.join_with_password("Mia", "battery staple");
+                mia.expect_error("Invalid username or password");
+                let mut mia = server.client();
+                mia.register("Mia", "battery staple");
+                mia.expect_error("Username already registered");
+
+                let mut mia = server.client();
+                mia.join_with_password("Mia", "correct horse");
+                assert!(matches!(mia.recv(), ServerToClientMsg::Welcome { .. }));
+
+                // Names without an account are free for anonymous users
+                let mut leo = server.client();
+                leo.join("Leo");
+                assert_eq!(mia.list_users(), vec!["Leo".to_string(), "Mia".to_string()]);
+
+                Ok(())
+            },
+        );
+    }
+
+    #[test]
+    fn accounts_register_invalid() {
+        let dir = TempDir::new().unwrap();
+        run_test(
+            opts_with_accounts(&dir, &ManualClock::new(), true),
+            |server| {
+                let mut leo = server.client();
+                leo.join("Leo");
+
+                let mut client = server.client();
+                client.register("Leo", "hunter2");
+                client.expect_error("Username already taken");
+                let mut client = server.client();
+                client.register("Zoe", "");
+                client.expect_error("Password cannot be empty");
+                let mut client = server.client();
+                client.join_with_password("Zoe", "hunter2");
+                client.expect_error("Invalid username or password");
+
+                Ok(())
+            },
+        );
+    }
+
+    #[test]
+    fn accounts_survive_restart() {
+        let dir = TempDir::new().unwrap();
+        let clock = ManualClock::new();
+        run_test(opts_with_accounts(&dir, &clock, true), |server| {
+            let mut mia = server.client();
+            mia.register("Mia", "correct horse");
+            assert!(matches!(mia.recv(), ServerToClientMsg::Welcome { .. }));
+
+            Ok(())
+        });
+        run_test(opts_with_accounts(&dir, &clock, true), |server| {
+            let mut mia = server.client();
+            mia.join_with_secret("Mia", None);
+            mia.expect_error("Username is reserved");
+            let mut mia = server.client();
+            mia.join_with_password("Mia", "correct horse");
+            assert!(matches!(mia.recv(), ServerToClientMsg::Welcome { .. }));
+
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn accounts_ignore_partial_record() {
+        let dir = TempDir::new().unwrap();
+        let clock = ManualClock::new();
+        run_test(opts_with_accounts(&dir, &clock, true), |server| {
+            let mut mia = server.client();
+            mia.register("Mia", "correct horse");
+            assert!(matches!(mia.recv(), ServerToClientMsg::Welcome { .. }));
+
+            Ok(())
+        });
+        // A server that crashed while writing a record leaves it without the newline
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(dir.path().join("accounts.jsonl"))
+            .unwrap();
+        file.write_all(b"{\"name\":\"Le").unwrap();
+        drop(file);
+
+        run_test(opts_with_accounts(&dir, &clock, true), |server| {
+            let mut leo = server.client();
+            leo.register("Leo", "battery staple");
+            assert!(matches!(leo.recv(), ServerToClientMsg::Welcome { .. }));
+
+            Ok(())
+        });
+        // The account registered after the partial record has to be readable as well
+        run_test(opts_with_accounts(&dir, &clock, true), |server| {
+            let mut mia = server.client();
+            mia.join_with_password("Mia", "correct horse");
+            assert!(matches!(mia.recv(), ServerToClientMsg::Welcome { .. }));
+            let mut leo = server.client();
+            leo.join_with_password("Leo", "battery staple");
+            assert!(matches!(leo.recv(), ServerToClientMsg::Welcome { .. }));
+
+            Ok(())
+        });
+    }
 
-            for mut user in users {
-                user.expect_message("Niko", "Borrow this!");
+    #[test]
+    fn accounts_failed_logins_are_limited() {
+        let dir = TempDir::new().unwrap();
+        let clock = ManualClock::new();
+        run_test(opts_with_accounts(&dir, &clock, true), |server| {
+            let mut mia = server.client();
+            mia.register("Mia", "correct horse");
+            assert!(matches!(mia.recv(), ServerToClientMsg::Welcome { .. }));
+            mia.close();
+            sleep(100);
+
+            for _ in 0..3 {
+                let mut client = server.client();
+                client.join_with_password("Mia", "hunter2");
+                client.expect_error("Invalid username or password");
             }
+            // Even the right password is rejected while the account is locked
+            let mut mia = server.client();
+            mia.join_with_password("Mia", "correct horse");
+            mia.expect_error("Too many failed logins, try again later");
+
+            clock.advance(Duration::from_secs(60));
+            let mut mia = server.client();
+            mia.join_with_password("Mia", "correct horse");
+            assert!(matches!(mia.recv(), ServerToClientMsg::Welcome { .. }));
 
             Ok(())
         });
     }
 
-    // TODO(bonus): uncomment the following test and make it pass
-    // The server should correctly close client socket when it shuts down,
-    // to avoid a situation where the clients would be stuck waiting for a message
-    // for some indeterminate amount of time.
-    /*
     #[test]
-    fn drop_clients_on_shutdown() {
-        let server = run_server(opts(2)).expect("creating server failed");
+    fn accounts_without_anonymous_users() {
+        let dir = TempDir::new().unwrap();
+        run_test(
+            opts_with_accounts(&dir, &ManualClock::new(), false),
+            |server| {
+                let mut leo = server.client();
+                leo.join_with_secret("Leo", None);
+                leo.expect_error("You have to log in");
+
+                let mut leo = server.client();
+                leo.register("Leo", "hunter2");
+                assert!(matches!(leo.recv(), ServerToClientMsg::Welcome { .. }));
+
+                Ok(())
+            },
+        );
+    }
 
-        let mut client = server.client();
-        client.join("Bar");
-        let mut client2 = server.client();
-        client2.join("Foo");
+    #[test]
+    fn accounts_disabled() {
+        run_test(opts(2), |server| {
+            let mut mia = server.client();
+            mia.register("Mia", "correct horse");
+            mia.expect_error("Accounts are not enabled");
+            let mut mia = server.client();
+            mia.join_with_password("Mia", "correct horse");
+            mia.expect_error("Accounts are not enabled");
 
-        drop(server);
+            Ok(())
+        });
+    }
 
-        assert!(client.reader.read().is_none());
-        assert!(client2.reader.read().is_none());
+    #[test]
+    fn missed_dms_stay_undelivered_until_sent() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("history");
+        let mut history = History::open(&path).unwrap();
+        history.record_dm("Leo", "Mia", "Hi", false).unwrap();
+        assert_eq!(history.user_joined("Mia").unwrap().len(), 1);
+
+        // Sending the DM to Mia failed, so it is still missed after a restart
+        drop(history);
+        let mut history = History::open(&path).unwrap();
+        assert_eq!(history.user_joined("Mia").unwrap().len(), 1);
+        history.mark_delivered("Mia").unwrap();
+
+        drop(history);
+        let mut history = History::open(&path).unwrap();
+        assert!(history.user_joined("Mia").unwrap().is_empty());
     }
-    */
+
+    #[test]
+    fn record_session() {
+        let dir = TempDir::new().unwrap();
+        let trace = dir.path().join("trace.jsonl");
+        let opts = ServerOpts {
+            record: Some(trace.clone()),
+            ..opts(1)
+        };
+        run_test(opts, |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            server.client().expect_error("Server is full");
+            mia.close();
+            sleep(100);
+
+            Ok(())
+        });
+
+        let events: Vec<Event> = read_trace(&trace)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.event)
+            .collect();
+        assert!(
+            matches!(
+                events.as_slice(),
+                [
+                    Event::Started(Settings { max_clients: 1, .. }),
+                    Event::Connected { client: 0 },
+                    Event::Received {
+                        client: 0,
+                        msg: ClientToServerMsg::Join { .. }
+                    },
+                    Event::Sent {
+                        client: 0,
+                        msg: ServerToClientMsg::Welcome { .. }
+                    },
+                    Event::Connected { client: 1 },
+                    Event::Sent {
+                        client: 1,
+                        msg: ServerToClientMsg::Error(_)
+                    },
+                    Event::Closed { client: 0 },
+                    Event::Stopped,
+                ]
+            ),
+            "{events:?}"
+        );
+    }
+
+    fn http_get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).expect("cannot connect to metrics");
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
     fn run_test<F: FnOnce(RunningServer) -> anyhow::Result<()>>(opts: ServerOpts, func: F) {
         let server = run_server(opts).expect("creating server failed");
-        let port = server.port;
+        let port = server.port();
         func(server).expect("test failed");
 
         TcpStream::connect(("127.0.0.1", port)).expect_err("server is still alive");
     }
 
     struct Client {
-        writer: MessageWriter<ClientToServerMsg, SocketWrapper>,
-        reader: MessageReader<ServerToClientMsg, SocketWrapper>,
+        writer: MessageWriter<ClientToServerMsg, SocketWrapper, WireFormat>,
+        reader: MessageReader<ServerToClientMsg, SocketWrapper, WireFormat>,
     }
 
     impl Client {
         #[track_caller]
         fn join(&mut self, name: &str) {
+            self.join_with_secret(name, None);
+            let msg = self.recv();
+            assert!(matches!(msg, ServerToClientMsg::Welcome { .. }));
+        }
+
+        #[track_caller]
+        fn join_with_secret(&mut self, name: &str, secret: Option<&str>) {
             self.send(ClientToServerMsg::Join {
                 name: name.to_string(),
+                secret: secret.map(|secret| secret.to_string()),
+                password: None,
+            });
+        }
+
+        #[track_caller]
+        fn join_with_password(&mut self, name: &str, password: &str) {
+            self.send(ClientToServerMsg::Join {
+                name: name.to_string(),
+                secret: None,
+                password: Some(password.to_string()),
+            });
+        }
+
+        #[track_caller]
+        fn register(&mut self, name: &str, password: &str) {
+            self.send(ClientToServerMsg::Register {
+                name: name.to_string(),
+                password: password.to_string(),
             });
-            let msg = self.recv();
-            assert!(matches!(msg, ServerToClientMsg::Welcome));
         }
 
         #[track_caller]
@@ -633,13 +1600,156 @@ mod tests {
         }
 
         #[track_caller]
-        fn send(&mut self, msg: ClientToServerMsg) {
-            self.writer.write(msg).expect("cannot send message");
+        fn create_room(&mut self, room: &str) {
+            self.send(ClientToServerMsg::CreateRoom {
+                room: room.to_string(),
+            });
+            self.expect_room_joined(room);
+        }
+
+        #[track_caller]
+        fn join_room(&mut self, room: &str) {
+            self.send(ClientToServerMsg::JoinRoom {
+                room: room.to_string(),
+            });
+            self.expect_room_joined(room);
+        }
+
+        #[track_caller]
+        fn expect_room_joined(&mut self, expected_room: &str) {
+            match self.recv() {
+                ServerToClientMsg::RoomJoined { room } => assert_eq!(room, expected_room),
+                msg => panic!("Unexpected response {msg:?}"),
+            }
+        }
+
+        #[track_caller]
+        fn leave_room(&mut self, room: &str) {
+            self.send(ClientToServerMsg::LeaveRoom {
+                room: room.to_string(),
+            });
+            match self.recv() {
+                ServerToClientMsg::RoomLeft { room: left } => assert_eq!(left, room),
+                msg => panic!("Unexpected response {msg:?}"),
+            }
+        }
+
+        #[track_caller]
+        fn list_rooms(&mut self) -> Vec<String> {
+            self.send(ClientToServerMsg::ListRooms);
+            match self.recv() {
+                ServerToClientMsg::RoomList { mut rooms } => {
+                    rooms.sort();
+                    rooms
+                }
+                msg => panic!("Unexpected response {msg:?}"),
+            }
+        }
+
+        #[track_caller]
+        fn room_message(&mut self, room: &str, message: &str) {
+            self.send(ClientToServerMsg::RoomMessage {
+                room: room.to_string(),
+                message: message.to_string(),
+            });
+        }
+
+        #[track_caller]
+        fn expect_room_message(
+            &mut self,
+            expected_room: &str,
+            expected_from: &str,
+            expected_message: &str,
+        ) {
+            match self.recv() {
+                ServerToClientMsg::RoomMessage {
+                    room,
+                    from,
+                    message,
+                } => {
+                    assert_eq!(room, expected_room);
+                    assert_eq!(from, expected_from);
+                    assert_eq!(message, expected_message);
+                }
+                msg => panic!("Unexpected message {msg:?}"),
+            }
+        }
+
+        /// Offers the file and returns the ID of the transfer.
+        #[track_caller]
+        fn offer_file(&mut self, to: &str, name: &str, data: &[u8]) -> u64 {
+            self.send(ClientToServerMsg::OfferFile {
+                to: to.to_string(),
+                name: name.to_string(),
+                size: data.len() as u64,
+                sha256: format!("{:x}", Sha256::digest(data)),
+            });
+            match self.recv() {
+                ServerToClientMsg::FileOffered {
+                    id,
+                    to: offered_to,
+                    name: offered_name,
+                } => {
+                    assert_eq!((offered_to.as_str(), offered_name.as_str()), (to, name));
+                    id
+                }
+                msg => panic!("Unexpected message {msg:?}"),
+            }
+        }
+
+        #[track_caller]
+        fn expect_file_offer(&mut self, expected_id: u64) {
+            let msg = self.recv();
+            assert!(
+                matches!(msg, ServerToClientMsg::FileOffer { id, .. } if id == expected_id),
+                "Unexpected message {msg:?}"
+            );
+        }
+
+        #[track_caller]
+        fn expect_file_accepted(&mut self, expected_id: u64) {
+            let msg = self.recv();
+            assert!(
+                matches!(msg, ServerToClientMsg::FileAccepted { id } if id == expected_id),
+                "Unexpected message {msg:?}"
+            );
+        }
+
+        #[track_caller]
+        fn send_chunk(&mut self, id: u64, index: u64, data: &[u8]) {
+            self.send(ClientToServerMsg::FileChunk {
+                id,
+                index,
+                data: data.to_vec(),
+            });
         }
 
+        /// Returns the data of the chunk.
         #[track_caller]
-        fn try_send(&mut self, msg: ClientToServerMsg) {
-            let _ = self.writer.write(msg);
+        fn expect_chunk(&mut self, expected_id: u64, expected_index: u64) -> Vec<u8> {
+            match self.recv() {
+                ServerToClientMsg::FileChunk { id, index, data } => {
+                    assert_eq!((id, index), (expected_id, expected_index));
+                    data
+                }
+                msg => panic!("Unexpected message {msg:?}"),
+            }
+        }
+
+        #[track_caller]
+        fn expect_file_cancelled(&mut self, expected_id: u64, expected_reason: &str) {
+            match self.recv() {
+                ServerToClientMsg::FileCancelled { id, reason } => {
+                    assert_eq!(id, expected_id);
+                    assert_eq!(reason, expected_reason);
+                }
+                msg => panic!("Unexpected message {msg:?}"),
+            }
+        }
+
+        #[track_caller]
+        fn send(&mut self, msg: ClientToServerMsg) {
+            self.writer.write(msg).expect("cannot send message");
         }
 
         #[track_caller]
@@ -673,7 +1783,7 @@ mod tests {
         }
     }
 
-    struct SocketWrapper(Arc<TcpStream>);
+    struct SocketWrapper(Arc<Stream>);
 
     impl Read for SocketWrapper {
         fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
@@ -695,16 +1805,45 @@ mod tests {
         fn client(&self) -> Client {
             let client =
                 TcpStream::connect(("127.0.0.1", self.port())).expect("cannot connect to server");
+            self.connect(Stream::Tcp(client))
+        }
+
+        /// Connects a client to the first Unix socket listener of the server.
+        fn unix_client(&self) -> Client {
+            let path = self
+                .addresses()
+                .iter()
+                .find_map(|address| match address {
+                    ListenAddr::Unix(path) => Some(path),
+                    ListenAddr::Tcp(_) => None,
+                })
+                .expect("server does not listen on a Unix socket");
+            let client = UnixStream::connect(path).expect("cannot connect to server");
+            self.connect(Stream::Unix(client))
+        }
+
+        fn connect(&self, client: Stream) -> Client {
             let client = Arc::new(client);
 
-            let writer = MessageWriter::<ClientToServerMsg, SocketWrapper>::new(SocketWrapper(
-                client.clone(),
-            ));
-            let reader = MessageReader::<ServerToClientMsg, SocketWrapper>::new(SocketWrapper(
-                client.clone(),
-            ));
+            let format = self.state.opts.wire_format;
+            let writer = MessageWriter::<ClientToServerMsg, _, _>::with_codec(
+                SocketWrapper(client.clone()),
+                format,
+            );
+            let reader = MessageReader::<ServerToClientMsg, _, _>::with_codec(
+                SocketWrapper(client.clone()),
+                format,
+            );
             Client { reader, writer }
         }
+
+        /// Connects a client that has joined as the admin configured by [`opts_with_admin`].
+        fn admin(&self) -> Client {
+            let mut client = self.client();
+            client.join_with_secret("Mod", Some("hunter2"));
+            assert!(matches!(client.recv(), ServerToClientMsg::Welcome { .. }));
+            client
+        }
     }
 
     fn sleep(duration_ms: u64) {
@@ -712,6 +1851,131 @@ mod tests {
     }
 
     fn opts(max_clients: usize) -> ServerOpts {
-        ServerOpts { max_clients }
+        ServerOpts {
+            listen: vec![ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 0)))],
+            max_clients,
+            history: None,
+            wire_format: WireFormat::Json,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            admin: None,
+            rate_limit: None,
+            clock: Arc::new(SystemClock),
+            metrics: None,
+            accounts: None,
+            record: None,
+        }
+    }
+
+    fn opts_with_rate_limit(clock: &ManualClock, burst: u32, max_violations: u32) -> ServerOpts {
+        ServerOpts {
+            rate_limit: Some(RateLimit {
+                messages_per_second: 1.0,
+                burst,
+                max_violations,
+            }),
+            clock: Arc::new(clock.clone()),
+            ..opts(2)
+        }
     }
+
+    /// Clock that only moves when a test tells it to.
+    #[derive(Clone)]
+    struct ManualClock(Arc<Mutex<Instant>>);
+
+    impl ManualClock {
+        fn new() -> Self {
+            Self(Arc::new(Mutex::new(Instant::now())))
+        }
+
+        fn advance(&self, duration: Duration) {
+            *self.0.lock().unwrap() += duration;
+        }
+    }
+
+    impl Clock for ManualClock {
+        fn now(&self) -> Instant {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    /// Listens on TCP/IP and on a Unix socket in `dir`.
+    fn opts_with_unix_socket(max_clients: usize, dir: &TempDir) -> ServerOpts {
+        let mut opts = opts(max_clients);
+        opts.listen
+            .push(ListenAddr::Unix(dir.path().join("chat.sock")));
+        opts
+    }
+
+    fn opts_with_admin(max_clients: usize) -> ServerOpts {
+        ServerOpts {
+            admin: Some(Admin {
+                name: "Mod".to_string(),
+                secret: "hunter2".to_string(),
+            }),
+            ..opts(max_clients)
+        }
+    }
+
+    /// Stores the accounts in `dir`, an account is locked for a minute after 3 failed logins.
+    fn opts_with_accounts(dir: &TempDir, clock: &ManualClock, allow_anonymous: bool) -> ServerOpts {
+        ServerOpts {
+            accounts: Some(Accounts {
+                database: dir.path().join("accounts.jsonl"),
+                allow_anonymous,
+                max_failed_logins: 3,
+                lockout: Duration::from_secs(60),
+            }),
+            clock: Arc::new(clock.clone()),
+            ..opts(5)
+        }
+    }
+}
+
+#[cfg(test)]
+mod conformance {
+    use crate::clock::SystemClock;
+    use crate::codec::WireFormat;
+    use crate::net::ListenAddr;
+    use crate::reader::DEFAULT_MAX_MESSAGE_SIZE;
+    use crate::{run_server, RunningServer, ServerOpts};
+    use chat_conformance::{Capabilities, ChatServer, Framing, ServerConfig};
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    impl ChatServer for RunningServer {
+        const FRAMING: Framing = Framing::LengthPrefixed;
+        const CAPABILITIES: Capabilities = Capabilities {
+            join_timeout: None,
+            idle_timeout: None,
+            history: true,
+        };
+        const SPAM_COUNT: usize = 100_000;
+
+        fn start(config: ServerConfig) -> anyhow::Result<Self> {
+            run_server(ServerOpts {
+                listen: vec![ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 0)))],
+                max_clients: config.max_clients,
+                history: config.history,
+                wire_format: WireFormat::Json,
+                max_message_size: config.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
+                admin: None,
+                rate_limit: None,
+                clock: Arc::new(SystemClock),
+                metrics: None,
+                accounts: None,
+                record: None,
+            })
+        }
+
+        fn port(&self) -> u16 {
+            RunningServer::port(self)
+        }
+    }
+
+    chat_conformance::conformance_tests!(
+        RunningServer;
+        join_timeout: false,
+        idle_timeout: false,
+        history: true,
+    );
 }
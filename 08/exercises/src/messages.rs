@@ -1,9 +1,39 @@
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
 pub enum ClientToServerMsg {
     /// This is the first message in the communication, which should be sent by the client.
     /// When some other client with the same name already exists, the server should respond
     /// with an error "Username already taken" and disconnect the new client.
-    Join { name: String },
+    /// The admin of the server (see [crate::ServerOpts::admin]) has to send its `secret`,
+    /// otherwise the server responds with an error "Invalid secret" and disconnects it.
+    /// A banned user receives an error "You are banned" and is disconnected.
+    ///
+    /// When accounts are enabled (see [crate::ServerOpts::accounts]), a user logs in to its
+    /// account by sending its `password`. The server responds with an error (and disconnects the
+    /// client) if:
+    /// - the name is registered and no password was sent: "Username is reserved"
+    /// - the password is wrong or the account does not exist: "Invalid username or password"
+    /// - the account had too many failed logins recently:
+    ///   "Too many failed logins, try again later"
+    /// - the name is not registered, no password was sent and anonymous users are not allowed:
+    ///   "You have to log in"
+    ///
+    /// If accounts are not enabled, sending a password results in an error
+    /// "Accounts are not enabled".
+    Join {
+        name: String,
+        #[serde(default)]
+        secret: Option<String>,
+        #[serde(default)]
+        password: Option<String>,
+    },
+    /// Can be sent instead of [ClientToServerMsg::Join] to create an account and join with it.
+    /// The server responds like to `Join`, or with an error (and disconnects the client) if:
+    /// - accounts are not enabled: "Accounts are not enabled"
+    /// - the name already has an account: "Username already registered"
+    /// - the name belongs to the admin: "Username is reserved"
+    /// - an anonymous user with the name is connected: "Username already taken"
+    /// - the password is empty: "Password cannot be empty"
+    Register { name: String, password: String },
     /// This message checks that the connection is OK.
     /// The server should respond with [ServerToClientMsg::Pong].
     Ping,
@@ -12,17 +42,132 @@ pub enum ClientToServerMsg {
     ListUsers,
     /// Sends a direct message to the user with the given name (`to`).
     /// If the user does not exist, the server responds with an error "User <to> does not exist".
+    /// When the history is enabled, a user that has joined the server in the past but is not
+    /// connected right now also exists. The DM is then stored and delivered to them the next
+    /// time they join, right after [ServerToClientMsg::Welcome].
     /// If the client tries to send a message to themselves, the server responds with an error
     /// "Cannot send a DM to yourself".
     SendDM { to: String, message: String },
     /// Sends a message to all currently connected users (except for the sender of the broadcast).
     Broadcast { message: String },
+    /// Creates a new room with the given name and makes the sender its first member.
+    /// The server responds with [ServerToClientMsg::RoomJoined].
+    /// If the room already exists, the server responds with an error "Room <room> already exists".
+    CreateRoom { room: String },
+    /// Joins an existing room. The server responds with [ServerToClientMsg::RoomJoined].
+    /// If the room does not exist, the server responds with an error "Room <room> does not exist".
+    /// If the sender is already a member, the server responds with an error
+    /// "You are already in room <room>".
+    JoinRoom { room: String },
+    /// Leaves a room. The server responds with [ServerToClientMsg::RoomLeft].
+    /// A room is removed once its last member leaves it or disconnects.
+    /// If the sender is not a member, the server responds with an error
+    /// "You are not in room <room>".
+    LeaveRoom { room: String },
+    /// Send a request to list the names of all existing rooms.
+    /// The order of the room names is not important.
+    ListRooms,
+    /// Sends a message to all members of a room (except for the sender of the message).
+    /// Only members of the room can send messages to it, otherwise the server responds with an
+    /// error "You are not in room <room>".
+    RoomMessage { room: String, message: String },
+    /// Send a request to return a page of past broadcasts and DMs sent or received by the sender,
+    /// with an ID larger than `since`, ordered by their ID.
+    /// At most `limit` messages (and never more than 100) are returned.
+    /// The server responds with [ServerToClientMsg::History].
+    /// If the history is not enabled, the server responds with an error "History is not enabled".
+    History { since: u64, limit: usize },
+    /// Disconnects the user with the given name, who receives an error "You have been kicked".
+    /// If the user is not connected, the server responds with an error
+    /// "User <name> does not exist".
+    /// Like all moderation messages, it can only be sent by the admin, otherwise the server
+    /// responds with an error "You are not an admin". The admin cannot moderate themselves, the
+    /// server responds with an error "Cannot moderate yourself".
+    Kick { name: String },
+    /// Disconnects the user with the given name (if it is connected) with an error
+    /// "You are banned", and refuses any client that tries to join with that name for the next
+    /// `duration` seconds.
+    Ban { name: String, duration: u64 },
+    /// Stops the user with the given name from sending broadcasts, DMs and room messages until the
+    /// server restarts. These are rejected with an error "You are muted".
+    Mute { name: String },
+    /// Offers a file to the user with the given name (`to`), who has to be connected.
+    /// The server responds with [ServerToClientMsg::FileOffered], which contains the ID of the
+    /// transfer, and sends [ServerToClientMsg::FileOffer] to the recipient.
+    /// `sha256` is the hex-encoded SHA-256 hash of the file, which the recipient checks once it
+    /// has received the whole file.
+    /// If the user does not exist, the server responds with an error "User <to> does not exist".
+    /// If the client offers a file to themselves, the server responds with an error
+    /// "Cannot send a file to yourself".
+    OfferFile {
+        to: String,
+        name: String,
+        size: u64,
+        sha256: String,
+    },
+    /// Accepts a file offered to the sender. The author of the offer receives
+    /// [ServerToClientMsg::FileAccepted] and can start sending [ClientToServerMsg::FileChunk]s.
+    /// If there is no such offer for the sender, the server responds with an error
+    /// "Unknown transfer <id>".
+    AcceptFile { id: u64 },
+    /// Sends a part of an accepted file, the server relays it to the recipient as
+    /// [ServerToClientMsg::FileChunk]. Chunks are numbered from 0 and have to be sent in order.
+    /// The transfer is finished once the chunks add up to the size of the file.
+    ///
+    /// Every chunk has to fit into the maximum message size announced in
+    /// [ServerToClientMsg::Welcome], including its encoding. Other messages of both users are
+    /// sent between the chunks, so smaller chunks keep the chat responsive during a transfer.
+    /// Chunks do not count towards the rate limit.
+    ///
+    /// If the transfer does not exist or was not offered by the sender, the server responds with
+    /// an error "Unknown transfer <id>", if it was not accepted yet with an error
+    /// "Transfer <id> was not accepted". A chunk out of order or beyond the size of the file
+    /// cancels the transfer, see [ServerToClientMsg::FileCancelled].
+    FileChunk {
+        id: u64,
+        index: u64,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    /// Cancels an offered or running transfer, on either side (e.g. to reject an offer).
+    /// The other side receives [ServerToClientMsg::FileCancelled]. If the sender is not a part
+    /// of the transfer, the server responds with an error "Unknown transfer <id>".
+    CancelFile { id: u64 },
+}
+
+impl ClientToServerMsg {
+    /// Name of the message type, used in metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientToServerMsg::Join { .. } => "Join",
+            ClientToServerMsg::Register { .. } => "Register",
+            ClientToServerMsg::Ping => "Ping",
+            ClientToServerMsg::ListUsers => "ListUsers",
+            ClientToServerMsg::SendDM { .. } => "SendDM",
+            ClientToServerMsg::Broadcast { .. } => "Broadcast",
+            ClientToServerMsg::CreateRoom { .. } => "CreateRoom",
+            ClientToServerMsg::JoinRoom { .. } => "JoinRoom",
+            ClientToServerMsg::LeaveRoom { .. } => "LeaveRoom",
+            ClientToServerMsg::ListRooms => "ListRooms",
+            ClientToServerMsg::RoomMessage { .. } => "RoomMessage",
+            ClientToServerMsg::History { .. } => "History",
+            ClientToServerMsg::Kick { .. } => "Kick",
+            ClientToServerMsg::Ban { .. } => "Ban",
+            ClientToServerMsg::Mute { .. } => "Mute",
+            ClientToServerMsg::OfferFile { .. } => "OfferFile",
+            ClientToServerMsg::AcceptFile { .. } => "AcceptFile",
+            ClientToServerMsg::FileChunk { .. } => "FileChunk",
+            ClientToServerMsg::CancelFile { .. } => "CancelFile",
+        }
+    }
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub enum ServerToClientMsg {
     /// Response to [ClientToServerMsg::Join].
-    Welcome,
+    /// Announces the maximum size of a single message (in bytes) that the server accepts.
+    /// Larger messages are rejected with an error "Message too large".
+    Welcome { max_message_size: usize },
     /// Response to [ClientToServerMsg::Ping].
     Pong,
     /// Response to [ClientToServerMsg::ListUsers].
@@ -30,6 +175,78 @@ pub enum ServerToClientMsg {
     /// This message is sent by the server to a client that should receive a message
     /// (that was sent either by [ClientToServerMsg::SendDM] or [ClientToServerMsg::Broadcast]).
     Message { from: String, message: String },
+    /// Response to [ClientToServerMsg::CreateRoom] and [ClientToServerMsg::JoinRoom].
+    RoomJoined { room: String },
+    /// Response to [ClientToServerMsg::LeaveRoom].
+    RoomLeft { room: String },
+    /// Response to [ClientToServerMsg::ListRooms].
+    RoomList { rooms: Vec<String> },
+    /// This message is sent by the server to all members of a room when one of them sends
+    /// a [ClientToServerMsg::RoomMessage].
+    RoomMessage {
+        room: String,
+        from: String,
+        message: String,
+    },
+    /// Response to [ClientToServerMsg::History].
+    History { messages: Vec<HistoryMessage> },
     /// This message is returned by the server when an error occurs.
     Error(String),
+    /// Response to [ClientToServerMsg::OfferFile].
+    FileOffered { id: u64, to: String, name: String },
+    /// Sent to the recipient of a [ClientToServerMsg::OfferFile], which can accept it with
+    /// [ClientToServerMsg::AcceptFile] or reject it with [ClientToServerMsg::CancelFile].
+    FileOffer {
+        id: u64,
+        from: String,
+        name: String,
+        size: u64,
+        sha256: String,
+    },
+    /// Tells the author of an offer that the recipient has accepted it.
+    FileAccepted { id: u64 },
+    /// A part of a file relayed from [ClientToServerMsg::FileChunk].
+    FileChunk {
+        id: u64,
+        index: u64,
+        #[serde(with = "serde_bytes")]
+        data: Vec<u8>,
+    },
+    /// Tells both sides of a transfer that it was cancelled, e.g. because the other side has
+    /// disconnected. The side that cancelled it with [ClientToServerMsg::CancelFile] is not told.
+    FileCancelled { id: u64, reason: String },
+}
+
+impl ServerToClientMsg {
+    /// Name of the message type, used in metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerToClientMsg::Welcome { .. } => "Welcome",
+            ServerToClientMsg::Pong => "Pong",
+            ServerToClientMsg::UserList { .. } => "UserList",
+            ServerToClientMsg::Message { .. } => "Message",
+            ServerToClientMsg::RoomJoined { .. } => "RoomJoined",
+            ServerToClientMsg::RoomLeft { .. } => "RoomLeft",
+            ServerToClientMsg::RoomList { .. } => "RoomList",
+            ServerToClientMsg::RoomMessage { .. } => "RoomMessage",
+            ServerToClientMsg::History { .. } => "History",
+            ServerToClientMsg::Error(_) => "Error",
+            ServerToClientMsg::FileOffered { .. } => "FileOffered",
+            ServerToClientMsg::FileOffer { .. } => "FileOffer",
+            ServerToClientMsg::FileAccepted { .. } => "FileAccepted",
+            ServerToClientMsg::FileChunk { .. } => "FileChunk",
+            ServerToClientMsg::FileCancelled { .. } => "FileCancelled",
+        }
+    }
+}
+
+/// A broadcast or a DM stored in the history of the server.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryMessage {
+    /// Unique ID of the message, IDs are assigned in increasing order starting from 1.
+    pub id: u64,
+    pub from: String,
+    /// Recipient of a DM, `None` for broadcasts.
+    pub to: Option<String>,
+    pub message: String,
 }
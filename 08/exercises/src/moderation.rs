@@ -0,0 +1,39 @@
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+/// Bans and mutes imposed by the admin.
+///
+/// Both are tracked by username, so that a user cannot get rid of them by reconnecting.
+#[derive(Default)]
+pub struct Moderation {
+    /// Banned usernames and the end of their ban (`None` if the ban never ends).
+    bans: HashMap<String, Option<Instant>>,
+    muted: HashSet<String>,
+}
+
+impl Moderation {
+    pub fn ban(&mut self, user: &str, now: Instant, duration: Duration) {
+        self.bans
+            .insert(user.to_string(), now.checked_add(duration));
+    }
+
+    /// Forgets the ban of `user` once it expires.
+    pub fn is_banned(&mut self, user: &str, now: Instant) -> bool {
+        match self.bans.get(user) {
+            Some(Some(until)) if *until <= now => {
+                self.bans.remove(user);
+                false
+            }
+            Some(_) => true,
+            None => false,
+        }
+    }
+
+    pub fn mute(&mut self, user: &str) {
+        self.muted.insert(user.to_string());
+    }
+
+    pub fn is_muted(&self, user: &str) -> bool {
+        self.muted.contains(user)
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt::{Display, Formatter};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, Ipv6Addr, Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+
+/// Address on which the server listens for new clients.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// TCP/IP address. Use port 0 to let the operating system choose a free port.
+    Tcp(SocketAddr),
+    /// Path of a Unix domain socket. Who can connect to it is decided by the permissions of the
+    /// socket file and its directory.
+    Unix(PathBuf),
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(address) => write!(f, "{address}"),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// A Unix socket file left behind by a server that has crashed is replaced.
+    pub fn bind(address: &ListenAddr) -> std::io::Result<Self> {
+        match address {
+            ListenAddr::Tcp(address) => Ok(Listener::Tcp(TcpListener::bind(address)?)),
+            ListenAddr::Unix(path) => match UnixListener::bind(path) {
+                Err(error)
+                    if error.kind() == ErrorKind::AddrInUse
+                        && UnixStream::connect(path).is_err() =>
+                {
+                    std::fs::remove_file(path)?;
+                    Ok(Listener::Unix(UnixListener::bind(path)?))
+                }
+                listener => Ok(Listener::Unix(listener?)),
+            },
+        }
+    }
+
+    /// Returns the address on which the listener listens, including the port chosen by the
+    /// operating system.
+    pub fn local_addr(&self) -> std::io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => Ok(ListenAddr::Tcp(listener.local_addr()?)),
+            Listener::Unix(listener) => {
+                let address = listener.local_addr()?;
+                let path = address.as_pathname().ok_or_else(|| {
+                    std::io::Error::new(ErrorKind::InvalidInput, "Unnamed Unix socket")
+                })?;
+                Ok(ListenAddr::Unix(path.to_path_buf()))
+            }
+        }
+    }
+
+    /// Returns the new connection together with a description of the client for logging.
+    pub fn accept(&self) -> std::io::Result<(Stream, String)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, peer) = listener.accept()?;
+                Ok((Stream::Tcp(stream), peer.to_string()))
+            }
+            Listener::Unix(listener) => {
+                let (stream, _) = listener.accept()?;
+                Ok((Stream::Unix(stream), "local client".to_string()))
+            }
+        }
+    }
+}
+
+/// Wakes up a thread blocked in [`Listener::accept`] with a dummy connection.
+pub fn wake_up(address: &ListenAddr) {
+    match address {
+        ListenAddr::Tcp(address) => {
+            let mut address = *address;
+            if address.ip().is_unspecified() {
+                address.set_ip(match address {
+                    SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+                    SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+                });
+            }
+            let _ = TcpStream::connect(address);
+        }
+        ListenAddr::Unix(path) => {
+            let _ = UnixStream::connect(path);
+        }
+    }
+}
+
+/// Removes the socket file of a Unix socket listener, so that the server can be started again.
+pub fn remove_socket(address: &ListenAddr) {
+    if let ListenAddr::Unix(path) = address {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Connection to a client over TCP/IP or a Unix socket.
+#[derive(Debug)]
+pub enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+impl Stream {
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        match self {
+            Stream::Tcp(stream) => Ok(Stream::Tcp(stream.try_clone()?)),
+            Stream::Unix(stream) => Ok(Stream::Unix(stream.try_clone()?)),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.shutdown(how),
+            Stream::Unix(stream) => stream.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        (&*self).read(buf)
+    }
+}
+
+impl Read for &Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).read(buf),
+            Stream::Unix(stream) => (&*stream).read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        (&*self).write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        (&*self).flush()
+    }
+}
+
+impl Write for &Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).write(buf),
+            Stream::Unix(stream) => (&*stream).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => (&*stream).flush(),
+            Stream::Unix(stream) => (&*stream).flush(),
+        }
+    }
+}
@@ -0,0 +1,57 @@
+use crate::RateLimit;
+use std::time::Instant;
+
+/// What should happen with a message of a client.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RateDecision {
+    Allow,
+    /// The client is over its limit, the message should be rejected.
+    Reject,
+    /// The client has been over its limit too many times, it should be disconnected.
+    Disconnect,
+}
+
+/// Token bucket that limits how fast a single client can send messages.
+///
+/// The bucket holds up to `burst` tokens and is refilled at `messages_per_second`, every
+/// message takes one token. Violations are forgiven once the bucket refills completely,
+/// i.e. when the client calms down for a while.
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+    violations: u32,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            tokens: limit.burst as f64,
+            limit,
+            last_refill: now,
+            violations: 0,
+        }
+    }
+
+    pub fn take(&mut self, now: Instant) -> RateDecision {
+        let burst = self.limit.burst as f64;
+        let elapsed = now.saturating_duration_since(self.last_refill);
+        self.tokens =
+            (self.tokens + elapsed.as_secs_f64() * self.limit.messages_per_second).min(burst);
+        self.last_refill = now;
+        if self.tokens >= burst {
+            self.violations = 0;
+        }
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            return RateDecision::Allow;
+        }
+        self.violations += 1;
+        if self.violations >= self.limit.max_violations {
+            RateDecision::Disconnect
+        } else {
+            RateDecision::Reject
+        }
+    }
+}
@@ -1,22 +1,58 @@
+use crate::codec::{Codec, JsonCodec};
 use serde::de::DeserializeOwned;
+use std::fmt::{Display, Formatter};
 use std::io::{ErrorKind, Read};
 use std::marker::PhantomData;
 
-const MAX_MESSAGE_SIZE: u32 = 256;
+/// Maximum size of a single message (in bytes), unless configured otherwise.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
 
-pub struct MessageReader<T, R> {
+/// A message was larger than the maximum message size of the reader.
+///
+/// The message is skipped, so the reader can still be used to read the following messages.
+#[derive(Debug)]
+pub struct MessageTooLarge {
+    pub size: usize,
+}
+
+impl Display for MessageTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Message too large ({} bytes)", self.size)
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
+pub struct MessageReader<T, R, C = JsonCodec> {
     stream: R,
+    codec: C,
+    max_message_size: usize,
     _phantom: PhantomData<T>,
 }
 
 impl<T: DeserializeOwned, R: Read> MessageReader<T, R> {
     pub fn new(read: R) -> Self {
+        Self::with_codec(read, JsonCodec)
+    }
+}
+
+impl<T: DeserializeOwned, R: Read, C: Codec> MessageReader<T, R, C> {
+    pub fn with_codec(read: R, codec: C) -> Self {
         Self {
             stream: read,
+            codec,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
             _phantom: Default::default(),
         }
     }
 
+    /// Sets the maximum size of a single message (in bytes).
+    /// Larger messages are skipped and reported as [`MessageTooLarge`] errors.
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
     pub fn read(&mut self) -> Option<anyhow::Result<T>> {
         // Read message size
         let mut message = [0; 4];
@@ -28,20 +64,28 @@ impl<T: DeserializeOwned, R: Read> MessageReader<T, R> {
             Err(error) => return Some(Err(error.into())),
         }
 
-        let size = u32::from_le_bytes(message);
-        if size > MAX_MESSAGE_SIZE {
-            return Some(Err(anyhow::anyhow!("Message too large ({size} bytes)")));
+        let size = u32::from_le_bytes(message) as usize;
+        if size > self.max_message_size {
+            // Skip the message, so that the next message can be read
+            let skipped = std::io::copy(
+                &mut (&mut self.stream).take(size as u64),
+                &mut std::io::sink(),
+            );
+            if let Err(error) = skipped {
+                return Some(Err(error.into()));
+            }
+            return Some(Err(MessageTooLarge { size }.into()));
         }
 
         // Read message
-        let mut buffer = vec![0; size as usize];
+        let mut buffer = vec![0; size];
 
         if let Err(error) = self.stream.read_exact(&mut buffer) {
             return Some(Err(anyhow::anyhow!("Cannot read message: {error:?}")));
         }
 
-        // Deserialize message from JSON
-        match serde_json::from_slice::<T>(&buffer) {
+        // Deserialize message
+        match self.codec.decode::<T>(&buffer) {
             Ok(msg) => Some(Ok(msg)),
             Err(error) => Some(Err(anyhow::anyhow!(
                 "Cannot deserialize message: {error:?}"
@@ -58,7 +102,7 @@ impl<T: DeserializeOwned, R: Read> MessageReader<T, R> {
     }
 }
 
-impl<T: DeserializeOwned, R: Read> Iterator for MessageReader<T, R> {
+impl<T: DeserializeOwned, R: Read, C: Codec> Iterator for MessageReader<T, R, C> {
     type Item = anyhow::Result<T>;
 
     fn next(&mut self) -> Option<Self::Item> {
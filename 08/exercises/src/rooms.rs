@@ -0,0 +1,100 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+
+/// Reasons why a room operation can be refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RoomError {
+    AlreadyExists(String),
+    DoesNotExist(String),
+    AlreadyMember(String),
+    NotMember(String),
+}
+
+impl Display for RoomError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoomError::AlreadyExists(room) => write!(f, "Room {room} already exists"),
+            RoomError::DoesNotExist(room) => write!(f, "Room {room} does not exist"),
+            RoomError::AlreadyMember(room) => write!(f, "You are already in room {room}"),
+            RoomError::NotMember(room) => write!(f, "You are not in room {room}"),
+        }
+    }
+}
+
+/// Named chat rooms and the usernames of their members.
+///
+/// A room exists as long as it has at least one member, it is removed when the last member
+/// leaves (or disconnects).
+#[derive(Default)]
+pub struct Rooms {
+    rooms: HashMap<String, HashSet<String>>,
+}
+
+impl Rooms {
+    /// Creates a new room, with `user` as its first member.
+    pub fn create(&mut self, room: &str, user: &str) -> Result<(), RoomError> {
+        if self.rooms.contains_key(room) {
+            return Err(RoomError::AlreadyExists(room.to_string()));
+        }
+        self.rooms
+            .insert(room.to_string(), HashSet::from([user.to_string()]));
+        Ok(())
+    }
+
+    pub fn join(&mut self, room: &str, user: &str) -> Result<(), RoomError> {
+        let members = self
+            .rooms
+            .get_mut(room)
+            .ok_or_else(|| RoomError::DoesNotExist(room.to_string()))?;
+        if !members.insert(user.to_string()) {
+            return Err(RoomError::AlreadyMember(room.to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn leave(&mut self, room: &str, user: &str) -> Result<(), RoomError> {
+        let members = self
+            .rooms
+            .get_mut(room)
+            .ok_or_else(|| RoomError::DoesNotExist(room.to_string()))?;
+        if !members.remove(user) {
+            return Err(RoomError::NotMember(room.to_string()));
+        }
+        if members.is_empty() {
+            self.rooms.remove(room);
+        }
+        Ok(())
+    }
+
+    /// Returns the members of `room` that should receive a message sent there by `user`.
+    pub fn recipients(&self, room: &str, user: &str) -> Result<Vec<String>, RoomError> {
+        let members = self
+            .rooms
+            .get(room)
+            .ok_or_else(|| RoomError::DoesNotExist(room.to_string()))?;
+        if !members.contains(user) {
+            return Err(RoomError::NotMember(room.to_string()));
+        }
+        Ok(members
+            .iter()
+            .filter(|member| *member != user)
+            .cloned()
+            .collect())
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.rooms.keys().cloned().collect()
+    }
+
+    /// Removes `user` from all rooms, e.g. when it disconnects.
+    pub fn remove_user(&mut self, user: &str) {
+        self.rooms.retain(|_, members| {
+            members.remove(user);
+            !members.is_empty()
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.rooms.clear();
+    }
+}
@@ -0,0 +1,397 @@
+use crate::accounts::{self, AccountDb, AccountError};
+use crate::client::ClientWriter;
+use crate::history::History;
+use crate::messages::HistoryMessage;
+use crate::metrics::{Metrics, Stats};
+use crate::moderation::Moderation;
+use crate::net::Stream;
+use crate::rooms::{RoomError, Rooms};
+use crate::trace::Recorder;
+use crate::transfers::{TransferError, TransferId, Transfers};
+use crate::ServerOpts;
+use std::collections::HashMap;
+use std::net::Shutdown;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Writer that is shared between all threads that want to send a message to a given client.
+/// The mutex makes sure that messages are never interleaved.
+pub type SharedWriter = Arc<Mutex<ClientWriter>>;
+
+pub type ConnectionId = usize;
+
+/// Where should a DM go.
+pub enum DmRoute {
+    /// The recipient is connected, the DM should be sent to it right away.
+    Online(SharedWriter),
+    /// The recipient is offline, the DM was stored in the history and will be delivered later.
+    Stored,
+    UnknownUser,
+}
+
+/// Result of an attempt to join the server.
+pub enum JoinOutcome {
+    /// The user has joined, these are the DMs that it has missed while it was offline.
+    Joined(Vec<HistoryMessage>),
+    UsernameTaken,
+    Banned,
+}
+
+/// State shared by all threads of the server.
+pub struct ServerState {
+    pub opts: ServerOpts,
+    pub metrics: Arc<Metrics>,
+    /// Records the server if [`ServerOpts::record`] is set.
+    pub recorder: Option<Arc<Recorder>>,
+    shutting_down: AtomicBool,
+    next_connection_id: AtomicUsize,
+    inner: Mutex<Inner>,
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+#[derive(Default)]
+struct Inner {
+    /// All open connections, including clients that have not joined yet.
+    connections: HashMap<ConnectionId, Stream>,
+    /// Clients that have joined, indexed by their username.
+    users: HashMap<String, SharedWriter>,
+    rooms: Rooms,
+    transfers: Transfers,
+    moderation: Moderation,
+    history: Option<History>,
+    accounts: Option<AccountDb>,
+}
+
+impl ServerState {
+    pub fn new(opts: ServerOpts) -> anyhow::Result<Self> {
+        let history = opts.history.as_deref().map(History::open).transpose()?;
+        let accounts = opts.accounts.as_ref().map(AccountDb::open).transpose()?;
+        let recorder = opts
+            .record
+            .as_deref()
+            .map(|path| Recorder::create(path, &opts))
+            .transpose()?
+            .map(Arc::new);
+        Ok(Self {
+            opts,
+            metrics: Default::default(),
+            recorder,
+            shutting_down: AtomicBool::new(false),
+            next_connection_id: AtomicUsize::new(0),
+            inner: Mutex::new(Inner {
+                history,
+                accounts,
+                ..Default::default()
+            }),
+            threads: Default::default(),
+        })
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    pub fn start_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    /// Registers a new connection, unless the server is already full.
+    pub fn add_connection(&self, stream: &Stream) -> anyhow::Result<Option<ConnectionId>> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.connections.len() >= self.opts.max_clients {
+            return Ok(None);
+        }
+        let id = self.next_connection_id.fetch_add(1, Ordering::SeqCst);
+        inner.connections.insert(id, stream.try_clone()?);
+        Ok(Some(id))
+    }
+
+    /// Closes the connection and forgets about it.
+    pub fn remove_connection(&self, id: ConnectionId) {
+        if let Some(stream) = self.inner.lock().unwrap().connections.remove(&id) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+
+    pub fn add_user(&self, name: &str, writer: SharedWriter) -> anyhow::Result<JoinOutcome> {
+        let now = self.opts.clock.now();
+        let mut inner = self.inner.lock().unwrap();
+        if inner.moderation.is_banned(name, now) {
+            return Ok(JoinOutcome::Banned);
+        }
+        if inner.users.contains_key(name) {
+            return Ok(JoinOutcome::UsernameTaken);
+        }
+        let missed = match &mut inner.history {
+            Some(history) => history.user_joined(name)?,
+            None => vec![],
+        };
+        inner.users.insert(name.to_string(), writer);
+        Ok(JoinOutcome::Joined(missed))
+    }
+
+    /// Marks the missed DMs returned when `name` joined as delivered, once they were sent to it.
+    pub fn missed_dms_delivered(&self, name: &str) -> anyhow::Result<()> {
+        match &mut self.inner.lock().unwrap().history {
+            Some(history) => history.mark_delivered(name),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that `name` can join with the given password. Fails with [`AccountError`] if it
+    /// cannot.
+    pub fn login(&self, name: &str, password: Option<&str>) -> anyhow::Result<()> {
+        let Some(opts) = &self.opts.accounts else {
+            return match password {
+                Some(_) => Err(AccountError::NotEnabled.into()),
+                None => Ok(()),
+            };
+        };
+        let password_hash = {
+            let mut inner = self.inner.lock().unwrap();
+            let db = inner.accounts.as_mut().expect("accounts are enabled");
+            let password_hash = db.password_hash(name).map(str::to_string);
+            match (password_hash, password) {
+                (None, None) if opts.allow_anonymous => return Ok(()),
+                (None, None) => return Err(AccountError::LoginRequired.into()),
+                (Some(_), None) => return Err(AccountError::Reserved.into()),
+                (None, Some(_)) => return Err(AccountError::InvalidCredentials.into()),
+                (Some(password_hash), Some(_)) => {
+                    db.start_login(name, self.opts.clock.now())?;
+                    password_hash
+                }
+            }
+        };
+        // Verifying the password is slow on purpose, so the state is not locked in the meantime
+        let password = password.unwrap_or_default();
+        if !accounts::verify_password(password, &password_hash) {
+            log::info!("Failed login of user {name}");
+            return Err(AccountError::InvalidCredentials.into());
+        }
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(db) = &mut inner.accounts {
+            db.login_succeeded(name);
+        }
+        Ok(())
+    }
+
+    /// Creates an account for `name`. Fails with [`AccountError`] if the name is not available.
+    pub fn register(&self, name: &str, password: &str) -> anyhow::Result<()> {
+        if self.opts.accounts.is_none() {
+            return Err(AccountError::NotEnabled.into());
+        }
+        if self
+            .opts
+            .admin
+            .as_ref()
+            .is_some_and(|admin| admin.name == name)
+        {
+            return Err(AccountError::Reserved.into());
+        }
+        if password.is_empty() {
+            return Err(AccountError::EmptyPassword.into());
+        }
+        let password_hash = accounts::hash_password(password)?;
+        let mut inner = self.inner.lock().unwrap();
+        // An anonymous user cannot lose its name while it is connected
+        if inner.users.contains_key(name) {
+            return Err(AccountError::UsernameTaken.into());
+        }
+        inner
+            .accounts
+            .as_mut()
+            .expect("accounts are enabled")
+            .register(name, password_hash)
+    }
+
+    /// Removes the user from the server and cancels its file transfers. Returns the cancelled
+    /// transfers with the writers of their other sides, so that they can be told.
+    pub fn remove_user(&self, name: &str) -> Vec<(TransferId, SharedWriter)> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.users.remove(name);
+        inner.rooms.remove_user(name);
+        let cancelled = inner.transfers.remove_user(name);
+        cancelled
+            .into_iter()
+            .filter_map(|(id, other)| Some((id, inner.users.get(&other)?.clone())))
+            .collect()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let inner = self.inner.lock().unwrap();
+        self.metrics
+            .snapshot(inner.connections.len(), inner.users.len())
+    }
+
+    pub fn usernames(&self) -> Vec<String> {
+        self.inner.lock().unwrap().users.keys().cloned().collect()
+    }
+
+    /// Returns the writer of `user`, if it is connected.
+    pub fn user_writer(&self, user: &str) -> Option<SharedWriter> {
+        self.inner.lock().unwrap().users.get(user).cloned()
+    }
+
+    /// Bans `user` and returns its writer if it is connected, so that it can be disconnected.
+    pub fn ban(&self, user: &str, duration: Duration) -> Option<SharedWriter> {
+        let now = self.opts.clock.now();
+        let mut inner = self.inner.lock().unwrap();
+        inner.moderation.ban(user, now, duration);
+        inner.users.get(user).cloned()
+    }
+
+    pub fn mute(&self, user: &str) {
+        self.inner.lock().unwrap().moderation.mute(user);
+    }
+
+    pub fn is_muted(&self, user: &str) -> bool {
+        self.inner.lock().unwrap().moderation.is_muted(user)
+    }
+
+    /// Decides where a DM should go and stores it in the history.
+    pub fn route_dm(&self, from: &str, to: &str, message: &str) -> anyhow::Result<DmRoute> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let recipient = inner.users.get(to).cloned();
+        let Some(history) = &mut inner.history else {
+            return Ok(recipient.map_or(DmRoute::UnknownUser, DmRoute::Online));
+        };
+        if recipient.is_none() && !history.is_known_user(to) {
+            return Ok(DmRoute::UnknownUser);
+        }
+        history.record_dm(from, to, message, recipient.is_some())?;
+        Ok(recipient.map_or(DmRoute::Stored, DmRoute::Online))
+    }
+
+    /// Stores a broadcast in the history and returns the writers of all joined users except for
+    /// `from`.
+    pub fn broadcast(&self, from: &str, message: &str) -> anyhow::Result<Vec<SharedWriter>> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(history) = &mut inner.history {
+            history.record_broadcast(from, message)?;
+        }
+        Ok(inner
+            .users
+            .iter()
+            .filter(|(user, _)| *user != from)
+            .map(|(_, writer)| writer.clone())
+            .collect())
+    }
+
+    /// Returns `None` if the history is not enabled.
+    pub fn history_page(
+        &self,
+        user: &str,
+        since: u64,
+        limit: usize,
+    ) -> Option<Vec<HistoryMessage>> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .history
+            .as_ref()
+            .map(|history| history.page(user, since, limit))
+    }
+
+    pub fn create_room(&self, room: &str, user: &str) -> Result<(), RoomError> {
+        self.inner.lock().unwrap().rooms.create(room, user)
+    }
+
+    pub fn join_room(&self, room: &str, user: &str) -> Result<(), RoomError> {
+        self.inner.lock().unwrap().rooms.join(room, user)
+    }
+
+    pub fn leave_room(&self, room: &str, user: &str) -> Result<(), RoomError> {
+        self.inner.lock().unwrap().rooms.leave(room, user)
+    }
+
+    pub fn room_names(&self) -> Vec<String> {
+        self.inner.lock().unwrap().rooms.names()
+    }
+
+    /// Returns the writers of the members of `room` (except for `user`).
+    pub fn room_recipients(&self, room: &str, user: &str) -> Result<Vec<SharedWriter>, RoomError> {
+        let inner = self.inner.lock().unwrap();
+        let members = inner.rooms.recipients(room, user)?;
+        Ok(members
+            .iter()
+            .filter_map(|member| inner.users.get(member).cloned())
+            .collect())
+    }
+
+    /// Registers a file offered by `from` to `to` and returns its ID with the writer of `to`, or
+    /// `None` if `to` is not connected.
+    pub fn offer_file(
+        &self,
+        from: &str,
+        to: &str,
+        size: u64,
+    ) -> Option<(TransferId, SharedWriter)> {
+        let mut inner = self.inner.lock().unwrap();
+        let recipient = inner.users.get(to)?.clone();
+        Some((inner.transfers.offer(from, to, size), recipient))
+    }
+
+    /// Accepts a file offered to `user`, returns the writer of the sender if it is connected.
+    pub fn accept_file(
+        &self,
+        id: TransferId,
+        user: &str,
+    ) -> Result<Option<SharedWriter>, TransferError> {
+        let mut inner = self.inner.lock().unwrap();
+        let from = inner.transfers.accept(id, user)?;
+        Ok(inner.users.get(&from).cloned())
+    }
+
+    /// Checks a chunk sent by `user` and returns the writer of its recipient. If the chunk
+    /// cancels the transfer, the writer of the recipient is returned with the error.
+    pub fn file_chunk(
+        &self,
+        id: TransferId,
+        user: &str,
+        index: u64,
+        size: usize,
+    ) -> Result<Option<SharedWriter>, (TransferError, Option<SharedWriter>)> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        match inner.transfers.chunk(id, user, index, size) {
+            Ok(to) => Ok(inner.users.get(&to).cloned()),
+            Err((error, to)) => Err((error, to.and_then(|to| inner.users.get(&to).cloned()))),
+        }
+    }
+
+    /// Cancels a transfer of `user`, returns the writer of the other side.
+    pub fn cancel_file(
+        &self,
+        id: TransferId,
+        user: &str,
+    ) -> Result<Option<SharedWriter>, TransferError> {
+        let mut inner = self.inner.lock().unwrap();
+        let other = inner.transfers.cancel(id, user)?;
+        Ok(inner.users.get(&other).cloned())
+    }
+
+    pub fn add_thread(&self, thread: JoinHandle<()>) {
+        let mut threads = self.threads.lock().unwrap();
+        threads.retain(|thread| !thread.is_finished());
+        threads.push(thread);
+    }
+
+    /// Closes all connections, which wakes up all client threads blocked on reading or writing.
+    pub fn disconnect_all(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        for stream in inner.connections.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        inner.rooms.clear();
+        inner.transfers.clear();
+    }
+
+    pub fn join_threads(&self) {
+        let threads = std::mem::take(&mut *self.threads.lock().unwrap());
+        for thread in threads {
+            let _ = thread.join();
+        }
+    }
+}
@@ -0,0 +1,134 @@
+use crate::codec::WireFormat;
+use crate::jsonl;
+use crate::messages::{ClientToServerMsg, ServerToClientMsg};
+use crate::ServerOpts;
+use anyhow::Context;
+use std::fs::File;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// A single line of a trace.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct Record {
+    /// Time since the recording started, in microseconds.
+    pub at: u64,
+    pub event: Event,
+}
+
+/// Something that has happened in a recorded server.
+///
+/// Connections are numbered from 0 in the order in which the server has accepted them, the
+/// events of a connection refer to it by this number (`client`).
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub enum Event {
+    /// The first event of every trace.
+    Started(Settings),
+    /// The server has accepted a connection, even if it rejects it because it is full.
+    Connected { client: u64 },
+    /// The server has read a message from the client.
+    Received { client: u64, msg: ClientToServerMsg },
+    /// The client has sent a message larger than the maximum message size.
+    TooLarge { client: u64 },
+    /// The client has closed the connection.
+    Closed { client: u64 },
+    /// Reading from the client has failed, e.g. because the connection was broken or the client
+    /// has sent something else than a message.
+    Lost { client: u64, error: String },
+    /// The server has sent a message to the client.
+    Sent { client: u64, msg: ServerToClientMsg },
+    /// The server has started to shut down.
+    Stopped,
+}
+
+/// Options of the recorded server that affect how it behaves.
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub struct Settings {
+    pub max_clients: usize,
+    pub max_message_size: usize,
+    pub wire_format: WireFormat,
+    /// Was the history enabled.
+    pub history: bool,
+}
+
+impl Settings {
+    fn new(opts: &ServerOpts) -> Self {
+        Self {
+            max_clients: opts.max_clients,
+            max_message_size: opts.max_message_size,
+            wire_format: opts.wire_format,
+            history: opts.history.is_some(),
+        }
+    }
+}
+
+/// Writes the events of a server to a trace. It is shared by all threads of the server.
+pub(crate) struct Recorder {
+    start: Instant,
+    file: Mutex<File>,
+    next_client: AtomicU64,
+}
+
+impl Recorder {
+    /// Replaces the file if it exists.
+    pub fn create(path: &Path, opts: &ServerOpts) -> anyhow::Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Cannot create trace file {}", path.display()))?;
+        let recorder = Self {
+            start: Instant::now(),
+            file: Mutex::new(file),
+            next_client: AtomicU64::new(0),
+        };
+        recorder.record(Event::Started(Settings::new(opts)));
+        Ok(recorder)
+    }
+
+    /// A trace that cannot be written does not stop the server.
+    pub fn record(&self, event: Event) {
+        let record = Record {
+            at: self.start.elapsed().as_micros() as u64,
+            event,
+        };
+        if let Err(error) = jsonl::append(&mut self.file.lock().unwrap(), &record) {
+            log::warn!("Cannot write trace: {error}");
+        }
+    }
+}
+
+/// Records the events of a single connection. Does nothing if the recording is disabled.
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionTrace {
+    recorder: Option<Arc<Recorder>>,
+    client: u64,
+}
+
+impl ConnectionTrace {
+    /// Records a new connection.
+    pub fn connected(recorder: Option<&Arc<Recorder>>) -> Self {
+        let Some(recorder) = recorder else {
+            return Self::default();
+        };
+        let client = recorder.next_client.fetch_add(1, Ordering::SeqCst);
+        recorder.record(Event::Connected { client });
+        Self {
+            recorder: Some(recorder.clone()),
+            client,
+        }
+    }
+
+    /// The event is only created when the recording is enabled.
+    pub fn record(&self, event: impl FnOnce(u64) -> Event) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(event(self.client));
+        }
+    }
+}
+
+/// Reads a trace written by a server with [`ServerOpts::record`]. The last record is left out if
+/// the server has crashed or is still running and has not written it whole.
+pub fn read_trace(path: &Path) -> anyhow::Result<Vec<Record>> {
+    let file = File::open(path).with_context(|| format!("Cannot open trace {}", path.display()))?;
+    let (records, _) = jsonl::read(file, "trace")?;
+    Ok(records)
+}
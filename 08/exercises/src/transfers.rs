@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+
+pub type TransferId = u64;
+
+/// Reasons why a file transfer operation can be refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TransferError {
+    /// The transfer does not exist, or the user is not the one who can do the operation.
+    Unknown(TransferId),
+    NotAccepted(TransferId),
+    /// The chunk does not continue the file, or the file would be larger than announced.
+    UnexpectedChunk(TransferId, u64),
+}
+
+impl Display for TransferError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferError::Unknown(id) => write!(f, "Unknown transfer {id}"),
+            TransferError::NotAccepted(id) => write!(f, "Transfer {id} was not accepted"),
+            TransferError::UnexpectedChunk(id, index) => {
+                write!(f, "Unexpected chunk {index} of transfer {id}")
+            }
+        }
+    }
+}
+
+/// A file offered by one user to another.
+struct Transfer {
+    from: String,
+    to: String,
+    size: u64,
+    accepted: bool,
+    /// Index of the next chunk.
+    next_chunk: u64,
+    /// Bytes relayed so far.
+    received: u64,
+}
+
+/// File transfers that are offered or running.
+///
+/// The server only relays the chunks in order and checks that the file has the announced size,
+/// it is up to the receiver to check its hash.
+#[derive(Default)]
+pub struct Transfers {
+    transfers: HashMap<TransferId, Transfer>,
+    last_id: TransferId,
+}
+
+impl Transfers {
+    pub fn offer(&mut self, from: &str, to: &str, size: u64) -> TransferId {
+        self.last_id += 1;
+        self.transfers.insert(
+            self.last_id,
+            Transfer {
+                from: from.to_string(),
+                to: to.to_string(),
+                size,
+                accepted: false,
+                next_chunk: 0,
+                received: 0,
+            },
+        );
+        self.last_id
+    }
+
+    /// Accepts an offer sent to `user`, returns the sender of the offer. An empty file needs no
+    /// chunks, so its transfer is finished right away.
+    pub fn accept(&mut self, id: TransferId, user: &str) -> Result<String, TransferError> {
+        let transfer = self
+            .transfers
+            .get_mut(&id)
+            .filter(|transfer| transfer.to == user && !transfer.accepted)
+            .ok_or(TransferError::Unknown(id))?;
+        transfer.accepted = true;
+        let from = transfer.from.clone();
+        if transfer.size == 0 {
+            self.transfers.remove(&id);
+        }
+        Ok(from)
+    }
+
+    /// Checks that `user` can send the chunk and returns the recipient of the chunk. The
+    /// transfer is finished after its last chunk.
+    /// A chunk that does not fit cancels the transfer, the recipient is returned with the error
+    /// then, so that it can be told.
+    pub fn chunk(
+        &mut self,
+        id: TransferId,
+        user: &str,
+        index: u64,
+        size: usize,
+    ) -> Result<String, (TransferError, Option<String>)> {
+        let transfer = self
+            .transfers
+            .get_mut(&id)
+            .filter(|transfer| transfer.from == user)
+            .ok_or((TransferError::Unknown(id), None))?;
+        if !transfer.accepted {
+            return Err((TransferError::NotAccepted(id), None));
+        }
+        let received = transfer.received + size as u64;
+        if index != transfer.next_chunk || received > transfer.size {
+            let transfer = self.transfers.remove(&id).unwrap();
+            return Err((TransferError::UnexpectedChunk(id, index), Some(transfer.to)));
+        }
+        transfer.next_chunk += 1;
+        transfer.received = received;
+        if received < transfer.size {
+            return Ok(transfer.to.clone());
+        }
+        Ok(self.transfers.remove(&id).unwrap().to)
+    }
+
+    /// Cancels a transfer of `user` (on either side), returns the other side.
+    pub fn cancel(&mut self, id: TransferId, user: &str) -> Result<String, TransferError> {
+        let transfer = self
+            .transfers
+            .get(&id)
+            .filter(|transfer| transfer.from == user || transfer.to == user)
+            .ok_or(TransferError::Unknown(id))?;
+        let other = match transfer.from == user {
+            true => transfer.to.clone(),
+            false => transfer.from.clone(),
+        };
+        self.transfers.remove(&id);
+        Ok(other)
+    }
+
+    /// Cancels all transfers of `user`, e.g. when it disconnects. Returns the cancelled
+    /// transfers with the other side of each.
+    pub fn remove_user(&mut self, user: &str) -> Vec<(TransferId, String)> {
+        let mut cancelled = vec![];
+        self.transfers.retain(|id, transfer| {
+            let other = if transfer.from == user {
+                &transfer.to
+            } else if transfer.to == user {
+                &transfer.from
+            } else {
+                return true;
+            };
+            cancelled.push((*id, other.clone()));
+            false
+        });
+        cancelled
+    }
+
+    pub fn clear(&mut self) {
+        self.transfers.clear();
+    }
+}
@@ -1,23 +1,32 @@
+use crate::codec::{Codec, JsonCodec};
 use serde::Serialize;
 use std::io::Write;
 use std::marker::PhantomData;
 
-pub struct MessageWriter<T, W> {
+pub struct MessageWriter<T, W, C = JsonCodec> {
     sink: W,
+    codec: C,
     _phantom: PhantomData<T>,
 }
 
 impl<W: Write, T: Serialize> MessageWriter<T, W> {
     pub fn new(write: W) -> Self {
+        Self::with_codec(write, JsonCodec)
+    }
+}
+
+impl<W: Write, T: Serialize, C: Codec> MessageWriter<T, W, C> {
+    pub fn with_codec(write: W, codec: C) -> Self {
         Self {
             sink: write,
+            codec,
             _phantom: Default::default(),
         }
     }
 
     pub fn write(&mut self, message: T) -> anyhow::Result<()> {
         // Serialize the data
-        let serialized = serde_json::to_vec(&message)?;
+        let serialized = self.codec.encode(&message)?;
 
         // Write size
         let size = serialized.len() as u32;
@@ -1,5 +1,161 @@
-//! You can use this file for experiments.
+//! Runs the chat server until it receives Ctrl-C.
+//!
+//! Every option can be passed as a command line flag (e.g. `--max-clients 10`), as an environment
+//! variable (e.g. `CHAT_MAX_CLIENTS=10`) or in a TOML file passed with `--config`
+//! (e.g. `max_clients = 10`), in this order of precedence.
+//! Keys that this server does not know are ignored, so that the same file can be shared with
+//! the servers from the other weeks.
 
-fn main() {
-    println!("Hello Rust!");
+use clap::Parser;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use week09::net::ListenAddr;
+use week09::reader::DEFAULT_MAX_MESSAGE_SIZE;
+use week09::{run_server, ServerOpts};
+
+#[derive(Parser, Deserialize, Default)]
+#[serde(default)]
+struct Config {
+    /// TOML file with the configuration
+    #[arg(long, env = "CHAT_CONFIG")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    /// Address on which the server listens [default: 127.0.0.1]
+    #[arg(long, env = "CHAT_ADDRESS")]
+    address: Option<IpAddr>,
+    /// Port on which the server listens, 0 lets the operating system choose one [default: 0]
+    #[arg(long, env = "CHAT_PORT")]
+    port: Option<u16>,
+    /// Path of a Unix socket on which the server listens in addition to TCP/IP
+    #[arg(long, env = "CHAT_UNIX_SOCKET")]
+    unix_socket: Option<PathBuf>,
+    /// Maximum number of clients connected at once [default: 100]
+    #[arg(long, env = "CHAT_MAX_CLIENTS")]
+    max_clients: Option<usize>,
+    /// Maximum size of a single message in bytes [default: 65536]
+    #[arg(long, env = "CHAT_MAX_MESSAGE_SIZE")]
+    max_message_size: Option<usize>,
+    /// Maximum number of bytes waiting to be sent to a single client [default: 1048576]
+    #[arg(long, env = "CHAT_MAX_OUTBOUND_BUFFER")]
+    max_outbound_buffer: Option<usize>,
+    /// How long can the outbound buffer of a client stay above its maximum before the client is
+    /// disconnected, in seconds [default: 1]
+    #[arg(long, env = "CHAT_SLOW_CONSUMER_TIMEOUT")]
+    slow_consumer_timeout: Option<f64>,
+    /// File where the message history is stored, the history is disabled if not set
+    #[arg(long, env = "CHAT_HISTORY")]
+    history: Option<PathBuf>,
+    /// How long to wait for the `Join` message, in seconds [default: 2]
+    #[arg(long, env = "CHAT_JOIN_TIMEOUT")]
+    join_timeout: Option<f64>,
+    /// Number of event loop threads [default: 1]
+    #[arg(long, env = "CHAT_THREADS")]
+    threads: Option<usize>,
+    /// Port of an HTTP endpoint on localhost that serves Prometheus metrics at `/metrics`,
+    /// the endpoint is disabled if not set
+    #[arg(long, env = "CHAT_METRICS_PORT")]
+    metrics_port: Option<u16>,
+    /// File where the server records its clients for debugging, the recording is disabled if
+    /// not set
+    #[arg(long, env = "CHAT_RECORD")]
+    record: Option<PathBuf>,
+    /// Log level: off, error, warn, info, debug or trace [default: info]
+    #[arg(long, env = "CHAT_LOG_LEVEL")]
+    log_level: Option<LevelFilter>,
+}
+
+impl Config {
+    fn load() -> anyhow::Result<Self> {
+        let args = Config::parse();
+        let file = match &args.config {
+            Some(path) => {
+                let content = std::fs::read_to_string(path).map_err(|error| {
+                    anyhow::anyhow!("Cannot read config file {}: {error}", path.display())
+                })?;
+                toml::from_str(&content).map_err(|error| {
+                    anyhow::anyhow!("Cannot parse config file {}: {error}", path.display())
+                })?
+            }
+            None => Config::default(),
+        };
+        Ok(args.or(file))
+    }
+
+    /// Fills the values that are missing in `self` with values from `other`.
+    fn or(self, other: Config) -> Config {
+        Config {
+            config: self.config,
+            address: self.address.or(other.address),
+            port: self.port.or(other.port),
+            unix_socket: self.unix_socket.or(other.unix_socket),
+            max_clients: self.max_clients.or(other.max_clients),
+            max_message_size: self.max_message_size.or(other.max_message_size),
+            max_outbound_buffer: self.max_outbound_buffer.or(other.max_outbound_buffer),
+            slow_consumer_timeout: self.slow_consumer_timeout.or(other.slow_consumer_timeout),
+            history: self.history.or(other.history),
+            join_timeout: self.join_timeout.or(other.join_timeout),
+            threads: self.threads.or(other.threads),
+            metrics_port: self.metrics_port.or(other.metrics_port),
+            record: self.record.or(other.record),
+            log_level: self.log_level.or(other.log_level),
+        }
+    }
+
+    fn listen(&self) -> Vec<ListenAddr> {
+        let tcp = SocketAddr::new(
+            self.address.unwrap_or(Ipv4Addr::LOCALHOST.into()),
+            self.port.unwrap_or(0),
+        );
+        let mut listen = vec![ListenAddr::Tcp(tcp)];
+        listen.extend(self.unix_socket.clone().map(ListenAddr::Unix));
+        listen
+    }
+
+    fn server_opts(&self) -> anyhow::Result<ServerOpts> {
+        Ok(ServerOpts {
+            listen: self.listen(),
+            metrics: self
+                .metrics_port
+                .map(|port| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)),
+            max_clients: self.max_clients.unwrap_or(100),
+            history: self.history.clone(),
+            join_timeout: Duration::try_from_secs_f64(self.join_timeout.unwrap_or(2.0))?,
+            max_message_size: self.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
+            max_outbound_buffer: self.max_outbound_buffer.unwrap_or(1024 * 1024),
+            slow_consumer_timeout: Duration::try_from_secs_f64(
+                self.slow_consumer_timeout.unwrap_or(1.0),
+            )?,
+            threads: self.threads.unwrap_or(1),
+            record: self.record.clone(),
+        })
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let config = Config::load()?;
+    env_logger::Builder::new()
+        .filter_level(config.log_level.unwrap_or(LevelFilter::Info))
+        .init();
+
+    let (stop_tx, stop_rx) = std::sync::mpsc::channel();
+    ctrlc::set_handler(move || {
+        let _ = stop_tx.send(());
+    })?;
+
+    let server = run_server(config.server_opts()?)?;
+    for address in server.addresses() {
+        log::info!("Listening on {address}");
+    }
+    if let Some(address) = server.metrics_address() {
+        log::info!("Serving metrics on http://{address}/metrics");
+    }
+
+    let _ = stop_rx.recv();
+    log::info!("Shutting down");
+    drop(server);
+    log::info!("Server stopped");
+    Ok(())
 }
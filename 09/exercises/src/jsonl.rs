@@ -0,0 +1,58 @@
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, Read, Write};
+use std::path::Path;
+
+/// Opens a file with a JSON line for every record, creating it if it does not exist, and reads
+/// its records. `what` describes the file in errors.
+///
+/// A server that crashed while appending a record can leave a partial last line without a
+/// newline. It is cut off, so that the next record starts on its own line.
+pub fn open<T: DeserializeOwned>(path: &Path, what: &str) -> anyhow::Result<(File, Vec<T>)> {
+    let file = OpenOptions::new()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Cannot open {what} {}", path.display()))?;
+    let (records, complete) = read(&file, what)?;
+    if complete < file.metadata()?.len() {
+        log::warn!(
+            "Removing a partial record from the end of {what} {}",
+            path.display()
+        );
+        file.set_len(complete)?;
+    }
+    Ok((file, records))
+}
+
+/// Reads the records of a JSON-lines file, ignoring a partial last line without a newline.
+/// Returns them together with the length of the complete lines.
+pub fn read<T: DeserializeOwned>(mut file: impl Read, what: &str) -> anyhow::Result<(Vec<T>, u64)> {
+    let mut data = vec![];
+    file.read_to_end(&mut data)?;
+    let complete = data
+        .iter()
+        .rposition(|&byte| byte == b'\n')
+        .map_or(0, |end| end + 1);
+    let mut records = vec![];
+    for (index, line) in data[..complete].lines().enumerate() {
+        let record = serde_json::from_str(&line?)
+            .with_context(|| format!("Corrupted {what} at line {}", index + 1))?;
+        records.push(record);
+    }
+    Ok((records, complete as u64))
+}
+
+/// Appends a record to a file opened by [`open`].
+pub fn append<T: Serialize>(file: &mut File, record: &T) -> anyhow::Result<()> {
+    // The newline is written together with the record, so a line without one is always a record
+    // cut short by a crash, which `open` can safely remove
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    file.flush()?;
+    Ok(())
+}
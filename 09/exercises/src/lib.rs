@@ -1,609 +1,756 @@
-//! TODO: implement a simple chat server using non-blocking I/O
+//! Chat server that uses non-blocking I/O with `epoll`.
 //!
-//! The chat server should behave identically as the one from last week, with one new feature.
-//! However, it should be implemented using non-blocking I/O and run on a single thread.
-//! It should still support concurrency and allow the connection of multiple clients at once.
+//! It speaks the same protocol as the week08 server, see [`messages`]. Each event loop serves its
+//! clients on a single thread and sleeps until the next I/O event or the next timeout, it never
+//! spins or blocks. Messages for each client are queued in an outbound buffer, which is flushed
+//! whenever the socket becomes writable, see [`ServerOpts::max_outbound_buffer`]. With more than
+//! one thread (see [`ServerOpts::threads`]), the clients are split between several event loops
+//! that talk to each other through mailboxes.
 //!
-//! Ideally, reuse your implementation from last week, but change blocking I/O to non-blocking I/O.
-//! Your code should not "spin", i.e. use 100% of CPU all the time. It should also not sleep for
-//! arbitrary duration of time, it should always sleep until the next I/O event or the next timeout
-//! event. Use `epoll` (or a similar mechanism on your OS) to achieve this. You can use `epoll`
-//! on Linux or the `mio` crate on macOS/Windows (or anything else that you want).
-//!
-//! **Do not use blocking I/O. Do not use `async/await` or any external crates that deal with
-//! networking, except for `epoll` and similar crates, for this assignment.**
-//!
-//! Note: it is enough to deal with non-blocking reads, you can simply assume that writes will be
-//! non-blocking. As a **bonus**, you can try to sketch a solution for also dealing with
-//! non-blocking writes.
-//!
-//! TODO(question): try to examine the message protocol from the last week. What issue would there be
-//! if we used non-blocking I/O for reading with the previous implementation? Could we just use the
-//! previous `MessageReader` as it was? Try to replace the current reader with it and describe what
-//! is the issue.
-
-/// The following modules were prepared for you. You should not need to modify them.
-///
-/// Take a look at this file to see how should the individual messages be handled
-mod messages;
+//! With non-blocking I/O, a read can return only a part of a message, so
+//! [`reader::MessageReader`] keeps the partial message in its buffer until the rest arrives,
+//! instead of waiting for the whole message like a blocking reader would.
+
+use crate::metrics::Stats;
+use crate::net::{ListenAddr, Listener};
+use crate::server::Server;
+use crate::shard::{ShardHandle, ShardMsg};
+use crate::state::SharedState;
+use crate::trace::Event;
+use std::net::{SocketAddr, TcpListener};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Persistent message history
+mod history;
+/// HTTP endpoint with the metrics
+mod http;
+/// Files with a JSON line for every record
+mod jsonl;
+/// Messages of the chat protocol, their documentation describes how the server handles them
+pub mod messages;
+/// Counters and gauges describing the running server
+pub mod metrics;
+/// Listening on TCP/IP and Unix sockets
+pub mod net;
 /// Message reading
-mod reader;
+pub mod reader;
+/// The event loop of the server
+mod server;
+/// Communication between event loops
+mod shard;
+/// State shared between the event loops
+mod state;
+/// Recording of the server for debugging
+pub mod trace;
 /// Message writing
-mod writer;
+pub mod writer;
+
+#[derive(Clone)]
+pub struct ServerOpts {
+    /// Addresses on which the server listens, there has to be at least one.
+    pub listen: Vec<ListenAddr>,
+    /// Maximum number of clients that can be connected to the server at once, through all
+    /// listeners together.
+    pub max_clients: usize,
+    /// File where the history of broadcasts and DMs is stored.
+    /// If it is `None`, the history is disabled.
+    pub history: Option<PathBuf>,
+    /// How long do we wait for the `Join` message after a client connects.
+    pub join_timeout: Duration,
+    /// Maximum size of a single message (in bytes) that a client can send to the server.
+    pub max_message_size: usize,
+    /// Maximum number of bytes waiting to be sent to a single client.
+    /// A client whose outbound buffer stays above this limit for longer than
+    /// `slow_consumer_timeout` is disconnected, so that it cannot make the server buffer an
+    /// unbounded amount of data. It should be larger than the largest message that the server
+    /// sends.
+    pub max_outbound_buffer: usize,
+    /// How long can the outbound buffer of a client stay above `max_outbound_buffer`, so that a
+    /// short burst of messages does not disconnect a client that is reading them.
+    pub slow_consumer_timeout: Duration,
+    /// Number of event loop threads. Each of them serves a part of the clients.
+    /// With a single thread (the default mode), the whole server runs on one thread.
+    pub threads: usize,
+    /// Address of an HTTP endpoint that serves the metrics of the server at `/metrics`, in the
+    /// Prometheus text format. The endpoint has no authentication, so it should listen on
+    /// localhost. If it is `None`, the endpoint is disabled.
+    pub metrics: Option<SocketAddr>,
+    /// File where the server records every connection and every message that it receives or
+    /// sends, see [`trace::read_trace`].
+    /// The file is replaced when the server starts. If it is `None`, the recording is disabled.
+    pub record: Option<PathBuf>,
+}
 
-#[derive(Copy, Clone)]
-struct ServerOpts {
-    /// Maximum number of clients that can be connected to the server at once.
-    max_clients: usize,
+/// Representation of a running server
+pub struct RunningServer {
+    /// Addresses on which the server is running, in the order of [`ServerOpts::listen`]
+    addresses: Vec<ListenAddr>,
+    /// Mailboxes used to tell the event loops to stop
+    shards: Vec<ShardHandle>,
+    /// Threads that run the event loops
+    threads: Vec<JoinHandle<anyhow::Result<()>>>,
+    state: Arc<SharedState>,
+    /// Address of the metrics endpoint, if it is enabled
+    metrics_address: Option<SocketAddr>,
+    /// Thread that serves the metrics endpoint. The requests are served with blocking I/O, so
+    /// they are kept away from the event loops, where a slow HTTP client could stall the chat.
+    metrics_thread: Option<JoinHandle<()>>,
+    /// Tells the metrics thread to stop after it is woken up
+    stopping: Arc<AtomicBool>,
 }
 
-/// TODO: implement the following function called `run_server`
-/// It should start a chat server on a TCP/IP port assigned to it by the operating system and
-/// return a structure called `RunningServer`. This struct should have a method called `port`,
-/// which returns the port on which the server is running.
-///
-/// You should create a single thread that will run the whole server, but you should not create
-/// any additional threads for handling clients. The thread is only used to let the tests know
-/// on which port is the server running.
-///
-/// The server should implement the messages described in `messages.rs`, see the message comments
-/// for more details. The details are the same as last week, with one exception described below.
+impl RunningServer {
+    /// Returns the port of the first TCP/IP listener.
+    ///
+    /// Panics if the server listens only on Unix sockets.
+    pub fn port(&self) -> u16 {
+        self.addresses
+            .iter()
+            .find_map(|address| match address {
+                ListenAddr::Tcp(address) => Some(address.port()),
+                ListenAddr::Unix(_) => None,
+            })
+            .expect("the server does not listen on TCP/IP")
+    }
+
+    /// Returns the addresses on which the server listens, including the ports chosen by the
+    /// operating system.
+    pub fn addresses(&self) -> &[ListenAddr] {
+        &self.addresses
+    }
+
+    /// Returns the address of the metrics endpoint, including the port chosen by the operating
+    /// system, or `None` if it is disabled.
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_address
+    }
+
+    /// Returns the current metrics of the server.
+    pub fn stats(&self) -> Stats {
+        self.state.stats()
+    }
+}
+
+impl Drop for RunningServer {
+    fn drop(&mut self) {
+        if let Some(recorder) = &self.state.recorder {
+            recorder.record(Event::Stopped);
+        }
+        for shard in &self.shards {
+            shard.post(ShardMsg::Stop);
+        }
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+        if let Some(thread) = self.metrics_thread.take() {
+            self.stopping.store(true, Ordering::SeqCst);
+            if let Some(address) = self.metrics_address {
+                net::wake_up(address);
+            }
+            let _ = thread.join();
+        }
+        for address in &self.addresses {
+            net::remove_socket(address);
+        }
+    }
+}
+
+/// Starts the chat server on the addresses from [`ServerOpts::listen`], with the event loops on
+/// their own threads; TCP/IP port 0 lets the operating system choose a free port, see
+/// [`RunningServer::port`]. No threads are created for individual clients.
 ///
 /// # Client connection
-/// When a client connects to the server, it should send a `Join` message.
-/// - **(NEW)** If the client does not send a `Join` message within two seconds, the server should
-/// send an error "Timed out waiting for Join" and disconnect the client immediately.
-/// If it sends anything else, the server should respond with an error "Unexpected message received"
-/// and disconnect the client immediately.
-/// If the user sends a Join message (with a unique username), the server should respond with
-/// the `Welcome` message.
-/// Then it should start receiving requests from the client.
-/// If the client ever sends the `Join` message again, the server should respond with an error
-/// "Unexpected message received" and disconnect the client immediately.
+/// A client has to send `Join` (with a unique username) within `opts.join_timeout`, otherwise it
+/// receives an error "Timed out waiting for Join" and is disconnected. The server responds to
+/// `Join` with `Welcome`. If the client sends anything else first, or sends `Join` again later,
+/// the server responds with an error "Unexpected message received" and disconnects it.
 ///
 /// # Maximum number of clients
-/// When a client connects and there are already `opts.max_clients` other clients connected, the
-/// server should respond with an error "Server is full" and disconnect the client immediately.
-/// Note that if the server is full, the client should be disconnected even before it sends the
-/// `Join` message.
+/// A client that connects while `opts.max_clients` other clients are connected receives an error
+/// "Server is full" and is disconnected right away, before it sends `Join`.
 ///
 /// # Graceful shutdown
-/// When `RunningServer` is dropped, it should:
-/// 1) Stop receiving new TCP/IP connections
-/// 2) Correctly disconnect all connected users
-/// 3) Wait until all threads that it has created has completed executing
-///
-/// Graceful shutdown, even with non-blocking I/O and `epoll`, can be challenging.
-/// Think about how you can get around this - can you find some way to "wake" the `epoll`?
-/// You can use the same mechanism as last week, or try something else.
-///
-/// See tests for more details.
-fn run_server(opts: ServerOpts) -> anyhow::Result<RunningServer> { todo!() }
+/// When the [`RunningServer`] is dropped, its event loops are woken up, stop accepting new
+/// connections, disconnect all users and finish.
+pub fn run_server(opts: ServerOpts) -> anyhow::Result<RunningServer> {
+    if opts.listen.is_empty() {
+        anyhow::bail!("The server has to listen on at least one address");
+    }
+    let mut listeners = vec![];
+    for address in &opts.listen {
+        let listener = Listener::bind(address)
+            .map_err(|error| anyhow::anyhow!("Cannot listen on {address}: {error}"))?;
+        listeners.push(listener);
+    }
+    let addresses = listeners
+        .iter()
+        .map(|listener| listener.local_addr())
+        .collect::<Result<Vec<_>, _>>()?;
+    let metrics_listener = opts
+        .metrics
+        .map(|address| {
+            TcpListener::bind(address)
+                .map_err(|error| anyhow::anyhow!("Cannot serve metrics on {address}: {error}"))
+        })
+        .transpose()?;
+    let metrics_address = metrics_listener
+        .as_ref()
+        .map(|listener| listener.local_addr())
+        .transpose()?;
+
+    let state = Arc::new(SharedState::new(&opts)?);
+    let (shards, mailboxes): (Vec<_>, Vec<_>) = (0..opts.threads.max(1))
+        .map(|_| shard::mailbox())
+        .collect::<std::io::Result<Vec<_>>>()?
+        .into_iter()
+        .unzip();
+
+    // The first event loop accepts the clients
+    let mut listeners = Some(listeners);
+    let servers = mailboxes
+        .into_iter()
+        .enumerate()
+        .map(|(id, mailbox)| {
+            Server::new(
+                opts.clone(),
+                id,
+                listeners.take().unwrap_or_default(),
+                mailbox,
+                shards.clone(),
+                state.clone(),
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let threads = servers
+        .into_iter()
+        .map(|server| std::thread::spawn(move || server.run()))
+        .collect();
+    let stopping = Arc::new(AtomicBool::new(false));
+    let metrics_thread = metrics_listener.map(|listener| {
+        let state = state.clone();
+        let stopping = stopping.clone();
+        std::thread::spawn(move || metrics_loop(listener, state, stopping))
+    });
+
+    Ok(RunningServer {
+        addresses,
+        shards,
+        threads,
+        state,
+        metrics_address,
+        metrics_thread,
+        stopping,
+    })
+}
 
+/// Serves the requests to the metrics endpoint one by one.
+fn metrics_loop(listener: TcpListener, state: Arc<SharedState>, stopping: Arc<AtomicBool>) {
+    for stream in listener.incoming() {
+        if stopping.load(Ordering::SeqCst) {
+            break;
+        }
+        let Ok(stream) = stream else {
+            continue;
+        };
+        if let Err(error) = http::handle_request(stream, || state.stats().to_prometheus()) {
+            log::debug!("Metrics request failed: {error}");
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
+    use crate::history::History;
     use crate::messages::{ClientToServerMsg, ServerToClientMsg};
-    use crate::reader::MessageReader;
+    use crate::net::{ListenAddr, Stream};
+    use crate::reader::{MessageReader, DEFAULT_MAX_MESSAGE_SIZE};
+    use crate::trace::{read_trace, Event, Settings};
     use crate::writer::MessageWriter;
     use crate::{run_server, RunningServer, ServerOpts};
     use std::io::{Read, Write};
-    use std::net::{Shutdown, TcpStream};
-    use std::sync::atomic::{AtomicUsize, Ordering};
-    use std::sync::{Arc, Barrier, Mutex};
-    use std::thread::spawn;
+    use std::net::{Shutdown, SocketAddr, TcpStream};
+    use std::os::unix::net::UnixStream;
+    use std::sync::Arc;
     use std::time::Duration;
-
-    // If you're struggling with this test, comment it and implement the rest of the
-    // functionality first.
-    #[test]
-    fn empty_server_shuts_down() {
-        run_test(opts(2), |_| Ok(()));
-    }
+    use tempfile::TempDir;
 
     #[test]
-    fn max_clients() {
-        run_test(opts(2), |server| {
-            let _client = server.client();
-            let _client2 = server.client();
-
-            let mut client3 = server.client();
-            client3.expect_error("Server is full");
-            client3.check_closed();
-
-            Ok(())
-        });
-    }
-
-    #[test]
-    fn max_clients_after_client_leaves() {
-        run_test(opts(2), |server| {
-            let _client = server.client();
-            let client2 = server.client();
-            client2.close();
+    fn disconnect_slow_consumer() {
+        let opts = ServerOpts {
+            max_outbound_buffer: 64 * 1024,
+            slow_consumer_timeout: Duration::from_millis(100),
+            ..opts(3)
+        };
+        run_test(opts, |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut leo = server.client();
+            leo.join("Leo");
+            // Zoe never reads its messages
+            let mut zoe = server.client();
+            zoe.join("Zoe");
+
+            let message = "a".repeat(8 * 1024);
+            let mut disconnected = false;
+            for i in 0..10000 {
+                mia.send(ClientToServerMsg::Broadcast {
+                    message: message.clone(),
+                });
+                leo.expect_message("Mia", &message);
+
+                if i % 10 == 0 && mia.list_users() == vec!["Leo".to_string(), "Mia".to_string()] {
+                    disconnected = true;
+                    break;
+                }
+            }
+            assert!(disconnected, "slow consumer was not disconnected");
 
-            sleep(1000);
+            // The others can keep chatting
+            leo.dm("Mia", "Hi");
+            mia.expect_message("Leo", "Hi");
 
-            let mut client3 = server.client();
-            client3.join("Foo");
+            // Zoe receives the rest of its current message and the error, and then the connection
+            // is closed
+            while let Some(Ok(_)) = zoe.reader.recv() {}
 
             Ok(())
         });
     }
 
     #[test]
-    fn max_clients_herd() {
-        let max_clients = 5;
-        run_test(opts(max_clients), |server| {
-            let thread_count = 50;
-
-            let server = Arc::new(server);
-            let barrier = Arc::new(Barrier::new(thread_count));
-
-            let errors = Arc::new(AtomicUsize::new(0));
-            let successes = Arc::new(AtomicUsize::new(0));
-
-            let joined_clients = Arc::new(Mutex::new(vec![]));
-            std::thread::scope(|s| {
-                for thread_id in 0..thread_count {
-                    let barrier = barrier.clone();
-                    let server = server.clone();
-                    let errors = errors.clone();
-                    let successes = successes.clone();
-                    let joined_clients = joined_clients.clone();
-                    s.spawn(move || {
-                        barrier.wait();
-                        let mut client = server.client();
-                        let _ = client.try_send(ClientToServerMsg::Join {
-                            name: format!("Thread {thread_id}"),
-                        });
-                        match client.recv() {
-                            ServerToClientMsg::Error(_) => {
-                                errors.fetch_add(1, Ordering::SeqCst);
-                            }
-                            ServerToClientMsg::Welcome => {
-                                successes.fetch_add(1, Ordering::SeqCst);
-                                // Make sure that the client doesn't disconnect
-                                joined_clients.lock().unwrap().push(client);
-                            }
-                            msg => {
-                                panic!("Unexpected message {msg:?}");
-                            }
-                        }
-                    });
+    fn disconnect_slow_consumer_after_burst() {
+        let opts = ServerOpts {
+            max_outbound_buffer: 64 * 1024,
+            slow_consumer_timeout: Duration::from_secs(2),
+            ..opts(2)
+        };
+        run_test(opts, |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            // Zoe does not read its messages until the burst is over
+            let mut zoe = server.client();
+            zoe.join("Zoe");
+
+            let message = "a".repeat(8 * 1024);
+            // The burst has to end well before the timeout, and the error has to be read before
+            // the connection is closed after another timeout
+            for _ in 0..1024 {
+                mia.send(ClientToServerMsg::Broadcast {
+                    message: message.clone(),
+                });
+            }
+            mia.ping();
+
+            // Nothing is sent to Zoe anymore, but it is disconnected anyway
+            sleep(2500);
+            assert_eq!(mia.list_users(), vec!["Mia".to_string()]);
+
+            // Zoe gets the rest of its current message, and then the error
+            loop {
+                match zoe.recv() {
+                    ServerToClientMsg::Message { from, message: msg } => {
+                        assert_eq!(from, "Mia");
+                        assert_eq!(msg, message);
+                    }
+                    ServerToClientMsg::Error(error) => {
+                        assert_eq!(error, "You are not reading messages fast enough");
+                        break;
+                    }
+                    msg => panic!("Unexpected response {msg:?}"),
                 }
-            });
-            assert_eq!(errors.load(Ordering::SeqCst), thread_count - max_clients);
-            assert_eq!(successes.load(Ordering::SeqCst), max_clients);
-
-            drop(joined_clients);
-
-            Ok(())
-        });
-    }
-
-    #[test]
-    fn list_users_before_join() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.send(ClientToServerMsg::ListUsers);
-            client.expect_error("Unexpected message received");
+            }
+            zoe.check_closed();
 
             Ok(())
         });
     }
 
     #[test]
-    fn join_after_half_sec() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            sleep(500);
-            client.join("Foo");
-            assert_eq!(client.list_users(), vec!["Foo".to_string()]);
-
-            Ok(())
-        });
-    }
+    fn slow_consumer_survives_burst() {
+        let opts = ServerOpts {
+            max_outbound_buffer: 64 * 1024,
+            slow_consumer_timeout: Duration::from_secs(10),
+            ..opts(2)
+        };
+        run_test(opts, |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut zoe = server.client();
+            zoe.join("Zoe");
+
+            // Much more than the socket buffers can hold, so most of it waits in the server
+            let message = "a".repeat(8 * 1024);
+            for _ in 0..2048 {
+                mia.send(ClientToServerMsg::Broadcast {
+                    message: message.clone(),
+                });
+            }
+            mia.ping();
 
-    #[test]
-    fn join_timeout() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            sleep(3000);
-            match client.try_send(ClientToServerMsg::Join {
-                name: "Bilbo".to_string(),
-            }) {
-                Ok(_) => {
-                    client.expect_error("Timed out waiting for Join");
-                }
-                Err(_) => {}
+            // Zoe reads the burst before the timeout, so it stays connected
+            for _ in 0..2048 {
+                zoe.expect_message("Mia", &message);
             }
+            assert_eq!(zoe.list_users(), vec!["Mia".to_string(), "Zoe".to_string()]);
 
             Ok(())
         });
     }
 
     #[test]
-    fn duplicated_join() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.join("Foo");
-            client.send(ClientToServerMsg::Join {
-                name: "Bar".to_string(),
-            });
-            client.expect_error("Unexpected message received");
+    fn sharded_dms_and_broadcasts() {
+        let opts = ServerOpts {
+            threads: 4,
+            ..opts(10)
+        };
+        run_test(opts, |server| {
+            // Clients are distributed between the event loops in a round-robin fashion
+            let mut clients: Vec<Client> = (0..8)
+                .map(|i| {
+                    let mut client = server.client();
+                    client.join(&format!("User {i}"));
+                    client
+                })
+                .collect();
+            assert_eq!(clients[3].list_users().len(), 8);
 
-            Ok(())
-        });
-    }
+            for (i, client) in clients.iter_mut().enumerate() {
+                let to = format!("User {}", (i + 1) % 8);
+                client.dm(&to, &format!("Hi from {i}"));
+            }
+            for (i, client) in clients.iter_mut().enumerate() {
+                let from = (i + 7) % 8;
+                client.expect_message(&format!("User {from}"), &format!("Hi from {from}"));
+            }
 
-    #[test]
-    fn error_then_disconnect() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.join("Foo");
-            client.send(ClientToServerMsg::Join {
-                name: "Bar".to_string(),
+            clients[5].send(ClientToServerMsg::Broadcast {
+                message: "Hello everyone".to_string(),
             });
-            client.close();
-
-            let mut client2 = server.client();
-            client2.join("Bar");
+            for (i, client) in clients.iter_mut().enumerate() {
+                if i != 5 {
+                    client.expect_message("User 5", "Hello everyone");
+                }
+            }
+            clients[5].ping();
 
             Ok(())
         });
     }
 
     #[test]
-    fn duplicated_username() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.join("Foo");
-
-            let mut client2 = server.client();
-            client2.send(ClientToServerMsg::Join {
-                name: "Foo".to_string(),
+    fn sharded_username_taken() {
+        let opts = ServerOpts {
+            threads: 2,
+            ..opts(10)
+        };
+        run_test(opts, |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+
+            // Served by the other event loop
+            let mut mia2 = server.client();
+            mia2.send(ClientToServerMsg::Join {
+                name: "Mia".to_string(),
             });
-            client2.expect_error("Username already taken");
+            mia2.expect_error("Username already taken");
+            mia2.check_closed();
 
-            Ok(())
-        });
-    }
-
-    #[test]
-    fn ping() {
-        run_test(opts(2), |server| {
-            let mut luca = server.client();
-            luca.join("Luca");
-            luca.ping();
+            // Free the name from a different event loop than the one that took it
+            mia.close();
+            sleep(100);
+            let mut mia3 = server.client();
+            mia3.join("Mia");
 
             Ok(())
         });
     }
 
     #[test]
-    fn ping_before_join() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.send(ClientToServerMsg::Ping);
-            client.expect_error("Unexpected message received");
-
-            Ok(())
-        });
-    }
-
-    #[test]
-    fn list_users_reconnect() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.join("Foo");
-            client.close();
+    fn sharded_max_clients() {
+        let opts = ServerOpts {
+            threads: 3,
+            ..opts(3)
+        };
+        run_test(opts, |server| {
+            let mut clients: Vec<Client> = (0..3)
+                .map(|i| {
+                    let mut client = server.client();
+                    client.join(&format!("User {i}"));
+                    client
+                })
+                .collect();
 
             let mut client = server.client();
-            client.join("Foo");
-            assert_eq!(client.list_users(), vec!["Foo".to_string()]);
+            client.expect_error("Server is full");
+            client.check_closed();
 
-            Ok(())
-        });
-    }
+            clients.remove(1).close();
+            sleep(100);
 
-    #[test]
-    fn list_users_self() {
-        run_test(opts(2), |server| {
             let mut client = server.client();
-            client.join("Martin");
-            assert_eq!(client.list_users(), vec!["Martin".to_string()]);
-
-            Ok(())
-        });
-    }
-
-    #[test]
-    fn list_users_ignore_not_joined_users() {
-        run_test(opts(2), |server| {
-            let _client = server.client();
-            let mut client2 = server.client();
-            client2.join("Joe");
-            assert_eq!(client2.list_users(), vec!["Joe".to_string()]);
+            client.join("User 3");
+            clients[0].dm("User 3", "Hi");
+            client.expect_message("User 0", "Hi");
 
             Ok(())
         });
     }
 
     #[test]
-    fn list_users_after_error() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.join("Terrence");
-
-            let mut client2 = server.client();
-            client2.join("Joe");
-
-            client.send(ClientToServerMsg::Join {
-                name: "Barbara".to_string(),
-            });
+    fn sharded_drop_clients_on_shutdown() {
+        let opts = ServerOpts {
+            threads: 4,
+            ..opts(10)
+        };
+        let server = run_server(opts).expect("creating server failed");
 
-            sleep(1000);
+        let clients: Vec<Client> = (0..6)
+            .map(|i| {
+                let mut client = server.client();
+                client.join(&format!("User {i}"));
+                client
+            })
+            .collect();
 
-            assert_eq!(client2.list_users(), vec!["Joe".to_string()]);
+        drop(server);
 
-            Ok(())
-        });
+        for client in clients {
+            client.check_closed();
+        }
     }
 
     #[test]
-    fn list_users() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.join("Terrence");
-
-            let mut client2 = server.client();
-            client2.join("Joe");
-            assert_eq!(
-                client2.list_users(),
-                vec!["Joe".to_string(), "Terrence".to_string()]
-            );
-            client2.close();
-
-            sleep(1000);
-
-            assert_eq!(client.list_users(), vec!["Terrence".to_string()]);
+    fn unix_socket_chat() {
+        let dir = TempDir::new().unwrap();
+        run_test(opts_with_unix_socket(2, &dir), |server| {
+            let mut mia = server.unix_client();
+            mia.join("Mia");
+            let mut leo = server.client();
+            leo.join("Leo");
+
+            leo.dm("Mia", "hi");
+            mia.expect_message("Leo", "hi");
+            mia.dm("Leo", "hello");
+            leo.expect_message("Mia", "hello");
 
             Ok(())
         });
     }
 
     #[test]
-    fn dm_nonexistent_user() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.join("Mark");
-            client.dm("Fiona", "Hi");
-            client.expect_error("User Fiona does not exist");
+    fn sharded_unix_socket_shares_max_clients() {
+        let dir = TempDir::new().unwrap();
+        let opts = ServerOpts {
+            threads: 2,
+            ..opts_with_unix_socket(3, &dir)
+        };
+        run_test(opts, |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut leo = server.unix_client();
+            leo.join("Leo");
+            let mut kim = server.unix_client();
+            kim.join("Kim");
+
+            let mut client = server.unix_client();
+            client.expect_error("Server is full");
+            client.check_closed();
+            server.client().expect_error("Server is full");
+
+            leo.close();
+            sleep(100);
+            let mut eva = server.client();
+            eva.join("Eva");
+            kim.dm("Eva", "Hi");
+            eva.expect_message("Kim", "Hi");
 
             Ok(())
         });
     }
 
     #[test]
-    fn dm_self() {
-        run_test(opts(2), |server| {
-            let mut client = server.client();
-            client.join("Xal'atath");
-            client.dm("Xal'atath", "I'm so lonely :(");
-            client.expect_error("Cannot send a DM to yourself");
-
+    fn unix_socket_is_removed() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("chat.sock");
+        run_test(opts_with_unix_socket(2, &dir), |server| {
+            assert!(path.exists());
+            server.unix_client().join("Mia");
             Ok(())
         });
+        assert!(!path.exists());
     }
 
     #[test]
-    fn dm_other() {
-        run_test(opts(2), |server| {
-            let mut terrence = server.client();
-            terrence.join("Terrence");
-
-            let mut joe = server.client();
-            joe.join("Joe");
+    fn sharded_stats_count_messages() {
+        let opts = ServerOpts {
+            threads: 2,
+            ..opts(3)
+        };
+        run_test(opts, |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut leo = server.client();
+            leo.join("Leo");
+
+            mia.ping();
+            mia.dm("Leo", "hi");
+            leo.expect_message("Mia", "hi");
+            for to in ["Eva", "Zoe"] {
+                mia.send(ClientToServerMsg::SendDM {
+                    to: to.to_string(),
+                    message: "hi".to_string(),
+                });
+                mia.expect_error(&format!("User {to} does not exist"));
+            }
 
-            terrence.dm("Joe", "How you doin'");
-            joe.expect_message("Terrence", "How you doin'");
+            let stats = server.stats();
+            assert_eq!(stats.messages_received["Join"], 2);
+            assert_eq!(stats.messages_received["Ping"], 1);
+            assert_eq!(stats.messages_received["SendDM"], 3);
+            assert_eq!(stats.messages_sent["Welcome"], 2);
+            assert_eq!(stats.messages_sent["Pong"], 1);
+            assert_eq!(stats.messages_sent["Message"], 1);
+            assert_eq!(stats.errors["User <name> does not exist"], 2);
+            assert!(stats.bytes_received > 0);
+            assert!(stats.bytes_sent > 0);
+            assert!(stats.busy_time > Duration::ZERO);
 
             Ok(())
         });
     }
 
     #[test]
-    fn dm_spam() {
-        run_test(opts(2), |server| {
-            let mut diana = server.client();
-            diana.join("Diana");
-
-            let mut francesca = server.client();
-            francesca.join("Francesca");
-
-            let barrier = Arc::new(Barrier::new(2));
-            let barrier2 = barrier.clone();
-
-            let count = 10000;
-
-            // Let's say that someone is spamming you...
-            let t1 = spawn(move || {
-                barrier.wait();
+    fn stats_gauges() {
+        run_test(opts(3), |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut leo = server.client();
+            leo.join("Leo");
+            let _client = server.client();
+            sleep(100);
 
-                for _ in 0..count {
-                    diana.dm("Francesca", "Can I borrow your brush? Pleeeeeease :(((");
-                }
-            });
+            let stats = server.stats();
+            assert_eq!(stats.connected_clients, 3);
+            assert_eq!(stats.joined_users, 2);
 
-            // ...so you get angry, and start spamming them back.
-            // But you make a critical *error*, because you're sending the message
-            // to the wrong account.
-            // Can your chat server handle that?
-            let t2 = spawn(move || {
-                // Sync the threads a little bit
-                barrier2.wait();
-
-                for _ in 0..count {
-                    francesca.dm("Daina", "NO! Get your own!");
-                    match francesca.recv() {
-                        ServerToClientMsg::Message { from, message } => {
-                            assert_eq!(from, "Diana");
-                            assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
-                        }
-                        ServerToClientMsg::Error(error) => {
-                            assert_eq!(error, "User Daina does not exist");
-                        }
-                        msg => panic!("Unexpected message {msg:?}"),
-                    }
-                }
-                // Francesca should receive count * 2 messages, `count` from Diana and `count`
-                // error messages
-                for _ in 0..count {
-                    match francesca.recv() {
-                        ServerToClientMsg::Message { from, message } => {
-                            assert_eq!(from, "Diana");
-                            assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
-                        }
-                        ServerToClientMsg::Error(error) => {
-                            assert_eq!(error, "User Daina does not exist");
-                        }
-                        msg => panic!("Unexpected message {msg:?}"),
-                    }
-                }
-            });
-            t1.join().unwrap();
-            t2.join().unwrap();
+            leo.close();
+            sleep(100);
+            let stats = server.stats();
+            assert_eq!(stats.connected_clients, 2);
+            assert_eq!(stats.joined_users, 1);
 
             Ok(())
         });
     }
 
     #[test]
-    fn dm_spam_2() {
-        // Meanwhile, in a parallel universe...
-        run_test(opts(2), |server| {
-            let mut diana = server.client();
-            diana.join("Diana");
-
-            let mut francesca = server.client();
-            francesca.join("Francesca");
-
-            let barrier = Arc::new(Barrier::new(2));
-            let barrier2 = barrier.clone();
-
-            let count = 10000;
-
-            // Let's say that someone is spamming you...
-            let t1 = spawn(move || {
-                barrier.wait();
-
-                for _ in 0..count {
-                    diana.dm("Francesca", "Can I borrow your brush? Pleeeeeease :(((");
-                }
-            });
-
-            // ...so you get angry, and start spamming them back.
-            // But you make a critical *error*, because you push the wrong button and start
-            // sending pings to the server instead.
-            // Can your chat server handle that?
-            let t2 = spawn(move || {
-                // Sync the threads a little bit
-                barrier2.wait();
-
-                for _ in 0..count {
-                    francesca.send(ClientToServerMsg::Ping);
-                    match francesca.recv() {
-                        ServerToClientMsg::Message { from, message } => {
-                            assert_eq!(from, "Diana");
-                            assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
-                        }
-                        ServerToClientMsg::Pong => {}
-                        msg => panic!("Unexpected message {msg:?}"),
-                    }
-                }
-                // Francesca should receive count * 2 messages, `count` from Diana and `count`
-                // pong messages
-                for _ in 0..count {
-                    match francesca.recv() {
-                        ServerToClientMsg::Message { from, message } => {
-                            assert_eq!(from, "Diana");
-                            assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
-                        }
-                        ServerToClientMsg::Pong => {}
-                        msg => panic!("Unexpected message {msg:?}"),
-                    }
-                }
-            });
-            t2.join().unwrap();
-            t1.join().unwrap();
+    fn metrics_endpoint() {
+        let opts = ServerOpts {
+            metrics: Some(SocketAddr::from(([127, 0, 0, 1], 0))),
+            ..opts(2)
+        };
+        run_test(opts, |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            mia.ping();
+
+            let address = server.metrics_address().expect("metrics are disabled");
+            let response = http_get(address, "/metrics");
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.contains("\nchat_joined_users 1\n"));
+            assert!(response.contains("\nchat_messages_received_total{type=\"Ping\"} 1\n"));
+            assert!(response.contains("\n# TYPE chat_busy_seconds_total counter\n"));
+
+            assert!(http_get(address, "/").starts_with("HTTP/1.1 404 Not Found\r\n"));
 
             Ok(())
         });
     }
 
     #[test]
-    fn broadcast_empty() {
-        run_test(opts(2), |server| {
-            let mut ji = server.client();
-            ji.join("Ji");
-            ji.send(ClientToServerMsg::Broadcast {
-                message: "Haaaaaai!".to_string(),
-            });
-            ji.ping();
+    fn missed_dms_stay_undelivered_until_sent() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("history");
+        let mut history = History::open(&path).unwrap();
+        history.record_dm("Leo", "Mia", "Hi", false).unwrap();
+        assert_eq!(history.user_joined("Mia").unwrap().len(), 1);
 
-            Ok(())
-        });
+        // Sending the DM to Mia failed, so it is still missed after a restart
+        drop(history);
+        let mut history = History::open(&path).unwrap();
+        assert_eq!(history.user_joined("Mia").unwrap().len(), 1);
+        history.mark_delivered("Mia").unwrap();
+
+        drop(history);
+        let mut history = History::open(&path).unwrap();
+        assert!(history.user_joined("Mia").unwrap().is_empty());
     }
 
     #[test]
-    fn broadcast() {
-        run_test(opts(10), |server| {
-            let mut niko = server.client();
-            niko.join("Niko");
-
-            let users: Vec<Client> = (0..5)
-                .map(|i| {
-                    let mut client = server.client();
-                    client.join(&format!("NPC {i}"));
-                    client
-                })
-                .collect();
-
-            niko.send(ClientToServerMsg::Broadcast {
-                message: "Borrow this!".to_string(),
-            });
-            niko.ping();
-
-            for mut user in users {
-                user.expect_message("Niko", "Borrow this!");
-            }
+    fn record_session() {
+        let dir = TempDir::new().unwrap();
+        let trace = dir.path().join("trace.jsonl");
+        let opts = ServerOpts {
+            record: Some(trace.clone()),
+            ..opts(1)
+        };
+        run_test(opts, |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            server.client().expect_error("Server is full");
+            mia.close();
+            sleep(100);
 
             Ok(())
         });
-    }
 
-    // The server should correctly close client socket when it shuts down,
-    // to avoid a situation where the clients would be stuck waiting for a message
-    // for some indeterminate amount of time.
-    #[test]
-    fn drop_clients_on_shutdown() {
-        let server = run_server(opts(2)).expect("creating server failed");
-
-        let mut client = server.client();
-        client.join("Bar");
-        let mut client2 = server.client();
-        client2.join("Foo");
-
-        drop(server);
-
-        assert!(client.reader.recv().is_none());
-        assert!(client2.reader.recv().is_none());
+        let events: Vec<Event> = read_trace(&trace)
+            .unwrap()
+            .into_iter()
+            .map(|record| record.event)
+            .collect();
+        assert!(
+            matches!(
+                events.as_slice(),
+                [
+                    Event::Started(Settings { max_clients: 1, .. }),
+                    Event::Connected { client: 0 },
+                    Event::Received {
+                        client: 0,
+                        msg: ClientToServerMsg::Join { .. }
+                    },
+                    Event::Sent {
+                        client: 0,
+                        msg: ServerToClientMsg::Welcome { .. }
+                    },
+                    Event::Connected { client: 1 },
+                    Event::Sent {
+                        client: 1,
+                        msg: ServerToClientMsg::Error(_)
+                    },
+                    Event::Closed { client: 0 },
+                    Event::Stopped,
+                ]
+            ),
+            "{events:?}"
+        );
+    }
+
+    fn http_get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address).expect("cannot connect to metrics");
+        write!(stream, "GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
     }
 
     fn run_test<F: FnOnce(RunningServer) -> anyhow::Result<()>>(opts: ServerOpts, func: F) {
         let server = run_server(opts).expect("creating server failed");
-        let port = server.port;
+        let port = server.port();
         func(server).expect("test failed");
 
         TcpStream::connect(("127.0.0.1", port)).expect_err("server is still alive");
@@ -621,7 +768,7 @@ mod tests {
                 name: name.to_string(),
             });
             let msg = self.recv();
-            assert!(matches!(msg, ServerToClientMsg::Welcome));
+            assert!(matches!(msg, ServerToClientMsg::Welcome { .. }));
         }
 
         #[track_caller]
@@ -671,11 +818,6 @@ mod tests {
             self.writer.send(msg).expect("cannot send message");
         }
 
-        #[track_caller]
-        fn try_send(&mut self, msg: ClientToServerMsg) -> anyhow::Result<()> {
-            self.writer.send(msg)
-        }
-
         #[track_caller]
         fn expect_error(&mut self, expected_error: &str) {
             let msg = self.recv();
@@ -708,7 +850,7 @@ mod tests {
     }
 
     #[derive(Clone)]
-    struct SocketWrapper(Arc<TcpStream>);
+    struct SocketWrapper(Arc<Stream>);
 
     impl Read for SocketWrapper {
         fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
@@ -730,6 +872,24 @@ mod tests {
         fn client(&self) -> Client {
             let client =
                 TcpStream::connect(("127.0.0.1", self.port())).expect("cannot connect to server");
+            self.connect(Stream::Tcp(client))
+        }
+
+        /// Connects a client to the first Unix socket listener of the server.
+        fn unix_client(&self) -> Client {
+            let path = self
+                .addresses()
+                .iter()
+                .find_map(|address| match address {
+                    ListenAddr::Unix(path) => Some(path),
+                    ListenAddr::Tcp(_) => None,
+                })
+                .expect("server does not listen on a Unix socket");
+            let client = UnixStream::connect(path).expect("cannot connect to server");
+            self.connect(Stream::Unix(client))
+        }
+
+        fn connect(&self, client: Stream) -> Client {
             let client = SocketWrapper(Arc::new(client));
 
             let writer = MessageWriter::<ClientToServerMsg, SocketWrapper>::new(client.clone());
@@ -743,6 +903,107 @@ mod tests {
     }
 
     fn opts(max_clients: usize) -> ServerOpts {
-        ServerOpts { max_clients }
+        ServerOpts {
+            listen: vec![ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 0)))],
+            max_clients,
+            history: None,
+            join_timeout: Duration::from_secs(2),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            max_outbound_buffer: 1024 * 1024,
+            slow_consumer_timeout: Duration::from_secs(1),
+            threads: 1,
+            metrics: None,
+            record: None,
+        }
+    }
+
+    /// Listens on TCP/IP and on a Unix socket in `dir`.
+    fn opts_with_unix_socket(max_clients: usize, dir: &TempDir) -> ServerOpts {
+        let mut opts = opts(max_clients);
+        opts.listen
+            .push(ListenAddr::Unix(dir.path().join("chat.sock")));
+        opts
+    }
+}
+
+#[cfg(test)]
+mod conformance {
+    use crate::net::ListenAddr;
+    use crate::reader::DEFAULT_MAX_MESSAGE_SIZE;
+    use crate::{run_server, RunningServer, ServerOpts};
+    use chat_conformance::{Capabilities, ChatServer, Framing, ServerConfig};
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    const JOIN_TIMEOUT: Duration = Duration::from_secs(2);
+    const CAPABILITIES: Capabilities = Capabilities {
+        join_timeout: Some(JOIN_TIMEOUT),
+        idle_timeout: None,
+        history: true,
+    };
+
+    impl ChatServer for RunningServer {
+        const FRAMING: Framing = Framing::NewlineDelimited;
+        const CAPABILITIES: Capabilities = CAPABILITIES;
+
+        fn start(config: ServerConfig) -> anyhow::Result<Self> {
+            run_server(opts(config, 1))
+        }
+
+        fn port(&self) -> u16 {
+            RunningServer::port(self)
+        }
+    }
+
+    chat_conformance::conformance_tests!(
+        RunningServer;
+        join_timeout: true,
+        idle_timeout: false,
+        history: true,
+    );
+
+    fn opts(config: ServerConfig, threads: usize) -> ServerOpts {
+        ServerOpts {
+            listen: vec![ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 0)))],
+            max_clients: config.max_clients,
+            history: config.history,
+            join_timeout: JOIN_TIMEOUT,
+            max_message_size: config.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
+            max_outbound_buffer: 1024 * 1024,
+            slow_consumer_timeout: Duration::from_secs(1),
+            threads,
+            metrics: None,
+            record: None,
+        }
+    }
+
+    /// The same suite against a server with several event loops, so that the clients of every
+    /// test are spread between them.
+    mod sharded {
+        use super::{opts, CAPABILITIES};
+        use crate::{run_server, RunningServer};
+        use chat_conformance::{Capabilities, ChatServer, Framing, ServerConfig};
+
+        struct ShardedServer(RunningServer);
+
+        impl ChatServer for ShardedServer {
+            const FRAMING: Framing = Framing::NewlineDelimited;
+            const CAPABILITIES: Capabilities = CAPABILITIES;
+
+            fn start(config: ServerConfig) -> anyhow::Result<Self> {
+                run_server(opts(config, 4)).map(ShardedServer)
+            }
+
+            fn port(&self) -> u16 {
+                self.0.port()
+            }
+        }
+
+        chat_conformance::conformance_tests!(
+            ShardedServer;
+            join_timeout: true,
+            idle_timeout: false,
+            history: true,
+        );
     }
 }
@@ -1,4 +1,4 @@
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub enum ClientToServerMsg {
     /// This is the first message in the communication, which should be sent by the client.
     /// When some other client with the same name already exists, the server should respond
@@ -12,17 +12,42 @@ pub enum ClientToServerMsg {
     ListUsers,
     /// Sends a direct message to the user with the given name (`to`).
     /// If the user does not exist, the server responds with an error "User <to> does not exist".
+    /// When the history is enabled, a user that has joined the server in the past but is not
+    /// connected right now also exists. The DM is then stored and delivered to them the next
+    /// time they join, right after [ServerToClientMsg::Welcome].
     /// If the client tries to send a message to themselves, the server responds with an error
     /// "Cannot send a DM to yourself".
     SendDM { to: String, message: String },
     /// Sends a message to all currently connected users (except for the sender of the broadcast).
     Broadcast { message: String },
+    /// Send a request to return a page of past broadcasts and DMs sent or received by the sender,
+    /// with an ID larger than `since`, ordered by their ID.
+    /// At most `limit` messages (and never more than 100) are returned.
+    /// The server responds with [ServerToClientMsg::History].
+    /// If the history is not enabled, the server responds with an error "History is not enabled".
+    History { since: u64, limit: usize },
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+impl ClientToServerMsg {
+    /// Name of the message type, used in metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientToServerMsg::Join { .. } => "Join",
+            ClientToServerMsg::Ping => "Ping",
+            ClientToServerMsg::ListUsers => "ListUsers",
+            ClientToServerMsg::SendDM { .. } => "SendDM",
+            ClientToServerMsg::Broadcast { .. } => "Broadcast",
+            ClientToServerMsg::History { .. } => "History",
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
 pub enum ServerToClientMsg {
     /// Response to [ClientToServerMsg::Join].
-    Welcome,
+    /// Announces the maximum size of a single message (in bytes) that the server accepts.
+    /// Larger messages are rejected with an error "Message too large".
+    Welcome { max_message_size: usize },
     /// Response to [ClientToServerMsg::Ping].
     Pong,
     /// Response to [ClientToServerMsg::ListUsers].
@@ -30,6 +55,33 @@ pub enum ServerToClientMsg {
     /// This message is sent by the server to a client that should receive a message
     /// (that was sent either by [ClientToServerMsg::SendDM] or [ClientToServerMsg::Broadcast]).
     Message { from: String, message: String },
+    /// Response to [ClientToServerMsg::History].
+    History { messages: Vec<HistoryMessage> },
     /// This message is returned by the server when an error occurs.
     Error(String),
 }
+
+impl ServerToClientMsg {
+    /// Name of the message type, used in metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerToClientMsg::Welcome { .. } => "Welcome",
+            ServerToClientMsg::Pong => "Pong",
+            ServerToClientMsg::UserList { .. } => "UserList",
+            ServerToClientMsg::Message { .. } => "Message",
+            ServerToClientMsg::History { .. } => "History",
+            ServerToClientMsg::Error(_) => "Error",
+        }
+    }
+}
+
+/// A broadcast or a DM stored in the history of the server.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryMessage {
+    /// Unique ID of the message, IDs are assigned in increasing order starting from 1.
+    pub id: u64,
+    pub from: String,
+    /// Recipient of a DM, `None` for broadcasts.
+    pub to: Option<String>,
+    pub message: String,
+}
@@ -0,0 +1,237 @@
+use crate::messages::{ClientToServerMsg, ServerToClientMsg};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Errors that contain the name of a user. They are counted under these templates, so that
+/// clients cannot create an unbounded number of error kinds.
+const ERROR_TEMPLATES: &[&str] = &["User <name> does not exist"];
+
+/// Counters of a running server, shared by all of its shards.
+#[derive(Default)]
+pub struct Metrics {
+    messages_received: Mutex<BTreeMap<&'static str, u64>>,
+    messages_sent: Mutex<BTreeMap<&'static str, u64>>,
+    errors: Mutex<BTreeMap<String, u64>>,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    busy_nanos: AtomicU64,
+}
+
+impl Metrics {
+    pub fn message_received(&self, msg: &ClientToServerMsg) {
+        *self
+            .messages_received
+            .lock()
+            .unwrap()
+            .entry(msg.kind())
+            .or_default() += 1;
+    }
+
+    pub fn message_sent(&self, msg: &ServerToClientMsg) {
+        *self
+            .messages_sent
+            .lock()
+            .unwrap()
+            .entry(msg.kind())
+            .or_default() += 1;
+        if let ServerToClientMsg::Error(error) = msg {
+            *self
+                .errors
+                .lock()
+                .unwrap()
+                .entry(error_kind(error))
+                .or_default() += 1;
+        }
+    }
+
+    /// Adds time that an event loop has spent handling events, as opposed to waiting for them.
+    pub fn add_busy_time(&self, duration: Duration) {
+        self.busy_nanos
+            .fetch_add(duration.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the current values of the counters. The gauges are not tracked here, so they
+    /// have to be passed in.
+    pub fn snapshot(&self, connected_clients: usize, joined_users: usize) -> Stats {
+        let to_owned = |counts: &Mutex<BTreeMap<&'static str, u64>>| {
+            counts
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|(kind, count)| (kind.to_string(), *count))
+                .collect()
+        };
+        Stats {
+            connected_clients,
+            joined_users,
+            messages_received: to_owned(&self.messages_received),
+            messages_sent: to_owned(&self.messages_sent),
+            errors: self.errors.lock().unwrap().clone(),
+            bytes_received: self.bytes_received.load(Ordering::Relaxed),
+            bytes_sent: self.bytes_sent.load(Ordering::Relaxed),
+            busy_time: Duration::from_nanos(self.busy_nanos.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+fn error_kind(error: &str) -> String {
+    ERROR_TEMPLATES
+        .iter()
+        .find(|template| {
+            let (prefix, rest) = template.split_once('<').unwrap();
+            let (_, suffix) = rest.split_once('>').unwrap();
+            error.len() > prefix.len() + suffix.len()
+                && error.starts_with(prefix)
+                && error.ends_with(suffix)
+        })
+        .map_or(error, |template| template)
+        .to_string()
+}
+
+/// Snapshot of the metrics of a running server.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// Connected clients, including clients that have not joined yet.
+    pub connected_clients: usize,
+    pub joined_users: usize,
+    /// Messages received from clients, by their type.
+    pub messages_received: BTreeMap<String, u64>,
+    /// Messages sent to clients, by their type.
+    pub messages_sent: BTreeMap<String, u64>,
+    /// Errors sent to clients, by their message. Names of users are replaced by a placeholder,
+    /// i.e. "User <name> does not exist".
+    pub errors: BTreeMap<String, u64>,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    /// Time that all event loops together have spent handling events, as opposed to waiting
+    /// for them.
+    pub busy_time: Duration,
+}
+
+impl Stats {
+    /// Formats the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{name}{labels} {value}");
+            }
+        };
+        let single = |value: String| vec![(String::new(), value)];
+        let labelled = |label: &str, counts: &BTreeMap<String, u64>| {
+            counts
+                .iter()
+                .map(|(key, count)| {
+                    let key = key
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n");
+                    (format!("{{{label}=\"{key}\"}}"), count.to_string())
+                })
+                .collect()
+        };
+
+        metric(
+            "chat_connected_clients",
+            "gauge",
+            "Connected clients, including clients that have not joined yet.",
+            single(self.connected_clients.to_string()),
+        );
+        metric(
+            "chat_joined_users",
+            "gauge",
+            "Users that have joined the server.",
+            single(self.joined_users.to_string()),
+        );
+        metric(
+            "chat_messages_received_total",
+            "counter",
+            "Messages received from clients.",
+            labelled("type", &self.messages_received),
+        );
+        metric(
+            "chat_messages_sent_total",
+            "counter",
+            "Messages sent to clients.",
+            labelled("type", &self.messages_sent),
+        );
+        metric(
+            "chat_errors_total",
+            "counter",
+            "Errors sent to clients.",
+            labelled("error", &self.errors),
+        );
+        metric(
+            "chat_received_bytes_total",
+            "counter",
+            "Bytes received from clients.",
+            single(self.bytes_received.to_string()),
+        );
+        metric(
+            "chat_sent_bytes_total",
+            "counter",
+            "Bytes sent to clients.",
+            single(self.bytes_sent.to_string()),
+        );
+        metric(
+            "chat_busy_seconds_total",
+            "counter",
+            "Time spent handling events, as opposed to waiting for them.",
+            single(self.busy_time.as_secs_f64().to_string()),
+        );
+        out
+    }
+}
+
+/// Stream that counts the bytes read from and written to a client.
+pub struct Counted<S> {
+    stream: S,
+    metrics: Arc<Metrics>,
+}
+
+impl Metrics {
+    /// Counts bytes written to a client outside of a [Counted] stream.
+    pub fn add_bytes_sent(&self, count: usize) {
+        self.bytes_sent.fetch_add(count as u64, Ordering::Relaxed);
+    }
+}
+
+impl<S> Counted<S> {
+    pub fn new(stream: S, metrics: Arc<Metrics>) -> Self {
+        Self { stream, metrics }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.stream
+    }
+}
+
+impl<S: Read> Read for Counted<S> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let count = self.stream.read(buf)?;
+        self.metrics
+            .bytes_received
+            .fetch_add(count as u64, Ordering::Relaxed);
+        Ok(count)
+    }
+}
+
+impl<S: Write> Write for Counted<S> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = self.stream.write(buf)?;
+        self.metrics
+            .bytes_sent
+            .fetch_add(count as u64, Ordering::Relaxed);
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
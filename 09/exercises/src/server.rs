@@ -0,0 +1,695 @@
+use crate::messages::{ClientToServerMsg, ServerToClientMsg};
+use crate::metrics::{Counted, Metrics};
+use crate::net::{Listener, Stream};
+use crate::reader::{MessageReader, MessageTooLarge};
+use crate::shard::{Address, Mailbox, ShardHandle, ShardId, ShardMsg, Token};
+use crate::state::{DmRoute, SharedState};
+use crate::trace::{self, ConnectionTrace};
+use crate::writer::MessageWriter;
+use crate::ServerOpts;
+use epoll::{ControlOptions, Event, Events};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::time::Instant;
+
+/// Epoll token of the mailbox, which receives messages from the other event loops.
+const MAILBOX_TOKEN: u64 = 0;
+/// Epoll token of the first listener, the other listeners follow it.
+const FIRST_LISTENER_TOKEN: u64 = 1;
+
+struct Client {
+    reader: MessageReader<ClientToServerMsg, Counted<Stream>>,
+    /// Serializes messages into the outbound buffer of the client.
+    writer: MessageWriter<ServerToClientMsg, Vec<u8>>,
+    stream: Stream,
+    /// Is the socket registered for `EPOLLOUT`, because the outbound buffer is not empty?
+    waiting_for_write: bool,
+    /// When did the outbound buffer grow above `max_outbound_buffer`, `None` if it is below.
+    overloaded_since: Option<Instant>,
+    /// When was the client dropped for not reading its messages fast enough. It only gets the
+    /// rest of its current message and the error, then the connection is closed.
+    closing_since: Option<Instant>,
+    /// `None` until the client sends the `Join` message.
+    name: Option<String>,
+    connected_at: Instant,
+    trace: ConnectionTrace,
+}
+
+impl Client {
+    /// Writes as much of the outbound buffer as the socket accepts without blocking.
+    fn flush(&mut self, metrics: &Metrics) -> std::io::Result<()> {
+        let buffer = self.writer.inner_mut();
+        let mut written = 0;
+        let result = loop {
+            if written == buffer.len() {
+                break Ok(());
+            }
+            match (&self.stream).write(&buffer[written..]) {
+                Ok(0) => break Err(ErrorKind::WriteZero.into()),
+                Ok(count) => written += count,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(error) => break Err(error),
+            }
+        };
+        buffer.drain(..written);
+        metrics.add_bytes_sent(written);
+        result
+    }
+
+    /// Number of bytes waiting to be written to the socket.
+    fn pending(&self) -> usize {
+        self.writer.inner().len()
+    }
+
+    /// Returns the earliest time when the client has to be checked even without any events.
+    fn deadline(&self, opts: &ServerOpts) -> Option<Instant> {
+        if let Some(since) = self.closing_since {
+            return Some(since + opts.slow_consumer_timeout);
+        }
+        let join = self
+            .name
+            .is_none()
+            .then(|| self.connected_at + opts.join_timeout);
+        let overload = self
+            .overloaded_since
+            .map(|since| since + opts.slow_consumer_timeout);
+        join.into_iter().chain(overload).min()
+    }
+}
+
+/// Event loop that serves a shard of the clients on a single thread.
+///
+/// Clients served by other event loops are reached by posting messages to their mailboxes.
+pub struct Server {
+    opts: ServerOpts,
+    id: ShardId,
+    epoll: RawFd,
+    /// Only the first event loop accepts new clients from all listeners, and distributes them
+    /// between all event loops in a round-robin fashion.
+    listeners: Vec<Listener>,
+    next_shard: ShardId,
+    mailbox: Mailbox,
+    /// Mailboxes of all event loops, including this one, indexed by their ID.
+    shards: Vec<ShardHandle>,
+    state: Arc<SharedState>,
+    clients: HashMap<Token, Client>,
+    next_token: Token,
+}
+
+impl Server {
+    pub fn new(
+        opts: ServerOpts,
+        id: ShardId,
+        listeners: Vec<Listener>,
+        mailbox: Mailbox,
+        shards: Vec<ShardHandle>,
+        state: Arc<SharedState>,
+    ) -> anyhow::Result<Self> {
+        for listener in &listeners {
+            listener.set_nonblocking(true)?;
+        }
+
+        let epoll = epoll::create(true)?;
+        let server = Self {
+            opts,
+            id,
+            epoll,
+            next_shard: 0,
+            mailbox,
+            shards,
+            state,
+            clients: Default::default(),
+            next_token: FIRST_LISTENER_TOKEN + listeners.len() as u64,
+            listeners,
+        };
+        for (token, listener) in (FIRST_LISTENER_TOKEN..).zip(&server.listeners) {
+            server.register(listener.as_raw_fd(), token)?;
+        }
+        server.register(server.mailbox.fd(), MAILBOX_TOKEN)?;
+        Ok(server)
+    }
+
+    /// Runs the event loop until it receives [`ShardMsg::Stop`].
+    pub fn run(mut self) -> anyhow::Result<()> {
+        let mut events = vec![Event::new(Events::empty(), 0); 1024];
+        loop {
+            let count = match epoll::wait(self.epoll, self.next_timeout(), &mut events) {
+                Ok(count) => count,
+                Err(error) if error.kind() == ErrorKind::Interrupted => continue,
+                Err(error) => {
+                    // Waiting again would fail again, so stop, but release the clients first
+                    log::error!("Event loop {} cannot wait for events: {error}", self.id);
+                    self.shutdown();
+                    return Err(error.into());
+                }
+            };
+            let busy_since = Instant::now();
+            for event in &events[..count] {
+                let token = event.data;
+                match token {
+                    MAILBOX_TOKEN => {
+                        if !self.handle_mailbox() {
+                            self.shutdown();
+                            return Ok(());
+                        }
+                    }
+                    token if token < FIRST_LISTENER_TOKEN + self.listeners.len() as u64 => {
+                        self.accept_clients((token - FIRST_LISTENER_TOKEN) as usize)
+                    }
+                    token => {
+                        let events = Events::from_bits_truncate(event.events);
+                        if events.contains(Events::EPOLLOUT) {
+                            self.flush(token);
+                        }
+                        if events.intersects(Events::EPOLLIN | Events::EPOLLHUP | Events::EPOLLERR)
+                        {
+                            self.read_messages(token);
+                        }
+                    }
+                }
+            }
+            self.expire_joins();
+            self.expire_slow_consumers();
+            self.state.metrics.add_busy_time(busy_since.elapsed());
+        }
+    }
+
+    /// Accepts the clients waiting on the listener with the given index. The `max_clients` limit
+    /// is shared by all listeners.
+    ///
+    /// Failed accepts do not stop the event loop, the listener is tried again on its next event.
+    fn accept_clients(&mut self, listener: usize) {
+        loop {
+            let (stream, peer) = match self.listeners[listener].accept() {
+                Ok(client) => client,
+                Err(error) if error.kind() == ErrorKind::WouldBlock => return,
+                Err(error)
+                    if matches!(
+                        error.kind(),
+                        ErrorKind::Interrupted | ErrorKind::ConnectionAborted
+                    ) =>
+                {
+                    continue
+                }
+                Err(error) => {
+                    // E.g. too many open files, trying again right away would fail again
+                    log::warn!("Cannot accept a client: {error}");
+                    return;
+                }
+            };
+            let trace = ConnectionTrace::connected(self.state.recorder.as_ref());
+            if !self.state.add_client(self.opts.max_clients) {
+                log::info!("Rejecting client {peer}, the server is full");
+                // The socket is still blocking, so the error can be written right away.
+                // The client might have disconnected already, which is not an error of the server.
+                let msg = ServerToClientMsg::Error("Server is full".to_string());
+                self.state.metrics.message_sent(&msg);
+                trace.record(|client| trace::Event::Sent {
+                    client,
+                    msg: msg.clone(),
+                });
+                let _ =
+                    MessageWriter::new(Counted::new(&stream, self.state.metrics.clone())).send(msg);
+                let _ = stream.shutdown(Shutdown::Both);
+                continue;
+            }
+
+            let shard = self.next_shard;
+            self.next_shard = (self.next_shard + 1) % self.shards.len();
+            log::debug!("Client {peer} connected, served by event loop {shard}");
+            if shard == self.id {
+                self.add_client(stream, trace);
+            } else {
+                self.shards[shard].post(ShardMsg::Connection(stream, trace));
+            }
+        }
+    }
+
+    /// Handles messages posted by the other event loops.
+    /// Returns `false` if the event loop should stop.
+    fn handle_mailbox(&mut self) -> bool {
+        for msg in self.mailbox.drain() {
+            match msg {
+                ShardMsg::Connection(stream, trace) => self.add_client(stream, trace),
+                ShardMsg::Deliver { token, msg } => {
+                    // The recipient might have disconnected in the meantime
+                    self.send(token, msg);
+                }
+                ShardMsg::Stop => return false,
+            }
+        }
+        true
+    }
+
+    /// Starts serving a client that has already been counted in the shared state.
+    fn add_client(&mut self, stream: Stream, trace: ConnectionTrace) {
+        if self.try_add_client(stream, trace).is_err() {
+            // The client might have disconnected already, which is not an error of the server
+            self.state.remove_client();
+        }
+    }
+
+    fn try_add_client(&mut self, stream: Stream, trace: ConnectionTrace) -> anyhow::Result<()> {
+        stream.set_nonblocking(true)?;
+        let token = self.next_token;
+        self.next_token += 1;
+        self.register(stream.as_raw_fd(), token)?;
+        self.clients.insert(
+            token,
+            Client {
+                reader: MessageReader::with_max_message_size(
+                    Counted::new(stream.try_clone()?, self.state.metrics.clone()),
+                    self.opts.max_message_size,
+                ),
+                writer: MessageWriter::new(vec![]),
+                stream,
+                waiting_for_write: false,
+                overloaded_since: None,
+                closing_since: None,
+                name: None,
+                connected_at: Instant::now(),
+                trace,
+            },
+        );
+        Ok(())
+    }
+
+    /// Reads and handles all messages that are available for the given client.
+    fn read_messages(&mut self, token: Token) {
+        loop {
+            let Some(client) = self.clients.get_mut(&token) else {
+                return;
+            };
+            if client.closing_since.is_some() {
+                // It is only registered for `EPOLLOUT`, so this is a hang up or an error
+                self.disconnect(token);
+                return;
+            }
+            match client.reader.recv() {
+                Some(Ok(msg)) => {
+                    self.state.metrics.message_received(&msg);
+                    client.trace.record(|client| trace::Event::Received {
+                        client,
+                        msg: msg.clone(),
+                    });
+                    if !self.handle_message(token, msg) {
+                        self.disconnect(token);
+                        return;
+                    }
+                }
+                Some(Err(error)) if error.kind() == ErrorKind::WouldBlock => return,
+                Some(Err(error)) if MessageTooLarge::is_cause_of(&error) => {
+                    client
+                        .trace
+                        .record(|client| trace::Event::TooLarge { client });
+                    let msg = ServerToClientMsg::Error("Message too large".to_string());
+                    if !self.send(token, msg) {
+                        self.disconnect(token);
+                        return;
+                    }
+                }
+                Some(Err(error)) => {
+                    client.trace.record(|client| trace::Event::Lost {
+                        client,
+                        error: error.to_string(),
+                    });
+                    self.disconnect(token);
+                    return;
+                }
+                None => {
+                    client
+                        .trace
+                        .record(|client| trace::Event::Closed { client });
+                    self.disconnect(token);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Returns `false` if the client should be disconnected.
+    fn handle_message(&mut self, token: Token, msg: ClientToServerMsg) -> bool {
+        let Some(name) = self.clients[&token].name.clone() else {
+            return match msg {
+                ClientToServerMsg::Join { name } => self.join(token, name),
+                _ => {
+                    self.send(token, unexpected_message());
+                    false
+                }
+            };
+        };
+
+        match msg {
+            ClientToServerMsg::Join { .. } => {
+                self.send(token, unexpected_message());
+                return false;
+            }
+            ClientToServerMsg::Ping => return self.send(token, ServerToClientMsg::Pong),
+            ClientToServerMsg::ListUsers => {
+                let users = self.state.usernames();
+                return self.send(token, ServerToClientMsg::UserList { users });
+            }
+            ClientToServerMsg::SendDM { to, message } => {
+                if to == name {
+                    return self.send(
+                        token,
+                        ServerToClientMsg::Error("Cannot send a DM to yourself".to_string()),
+                    );
+                }
+                let Ok(route) = self.state.route_dm(&name, &to, &message) else {
+                    return false;
+                };
+                match route {
+                    DmRoute::Online(recipient) => {
+                        // The recipient might have disconnected in the meantime, which is not
+                        // an error of the sender.
+                        self.send_to(
+                            recipient,
+                            ServerToClientMsg::Message {
+                                from: name,
+                                message,
+                            },
+                        );
+                    }
+                    DmRoute::Stored => {}
+                    DmRoute::UnknownUser => {
+                        return self.send(
+                            token,
+                            ServerToClientMsg::Error(format!("User {to} does not exist")),
+                        );
+                    }
+                }
+            }
+            ClientToServerMsg::Broadcast { message } => {
+                let Ok(recipients) = self.state.broadcast(&name, &message) else {
+                    return false;
+                };
+                for recipient in recipients {
+                    self.send_to(
+                        recipient,
+                        ServerToClientMsg::Message {
+                            from: name.clone(),
+                            message: message.clone(),
+                        },
+                    );
+                }
+            }
+            ClientToServerMsg::History { since, limit } => {
+                let response = match self.state.history_page(&name, since, limit) {
+                    Some(messages) => ServerToClientMsg::History { messages },
+                    None => ServerToClientMsg::Error("History is not enabled".to_string()),
+                };
+                return self.send(token, response);
+            }
+        }
+        true
+    }
+
+    fn join(&mut self, token: Token, name: String) -> bool {
+        let address = Address {
+            shard: self.id,
+            token,
+        };
+        let missed = match self.state.add_user(&name, address) {
+            Ok(Some(missed)) => missed,
+            Ok(None) => {
+                self.send(
+                    token,
+                    ServerToClientMsg::Error("Username already taken".to_string()),
+                );
+                return false;
+            }
+            Err(_) => return false,
+        };
+        // Messages from the other event loops wait in the mailbox until we are done here, so
+        // they cannot overtake the welcome message or the missed DMs.
+        log::debug!("User {name} joined");
+        self.clients.get_mut(&token).unwrap().name = Some(name.clone());
+
+        let welcome = ServerToClientMsg::Welcome {
+            max_message_size: self.opts.max_message_size,
+        };
+        if !self.send(token, welcome) {
+            return false;
+        }
+        let sent = missed.into_iter().all(|msg| {
+            self.send(
+                token,
+                ServerToClientMsg::Message {
+                    from: msg.from,
+                    message: msg.message,
+                },
+            )
+        });
+        // Otherwise the missed DMs stay undelivered, so that the user gets them the next time
+        sent && self.state.missed_dms_delivered(&name).is_ok()
+    }
+
+    /// Sends a message to a client served by any event loop.
+    fn send_to(&mut self, address: Address, msg: ServerToClientMsg) {
+        if address.shard == self.id {
+            self.send(address.token, msg);
+        } else {
+            self.shards[address.shard].post(ShardMsg::Deliver {
+                token: address.token,
+                msg,
+            });
+        }
+    }
+
+    /// Queues a message for the client and writes as much of its outbound buffer as possible.
+    ///
+    /// Returns `false` if the message could not be sent, in which case the client is
+    /// disconnected, or is already being disconnected as a slow consumer.
+    fn send(&mut self, token: Token, msg: ServerToClientMsg) -> bool {
+        let Some(client) = self.clients.get_mut(&token) else {
+            return false;
+        };
+        if client.closing_since.is_some() {
+            return false;
+        }
+        self.state.metrics.message_sent(&msg);
+        client.trace.record(|client| trace::Event::Sent {
+            client,
+            msg: msg.clone(),
+        });
+        if client.writer.send(msg).is_err() {
+            self.disconnect(token);
+            return false;
+        }
+        self.flush(token)
+    }
+
+    /// Writes as much of the outbound buffer of the client as possible and disconnects it if it
+    /// does not read its messages fast enough, i.e. the buffer stays above the limit for longer
+    /// than `slow_consumer_timeout`.
+    ///
+    /// Returns `false` if the client was disconnected.
+    fn flush(&mut self, token: Token) -> bool {
+        let Some(client) = self.clients.get_mut(&token) else {
+            return false;
+        };
+        if client.flush(&self.state.metrics).is_err() {
+            self.disconnect(token);
+            return false;
+        }
+        if client.closing_since.is_some() {
+            if client.pending() == 0 {
+                self.disconnect(token);
+            }
+            return false;
+        }
+        if client.pending() <= self.opts.max_outbound_buffer {
+            client.overloaded_since = None;
+        } else if client
+            .overloaded_since
+            .get_or_insert_with(Instant::now)
+            .elapsed()
+            >= self.opts.slow_consumer_timeout
+        {
+            self.drop_slow_consumer(token);
+            return false;
+        }
+
+        let waiting_for_write = client.pending() > 0;
+        if waiting_for_write != client.waiting_for_write {
+            client.waiting_for_write = waiting_for_write;
+            let events = if waiting_for_write {
+                Events::EPOLLIN | Events::EPOLLOUT
+            } else {
+                Events::EPOLLIN
+            };
+            let fd = client.stream.as_raw_fd();
+            let result = epoll::ctl(
+                self.epoll,
+                ControlOptions::EPOLL_CTL_MOD,
+                fd,
+                Event::new(events, token),
+            );
+            if result.is_err() {
+                self.disconnect(token);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Removes a client that does not read its messages fast enough from the chat.
+    ///
+    /// Its queued messages are dropped, so that the error reaches it right after the message it
+    /// has started to read. The connection is closed once the error is written, or after another
+    /// `slow_consumer_timeout`.
+    fn drop_slow_consumer(&mut self, token: Token) {
+        let Some(client) = self.clients.get_mut(&token) else {
+            return;
+        };
+        let name = client.name.as_deref().unwrap_or("(not joined)");
+        log::info!("Disconnecting user {name}, it does not read its messages fast enough");
+        let buffer = client.writer.inner_mut();
+        let current = buffer
+            .iter()
+            .position(|&byte| byte == b'\n')
+            .map_or(0, |end| end + 1);
+        buffer.truncate(current);
+        let msg = ServerToClientMsg::Error("You are not reading messages fast enough".to_string());
+        self.state.metrics.message_sent(&msg);
+        client.trace.record(|client| trace::Event::Sent {
+            client,
+            msg: msg.clone(),
+        });
+        let _ = client.writer.send(msg);
+        client.closing_since = Some(Instant::now());
+        client.waiting_for_write = true;
+        if let Some(name) = &client.name {
+            self.state.remove_user(name);
+        }
+        self.state.remove_client();
+
+        let fd = client.stream.as_raw_fd();
+        let result = epoll::ctl(
+            self.epoll,
+            ControlOptions::EPOLL_CTL_MOD,
+            fd,
+            Event::new(Events::EPOLLOUT, token),
+        );
+        if result.is_err() {
+            self.disconnect(token);
+        }
+    }
+
+    /// Disconnects clients that have not sent `Join` in time.
+    fn expire_joins(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<Token> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| {
+                client.name.is_none()
+                    && client.closing_since.is_none()
+                    && now >= client.connected_at + self.opts.join_timeout
+            })
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            self.send(
+                token,
+                ServerToClientMsg::Error("Timed out waiting for Join".to_string()),
+            );
+            self.disconnect(token);
+        }
+    }
+
+    /// Drops clients whose outbound buffer stayed above the limit for too long, even if nothing
+    /// is sent to them anymore, and closes the connections that did not take their error in time.
+    fn expire_slow_consumers(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<Token> = self
+            .clients
+            .iter()
+            .filter(|(_, client)| {
+                client
+                    .closing_since
+                    .or(client.overloaded_since)
+                    .is_some_and(|since| now >= since + self.opts.slow_consumer_timeout)
+            })
+            .map(|(token, _)| *token)
+            .collect();
+        for token in expired {
+            if self.clients[&token].closing_since.is_some() {
+                self.disconnect(token);
+            } else {
+                self.flush(token);
+            }
+        }
+    }
+
+    /// Returns how long should epoll wait for events, in milliseconds (-1 means forever).
+    fn next_timeout(&self) -> i32 {
+        let Some(deadline) = self
+            .clients
+            .values()
+            .filter_map(|client| client.deadline(&self.opts))
+            .min()
+        else {
+            return -1;
+        };
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        // Round up, so that we do not wake up right before the deadline
+        i32::try_from(remaining.as_millis().saturating_add(1)).unwrap_or(i32::MAX)
+    }
+
+    fn disconnect(&mut self, token: Token) {
+        let Some(mut client) = self.clients.remove(&token) else {
+            return;
+        };
+        // A slow consumer has already left the chat
+        if client.closing_since.is_none() {
+            if let Some(name) = &client.name {
+                self.state.remove_user(name);
+                log::debug!("User {name} left");
+            }
+            self.state.remove_client();
+        }
+        // Try to deliver the rest of the outbound buffer (e.g. an error message)
+        let _ = client.flush(&self.state.metrics);
+        let stream = &client.stream;
+        let _ = epoll::ctl(
+            self.epoll,
+            ControlOptions::EPOLL_CTL_DEL,
+            stream.as_raw_fd(),
+            Event::new(Events::empty(), 0),
+        );
+        let _ = stream.shutdown(Shutdown::Both);
+    }
+
+    fn shutdown(&mut self) {
+        let tokens: Vec<Token> = self.clients.keys().copied().collect();
+        for token in tokens {
+            self.disconnect(token);
+        }
+    }
+
+    fn register(&self, fd: RawFd, token: Token) -> anyhow::Result<()> {
+        epoll::ctl(
+            self.epoll,
+            ControlOptions::EPOLL_CTL_ADD,
+            fd,
+            Event::new(Events::EPOLLIN, token),
+        )?;
+        Ok(())
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = epoll::close(self.epoll);
+    }
+}
+
+fn unexpected_message() -> ServerToClientMsg {
+    ServerToClientMsg::Error("Unexpected message received".to_string())
+}
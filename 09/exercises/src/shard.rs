@@ -0,0 +1,128 @@
+use crate::messages::ServerToClientMsg;
+use crate::net::Stream;
+use crate::trace::ConnectionTrace;
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+
+/// Index of an event loop thread.
+pub type ShardId = usize;
+
+/// Identifies a client within the event loop that serves it.
+pub type Token = u64;
+
+/// Identifies a client across all event loops of the server.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Address {
+    pub shard: ShardId,
+    pub token: Token,
+}
+
+/// Message posted from one event loop to another one.
+pub enum ShardMsg {
+    /// A newly accepted client that should be served by the receiving event loop.
+    Connection(Stream, ConnectionTrace),
+    /// A message that should be sent to a client of the receiving event loop.
+    Deliver {
+        token: Token,
+        msg: ServerToClientMsg,
+    },
+    /// The server is shutting down.
+    Stop,
+}
+
+/// Sending side of the mailbox of an event loop.
+#[derive(Clone)]
+pub struct ShardHandle {
+    sender: Sender<ShardMsg>,
+    waker: Arc<EventFd>,
+}
+
+impl ShardHandle {
+    /// Posts a message to the event loop and wakes it up.
+    /// Messages posted to an event loop that has already stopped are dropped.
+    pub fn post(&self, msg: ShardMsg) {
+        if self.sender.send(msg).is_ok() {
+            let _ = self.waker.wake();
+        }
+    }
+}
+
+/// Receiving side of the mailbox of an event loop.
+pub struct Mailbox {
+    receiver: Receiver<ShardMsg>,
+    waker: Arc<EventFd>,
+}
+
+impl Mailbox {
+    /// File descriptor that becomes readable when a message is posted to the mailbox.
+    pub fn fd(&self) -> RawFd {
+        self.waker.as_raw_fd()
+    }
+
+    /// Returns all messages that were posted since the last call.
+    pub fn drain(&self) -> Vec<ShardMsg> {
+        // Reset the eventfd first, so that a message posted in the meantime wakes us up again
+        self.waker.reset();
+        let mut messages = vec![];
+        loop {
+            match self.receiver.try_recv() {
+                Ok(msg) => messages.push(msg),
+                Err(TryRecvError::Empty) => return messages,
+                // Everybody who could post a message is gone, so we should stop as well
+                Err(TryRecvError::Disconnected) => {
+                    messages.push(ShardMsg::Stop);
+                    return messages;
+                }
+            }
+        }
+    }
+}
+
+pub fn mailbox() -> std::io::Result<(ShardHandle, Mailbox)> {
+    let (sender, receiver) = mpsc::channel();
+    let waker = Arc::new(EventFd::new()?);
+    Ok((
+        ShardHandle {
+            sender,
+            waker: waker.clone(),
+        },
+        Mailbox { receiver, waker },
+    ))
+}
+
+/// Non-blocking Linux eventfd, used to wake up an event loop that waits in `epoll`.
+struct EventFd(File);
+
+impl EventFd {
+    fn new() -> std::io::Result<Self> {
+        // SAFETY: eventfd does not access any memory owned by us
+        let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        // SAFETY: the file descriptor was just created and nobody else owns it
+        Ok(Self(unsafe { File::from_raw_fd(fd) }))
+    }
+
+    fn wake(&self) -> std::io::Result<()> {
+        match (&self.0).write(&1u64.to_ne_bytes()) {
+            // The counter is full, so the event loop will wake up anyway
+            Err(error) if error.kind() == ErrorKind::WouldBlock => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    fn reset(&self) {
+        let mut counter = [0; 8];
+        let _ = (&self.0).read(&mut counter);
+    }
+}
+
+impl AsRawFd for EventFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
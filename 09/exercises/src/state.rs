@@ -0,0 +1,153 @@
+use crate::history::History;
+use crate::messages::HistoryMessage;
+use crate::metrics::{Metrics, Stats};
+use crate::shard::Address;
+use crate::trace::Recorder;
+use crate::ServerOpts;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// Where should a DM go.
+pub enum DmRoute {
+    /// The recipient is connected, the DM should be sent to it right away.
+    Online(Address),
+    /// The recipient is offline, the DM was stored in the history and will be delivered later.
+    Stored,
+    UnknownUser,
+}
+
+/// State shared by all event loops of the server.
+///
+/// Event loops only hold the lock for short bookkeeping, never while doing I/O on sockets.
+pub struct SharedState {
+    inner: Mutex<Inner>,
+    pub metrics: Arc<Metrics>,
+    /// Records the server if [`ServerOpts::record`] is set.
+    pub recorder: Option<Arc<Recorder>>,
+}
+
+struct Inner {
+    /// Number of connected clients, including clients that have not joined yet.
+    client_count: usize,
+    /// Clients that have joined, indexed by their username.
+    users: HashMap<String, Address>,
+    history: Option<History>,
+}
+
+impl SharedState {
+    pub fn new(opts: &ServerOpts) -> anyhow::Result<Self> {
+        let history = opts.history.as_deref().map(History::open).transpose()?;
+        let recorder = opts
+            .record
+            .as_deref()
+            .map(|path| Recorder::create(path, opts))
+            .transpose()?
+            .map(Arc::new);
+        Ok(Self {
+            inner: Mutex::new(Inner {
+                client_count: 0,
+                users: Default::default(),
+                history,
+            }),
+            metrics: Default::default(),
+            recorder,
+        })
+    }
+
+    /// Returns `false` if there are already `max_clients` connected clients.
+    pub fn add_client(&self, max_clients: usize) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.client_count >= max_clients {
+            return false;
+        }
+        inner.client_count += 1;
+        true
+    }
+
+    pub fn remove_client(&self) {
+        self.inner.lock().unwrap().client_count -= 1;
+    }
+
+    pub fn stats(&self) -> Stats {
+        let inner = self.inner.lock().unwrap();
+        self.metrics.snapshot(inner.client_count, inner.users.len())
+    }
+
+    /// Returns `None` if the username is already taken, otherwise returns the DMs that the user
+    /// has missed while it was offline.
+    pub fn add_user(
+        &self,
+        name: &str,
+        address: Address,
+    ) -> anyhow::Result<Option<Vec<HistoryMessage>>> {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.users.contains_key(name) {
+            return Ok(None);
+        }
+        let missed = match &mut inner.history {
+            Some(history) => history.user_joined(name)?,
+            None => vec![],
+        };
+        inner.users.insert(name.to_string(), address);
+        Ok(Some(missed))
+    }
+
+    /// Marks the missed DMs returned when `name` joined as delivered, once they were sent to it.
+    pub fn missed_dms_delivered(&self, name: &str) -> anyhow::Result<()> {
+        match &mut self.inner.lock().unwrap().history {
+            Some(history) => history.mark_delivered(name),
+            None => Ok(()),
+        }
+    }
+
+    pub fn remove_user(&self, name: &str) {
+        self.inner.lock().unwrap().users.remove(name);
+    }
+
+    pub fn usernames(&self) -> Vec<String> {
+        self.inner.lock().unwrap().users.keys().cloned().collect()
+    }
+
+    /// Decides where a DM should go and stores it in the history.
+    pub fn route_dm(&self, from: &str, to: &str, message: &str) -> anyhow::Result<DmRoute> {
+        let mut inner = self.inner.lock().unwrap();
+        let recipient = inner.users.get(to).copied();
+        let Some(history) = &mut inner.history else {
+            return Ok(recipient.map_or(DmRoute::UnknownUser, DmRoute::Online));
+        };
+        if recipient.is_none() && !history.is_known_user(to) {
+            return Ok(DmRoute::UnknownUser);
+        }
+        history.record_dm(from, to, message, recipient.is_some())?;
+        Ok(recipient.map_or(DmRoute::Stored, DmRoute::Online))
+    }
+
+    /// Stores a broadcast in the history and returns the addresses of all joined users except
+    /// for `from`.
+    pub fn broadcast(&self, from: &str, message: &str) -> anyhow::Result<Vec<Address>> {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(history) = &mut inner.history {
+            history.record_broadcast(from, message)?;
+        }
+        Ok(inner
+            .users
+            .iter()
+            .filter(|(user, _)| *user != from)
+            .map(|(_, address)| *address)
+            .collect())
+    }
+
+    /// Returns `None` if the history is not enabled.
+    pub fn history_page(
+        &self,
+        user: &str,
+        since: u64,
+        limit: usize,
+    ) -> Option<Vec<HistoryMessage>> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .history
+            .as_ref()
+            .map(|history| history.page(user, since, limit))
+    }
+}
@@ -27,4 +27,8 @@ impl<T: Serialize, W: Write> MessageWriter<T, W> {
     pub fn inner(&self) -> &W {
         &self.sink
     }
+
+    pub fn inner_mut(&mut self) -> &mut W {
+        &mut self.sink
+    }
 }
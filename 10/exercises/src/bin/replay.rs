@@ -0,0 +1,51 @@
+//! Replays a trace recorded by the server (started with `--record`) in a fresh server, and
+//! reports the clients to which the server has sent something else than when it was recorded,
+//! or whose timers have fired differently.
+//!
+//! The replay runs in virtual time, so it does not wait for the timeouts of the server.
+//! Federation and plugins are not replayed.
+
+use clap::Parser;
+use log::LevelFilter;
+use std::path::PathBuf;
+use tokio::task::LocalSet;
+use week10::replay::replay;
+use week10::trace::{read_trace, Event};
+
+#[derive(Parser)]
+struct Args {
+    /// Trace file written by the server
+    trace: PathBuf,
+    /// History file of the replayed server, required if the recorded server had the history
+    /// enabled. It should contain the history as it was when the recording started, it is
+    /// modified by the replay.
+    #[arg(long)]
+    history: Option<PathBuf>,
+    /// Log level of the replayed server: off, error, warn, info, debug or trace [default: warn]
+    #[arg(long)]
+    log_level: Option<LevelFilter>,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+    env_logger::Builder::new()
+        .filter_level(args.log_level.unwrap_or(LevelFilter::Warn))
+        .init();
+    let records = read_trace(&args.trace)?;
+    let Some(Event::Started(settings)) = records.first().map(|record| &record.event) else {
+        anyhow::bail!("The trace does not start with the settings of the server");
+    };
+    let opts = settings.server_opts(args.history)?;
+
+    // The server spawns its tasks with `spawn_local`
+    let divergences = LocalSet::new().run_until(replay(&records, opts)).await?;
+    if divergences.is_empty() {
+        println!("The replay matches the trace ({} events)", records.len());
+        return Ok(());
+    }
+    for divergence in &divergences {
+        println!("{divergence}");
+    }
+    anyhow::bail!("Clients with a divergence: {}", divergences.len())
+}
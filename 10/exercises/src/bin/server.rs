@@ -1,5 +1,217 @@
-//! You can use this file for experiments.
+//! Runs the chat server until it receives Ctrl-C.
+//!
+//! Every option can be passed as a command line flag (e.g. `--max-clients 10`), as an environment
+//! variable (e.g. `CHAT_MAX_CLIENTS=10`) or in a TOML file passed with `--config`
+//! (e.g. `max_clients = 10`), in this order of precedence.
+//! Keys that this server does not know are ignored, so that the same file can be shared with
+//! the servers from the other weeks.
 
-fn main() {
-    println!("Hello Rust!");
+use clap::Parser;
+use log::LevelFilter;
+use serde::Deserialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::task::LocalSet;
+use week10::net::ListenAddr;
+use week10::reader::DEFAULT_MAX_MESSAGE_SIZE;
+use week10::{run_server, FederationConfig, RunningServer, ServerOpts, TlsConfig};
+
+#[derive(Parser, Deserialize, Default)]
+#[serde(default)]
+struct Config {
+    /// TOML file with the configuration
+    #[arg(long, env = "CHAT_CONFIG")]
+    #[serde(skip)]
+    config: Option<PathBuf>,
+    /// Address on which the server listens [default: 127.0.0.1]
+    #[arg(long, env = "CHAT_ADDRESS")]
+    address: Option<IpAddr>,
+    /// Port on which the server listens, 0 lets the operating system choose one [default: 0]
+    #[arg(long, env = "CHAT_PORT")]
+    port: Option<u16>,
+    /// Path of a Unix socket on which the server listens in addition to TCP/IP
+    #[arg(long, env = "CHAT_UNIX_SOCKET")]
+    unix_socket: Option<PathBuf>,
+    /// Port on which the server accepts WebSocket clients, on the same address as TCP/IP
+    /// clients; WebSocket is disabled if not set
+    #[arg(long, env = "CHAT_WEBSOCKET_PORT")]
+    websocket_port: Option<u16>,
+    /// Maximum number of clients connected at once [default: 100]
+    #[arg(long, env = "CHAT_MAX_CLIENTS")]
+    max_clients: Option<usize>,
+    /// Maximum size of a single message in bytes [default: 65536]
+    #[arg(long, env = "CHAT_MAX_MESSAGE_SIZE")]
+    max_message_size: Option<usize>,
+    /// File where the message history is stored, the history is disabled if not set
+    #[arg(long, env = "CHAT_HISTORY")]
+    history: Option<PathBuf>,
+    /// How long to wait for the `Join` message, in seconds [default: 2]
+    #[arg(long, env = "CHAT_JOIN_TIMEOUT")]
+    join_timeout: Option<f64>,
+    /// How long can a client stay idle before it is disconnected, in seconds [default: 3]
+    #[arg(long, env = "CHAT_IDLE_TIMEOUT")]
+    idle_timeout: Option<f64>,
+    /// How long is the session of a disconnected user kept so that it can be resumed, in seconds,
+    /// 0 disables resuming [default: 30]
+    #[arg(long, env = "CHAT_RESUME_GRACE")]
+    resume_grace: Option<f64>,
+    /// PEM file with the TLS certificate chain, TLS is disabled if not set
+    #[arg(long, env = "CHAT_TLS_CERTIFICATE")]
+    tls_certificate: Option<PathBuf>,
+    /// PEM file with the private key of the TLS certificate
+    #[arg(long, env = "CHAT_TLS_KEY")]
+    tls_key: Option<PathBuf>,
+    /// Port of an HTTP endpoint on localhost that serves Prometheus metrics at `/metrics`,
+    /// the endpoint is disabled if not set
+    #[arg(long, env = "CHAT_METRICS_PORT")]
+    metrics_port: Option<u16>,
+    /// Name of this server in a federation of servers, federation is disabled if not set
+    #[arg(long, env = "CHAT_SERVER_NAME")]
+    server_name: Option<String>,
+    /// Port on which the server accepts links from federated servers, on the same address as
+    /// TCP/IP clients
+    #[arg(long, env = "CHAT_FEDERATION_PORT")]
+    federation_port: Option<u16>,
+    /// Comma-separated addresses of federated servers to link to, e.g. `10.0.0.2:6000`
+    #[arg(long, env = "CHAT_PEERS", value_delimiter = ',')]
+    peers: Option<Vec<SocketAddr>>,
+    /// File where the server records its clients for debugging, see the `replay` binary;
+    /// the recording is disabled if not set
+    #[arg(long, env = "CHAT_RECORD")]
+    record: Option<PathBuf>,
+    /// Log level: off, error, warn, info, debug or trace [default: info]
+    #[arg(long, env = "CHAT_LOG_LEVEL")]
+    log_level: Option<LevelFilter>,
+}
+
+impl Config {
+    fn load() -> anyhow::Result<Self> {
+        let args = Config::parse();
+        let file = match &args.config {
+            Some(path) => {
+                let content = std::fs::read_to_string(path).map_err(|error| {
+                    anyhow::anyhow!("Cannot read config file {}: {error}", path.display())
+                })?;
+                toml::from_str(&content).map_err(|error| {
+                    anyhow::anyhow!("Cannot parse config file {}: {error}", path.display())
+                })?
+            }
+            None => Config::default(),
+        };
+        Ok(args.or(file))
+    }
+
+    /// Fills the values that are missing in `self` with values from `other`.
+    fn or(self, other: Config) -> Config {
+        Config {
+            config: self.config,
+            address: self.address.or(other.address),
+            port: self.port.or(other.port),
+            unix_socket: self.unix_socket.or(other.unix_socket),
+            websocket_port: self.websocket_port.or(other.websocket_port),
+            max_clients: self.max_clients.or(other.max_clients),
+            max_message_size: self.max_message_size.or(other.max_message_size),
+            history: self.history.or(other.history),
+            join_timeout: self.join_timeout.or(other.join_timeout),
+            idle_timeout: self.idle_timeout.or(other.idle_timeout),
+            resume_grace: self.resume_grace.or(other.resume_grace),
+            tls_certificate: self.tls_certificate.or(other.tls_certificate),
+            tls_key: self.tls_key.or(other.tls_key),
+            metrics_port: self.metrics_port.or(other.metrics_port),
+            server_name: self.server_name.or(other.server_name),
+            federation_port: self.federation_port.or(other.federation_port),
+            peers: self.peers.or(other.peers),
+            record: self.record.or(other.record),
+            log_level: self.log_level.or(other.log_level),
+        }
+    }
+
+    fn listen(&self) -> Vec<ListenAddr> {
+        let address = self.address.unwrap_or(Ipv4Addr::LOCALHOST.into());
+        let tcp = SocketAddr::new(address, self.port.unwrap_or(0));
+        let mut listen = vec![ListenAddr::Tcp(tcp)];
+        listen.extend(self.unix_socket.clone().map(ListenAddr::Unix));
+        listen.extend(
+            self.websocket_port
+                .map(|port| ListenAddr::WebSocket(SocketAddr::new(address, port))),
+        );
+        listen
+    }
+
+    fn server_opts(&self) -> anyhow::Result<ServerOpts> {
+        let tls = match (&self.tls_certificate, &self.tls_key) {
+            (Some(certificate), Some(key)) => Some(TlsConfig {
+                certificate: certificate.clone(),
+                key: key.clone(),
+            }),
+            (None, None) => None,
+            _ => anyhow::bail!("Both tls_certificate and tls_key have to be set to enable TLS"),
+        };
+        let federation = match &self.server_name {
+            Some(name) => Some(FederationConfig {
+                name: name.clone(),
+                listen: self.federation_port.map(|port| {
+                    SocketAddr::new(self.address.unwrap_or(Ipv4Addr::LOCALHOST.into()), port)
+                }),
+                peers: self.peers.clone().unwrap_or_default(),
+            }),
+            None if self.federation_port.is_some() || self.peers.is_some() => {
+                anyhow::bail!("server_name has to be set to enable federation")
+            }
+            None => None,
+        };
+        Ok(ServerOpts {
+            listen: self.listen(),
+            metrics: self
+                .metrics_port
+                .map(|port| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)),
+            max_clients: self.max_clients.unwrap_or(100),
+            history: self.history.clone(),
+            join_timeout: Duration::try_from_secs_f64(self.join_timeout.unwrap_or(2.0))?,
+            idle_timeout: Duration::try_from_secs_f64(self.idle_timeout.unwrap_or(3.0))?,
+            max_message_size: self.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
+            resume_grace: Duration::try_from_secs_f64(self.resume_grace.unwrap_or(30.0))?,
+            tls,
+            federation,
+            plugins: vec![],
+            record: self.record.clone(),
+        })
+    }
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() -> anyhow::Result<()> {
+    let config = Config::load()?;
+    env_logger::Builder::new()
+        .filter_level(config.log_level.unwrap_or(LevelFilter::Info))
+        .init();
+    let opts = config.server_opts()?;
+
+    // The server spawns its tasks with `spawn_local`
+    LocalSet::new()
+        .run_until(async move {
+            let server = run_server(opts).await?;
+            for address in &server.addresses {
+                log::info!("Listening on {address}");
+            }
+            if let Some(address) = server.metrics_address() {
+                log::info!("Serving metrics on http://{address}/metrics");
+            }
+            if let Some(address) = server.federation_address() {
+                log::info!("Accepting federation peers on {address}");
+            }
+            let RunningServer { mut future, tx, .. } = server;
+
+            tokio::select! {
+                result = &mut future => return result,
+                result = tokio::signal::ctrl_c() => result?,
+            }
+            log::info!("Shutting down");
+            let _ = tx.send(());
+            future.await?;
+            log::info!("Server stopped");
+            Ok(())
+        })
+        .await
 }
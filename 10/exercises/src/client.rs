@@ -0,0 +1,524 @@
+use crate::messages::{ClientToServerMsg, LeaveReason, ServerToClientMsg};
+use crate::metrics::Metrics;
+use crate::net::Framing;
+use crate::reader::{MessageReader, MessageTooLarge};
+use crate::state::{BroadcastRoute, DmRoute, SharedState, MAX_UNACKED};
+use crate::trace::{ConnectionTrace, Event, Timer};
+use crate::websocket;
+use crate::writer::MessageWriter;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadHalf, WriteHalf};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tokio::sync::watch;
+use tokio::time::error::Elapsed;
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+
+/// Connection to a client, either in plain text or encrypted with TLS.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Stream for T {}
+
+pub type ClientStream = Box<dyn Stream>;
+
+/// Reads messages from a client, counts them in the metrics and records them.
+struct Reader {
+    reader: MessageReader<ClientToServerMsg, ReadHalf<ClientStream>>,
+    metrics: Rc<Metrics>,
+    trace: ConnectionTrace,
+}
+
+impl Reader {
+    /// Cancel safe, like [MessageReader::recv].
+    async fn recv(&mut self) -> Option<std::io::Result<ClientToServerMsg>> {
+        let msg = self.reader.recv().await;
+        match &msg {
+            Some(Ok(msg)) => {
+                self.metrics.message_received(msg);
+                self.trace.record(|client| Event::Received {
+                    client,
+                    msg: msg.clone(),
+                });
+            }
+            Some(Err(error)) if MessageTooLarge::is_cause_of(error) => {
+                self.trace.record(|client| Event::TooLarge { client });
+            }
+            Some(Err(error)) => self.trace.record(|client| Event::Lost {
+                client,
+                error: error.to_string(),
+            }),
+            None => self.trace.record(|client| Event::Closed { client }),
+        }
+        msg
+    }
+}
+
+/// Writes messages to a client, counts them in the metrics and records them.
+struct Writer<W = WriteHalf<ClientStream>> {
+    writer: MessageWriter<ServerToClientMsg, W>,
+    metrics: Rc<Metrics>,
+    trace: ConnectionTrace,
+}
+
+impl<W: AsyncWrite + Unpin> Writer<W> {
+    fn new(stream: W, metrics: Rc<Metrics>, trace: ConnectionTrace) -> Self {
+        Self {
+            writer: MessageWriter::new(stream),
+            metrics,
+            trace,
+        }
+    }
+
+    async fn send(&mut self, msg: ServerToClientMsg) -> anyhow::Result<()> {
+        self.metrics.message_sent(&msg);
+        self.trace.record(|client| Event::Sent {
+            client,
+            msg: msg.clone(),
+        });
+        self.writer.send(msg).await
+    }
+}
+
+/// Serves a single connected client until it disconnects, misbehaves or the server shuts down.
+pub async fn handle_client(
+    stream: ClientStream,
+    framing: Framing,
+    tls: Option<TlsAcceptor>,
+    state: SharedState,
+    mut shutdown: watch::Receiver<bool>,
+    trace: ConnectionTrace,
+) {
+    let (join_timeout, max_message_size) = {
+        let opts = &state.borrow().opts;
+        (opts.join_timeout, opts.max_message_size)
+    };
+    let suspended = tokio::select! {
+        _ = shutdown.changed() => Ok(None),
+        stream = handshake(stream, framing, tls, join_timeout, max_message_size) => match stream {
+            Ok(stream) => serve_client(stream, &state, &mut shutdown, &trace).await,
+            Err(error) => {
+                log::debug!("Handshake failed: {error}");
+                if error.is::<Elapsed>() {
+                    trace.record(|client| Event::Timer {
+                        client,
+                        timer: Timer::Join,
+                    });
+                }
+                Err(error)
+            }
+        },
+    };
+    state.borrow_mut().client_count -= 1;
+
+    // The client is gone, but its session can be resumed for a while
+    if let Ok(Some(Suspended { name, token })) = suspended {
+        let resume_grace = state.borrow().opts.resume_grace;
+        tokio::select! {
+            _ = shutdown.changed() => {}
+            _ = tokio::time::sleep(resume_grace) => {
+                trace.record(|client| Event::Timer { client, timer: Timer::ResumeGrace });
+                state.borrow_mut().expire_session(&name, &token);
+            }
+        }
+    }
+}
+
+/// Tells a client that the server is full.
+pub async fn reject_client(
+    stream: ClientStream,
+    framing: Framing,
+    tls: Option<TlsAcceptor>,
+    timeout: Duration,
+    max_message_size: usize,
+    metrics: Rc<Metrics>,
+    trace: ConnectionTrace,
+) {
+    let Ok(stream) = handshake(stream, framing, tls, timeout, max_message_size).await else {
+        return;
+    };
+    let mut writer = Writer::new(stream, metrics, trace);
+    let _ = writer
+        .send(ServerToClientMsg::Error("Server is full".to_string()))
+        .await;
+}
+
+/// Performs the TLS handshake if TLS is enabled, and then the WebSocket handshake if the client
+/// talks WebSocket. The client has to finish both within `timeout`.
+async fn handshake(
+    stream: ClientStream,
+    framing: Framing,
+    tls: Option<TlsAcceptor>,
+    timeout: Duration,
+    max_message_size: usize,
+) -> anyhow::Result<ClientStream> {
+    tokio::time::timeout(timeout, async move {
+        let stream: ClientStream = match tls {
+            Some(acceptor) => Box::new(acceptor.accept(stream).await?),
+            None => stream,
+        };
+        Ok(match framing {
+            Framing::Newline => stream,
+            Framing::WebSocket => Box::new(websocket::accept(stream, max_message_size).await?),
+        })
+    })
+    .await?
+}
+
+/// How does a client start talking to the server.
+enum Login {
+    Join(String),
+    Resume(String),
+}
+
+/// A user whose connection was lost, but whose session can still be resumed.
+struct Suspended {
+    name: String,
+    token: String,
+}
+
+async fn serve_client(
+    stream: ClientStream,
+    state: &SharedState,
+    shutdown: &mut watch::Receiver<bool>,
+    trace: &ConnectionTrace,
+) -> anyhow::Result<Option<Suspended>> {
+    let (rx, tx) = tokio::io::split(stream);
+    let max_message_size = state.borrow().opts.max_message_size;
+    let metrics = state.borrow().metrics.clone();
+    let mut reader = Reader {
+        reader: MessageReader::with_max_message_size(rx, max_message_size),
+        metrics: metrics.clone(),
+        trace: trace.clone(),
+    };
+    let mut writer = Writer::new(tx, metrics, trace.clone());
+
+    let Some(login) = wait_for_login(state, &mut reader, &mut writer, shutdown).await? else {
+        return Ok(None);
+    };
+
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let (name, token, backlog, unacked) = match login {
+        Login::Join(name) => {
+            // Users of federated servers are qualified by the name of their server
+            if name.contains('@') && state.borrow().server_name().is_some() {
+                writer
+                    .send(ServerToClientMsg::Error(
+                        "Username cannot contain @".to_string(),
+                    ))
+                    .await?;
+                return Ok(None);
+            }
+            let added = state.borrow_mut().add_user(&name, sender)?;
+            let Some((token, missed)) = added else {
+                writer
+                    .send(ServerToClientMsg::Error(
+                        "Username already taken".to_string(),
+                    ))
+                    .await?;
+                return Ok(None);
+            };
+            log::debug!("User {name} joined");
+            let missed = missed
+                .into_iter()
+                .map(|msg| ServerToClientMsg::Message {
+                    id: msg.id,
+                    from: msg.from,
+                    message: msg.message,
+                })
+                .collect();
+            (name, token, missed, BTreeMap::new())
+        }
+        Login::Resume(token) => {
+            let resumed = state.borrow_mut().resume_user(&token, sender)?;
+            let Some((name, token, session)) = resumed else {
+                writer
+                    .send(ServerToClientMsg::Error("Invalid resume token".to_string()))
+                    .await?;
+                return Ok(None);
+            };
+            log::debug!("User {name} resumed its session");
+            (name, token, session.backlog.into(), session.unacked)
+        }
+    };
+
+    let mut client = JoinedClient {
+        name,
+        state,
+        reader,
+        writer,
+        receiver,
+        unacked,
+    };
+    let result = async {
+        client.welcome(token, backlog).await?;
+        client.run(shutdown).await
+    }
+    .await;
+
+    // Keep the session of a client whose connection was lost unexpectedly
+    let (reason, resumable) = match &result {
+        Ok(reason) => (*reason, *reason == LeaveReason::TimedOut),
+        Err(_) => (LeaveReason::Disconnected, true),
+    };
+    if resumable && !state.borrow().opts.resume_grace.is_zero() {
+        let mut pending = vec![];
+        while let Ok(msg) = client.receiver.try_recv() {
+            pending.push(msg);
+        }
+        let token = state.borrow_mut().suspend_user(
+            &client.name,
+            pending,
+            std::mem::take(&mut client.unacked),
+            reason,
+        );
+        log::debug!("User {} lost its connection ({reason:?})", client.name);
+        return Ok(token.map(|token| Suspended {
+            name: client.name,
+            token,
+        }));
+    }
+
+    state.borrow_mut().remove_user(&client.name, reason);
+    log::debug!("User {} left ({reason:?})", client.name);
+    result.map(|_| None)
+}
+
+/// Waits until the client sends `Join` or `Resume`. Returns `None` if the client should be
+/// disconnected.
+async fn wait_for_login(
+    state: &SharedState,
+    reader: &mut Reader,
+    writer: &mut Writer,
+    shutdown: &mut watch::Receiver<bool>,
+) -> anyhow::Result<Option<Login>> {
+    let join_deadline = Instant::now() + state.borrow().opts.join_timeout;
+    loop {
+        let msg = tokio::select! {
+            _ = shutdown.changed() => return Ok(None),
+            msg = tokio::time::timeout_at(join_deadline, reader.recv()) => msg,
+        };
+        match msg {
+            Err(_) => {
+                writer.trace.record(|client| Event::Timer {
+                    client,
+                    timer: Timer::Join,
+                });
+                writer
+                    .send(ServerToClientMsg::Error(
+                        "Timed out waiting for Join".to_string(),
+                    ))
+                    .await?;
+                return Ok(None);
+            }
+            Ok(Some(Ok(ClientToServerMsg::Join { name }))) => return Ok(Some(Login::Join(name))),
+            Ok(Some(Ok(ClientToServerMsg::Resume { token }))) => {
+                return Ok(Some(Login::Resume(token)))
+            }
+            Ok(Some(Ok(_))) => {
+                writer.send(unexpected_message()).await?;
+                return Ok(None);
+            }
+            Ok(Some(Err(error))) if MessageTooLarge::is_cause_of(&error) => {
+                writer.send(message_too_large()).await?
+            }
+            Ok(Some(Err(error))) => return Err(error.into()),
+            Ok(None) => return Ok(None),
+        }
+    }
+}
+
+/// A client that has successfully joined the server.
+struct JoinedClient<'a> {
+    name: String,
+    state: &'a SharedState,
+    reader: Reader,
+    writer: Writer,
+    /// Messages sent to this client by other clients.
+    receiver: UnboundedReceiver<ServerToClientMsg>,
+    /// Authors of the messages delivered to this client that it has not acknowledged yet,
+    /// indexed by the message ID, so that the oldest ones can be dropped first.
+    unacked: BTreeMap<u64, String>,
+}
+
+impl JoinedClient<'_> {
+    /// Sends the welcome message, followed by the `backlog` of messages that the user has
+    /// missed. Messages sent to the user in the meantime wait in the channel, so they cannot
+    /// overtake the backlog.
+    async fn welcome(
+        &mut self,
+        token: String,
+        backlog: Vec<ServerToClientMsg>,
+    ) -> anyhow::Result<()> {
+        let welcome = ServerToClientMsg::Welcome {
+            max_message_size: self.state.borrow().opts.max_message_size,
+            token,
+        };
+        self.writer.send(welcome).await?;
+        for msg in backlog {
+            self.deliver(msg).await?;
+        }
+        Ok(())
+    }
+
+    /// Sends a message to the client. If it is a chat message, its author is told that it was
+    /// delivered.
+    async fn deliver(&mut self, msg: ServerToClientMsg) -> anyhow::Result<()> {
+        let delivered = match &msg {
+            ServerToClientMsg::Message { id, from, .. } => Some((*id, from.clone())),
+            _ => None,
+        };
+        self.writer.send(msg).await?;
+        if let Some((id, from)) = delivered {
+            self.state.borrow_mut().notify(
+                &from,
+                ServerToClientMsg::Delivered {
+                    id,
+                    to: self.name.clone(),
+                },
+            );
+            self.unacked.insert(id, from);
+            if self.unacked.len() > MAX_UNACKED {
+                self.unacked.pop_first();
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the reason why the client has left.
+    async fn run(&mut self, shutdown: &mut watch::Receiver<bool>) -> anyhow::Result<LeaveReason> {
+        let idle_timeout = self.state.borrow().opts.idle_timeout;
+        let mut deadline = Instant::now() + idle_timeout;
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => {
+                    // Send out the notifications about the other users that have left
+                    while let Ok(msg) = self.receiver.try_recv() {
+                        self.deliver(msg).await?;
+                    }
+                    return Ok(LeaveReason::Shutdown);
+                }
+                _ = tokio::time::sleep_until(deadline) => {
+                    self.writer.trace.record(|client| Event::Timer { client, timer: Timer::Idle });
+                    self.writer.send(ServerToClientMsg::Error("Timeouted".to_string())).await?;
+                    return Ok(LeaveReason::TimedOut);
+                }
+                Some(msg) = self.receiver.recv() => {
+                    // Only DMs and broadcasts keep the client alive, presence notifications and
+                    // receipts of its own messages do not
+                    if matches!(msg, ServerToClientMsg::Message { .. }) {
+                        deadline = Instant::now() + idle_timeout;
+                    }
+                    self.deliver(msg).await?;
+                }
+                msg = self.reader.recv() => {
+                    let Some(msg) = msg else {
+                        return Ok(LeaveReason::Disconnected);
+                    };
+                    deadline = Instant::now() + idle_timeout;
+                    match msg {
+                        Ok(msg) => {
+                            if !self.handle_message(msg).await? {
+                                return Ok(LeaveReason::Error);
+                            }
+                        }
+                        Err(error) if MessageTooLarge::is_cause_of(&error) => {
+                            self.writer.send(message_too_large()).await?;
+                        }
+                        Err(error) => return Err(error.into()),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns `false` if the client should be disconnected.
+    async fn handle_message(&mut self, msg: ClientToServerMsg) -> anyhow::Result<bool> {
+        let name = self.name.as_str();
+        match msg {
+            ClientToServerMsg::Join { .. } | ClientToServerMsg::Resume { .. } => {
+                self.writer.send(unexpected_message()).await?;
+                return Ok(false);
+            }
+            ClientToServerMsg::Ping => self.writer.send(ServerToClientMsg::Pong).await?,
+            ClientToServerMsg::ListUsers => {
+                let users = self.state.borrow().all_usernames();
+                self.writer
+                    .send(ServerToClientMsg::UserList { users })
+                    .await?;
+            }
+            ClientToServerMsg::SendDM { to, message } => {
+                let qualified = match self.state.borrow().server_name() {
+                    Some(server) => to == format!("{name}@{server}"),
+                    None => false,
+                };
+                if to == name || qualified {
+                    self.writer
+                        .send(ServerToClientMsg::Error(
+                            "Cannot send a DM to yourself".to_string(),
+                        ))
+                        .await?;
+                    return Ok(true);
+                }
+                let route = self.state.borrow_mut().route_dm(name, &to, &message)?;
+                match route {
+                    DmRoute::Online { id } | DmRoute::Stored { id } => {
+                        self.writer.send(ServerToClientMsg::Accepted { id }).await?;
+                    }
+                    DmRoute::UnknownUser => {
+                        self.writer
+                            .send(ServerToClientMsg::Error(format!(
+                                "User {to} does not exist"
+                            )))
+                            .await?;
+                    }
+                    DmRoute::Dropped(reason) => {
+                        self.writer.send(ServerToClientMsg::Error(reason)).await?;
+                    }
+                }
+            }
+            ClientToServerMsg::Broadcast { message } => {
+                let route = self.state.borrow_mut().broadcast(name, &message)?;
+                let response = match route {
+                    BroadcastRoute::Sent { id } => ServerToClientMsg::Accepted { id },
+                    BroadcastRoute::Dropped(reason) => ServerToClientMsg::Error(reason),
+                };
+                self.writer.send(response).await?;
+            }
+            ClientToServerMsg::History { since, limit } => {
+                let page = self.state.borrow().history_page(name, since, limit);
+                let response = match page {
+                    Some(messages) => ServerToClientMsg::History { messages },
+                    None => ServerToClientMsg::Error("History is not enabled".to_string()),
+                };
+                self.writer.send(response).await?;
+            }
+            ClientToServerMsg::Ack { id } => match self.unacked.remove(&id) {
+                Some(author) => self.state.borrow_mut().notify(
+                    &author,
+                    ServerToClientMsg::Read {
+                        id,
+                        by: name.to_string(),
+                    },
+                ),
+                None => {
+                    self.writer
+                        .send(ServerToClientMsg::Error(format!("Unknown message {id}")))
+                        .await?
+                }
+            },
+            ClientToServerMsg::Subscribe => self.state.borrow_mut().set_subscribed(name, true),
+            ClientToServerMsg::Unsubscribe => self.state.borrow_mut().set_subscribed(name, false),
+        }
+        Ok(true)
+    }
+}
+
+fn unexpected_message() -> ServerToClientMsg {
+    ServerToClientMsg::Error("Unexpected message received".to_string())
+}
+
+fn message_too_large() -> ServerToClientMsg {
+    ServerToClientMsg::Error("Message too large".to_string())
+}
@@ -0,0 +1,148 @@
+use crate::net::accept_failed;
+use crate::reader::MessageReader;
+use crate::state::SharedState;
+use crate::writer::MessageWriter;
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinSet;
+
+/// How long do we wait before we try to link to a peer again, after the link was lost or could
+/// not be established.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// How long does a peer have to introduce itself after it connects.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Messages exchanged by linked servers, in both directions.
+///
+/// Users of the sending server are referred to by their plain names, the receiving server
+/// qualifies them with the name of the sender (`<user>@<server>`) before it shows them to its
+/// users.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone)]
+pub enum PeerMsg {
+    /// First message sent by both servers after they connect.
+    Hello { server: String },
+    /// All users of the sending server. Sent after the link is established and whenever the
+    /// users change.
+    Users { users: Vec<String> },
+    /// DM from a user of the sending server to a user of the receiving server.
+    Dm {
+        from: String,
+        to: String,
+        message: String,
+    },
+    /// Broadcast from a user of the sending server. It is only delivered to the users of the
+    /// receiving server, servers never forward messages further.
+    Broadcast { from: String, message: String },
+}
+
+/// Accepts links from peers until the server shuts down.
+pub async fn accept_peers(
+    listener: TcpListener,
+    state: SharedState,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut links = JoinSet::new();
+    loop {
+        tokio::select! {
+            _ = shutdown.changed() => break,
+            Some(_) = links.join_next() => {}
+            peer = listener.accept() => {
+                let (stream, address) = match peer {
+                    Ok(peer) => peer,
+                    Err(error) => {
+                        accept_failed(error).await;
+                        continue;
+                    }
+                };
+                let (state, mut shutdown) = (state.clone(), shutdown.clone());
+                links.spawn_local(async move {
+                    if let Err(error) = run_link(stream, &state, &mut shutdown).await {
+                        log::warn!("Link with peer {address} failed: {error}");
+                    }
+                });
+            }
+        }
+    }
+    while links.join_next().await.is_some() {}
+}
+
+/// Keeps the server linked to the peer at `address` until the server shuts down.
+pub async fn link_to_peer(
+    address: SocketAddr,
+    state: SharedState,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        let stream = tokio::select! {
+            _ = shutdown.changed() => return,
+            stream = TcpStream::connect(address) => stream,
+        };
+        match stream {
+            Ok(stream) => {
+                if let Err(error) = run_link(stream, &state, &mut shutdown).await {
+                    log::warn!("Link with peer {address} failed: {error}");
+                }
+            }
+            Err(error) => log::debug!("Cannot connect to peer {address}: {error}"),
+        }
+        if *shutdown.borrow() {
+            return;
+        }
+        tokio::select! {
+            _ = shutdown.changed() => return,
+            _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+        }
+    }
+}
+
+/// Exchanges messages with a peer until the link is lost or the server shuts down.
+async fn run_link(
+    stream: TcpStream,
+    state: &SharedState,
+    shutdown: &mut watch::Receiver<bool>,
+) -> anyhow::Result<()> {
+    let (rx, tx) = stream.into_split();
+    let mut reader = MessageReader::<PeerMsg, _>::new(rx);
+    let mut writer = MessageWriter::<PeerMsg, _>::new(tx);
+
+    let own_name = state.borrow().server_name().unwrap_or_default().to_string();
+    writer
+        .send(PeerMsg::Hello {
+            server: own_name.clone(),
+        })
+        .await?;
+    let peer = match tokio::time::timeout(HELLO_TIMEOUT, reader.recv()).await {
+        Ok(Some(Ok(PeerMsg::Hello { server }))) => server,
+        Ok(Some(Ok(msg))) => anyhow::bail!("Unexpected message from peer: {msg:?}"),
+        Ok(Some(Err(error))) => return Err(error.into()),
+        Ok(None) => anyhow::bail!("The peer closed the link"),
+        Err(_) => anyhow::bail!("Timed out waiting for Hello"),
+    };
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    if !state.borrow_mut().add_peer(&peer, sender) {
+        anyhow::bail!("Server {peer} is already linked or has the same name as this server");
+    }
+    log::info!("Linked with server {peer}");
+
+    let result = async {
+        loop {
+            tokio::select! {
+                _ = shutdown.changed() => return Ok(()),
+                Some(msg) = receiver.recv() => writer.send(msg).await?,
+                msg = reader.recv() => match msg {
+                    Some(Ok(msg)) => state.borrow_mut().handle_peer_msg(&peer, msg)?,
+                    Some(Err(error)) => return Err(error.into()),
+                    None => return Ok(()),
+                },
+            }
+        }
+    }
+    .await;
+
+    state.borrow_mut().remove_peer(&peer);
+    log::info!("Link with server {peer} was closed");
+    result
+}
@@ -0,0 +1,57 @@
+use std::io::ErrorKind;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// How long does a client of the endpoint have to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(1);
+/// Maximum size of the request line and headers.
+const MAX_REQUEST_SIZE: u64 = 8 * 1024;
+
+/// Answers a single HTTP request and closes the connection. `GET /metrics` is answered with the
+/// text returned by `metrics`.
+pub async fn handle_request(
+    mut stream: TcpStream,
+    metrics: impl FnOnce() -> String,
+) -> std::io::Result<()> {
+    let request_line = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| std::io::Error::from(ErrorKind::TimedOut))??;
+
+    let mut parts = request_line.split_whitespace();
+    let response = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            response("200 OK", "text/plain; version=0.0.4", &metrics())
+        }
+        (Some("GET"), Some(_)) => response("404 Not Found", "text/plain", "Not found\n"),
+        _ => response(
+            "405 Method Not Allowed",
+            "text/plain",
+            "Method not allowed\n",
+        ),
+    };
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Returns the request line.
+async fn read_request(stream: &mut TcpStream) -> std::io::Result<String> {
+    let mut reader = BufReader::new(stream.take(MAX_REQUEST_SIZE));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    // The headers are not needed, but they have to be read before the response is sent
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).await? == 0 || header.trim_end().is_empty() {
+            break;
+        }
+    }
+    Ok(request_line)
+}
+
+fn response(status: &str, content_type: &str, body: &str) -> String {
+    format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    )
+}
@@ -1,123 +1,489 @@
 #![warn(clippy::await_holding_refcell_ref)]
 
-//! TODO: implement a simple chat server using async/await and tokio (still using non-blocking I/O)
+//! Chat server that uses async/await and tokio.
 //!
-//! The chat server should behave identically as the one from last week, with one new feature.
-//! However, it should be implemented using async/await (with non-blocking I/O) and run on a
-//! single thread. It should still support concurrency and allow the connection of multiple clients
-//! at once.
-//!
-//! Ideally, reuse your implementation from **week 08** (from two weeks ago), but remove threads and
-//! add `await` and `tokio`. Remember not to block inside `async` functions and blocks.
-//!
-//! Your code will run inside [`tokio::task::LocalSet`], so you can use [`tokio::task::spawn_local`]
-//! to spawn new asynchronous tasks.
-
-/// The following modules were prepared for you. You should not need to modify them.
-///
-/// Take a look at this file to see how should the individual messages be handled
-mod messages;
+//! It speaks the same protocol as the week08 server, see [`messages`], and adds receipts,
+//! presence notifications, resumable sessions, federation and plugins. The whole server runs on
+//! a single thread inside a [`tokio::task::LocalSet`], every client is served by its own task
+//! spawned with [`tokio::task::spawn_local`]. The tasks share the state of the server through
+//! `Rc<RefCell<...>>`, whose borrows are never held across an `await` point.
+
+use crate::client::ClientStream;
+use crate::metrics::{Counted, Stats, Timed};
+use crate::net::{accept_failed, Acceptor, Incoming, ListenAddr, Listener};
+use crate::plugins::PluginFactory;
+use crate::state::{ServerState, SharedState};
+use crate::trace::{ConnectionTrace, Event};
+use std::cell::RefCell;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::{oneshot, watch};
+use tokio::task::JoinSet;
+use tokio_rustls::TlsAcceptor;
+
+/// Handling of a single connected client
+mod client;
+/// Links between federated servers
+mod federation;
+/// Persistent message history
+mod history;
+/// HTTP endpoint with the metrics
+mod http;
+/// Files with a JSON line for every record
+mod jsonl;
+/// Messages of the chat protocol, their documentation describes how the server handles them
+pub mod messages;
+/// Counters and gauges describing the running server
+pub mod metrics;
+/// Listening on TCP/IP and Unix sockets
+pub mod net;
+/// Bots and other extensions of the server
+pub mod plugins;
 /// Message reading
-mod reader;
+pub mod reader;
+/// Replay of the recordings in virtual time
+#[cfg(any(test, feature = "replay"))]
+pub mod replay;
+/// State shared between the tasks of the server
+mod state;
+/// Encryption of the connections
+mod tls;
+/// Recording of the server for debugging
+pub mod trace;
+/// Serving clients over WebSocket
+mod websocket;
 /// Message writing
-mod writer;
+pub mod writer;
+
+#[derive(Clone)]
+pub struct ServerOpts {
+    /// Addresses on which the server listens, there has to be at least one.
+    pub listen: Vec<ListenAddr>,
+    /// Maximum number of clients that can be connected to the server at once, through all
+    /// listeners together.
+    pub max_clients: usize,
+    /// File where the history of broadcasts and DMs is stored.
+    /// If it is `None`, the history is disabled.
+    pub history: Option<PathBuf>,
+    /// How long do we wait for the `Join` message after a client connects.
+    pub join_timeout: Duration,
+    /// How long can a client stay connected without sending or receiving anything.
+    pub idle_timeout: Duration,
+    /// Maximum size of a single message (in bytes) that a client can send to the server.
+    pub max_message_size: usize,
+    /// How long is the session of a user kept after its connection is lost, so that it can be
+    /// resumed. Zero disables resuming.
+    pub resume_grace: Duration,
+    /// Encrypts all connections with TLS. If it is `None`, the server talks in plain text.
+    pub tls: Option<TlsConfig>,
+    /// Address of an HTTP endpoint that serves the metrics of the server at `/metrics`, in the
+    /// Prometheus text format. The endpoint has no authentication, so it should listen on
+    /// localhost. If it is `None`, the endpoint is disabled.
+    pub metrics: Option<SocketAddr>,
+    /// Links the server with other servers, so that their users can talk to each other.
+    /// If it is `None`, the server is on its own.
+    pub federation: Option<FederationConfig>,
+    /// Plugins that can answer, rewrite or drop the messages of users, see [`plugins::Plugin`].
+    pub plugins: Vec<PluginFactory>,
+    /// File where the server records every connection, every message that it receives or sends
+    /// and every timeout, so that the session can be replayed with `replay::replay`.
+    /// The file is replaced when the server starts. If it is `None`, the recording is disabled.
+    pub record: Option<PathBuf>,
+}
+
+/// Federation of several servers.
+///
+/// Every server has to be linked directly with every other server of the federation, because
+/// servers only deliver messages from their peers to their own users and never forward them
+/// further, so that a message cannot go around in a loop. Only one server of each pair should
+/// list the other one in [`FederationConfig::peers`], a second link between the same servers is
+/// rejected.
+///
+/// Users of a peer are shown as `<user>@<server>`, and DMs sent to such names are routed to the
+/// peer. Broadcasts reach the users of all linked servers. Receipts are not sent across servers.
+///
+/// The links are plain TCP/IP without any authentication, so the peer listener should only be
+/// reachable by the other servers.
+#[derive(Clone, Debug)]
+pub struct FederationConfig {
+    /// Name of this server, it has to be unique within the federation.
+    pub name: String,
+    /// Address on which the server accepts links from its peers. If it is `None`, the server
+    /// only links to [`FederationConfig::peers`].
+    pub listen: Option<SocketAddr>,
+    /// Addresses of the peers that this server links to. Lost links are reestablished.
+    pub peers: Vec<SocketAddr>,
+}
 
-#[derive(Copy, Clone)]
-struct ServerOpts {
-    /// Maximum number of clients that can be connected to the server at once.
-    max_clients: usize,
+/// Certificate of the server, both files use the PEM format.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// The certificate chain, starting with the certificate of the server.
+    pub certificate: PathBuf,
+    /// The private key of the server.
+    pub key: PathBuf,
 }
 
 /// Representation of a running server
-struct RunningServer {
-    /// Port on which the server is running
-    port: u16,
+pub struct RunningServer {
+    /// Addresses on which the server is running, in the order of [`ServerOpts::listen`],
+    /// including the ports chosen by the operating system
+    pub addresses: Vec<ListenAddr>,
     /// Main future of the server
-    future: Pin<Box<dyn Future<Output = anyhow::Result<()>>>>,
+    pub future: Pin<Box<dyn Future<Output = anyhow::Result<()>>>>,
     /// Channel that can be used to tell the server to stop
-    tx: tokio::sync::oneshot::Sender<()>,
+    pub tx: oneshot::Sender<()>,
+    state: SharedState,
+    /// Address of the metrics endpoint, if it is enabled
+    metrics_address: Option<SocketAddr>,
+    /// Address on which the server accepts links from its peers, if it is enabled
+    federation_address: Option<SocketAddr>,
 }
 
-/// TODO: implement the following asynchronous function called `run_server`
-/// It should start a chat server on a TCP/IP port assigned to it by the operating system and
-/// return a [`RunningServer`]. Note that the function is asynchronous, but it should create a
-/// separate future that will run the server loop, and return that future inside the returned
-/// [`RunningServer`].
-///
-/// You should not create any threads anywhere in this assignment. Everything should run on a single
-/// thread. You should not need `Arc<Mutex<...>>` anywhere, although `Rc<RefCell<...>>` could be
-/// useful. Just be careful not to hold any `RefCell` borrows across `await` points if some other
-/// async task might also access that same `RefCell` and break the alias xor mut rule.
-///
-/// The server should implement the messages described in `messages.rs`, see the message comments
-/// for more details. The details are the same as last week, with one exception described below.
+impl RunningServer {
+    /// Returns the port of the first TCP/IP listener, not counting WebSocket listeners.
+    ///
+    /// Panics if there is no such listener.
+    pub fn port(&self) -> u16 {
+        self.addresses
+            .iter()
+            .find_map(|address| match address {
+                ListenAddr::Tcp(address) => Some(address.port()),
+                ListenAddr::Unix(_) | ListenAddr::WebSocket(_) => None,
+            })
+            .expect("the server does not listen on TCP/IP")
+    }
+
+    /// Returns the address of the metrics endpoint, including the port chosen by the operating
+    /// system, or `None` if it is disabled.
+    pub fn metrics_address(&self) -> Option<SocketAddr> {
+        self.metrics_address
+    }
+
+    /// Returns the address on which the server accepts links from its federation peers,
+    /// including the port chosen by the operating system, or `None` if it does not accept them.
+    pub fn federation_address(&self) -> Option<SocketAddr> {
+        self.federation_address
+    }
+
+    /// Returns the current metrics of the server.
+    pub fn stats(&self) -> Stats {
+        self.state.borrow().stats()
+    }
+}
+
+/// Starts the chat server on the addresses from [`ServerOpts::listen`]. The server runs in the
+/// future returned inside [`RunningServer`], which has to be polled inside a
+/// [`tokio::task::LocalSet`].
 ///
 /// # Client connection
-/// When a client connects to the server, it should send a `Join` message.
-/// - If the client does not send a `Join` message within two seconds, the server should
-/// send an error "Timed out waiting for Join" and disconnect the client immediately.
-/// - If it sends anything else, the server should respond with an error "Unexpected message received"
-/// and disconnect the client immediately.
-/// - If the user sends a Join message (with a unique username), the server should respond with
-/// the `Welcome` message.
+/// A client has to send `Join` (with a unique username) within `opts.join_timeout`, otherwise it
+/// receives an error "Timed out waiting for Join" and is disconnected. The server responds to
+/// `Join` with `Welcome`. A client whose connection was lost can send `Resume` instead of `Join`
+/// to continue its session within `opts.resume_grace`, see `messages.rs` for details. If the
+/// client sends anything else first, or sends `Join` again later, the server responds with an
+/// error "Unexpected message received" and disconnects it.
 ///
-/// Then it should start receiving requests from the client.
-/// - If the client ever sends the `Join` message again, the server should respond with an error
-/// "Unexpected message received" and disconnect the client immediately.
-/// - **(NEW)** If the client does not send any message in three seconds AND it does not receive
-/// any message (through a DM or a broadcast) within that duration, the server should respond with
-/// an error "Timeouted" and disconnect the client immediately. This three second timer is refreshed
-/// everytime the client sends something or receives a DM/broadcast.
+/// A client that neither sends anything nor receives a DM or a broadcast within
+/// `opts.idle_timeout` receives an error "Timeouted" and is disconnected.
 ///
 /// # Maximum number of clients
-/// When a client connects and there are already `opts.max_clients` other clients connected, the
-/// server should respond with an error "Server is full" and disconnect the client immediately.
-/// Note that if the server is full, the client should be disconnected even before it sends the
-/// `Join` message.
+/// A client that connects while `opts.max_clients` other clients are connected receives an error
+/// "Server is full" and is disconnected right away, before it sends `Join`.
 ///
 /// # Graceful shutdown
-/// Your server should react to a message sent through the oneshot channel that you should create
-/// in `RunningServer`. When a message is received on this channel, the server should:
-/// 1) Stop receiving new TCP/IP connections
-/// 2) Correctly disconnect all connected users (bonus, see [`tests::drop_clients_on_shutdown`])
-/// 3) Wait until all async tasks that it has created has completed executing (bonus)
-/// The rest is handled by the test infrastructure.
-///
-/// See tests for more details.
-async fn run_server(opts: ServerOpts) -> anyhow::Result<RunningServer> { todo!() }
+/// When a message is sent through [`RunningServer::tx`], the server stops accepting new
+/// connections, disconnects all users and waits until all of its tasks finish.
+pub async fn run_server(opts: ServerOpts) -> anyhow::Result<RunningServer> {
+    if opts.listen.is_empty() {
+        anyhow::bail!("The server has to listen on at least one address");
+    }
+    let mut listeners = vec![];
+    for address in &opts.listen {
+        let listener = Listener::bind(address)
+            .await
+            .map_err(|error| anyhow::anyhow!("Cannot listen on {address}: {error}"))?;
+        listeners.push(listener);
+    }
+    let addresses = listeners
+        .iter()
+        .map(|listener| listener.local_addr())
+        .collect::<Result<Vec<_>, _>>()?;
+    start_server(opts, listeners, addresses).await
+}
+
+/// Starts the chat server with the clients from `acceptor`, which listens on `addresses`.
+async fn start_server(
+    opts: ServerOpts,
+    acceptor: impl Acceptor + 'static,
+    addresses: Vec<ListenAddr>,
+) -> anyhow::Result<RunningServer> {
+    let metrics_listener = match opts.metrics {
+        Some(address) => Some(
+            TcpListener::bind(address)
+                .await
+                .map_err(|error| anyhow::anyhow!("Cannot serve metrics on {address}: {error}"))?,
+        ),
+        None => None,
+    };
+    let metrics_address = metrics_listener
+        .as_ref()
+        .map(|listener| listener.local_addr())
+        .transpose()?;
+    let federation_listener = match opts.federation.as_ref().and_then(|f| f.listen) {
+        Some(address) => Some(TcpListener::bind(address).await.map_err(|error| {
+            anyhow::anyhow!("Cannot listen for federation peers on {address}: {error}")
+        })?),
+        None => None,
+    };
+    let federation_address = federation_listener
+        .as_ref()
+        .map(|listener| listener.local_addr())
+        .transpose()?;
+
+    let tls = opts.tls.as_ref().map(tls::acceptor).transpose()?;
+    let state = Rc::new(RefCell::new(ServerState::new(opts)?));
+    let metrics = state.borrow().metrics.clone();
+    let (tx, rx) = oneshot::channel();
+    let future = serve(
+        acceptor,
+        addresses.clone(),
+        metrics_listener,
+        federation_listener,
+        tls,
+        state.clone(),
+        rx,
+    );
+    Ok(RunningServer {
+        future: Box::pin(Timed::new(future, metrics)),
+        addresses,
+        tx,
+        state,
+        metrics_address,
+        federation_address,
+    })
+}
+
+/// Accepts clients on all listeners, the `max_clients` limit is shared by all of them.
+async fn serve(
+    mut acceptor: impl Acceptor,
+    addresses: Vec<ListenAddr>,
+    metrics_listener: Option<TcpListener>,
+    federation_listener: Option<TcpListener>,
+    tls: Option<TlsAcceptor>,
+    state: SharedState,
+    mut stop: oneshot::Receiver<()>,
+) -> anyhow::Result<()> {
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
+    let mut tasks = JoinSet::new();
+    // Every task counts the time it spends running into the busy time
+    let metrics = state.borrow().metrics.clone();
+    if let Some(listener) = metrics_listener {
+        let task = serve_metrics(listener, state.clone(), shutdown_rx.clone());
+        tasks.spawn_local(Timed::new(task, metrics.clone()));
+    }
+    if let Some(listener) = federation_listener {
+        let task = federation::accept_peers(listener, state.clone(), shutdown_rx.clone());
+        tasks.spawn_local(Timed::new(task, metrics.clone()));
+    }
+    let peers = state
+        .borrow()
+        .opts
+        .federation
+        .as_ref()
+        .map(|federation| federation.peers.clone())
+        .unwrap_or_default();
+    for address in peers {
+        let task = federation::link_to_peer(address, state.clone(), shutdown_rx.clone());
+        tasks.spawn_local(Timed::new(task, metrics.clone()));
+    }
+
+    loop {
+        let client = tokio::select! {
+            _ = &mut stop => break,
+            // Reap finished client tasks
+            Some(_) = tasks.join_next() => continue,
+            client = acceptor.accept() => match client {
+                Ok(client) => client,
+                Err(error) => {
+                    accept_failed(error).await;
+                    continue;
+                }
+            },
+        };
+        let Incoming {
+            stream,
+            framing,
+            peer,
+        } = client;
+        let stream: ClientStream = Box::new(Counted::new(stream, metrics.clone()));
+        let mut state_ref = state.borrow_mut();
+        let trace = ConnectionTrace::connected(state_ref.recorder.as_ref());
+        if state_ref.client_count >= state_ref.opts.max_clients {
+            log::info!("Rejecting client {peer}, the server is full");
+            let task = client::reject_client(
+                stream,
+                framing,
+                tls.clone(),
+                state_ref.opts.join_timeout,
+                state_ref.opts.max_message_size,
+                metrics.clone(),
+                trace,
+            );
+            tasks.spawn_local(Timed::new(task, metrics.clone()));
+            continue;
+        }
+        state_ref.client_count += 1;
+        drop(state_ref);
+        log::debug!("Client {peer} connected");
+        let task = client::handle_client(
+            stream,
+            framing,
+            tls.clone(),
+            state.clone(),
+            shutdown_rx.clone(),
+            trace,
+        );
+        tasks.spawn_local(Timed::new(task, metrics.clone()));
+    }
+
+    // Stop receiving new connections, and wait until all clients are disconnected
+    if let Some(recorder) = &state.borrow().recorder {
+        recorder.record(Event::Stopped);
+    }
+    drop(acceptor);
+    for address in &addresses {
+        net::remove_socket(address);
+    }
+    state.borrow_mut().remove_all_users();
+    let _ = shutdown_tx.send(true);
+    while tasks.join_next().await.is_some() {}
+    Ok(())
+}
 
+/// Serves the requests to the metrics endpoint one by one, until the server shuts down.
+async fn serve_metrics(
+    listener: TcpListener,
+    state: SharedState,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    loop {
+        let stream = tokio::select! {
+            _ = shutdown.changed() => return,
+            stream = listener.accept() => stream,
+        };
+        let stream = match stream {
+            Ok((stream, _)) => stream,
+            Err(error) => {
+                accept_failed(error).await;
+                continue;
+            }
+        };
+        let metrics = || state.borrow().stats().to_prometheus();
+        if let Err(error) = http::handle_request(stream, metrics).await {
+            log::debug!("Metrics request failed: {error}");
+        }
+    }
+}
 
 #[cfg(test)]
 mod tests {
-    use crate::messages::{ClientToServerMsg, ServerToClientMsg};
-    use crate::reader::MessageReader;
+    use crate::client::Stream;
+    use crate::messages::{ClientToServerMsg, LeaveReason, ServerToClientMsg};
+    use crate::metrics::Stats;
+    use crate::net::ListenAddr;
+    use crate::plugins::{Context, Plugin, Verdict};
+    use crate::reader::{MessageReader, DEFAULT_MAX_MESSAGE_SIZE};
+    use crate::replay::replay;
+    use crate::state::{SharedState, MAX_RESUME_BACKLOG, MAX_UNACKED};
+    use crate::trace::{read_trace, Event, Record, Timer};
+    use crate::websocket::WebSocketLines;
     use crate::writer::MessageWriter;
-    use crate::{run_server, ServerOpts};
-    use std::cell::{Cell, RefCell};
+    use crate::{run_server, FederationConfig, ServerOpts, TlsConfig};
+    use futures_util::{SinkExt, StreamExt};
+    use std::collections::HashSet;
     use std::future::Future;
-    use std::rc::Rc;
+    use std::net::SocketAddr;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
     use std::time::Duration;
-    use tokio::io::AsyncWriteExt;
-    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
-    use tokio::net::TcpStream;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
+    use tokio::net::{TcpStream, UnixStream};
+    use tokio::sync::oneshot;
     use tokio::task::LocalSet;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+    use tokio_rustls::rustls::pki_types::{CertificateDer, ServerName};
+    use tokio_rustls::rustls::{ClientConfig, RootCertStore};
+    use tokio_rustls::TlsConnector;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::WebSocketStream;
 
-    // If you're struggling with this test, comment it and implement the rest of the
-    // functionality first.
     #[tokio::test]
-    async fn empty_server_shuts_down() {
-        run_test(opts(2), |_| async move { Ok(()) }).await;
+    async fn dm_receipts() {
+        run_test(opts(2), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+
+            mia.dm("Leo", "Hi").await;
+            let id = mia.expect_accepted().await;
+            assert_eq!(leo.expect_message("Mia", "Hi").await, id);
+            mia.expect_delivered(id, "Leo").await;
+
+            leo.ack(id).await;
+            mia.expect_read(id, "Leo").await;
+
+            Ok(())
+        })
+        .await;
     }
 
     #[tokio::test]
-    async fn max_clients() {
-        run_test(opts(2), |server| async move {
-            let _client = server.client().await;
-            let _client2 = server.client().await;
+    async fn broadcast_receipts() {
+        run_test(opts(3), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+            let mut zoe = spawner.client().await;
+            zoe.join("Zoe").await;
+
+            mia.send(ClientToServerMsg::Broadcast {
+                message: "Hi all".to_string(),
+            })
+            .await;
+            let id = mia.expect_accepted().await;
+            assert_eq!(leo.expect_message("Mia", "Hi all").await, id);
+            assert_eq!(zoe.expect_message("Mia", "Hi all").await, id);
+
+            let mut delivered = HashSet::new();
+            for _ in 0..2 {
+                match mia.recv().await {
+                    ServerToClientMsg::Delivered {
+                        id: delivered_id,
+                        to,
+                    } => {
+                        assert_eq!(delivered_id, id);
+                        delivered.insert(to);
+                    }
+                    msg => panic!("Unexpected message {msg:?}"),
+                }
+            }
+            assert_eq!(
+                delivered,
+                HashSet::from(["Leo".to_string(), "Zoe".to_string()])
+            );
 
-            let mut client3 = server.client().await;
-            client3.expect_error("Server is full").await;
-            client3.check_closed().await;
+            zoe.ack(id).await;
+            mia.expect_read(id, "Zoe").await;
+            leo.ack(id).await;
+            mia.expect_read(id, "Leo").await;
 
             Ok(())
         })
@@ -125,16 +491,62 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn max_clients_after_client_leaves() {
+    async fn message_ids_increase() {
         run_test(opts(2), |spawner| async move {
-            let _client = spawner.client().await;
-            let client2 = spawner.client().await;
-            client2.close().await;
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+
+            mia.dm("Leo", "1").await;
+            let first = mia.expect_accepted().await;
+            leo.expect_message("Mia", "1").await;
+            mia.expect_delivered(first, "Leo").await;
+
+            leo.dm("Mia", "2").await;
+            let second = leo.expect_accepted().await;
+            mia.expect_message("Leo", "2").await;
+            leo.expect_delivered(second, "Mia").await;
+
+            mia.send(ClientToServerMsg::Broadcast {
+                message: "3".to_string(),
+            })
+            .await;
+            let third = mia.expect_accepted().await;
+            assert!(first < second && second < third);
 
-            sleep(500).await;
+            Ok(())
+        })
+        .await;
+    }
 
-            let mut client3 = spawner.client().await;
-            client3.join("Foo").await;
+    #[tokio::test]
+    async fn ack_unknown_message() {
+        run_test(opts(3), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+            let mut zoe = spawner.client().await;
+            zoe.join("Zoe").await;
+
+            leo.ack(42).await;
+            leo.expect_error("Unknown message 42").await;
+
+            mia.dm("Leo", "Hi").await;
+            let id = mia.expect_accepted().await;
+            leo.expect_message("Mia", "Hi").await;
+            mia.expect_delivered(id, "Leo").await;
+
+            // Only the recipient can acknowledge a message
+            zoe.ack(id).await;
+            zoe.expect_error(&format!("Unknown message {id}")).await;
+
+            // And only once
+            leo.ack(id).await;
+            mia.expect_read(id, "Leo").await;
+            leo.ack(id).await;
+            leo.expect_error(&format!("Unknown message {id}")).await;
 
             Ok(())
         })
@@ -142,49 +554,79 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn max_clients_herd() {
-        let max_clients = 5;
-        run_test(opts(max_clients), |spawner| async move {
-            let client_count = 50;
+    async fn unacked_messages_are_bounded() {
+        run_test(opts(2), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+
+            let count = MAX_UNACKED + 1;
+            for i in 0..count {
+                leo.dm("Mia", &i.to_string()).await;
+            }
+            let mut ids = vec![];
+            for i in 0..count {
+                ids.push(mia.expect_message("Leo", &i.to_string()).await);
+            }
 
-            let errors = Rc::new(Cell::new(0));
-            let successes = Rc::new(Cell::new(0));
+            // The oldest message was forgotten
+            mia.ack(ids[0]).await;
+            mia.expect_error(&format!("Unknown message {}", ids[0]))
+                .await;
+            mia.ack(ids[1]).await;
+            mia.expect_only_pong().await;
 
-            let joined_clients = Rc::new(RefCell::new(vec![]));
+            Ok(())
+        })
+        .await;
+    }
 
-            let futs = (0..client_count).map(|client_id| {
-                let errors = errors.clone();
-                let successes = successes.clone();
-                let joined_clients = joined_clients.clone();
+    #[tokio::test]
+    async fn stored_dm_receipts() {
+        let dir = tempfile::tempdir().unwrap();
+        run_test(
+            opts_with_history(2, &dir.path().join("history")),
+            |spawner| async move {
+                let mut leo = spawner.client().await;
+                leo.join("Leo").await;
+                leo.close().await;
+
+                sleep(500).await;
+
+                let mut mia = spawner.client().await;
+                mia.join("Mia").await;
+                mia.dm("Leo", "While you were away").await;
+                let id = mia.expect_accepted().await;
+
+                let mut leo = spawner.client().await;
+                leo.join("Leo").await;
+                assert_eq!(leo.expect_message("Mia", "While you were away").await, id);
+                mia.expect_delivered(id, "Leo").await;
+                leo.ack(id).await;
+                mia.expect_read(id, "Leo").await;
+
+                Ok(())
+            },
+        )
+        .await;
+    }
 
-                async move {
-                    let mut client = spawner.client().await;
-                    let _ = client
-                        .try_send(ClientToServerMsg::Join {
-                            name: format!("Client {client_id}"),
-                        })
-                        .await;
-                    match client.recv().await {
-                        ServerToClientMsg::Error(_) => {
-                            errors.set(errors.get() + 1);
-                        }
-                        ServerToClientMsg::Welcome => {
-                            successes.set(successes.get() + 1);
-                            // Make sure that the client doesn't disconnect
-                            joined_clients.borrow_mut().push(client);
-                        }
-                        msg => {
-                            panic!("Unexpected message {msg:?}");
-                        }
-                    }
-                }
-            });
-            futures_util::future::join_all(futs).await;
+    #[tokio::test]
+    async fn receipts_for_disconnected_author() {
+        run_test(opts(2), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
 
-            assert_eq!(errors.get(), client_count - max_clients);
-            assert_eq!(successes.get(), max_clients);
+            mia.dm("Leo", "Bye").await;
+            let id = mia.expect_accepted().await;
+            mia.close().await;
 
-            drop(joined_clients);
+            leo.expect_message("Mia", "Bye").await;
+            leo.ack(id).await;
+            leo.ping().await;
 
             Ok(())
         })
@@ -192,11 +634,29 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn list_users_before_join() {
-        run_test(opts(2), |spawner| async move {
-            let mut client = spawner.client().await;
-            client.send(ClientToServerMsg::ListUsers).await;
-            client.expect_error("Unexpected message received").await;
+    async fn presence() {
+        run_test(opts(3), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+            mia.expect_joined("Leo").await;
+            // Users are not told about themselves or about users that joined before them
+            leo.expect_only_pong().await;
+
+            leo.close().await;
+            mia.expect_left("Leo", LeaveReason::Disconnected).await;
+
+            let mut zoe = spawner.client().await;
+            zoe.join("Zoe").await;
+            mia.expect_joined("Zoe").await;
+            zoe.send(ClientToServerMsg::Join {
+                name: "Zoe".to_string(),
+            })
+            .await;
+            zoe.expect_error("Unexpected message received").await;
+            mia.expect_left("Zoe", LeaveReason::Error).await;
 
             Ok(())
         })
@@ -204,12 +664,19 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn join_after_half_sec() {
+    async fn presence_ignores_clients_that_did_not_join() {
         run_test(opts(2), |spawner| async move {
-            let mut client = spawner.client().await;
-            sleep(500).await;
-            client.join("Foo").await;
-            assert_eq!(client.list_users().await, vec!["Foo".to_string()]);
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+
+            let mut leo = spawner.client().await;
+            leo.send(ClientToServerMsg::Join {
+                name: "Mia".to_string(),
+            })
+            .await;
+            leo.expect_error("Username already taken").await;
+            leo.check_closed().await;
+            mia.expect_only_pong().await;
 
             Ok(())
         })
@@ -217,21 +684,26 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn join_timeout() {
-        run_test(opts(2), |spawner| async move {
-            let mut client = spawner.client().await;
-            sleep(3000).await;
-            match client
-                .try_send(ClientToServerMsg::Join {
-                    name: "Bilbo".to_string(),
-                })
-                .await
-            {
-                Ok(_) => {
-                    client.expect_error("Timed out waiting for Join").await;
-                }
-                Err(_) => {}
-            }
+    async fn presence_timeout() {
+        let opts = ServerOpts {
+            idle_timeout: Duration::from_secs(1),
+            resume_grace: Duration::ZERO,
+            ..opts(2)
+        };
+        run_test(opts, |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            sleep(500).await;
+
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+            mia.expect_joined("Leo").await;
+
+            // Keep Mia alive until Leo times out
+            sleep(200).await;
+            mia.send(ClientToServerMsg::Ping).await;
+            assert!(matches!(mia.recv_any().await, ServerToClientMsg::Pong));
+            mia.expect_left("Leo", LeaveReason::TimedOut).await;
 
             Ok(())
         })
@@ -239,16 +711,40 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn duplicated_join() {
-        run_test(opts(2), |spawner| async move {
-            let mut client = spawner.client().await;
-            client.join("Foo").await;
-            client
-                .send(ClientToServerMsg::Join {
-                    name: "Bar".to_string(),
-                })
-                .await;
-            client.expect_error("Unexpected message received").await;
+    async fn receipts_do_not_reset_idle_timeout() {
+        let opts = ServerOpts {
+            idle_timeout: Duration::from_secs(1),
+            resume_grace: Duration::ZERO,
+            ..opts(2)
+        };
+        run_test(opts, |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+            mia.expect_joined("Leo").await;
+
+            mia.dm("Leo", "First").await;
+            let first = mia.expect_accepted().await;
+            mia.expect_delivered(first, "Leo").await;
+            mia.dm("Leo", "Second").await;
+            let second = mia.expect_accepted().await;
+            mia.expect_delivered(second, "Leo").await;
+            leo.expect_message("Mia", "First").await;
+            leo.expect_message("Mia", "Second").await;
+
+            // Mia keeps receiving receipts, but does not send anything
+            sleep(600).await;
+            leo.ack(first).await;
+            mia.expect_read(first, "Leo").await;
+            sleep(300).await;
+            leo.ack(second).await;
+            mia.expect_read(second, "Leo").await;
+
+            sleep(300).await;
+            leo.send(ClientToServerMsg::Ping).await;
+            leo.expect_left("Mia", LeaveReason::TimedOut).await;
+            mia.expect_error("Timeouted").await;
 
             Ok(())
         })
@@ -256,19 +752,24 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn error_then_disconnect() {
-        run_test(opts(2), |spawner| async move {
-            let mut client = spawner.client().await;
-            client.join("Foo").await;
-            client
-                .send(ClientToServerMsg::Join {
-                    name: "Bar".to_string(),
-                })
-                .await;
-            client.close().await;
-
-            let mut client2 = spawner.client().await;
-            client2.join("Bar").await;
+    async fn presence_unsubscribe() {
+        run_test(opts(3), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            mia.send(ClientToServerMsg::Unsubscribe).await;
+            mia.expect_only_pong().await;
+
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+            leo.close().await;
+            sleep(100).await;
+            mia.expect_only_pong().await;
+
+            mia.send(ClientToServerMsg::Subscribe).await;
+            mia.expect_only_pong().await;
+            let mut zoe = spawner.client().await;
+            zoe.join("Zoe").await;
+            mia.expect_joined("Zoe").await;
 
             Ok(())
         })
@@ -276,30 +777,54 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn duplicated_username() {
-        run_test(opts(2), |spawner| async move {
-            let mut client = spawner.client().await;
-            client.join("Foo").await;
+    async fn presence_on_shutdown() {
+        let (mia, leo) = run_test(opts(2), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+            mia.expect_joined("Leo").await;
+            Ok((mia, leo))
+        })
+        .await;
+
+        for (mut client, other) in [(mia, "Leo"), (leo, "Mia")] {
+            client.expect_left(other, LeaveReason::Shutdown).await;
+            client.check_closed().await;
+        }
+    }
 
-            let mut client2 = spawner.client().await;
-            client2
+    #[tokio::test]
+    async fn resume_session() {
+        run_test(opts(3), |spawner| async move {
+            let mut mia = spawner.client().await;
+            let token = mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+
+            mia.lose_connection().await;
+            sleep(100).await;
+
+            // The session keeps the name of the user
+            let mut impostor = spawner.client().await;
+            impostor
                 .send(ClientToServerMsg::Join {
-                    name: "Foo".to_string(),
+                    name: "Mia".to_string(),
                 })
                 .await;
-            client2.expect_error("Username already taken").await;
+            impostor.expect_error("Username already taken").await;
 
-            Ok(())
-        })
-        .await;
-    }
+            leo.dm("Mia", "Are you there?").await;
+            let id = leo.expect_accepted().await;
 
-    #[tokio::test]
-    async fn ping() {
-        run_test(opts(2), |spawner| async move {
-            let mut luca = spawner.client().await;
-            luca.join("Luca").await;
-            luca.ping().await;
+            let mut mia = spawner.client().await;
+            let new_token = mia.resume(&token).await;
+            assert_ne!(new_token, token);
+            assert_eq!(mia.expect_message("Leo", "Are you there?").await, id);
+            leo.expect_delivered(id, "Mia").await;
+
+            // The other users have not noticed anything
+            leo.expect_only_pong().await;
 
             Ok(())
         })
@@ -307,11 +832,19 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn ping_before_join() {
+    async fn resume_invalid_token() {
         run_test(opts(2), |spawner| async move {
-            let mut client = spawner.client().await;
-            client.send(ClientToServerMsg::Ping).await;
-            client.expect_error("Unexpected message received").await;
+            let mia = spawner.client().await;
+            mia.resume_expect_error("foo").await;
+
+            // A client that closes the connection on purpose leaves for good
+            let mut mia = spawner.client().await;
+            let token = mia.join("Mia").await;
+            mia.close().await;
+            sleep(100).await;
+
+            let mia = spawner.client().await;
+            mia.resume_expect_error(&token).await;
 
             Ok(())
         })
@@ -319,15 +852,20 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn list_users_reconnect() {
+    async fn resume_token_is_single_use() {
         run_test(opts(2), |spawner| async move {
-            let mut client = spawner.client().await;
-            client.join("Foo").await;
-            client.close().await;
+            let mut mia = spawner.client().await;
+            let token = mia.join("Mia").await;
+            mia.lose_connection().await;
+            sleep(100).await;
 
-            let mut client = spawner.client().await;
-            client.join("Foo").await;
-            assert_eq!(client.list_users().await, vec!["Foo".to_string()]);
+            let mut mia = spawner.client().await;
+            mia.resume(&token).await;
+            mia.lose_connection().await;
+            sleep(100).await;
+
+            let mia = spawner.client().await;
+            mia.resume_expect_error(&token).await;
 
             Ok(())
         })
@@ -335,11 +873,24 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn list_users_self() {
-        run_test(opts(2), |spawner| async move {
-            let mut client = spawner.client().await;
-            client.join("Martin").await;
-            assert_eq!(client.list_users().await, vec!["Martin".to_string()]);
+    async fn resume_grace_expires() {
+        let opts = ServerOpts {
+            resume_grace: Duration::from_millis(500),
+            ..opts(2)
+        };
+        run_test(opts, |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            let token = leo.join("Leo").await;
+            mia.expect_joined("Leo").await;
+
+            leo.lose_connection().await;
+            sleep(1000).await;
+            mia.expect_left("Leo", LeaveReason::Disconnected).await;
+
+            let leo = spawner.client().await;
+            leo.resume_expect_error(&token).await;
 
             Ok(())
         })
@@ -347,12 +898,35 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn list_users_ignore_not_joined_users() {
-        run_test(opts(2), |spawner| async move {
-            let _client = spawner.client().await;
-            let mut client2 = spawner.client().await;
-            client2.join("Joe").await;
-            assert_eq!(client2.list_users().await, vec!["Joe".to_string()]);
+    async fn resume_after_timeout() {
+        let opts = ServerOpts {
+            idle_timeout: Duration::from_secs(1),
+            ..opts(2)
+        };
+        run_test(opts, |spawner| async move {
+            let mut mia = spawner.client().await;
+            let mia_token = mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            let leo_token = leo.join("Leo").await;
+
+            leo.dm("Mia", "Hi").await;
+            let id = leo.expect_accepted().await;
+            mia.expect_message("Leo", "Hi").await;
+            leo.expect_delivered(id, "Mia").await;
+
+            mia.expect_error("Timeouted").await;
+            leo.expect_error("Timeouted").await;
+            sleep(100).await;
+
+            // The message can still be acknowledged, and the author learns about it once it
+            // comes back
+            let mut mia = spawner.client().await;
+            mia.resume(&mia_token).await;
+            mia.ack(id).await;
+
+            let mut leo = spawner.client().await;
+            leo.resume(&leo_token).await;
+            leo.expect_read(id, "Mia").await;
 
             Ok(())
         })
@@ -360,23 +934,33 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn list_users_after_error() {
+    async fn resume_backlog_is_bounded() {
         run_test(opts(2), |spawner| async move {
-            let mut client = spawner.client().await;
-            client.join("Terrence").await;
-
-            let mut client2 = spawner.client().await;
-            client2.join("Joe").await;
-
-            client
-                .send(ClientToServerMsg::Join {
-                    name: "Barbara".to_string(),
+            let mut mia = spawner.client().await;
+            let token = mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+
+            mia.lose_connection().await;
+            sleep(100).await;
+
+            let count = MAX_RESUME_BACKLOG + 10;
+            for i in 0..count {
+                leo.send(ClientToServerMsg::Broadcast {
+                    message: i.to_string(),
                 })
                 .await;
+            }
+            for _ in 0..count {
+                leo.expect_accepted().await;
+            }
 
-            sleep(1000).await;
-
-            assert_eq!(client2.list_users().await, vec!["Joe".to_string()]);
+            let mut mia = spawner.client().await;
+            mia.resume(&token).await;
+            for i in count - MAX_RESUME_BACKLOG..count {
+                mia.expect_message("Leo", &i.to_string()).await;
+            }
+            mia.expect_only_pong().await;
 
             Ok(())
         })
@@ -384,22 +968,68 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn list_users() {
-        run_test(opts(2), |spawner| async move {
-            let mut client = spawner.client().await;
-            client.join("Terrence").await;
+    async fn resume_keeps_all_dms_with_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = opts_with_history(2, &dir.path().join("history"));
+        run_test(opts, |spawner| async move {
+            let mut mia = spawner.client().await;
+            let token = mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+
+            mia.lose_connection().await;
+            sleep(100).await;
+
+            // More DMs than fit into the backlog
+            let count = MAX_RESUME_BACKLOG + 10;
+            for i in 0..count {
+                leo.dm("Mia", &i.to_string()).await;
+            }
+            let mut ids = vec![];
+            for _ in 0..count {
+                ids.push(leo.expect_accepted().await);
+            }
 
-            let mut client2 = spawner.client().await;
-            client2.join("Joe").await;
-            assert_eq!(
-                client2.list_users().await,
-                vec!["Joe".to_string(), "Terrence".to_string()]
-            );
-            client2.close().await;
+            let mut mia = spawner.client().await;
+            mia.resume(&token).await;
+            for (i, id) in ids.iter().enumerate() {
+                assert_eq!(mia.expect_message("Leo", &i.to_string()).await, *id);
+            }
+            mia.expect_only_pong().await;
+            for id in ids {
+                leo.expect_delivered(id, "Mia").await;
+            }
 
-            sleep(1000).await;
+            Ok(())
+        })
+        .await;
+    }
 
-            assert_eq!(client.list_users().await, vec!["Terrence".to_string()]);
+    #[tokio::test]
+    async fn expired_session_keeps_dms_with_history() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = ServerOpts {
+            resume_grace: Duration::from_millis(500),
+            ..opts_with_history(2, &dir.path().join("history"))
+        };
+        run_test(opts, |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+
+            mia.lose_connection().await;
+            sleep(100).await;
+            leo.dm("Mia", "Are you there?").await;
+            let id = leo.expect_accepted().await;
+            leo.expect_left("Mia", LeaveReason::Disconnected).await;
+
+            // The DM waits in the history after the session has expired
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            assert_eq!(mia.expect_message("Leo", "Are you there?").await, id);
+            leo.expect_joined("Mia").await;
+            leo.expect_delivered(id, "Mia").await;
 
             Ok(())
         })
@@ -407,12 +1037,25 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn dm_nonexistent_user() {
-        run_test(opts(2), |spawner| async move {
-            let mut client = spawner.client().await;
-            client.join("Mark").await;
-            client.dm("Fiona", "Hi").await;
-            client.expect_error("User Fiona does not exist").await;
+    async fn tls_chat() {
+        let dir = tempfile::tempdir().unwrap();
+        run_test(opts_with_tls(2, dir.path()), |spawner| async move {
+            let mut mia = spawner.client().await;
+            let token = mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+
+            leo.dm("Mia", "Nobody can read this").await;
+            let id = leo.expect_accepted().await;
+            mia.expect_message("Leo", "Nobody can read this").await;
+            leo.expect_delivered(id, "Mia").await;
+
+            mia.lose_connection().await;
+            sleep(100).await;
+            let mut mia = spawner.client().await;
+            mia.resume(&token).await;
+            mia.ack(id).await;
+            leo.expect_read(id, "Mia").await;
 
             Ok(())
         })
@@ -420,12 +1063,14 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn dm_self() {
-        run_test(opts(2), |spawner| async move {
-            let mut client = spawner.client().await;
-            client.join("Xal'atath").await;
-            client.dm("Xal'atath", "I'm so lonely :(").await;
-            client.expect_error("Cannot send a DM to yourself").await;
+    async fn tls_server_full() {
+        let dir = tempfile::tempdir().unwrap();
+        run_test(opts_with_tls(1, dir.path()), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+
+            let mut leo = spawner.client().await;
+            leo.expect_error("Server is full").await;
 
             Ok(())
         })
@@ -433,16 +1078,25 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn dm_other() {
-        run_test(opts(2), |spawner| async move {
-            let mut terrence = spawner.client().await;
-            terrence.join("Terrence").await;
-
-            let mut joe = spawner.client().await;
-            joe.join("Joe").await;
+    async fn tls_rejects_plain_text() {
+        let dir = tempfile::tempdir().unwrap();
+        run_test(opts_with_tls(1, dir.path()), |spawner| async move {
+            let plain = ClientSpawner {
+                tls: None,
+                ..spawner.clone()
+            };
+            let mut client = plain.client().await;
+            let _ = client
+                .writer
+                .send(ClientToServerMsg::Join {
+                    name: "Mia".to_string(),
+                })
+                .await;
+            client.check_closed().await;
 
-            terrence.dm("Joe", "How you doin'").await;
-            joe.expect_message("Terrence", "How you doin'").await;
+            // The slot of the client was freed
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
 
             Ok(())
         })
@@ -450,137 +1104,123 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn dm_spam() {
-        run_test(opts(2), |spawner| async move {
-            let mut diana = spawner.client().await;
-            diana.join("Diana").await;
+    async fn tls_invalid_certificate() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = opts_with_tls(1, dir.path());
 
-            let mut francesca = spawner.client().await;
-            francesca.join("Francesca").await;
+        let mut missing = opts.clone();
+        missing.tls.as_mut().unwrap().certificate = dir.path().join("missing.pem");
+        assert!(run_server(missing).await.is_err());
 
-            let count = 10000;
+        // A key is not a certificate
+        let mut swapped = opts.clone();
+        let tls = swapped.tls.as_mut().unwrap();
+        tls.certificate = tls.key.clone();
+        assert!(run_server(swapped).await.is_err());
 
-            // Let's say that someone is spamming you...
-            let t1 = async move {
-                for _ in 0..count {
-                    diana
-                        .dm("Francesca", "Can I borrow your brush? Pleeeeeease :(((")
-                        .await;
-                }
-            };
+        assert!(run_server(opts).await.is_ok());
+    }
 
-            // ...so you get angry, and start spamming them back.
-            // But you make a critical *error*, because you're sending the message
-            // to the wrong account.
-            // Can your chat server handle that?
-            let t2 = async move {
-                for _ in 0..count {
-                    francesca.dm("Daina", "NO! Get your own!").await;
-                    match francesca.recv().await {
-                        ServerToClientMsg::Message { from, message } => {
-                            assert_eq!(from, "Diana");
-                            assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
-                        }
-                        ServerToClientMsg::Error(error) => {
-                            assert_eq!(error, "User Daina does not exist");
-                        }
-                        msg => panic!("Unexpected message {msg:?}"),
-                    }
-                }
-                // Francesca should receive count * 2 messages, `count` from Diana and `count`
-                // error messages
-                for _ in 0..count {
-                    match francesca.recv().await {
-                        ServerToClientMsg::Message { from, message } => {
-                            assert_eq!(from, "Diana");
-                            assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
-                        }
-                        ServerToClientMsg::Error(error) => {
-                            assert_eq!(error, "User Daina does not exist");
-                        }
-                        msg => panic!("Unexpected message {msg:?}"),
-                    }
-                }
-            };
+    #[tokio::test]
+    async fn unix_socket_chat() {
+        let dir = tempfile::tempdir().unwrap();
+        run_test(opts_with_unix_socket(2, dir.path()), |spawner| async move {
+            let mut mia = spawner.unix_client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+            mia.expect_joined("Leo").await;
+
+            leo.dm("Mia", "hi").await;
+            let id = leo.expect_accepted().await;
+            mia.expect_message("Leo", "hi").await;
+            leo.expect_delivered(id, "Mia").await;
+            mia.dm("Leo", "hello").await;
+            let id = mia.expect_accepted().await;
+            leo.expect_message("Mia", "hello").await;
+            mia.expect_delivered(id, "Leo").await;
 
-            // Wait until both processes complete
-            let t1 = tokio::task::spawn_local(t1);
-            let t2 = tokio::task::spawn_local(t2);
-            let (ret1, ret2) = tokio::join!(t1, t2);
-            Ok(ret1.and(ret2)?)
+            Ok(())
         })
         .await;
     }
 
     #[tokio::test]
-    async fn dm_spam_2() {
-        // Meanwhile, in a parallel universe...
-        run_test(opts(2), |spawner| async move {
-            let mut diana = spawner.client().await;
-            diana.join("Diana").await;
+    async fn unix_socket_shares_max_clients() {
+        let dir = tempfile::tempdir().unwrap();
+        run_test(opts_with_unix_socket(2, dir.path()), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.unix_client().await;
+            leo.join("Leo").await;
+
+            spawner
+                .unix_client()
+                .await
+                .expect_error("Server is full")
+                .await;
+            spawner.client().await.expect_error("Server is full").await;
 
-            let mut francesca = spawner.client().await;
-            francesca.join("Francesca").await;
+            mia.close().await;
+            sleep(100).await;
+            let mut kim = spawner.unix_client().await;
+            kim.join("Kim").await;
 
-            let count = 10000;
+            Ok(())
+        })
+        .await;
+    }
 
-            // Let's say that someone is spamming you...
-            let t1 = async move {
-                for _ in 0..count {
-                    diana
-                        .dm("Francesca", "Can I borrow your brush? Pleeeeeease :(((")
-                        .await;
-                }
-            };
+    #[tokio::test]
+    async fn unix_socket_with_tls() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = ServerOpts {
+            listen: opts_with_unix_socket(1, dir.path()).listen,
+            ..opts_with_tls(1, dir.path())
+        };
+        run_test(opts, |spawner| async move {
+            let mut mia = spawner.unix_client().await;
+            mia.join("Mia").await;
+            mia.ping().await;
 
-            // ...so you get angry, and start spamming them back.
-            // But you make a critical *error*, because you push the wrong button and start
-            // sending pings to the server instead.
-            // Can your chat server handle that?
-            let t2 = async move {
-                for _ in 0..count {
-                    francesca.send(ClientToServerMsg::Ping).await;
-                    match francesca.recv().await {
-                        ServerToClientMsg::Message { from, message } => {
-                            assert_eq!(from, "Diana");
-                            assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
-                        }
-                        ServerToClientMsg::Pong => {}
-                        msg => panic!("Unexpected message {msg:?}"),
-                    }
-                }
-                // Francesca should receive count * 2 messages, `count` from Diana and `count`
-                // pong messages
-                for _ in 0..count {
-                    match francesca.recv().await {
-                        ServerToClientMsg::Message { from, message } => {
-                            assert_eq!(from, "Diana");
-                            assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
-                        }
-                        ServerToClientMsg::Pong => {}
-                        msg => panic!("Unexpected message {msg:?}"),
-                    }
-                }
-            };
+            Ok(())
+        })
+        .await;
+    }
 
-            let t1 = tokio::task::spawn_local(t1);
-            let t2 = tokio::task::spawn_local(t2);
-            let (ret1, ret2) = tokio::join!(t1, t2);
-            Ok(ret1.and(ret2)?)
+    #[tokio::test]
+    async fn unix_socket_is_removed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("chat.sock");
+        run_test(opts_with_unix_socket(1, dir.path()), |spawner| async move {
+            assert!(path.exists());
+            spawner.unix_client().await.join("Mia").await;
+            Ok(())
         })
         .await;
+        assert!(!dir.path().join("chat.sock").exists());
     }
 
     #[tokio::test]
-    async fn broadcast_empty() {
-        run_test(opts(2), |spawner| async move {
-            let mut ji = spawner.client().await;
-            ji.join("Ji").await;
-            ji.send(ClientToServerMsg::Broadcast {
-                message: "Haaaaaai!".to_string(),
+    async fn websocket_chat() {
+        run_test(opts_with_websocket(2), |spawner| async move {
+            let mut mia = spawner.websocket_client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+            mia.expect_joined("Leo").await;
+
+            leo.dm("Mia", "hi").await;
+            let id = leo.expect_accepted().await;
+            mia.expect_message("Leo", "hi").await;
+            leo.expect_delivered(id, "Mia").await;
+
+            mia.send(ClientToServerMsg::Broadcast {
+                message: "hello everyone".to_string(),
             })
             .await;
-            ji.ping().await;
+            mia.expect_accepted().await;
+            leo.expect_message("Mia", "hello everyone").await;
 
             Ok(())
         })
@@ -588,143 +1228,617 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn broadcast() {
-        run_test(opts(10), |spawner| async move {
-            let mut niko = spawner.client().await;
-            niko.join("Niko").await;
-
-            let users: Vec<_> = (0..5)
-                .map(|i| async move {
-                    let mut client = spawner.client().await;
-                    client.join(&format!("NPC {i}")).await;
-                    client
-                })
-                .collect();
-            let users: Vec<Client> = futures_util::future::join_all(users).await;
+    async fn websocket_frame_per_message() {
+        run_test(opts_with_websocket(1), |spawner| async move {
+            let mut socket = spawner.raw_websocket().await;
+
+            // Pretty-printed JSON contains newlines
+            let join = serde_json::to_string_pretty(&ClientToServerMsg::Join {
+                name: "Mia".to_string(),
+            })?;
+            socket.send(Message::text(join)).await?;
+            let ping = serde_json::to_string(&ClientToServerMsg::Ping)?;
+            socket.send(Message::text(ping)).await?;
+
+            let mut received = vec![];
+            while received.len() < 2 {
+                match socket.next().await.expect("connection was closed")? {
+                    Message::Text(text) => {
+                        received.push(serde_json::from_str::<ServerToClientMsg>(&text)?);
+                    }
+                    Message::Ping(_) | Message::Pong(_) => {}
+                    msg => panic!("Unexpected frame {msg:?}"),
+                }
+            }
+            assert!(matches!(received[0], ServerToClientMsg::Welcome { .. }));
+            assert!(matches!(received[1], ServerToClientMsg::Pong));
 
-            niko.send(ClientToServerMsg::Broadcast {
-                message: "Borrow this!".to_string(),
-            })
-            .await;
-            niko.ping().await;
+            Ok(())
+        })
+        .await;
+    }
 
-            for mut user in users {
-                user.expect_message("Niko", "Borrow this!").await;
+    #[tokio::test]
+    async fn websocket_rejects_binary_frames() {
+        run_test(opts_with_websocket(1), |spawner| async move {
+            let mut socket = spawner.raw_websocket().await;
+            let join = serde_json::to_vec(&ClientToServerMsg::Join {
+                name: "Mia".to_string(),
+            })?;
+            socket.send(Message::binary(join)).await?;
+            while let Some(Ok(msg)) = socket.next().await {
+                assert!(!matches!(msg, Message::Text(_)), "Unexpected frame {msg:?}");
             }
 
+            // The slot of the client was freed
+            let mut mia = spawner.websocket_client().await;
+            mia.join("Mia").await;
+
             Ok(())
         })
         .await;
     }
 
     #[tokio::test]
-    async fn message_timeout() {
-        run_test(opts(2), |spawner| async move {
-            let mut niko = spawner.client().await;
-            niko.join("Niko").await;
+    async fn websocket_rejects_large_frames() {
+        let opts = ServerOpts {
+            max_message_size: 1000,
+            ..opts_with_websocket(2)
+        };
+        run_test(opts, |spawner| async move {
+            let mut mia = spawner.websocket_client().await;
+            mia.join("Mia").await;
+
+            mia.dm("Leo", &"x".repeat(2000)).await;
+            mia.expect_error("Message too large").await;
+            // The rest of the frame is not read, so the connection cannot go on
+            mia.check_closed().await;
 
-            sleep(1000).await;
-            niko.ping().await;
-            sleep(1000).await;
-            niko.list_users().await;
-            sleep(4000).await;
+            Ok(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn websocket_shares_max_clients() {
+        run_test(opts_with_websocket(1), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
 
-            niko.expect_error("Timeouted").await;
-            niko.check_closed().await;
+            let mut leo = spawner.websocket_client().await;
+            leo.expect_error("Server is full").await;
 
             Ok(())
         })
         .await;
     }
 
-    // This test runs for ~10s
     #[tokio::test]
-    async fn message_timeout_receiving_dms() {
-        run_test(opts(10), |spawner| async move {
-            // Do not timeout user if he is receiving DMs
-            let mut niko = spawner.client().await;
-            niko.join("Niko").await;
+    async fn websocket_with_tls() {
+        let dir = tempfile::tempdir().unwrap();
+        let opts = ServerOpts {
+            listen: opts_with_websocket(2).listen,
+            ..opts_with_tls(2, dir.path())
+        };
+        run_test(opts, |spawner| async move {
+            let mut mia = spawner.websocket_client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+
+            leo.dm("Mia", "Nobody can read this").await;
+            leo.expect_accepted().await;
+            mia.expect_message("Leo", "Nobody can read this").await;
 
-            let mut kobzol = spawner.client().await;
-            kobzol.join("Kobzol").await;
+            Ok(())
+        })
+        .await;
+    }
 
-            sleep(1500).await;
-            kobzol.dm("Niko", "Hi there!").await;
-            niko.recv().await;
-            sleep(1500).await;
-            kobzol.dm("Niko", "So, what you're up to?").await;
-            niko.recv().await;
-            sleep(1500).await;
-            kobzol.dm("Niko", "See you at RustWeek?").await;
-            niko.recv().await;
-            sleep(1500).await;
-            kobzol
-                .send(ClientToServerMsg::Broadcast {
-                    message: "Rust is really cool, you know".to_string(),
-                })
-                .await;
-            niko.recv().await;
-            sleep(2000).await;
-            kobzol
-                .send(ClientToServerMsg::Broadcast {
-                    message: "...anyone here?".to_string(),
-                })
+    #[tokio::test]
+    async fn stats_count_messages() {
+        run_test(opts(2), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+
+            mia.ping().await;
+            mia.dm("Leo", "hi").await;
+            let id = mia.expect_accepted().await;
+            leo.expect_message("Mia", "hi").await;
+            mia.expect_delivered(id, "Leo").await;
+            for to in ["Eva", "Zoe"] {
+                mia.dm(to, "hi").await;
+                mia.expect_error(&format!("User {to} does not exist")).await;
+            }
+            leo.ack(id + 100).await;
+            leo.expect_error(&format!("Unknown message {}", id + 100))
                 .await;
-            niko.recv().await;
-            sleep(2000).await;
 
-            niko.ping().await;
+            let stats = spawner.stats();
+            assert_eq!(stats.messages_received["Join"], 2);
+            assert_eq!(stats.messages_received["Ping"], 1);
+            assert_eq!(stats.messages_received["SendDM"], 3);
+            assert_eq!(stats.messages_received["Ack"], 1);
+            assert_eq!(stats.messages_sent["Welcome"], 2);
+            assert_eq!(stats.messages_sent["Pong"], 1);
+            assert_eq!(stats.messages_sent["Message"], 1);
+            assert_eq!(stats.messages_sent["Delivered"], 1);
+            assert_eq!(stats.errors["User <name> does not exist"], 2);
+            assert_eq!(stats.errors["Unknown message <id>"], 1);
+            assert!(stats.bytes_received > 0);
+            assert!(stats.bytes_sent > 0);
+            assert!(stats.busy_time > Duration::ZERO);
 
             Ok(())
         })
         .await;
     }
 
-    // TODO(bonus): The server should correctly close client sockets when it shuts down,
-    // to avoid a situation where the clients would be stuck waiting for a message
-    // for some indeterminate amount of time.
-    /*
     #[tokio::test]
-    async fn drop_clients_on_shutdown() {
-        let (mut client, mut client2) = run_test(opts(10), |spawner| async move {
-            let mut client = spawner.client().await;
-            client.join("Bar").await;
-            let mut client2 = spawner.client().await;
-            client2.join("Foo").await;
-            Ok((client, client2))
+    async fn stats_gauges() {
+        run_test(opts(3), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+            let _client = spawner.client().await;
+            sleep(100).await;
+
+            let stats = spawner.stats();
+            assert_eq!(stats.connected_clients, 3);
+            assert_eq!(stats.joined_users, 2);
+
+            leo.close().await;
+            sleep(100).await;
+            let stats = spawner.stats();
+            assert_eq!(stats.connected_clients, 2);
+            assert_eq!(stats.joined_users, 1);
+
+            Ok(())
         })
         .await;
-
-        assert!(client.reader.recv().await.is_none());
-        assert!(client2.reader.recv().await.is_none());
     }
-    */
-    async fn run_test<C, F, R>(opts: ServerOpts, func: C) -> R
-    where
+
+    #[tokio::test]
+    async fn metrics_endpoint() {
+        let opts = ServerOpts {
+            metrics: Some(SocketAddr::from(([127, 0, 0, 1], 0))),
+            ..opts(2)
+        };
+        run_test(opts, |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            mia.ping().await;
+
+            let address = spawner.metrics.expect("metrics are disabled");
+            let response = http_get(address, "/metrics").await;
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"));
+            assert!(response.contains("\nchat_joined_users 1\n"));
+            assert!(response.contains("\nchat_messages_received_total{type=\"Ping\"} 1\n"));
+            assert!(response.contains("\n# TYPE chat_busy_seconds_total counter\n"));
+
+            assert!(http_get(address, "/")
+                .await
+                .starts_with("HTTP/1.1 404 Not Found\r\n"));
+
+            Ok(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn federation_dm_and_list_users() {
+        run_federation_test(&["office1", "office2"], |spawners| async move {
+            let mut mia = spawners[0].client().await;
+            mia.join("Mia").await;
+            let mut leo = spawners[1].client().await;
+            leo.join("Leo").await;
+
+            mia.wait_for_user("Leo@office2").await;
+            leo.wait_for_user("Mia@office1").await;
+            let mut users = mia.list_users().await;
+            users.sort();
+            assert_eq!(users, vec!["Leo@office2", "Mia"]);
+
+            mia.dm("Leo@office2", "Hi from the other office").await;
+            mia.expect_accepted().await;
+            leo.expect_message("Mia@office1", "Hi from the other office")
+                .await;
+            // Users of the same server can qualify each other with its name
+            leo.dm("Leo@office2", "Note to self").await;
+            leo.expect_error("Cannot send a DM to yourself").await;
+
+            mia.dm("Eva@office2", "Hi").await;
+            mia.expect_error("User Eva@office2 does not exist").await;
+            mia.dm("Leo@office3", "Hi").await;
+            mia.expect_error("User Leo@office3 does not exist").await;
+
+            Ok(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn federation_users_leave() {
+        run_federation_test(&["office1", "office2"], |spawners| async move {
+            let mut mia = spawners[0].client().await;
+            mia.join("Mia").await;
+            let mut leo = spawners[1].client().await;
+            leo.join("Leo").await;
+            mia.wait_for_user("Leo@office2").await;
+
+            leo.close().await;
+            for _ in 0..50 {
+                if mia.list_users().await == vec!["Mia"] {
+                    break;
+                }
+                sleep(100).await;
+            }
+            assert_eq!(mia.list_users().await, vec!["Mia"]);
+            mia.dm("Leo@office2", "Hi").await;
+            mia.expect_error("User Leo@office2 does not exist").await;
+
+            Ok(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn dm_name_with_at_without_federation() {
+        run_test(opts(2), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia@home").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+            mia.expect_joined("Leo").await;
+
+            leo.dm("Mia@home", "Hi").await;
+            let id = leo.expect_accepted().await;
+            assert_eq!(mia.expect_message("Leo", "Hi").await, id);
+            leo.expect_delivered(id, "Mia@home").await;
+
+            Ok(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn federation_join_rejects_qualified_name() {
+        run_federation_test(&["office1"], |spawners| async move {
+            let mut client = spawners[0].client().await;
+            client
+                .send(ClientToServerMsg::Join {
+                    name: "Mia@office2".to_string(),
+                })
+                .await;
+            client.expect_error("Username cannot contain @").await;
+            client.check_closed().await;
+
+            Ok(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn federation_broadcast_reaches_every_server_once() {
+        run_federation_test(&["office1", "office2", "office3"], |spawners| async move {
+            let mut clients = vec![];
+            for (spawner, name) in spawners.iter().zip(["Mia", "Leo", "Eva"]) {
+                let mut client = spawner.client().await;
+                client.join(name).await;
+                clients.push(client);
+            }
+            clients[0].wait_for_user("Leo@office2").await;
+            clients[0].wait_for_user("Eva@office3").await;
+            clients[1].wait_for_user("Eva@office3").await;
+
+            clients[0]
+                .send(ClientToServerMsg::Broadcast {
+                    message: "Hello everyone".to_string(),
+                })
+                .await;
+            clients[0].expect_accepted().await;
+            for client in &mut clients[1..] {
+                client.expect_message("Mia@office1", "Hello everyone").await;
+            }
+            sleep(200).await;
+            for client in &mut clients {
+                client.expect_only_pong().await;
+            }
+
+            Ok(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn plugin_virtual_user() {
+        run_test(opts_with_plugins(3), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            mia.expect_message("echo", "Welcome Mia!").await;
+            let mut users = mia.list_users().await;
+            users.sort();
+            assert_eq!(users, vec!["Mia".to_string(), "echo".to_string()]);
+
+            mia.dm("echo", "Hello?").await;
+            mia.expect_accepted().await;
+            mia.expect_message("echo", "Hello?").await;
+
+            let mut client = spawner.client().await;
+            client
+                .send(ClientToServerMsg::Join {
+                    name: "echo".to_string(),
+                })
+                .await;
+            client.expect_error("Username already taken").await;
+
+            Ok(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn plugin_rewrites_and_drops_messages() {
+        run_test(opts_with_plugins(3), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            mia.expect_message("echo", "Welcome Mia!").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+            leo.expect_message("echo", "Welcome Leo!").await;
+
+            mia.dm("Leo", "Oh darn").await;
+            let id = mia.expect_accepted().await;
+            leo.expect_message("Mia", "Oh ****").await;
+            mia.expect_delivered(id, "Leo").await;
+
+            mia.send(ClientToServerMsg::Broadcast {
+                message: "Cheap spam here".to_string(),
+            })
+            .await;
+            mia.expect_error("Spam is not allowed").await;
+            mia.dm("Leo", "More spam").await;
+            mia.expect_error("Spam is not allowed").await;
+
+            // The echo bot sees the message rewritten by the filter, which was registered first
+            mia.dm("echo", "darn").await;
+            mia.expect_accepted().await;
+            mia.expect_message("echo", "****").await;
+
+            // Nothing else reached Leo
+            leo.expect_only_pong().await;
+
+            Ok(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn plugin_answers_broadcast() {
+        run_test(opts_with_plugins(3), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            mia.expect_message("echo", "Welcome Mia!").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+            leo.expect_message("echo", "Welcome Leo!").await;
+
+            mia.send(ClientToServerMsg::Broadcast {
+                message: "/weather".to_string(),
+            })
+            .await;
+            mia.expect_accepted().await;
+            mia.expect_message("echo", "It is sunny").await;
+            leo.expect_message("Mia", "/weather").await;
+            leo.expect_message("echo", "It is sunny").await;
+
+            Ok(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn plugin_answers_dropped_dm() {
+        let opts = ServerOpts {
+            plugins: vec![Arc::new(|| Box::new(Moderator))],
+            ..opts(3)
+        };
+        run_test(opts, |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+
+            mia.dm("Leo", "My password is hunter2").await;
+            mia.expect_error("Passwords are not allowed").await;
+            mia.expect_message("moderator", "Do not share your password")
+                .await;
+
+            // Nothing reached Leo
+            leo.expect_only_pong().await;
+
+            Ok(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn plugin_sees_leaving_users() {
+        run_test(opts_with_plugins(3), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            mia.expect_message("echo", "Welcome Mia!").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+            leo.expect_message("echo", "Welcome Leo!").await;
+
+            leo.close().await;
+            mia.expect_message("echo", "Bye Leo").await;
+
+            Ok(())
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn record_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let trace = dir.path().join("trace.jsonl");
+        let opts = ServerOpts {
+            join_timeout: Duration::from_millis(300),
+            idle_timeout: Duration::from_millis(500),
+            resume_grace: Duration::from_millis(300),
+            max_message_size: 100,
+            record: Some(trace.clone()),
+            ..opts(3)
+        };
+        run_test(opts.clone(), |spawner| async move {
+            let mut mia = spawner.client().await;
+            let token = mia.join("Mia").await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+
+            mia.dm("Leo", "Hi").await;
+            let id = mia.expect_accepted().await;
+            leo.expect_message("Mia", "Hi").await;
+            mia.expect_delivered(id, "Leo").await;
+            leo.dm("Mia", &"x".repeat(200)).await;
+            leo.expect_error("Message too large").await;
+            assert_eq!(leo.list_users().await.len(), 2);
+
+            mia.lose_connection().await;
+            sleep(100).await;
+            let mut mia = spawner.client().await;
+            mia.resume(&token).await;
+            mia.ping().await;
+            leo.close().await;
+
+            let mut idle = spawner.client().await;
+            idle.expect_error("Timed out waiting for Join").await;
+            mia.expect_error("Timeouted").await;
+            sleep(400).await;
+
+            Ok(())
+        })
+        .await;
+
+        let records = read_trace(&trace).unwrap();
+        for expected in [Timer::Join, Timer::Idle, Timer::ResumeGrace] {
+            assert!(records.iter().any(
+                |record| matches!(record.event, Event::Timer { timer, .. } if timer == expected)
+            ));
+        }
+        assert_eq!(records.last().unwrap().event, Event::Stopped);
+
+        // A trace cut short in the middle of a record is still readable
+        let mut data = std::fs::read(&trace).unwrap();
+        data.extend_from_slice(b"{\"at\":12");
+        std::fs::write(&trace, data).unwrap();
+        assert_eq!(read_trace(&trace).unwrap(), records);
+
+        let divergences = LocalSet::new()
+            .run_until(replay(&records, opts))
+            .await
+            .unwrap();
+        assert!(divergences.is_empty(), "{divergences:?}");
+    }
+
+    #[tokio::test]
+    async fn replay_reports_divergences() {
+        let dir = tempfile::tempdir().unwrap();
+        let trace = dir.path().join("trace.jsonl");
+        let opts = ServerOpts {
+            record: Some(trace.clone()),
+            ..opts(2)
+        };
+        run_test(opts.clone(), |spawner| async move {
+            let mut mia = spawner.client().await;
+            mia.join("Mia").await;
+            mia.ping().await;
+            let mut leo = spawner.client().await;
+            leo.join("Leo").await;
+            leo.ping().await;
+
+            Ok(())
+        })
+        .await;
+
+        // Mia asks for the user list instead of sending Ping
+        let mut records = read_trace(&trace).unwrap();
+        let ping = records
+            .iter_mut()
+            .find(|record| {
+                matches!(
+                    record.event,
+                    Event::Received {
+                        msg: ClientToServerMsg::Ping,
+                        ..
+                    }
+                )
+            })
+            .unwrap();
+        ping.event = Event::Received {
+            client: 0,
+            msg: ClientToServerMsg::ListUsers,
+        };
+
+        let divergences = LocalSet::new()
+            .run_until(replay(&records, opts))
+            .await
+            .unwrap();
+        assert_eq!(divergences.len(), 1);
+        assert_eq!(divergences[0].client, 0);
+        assert!(matches!(
+            divergences[0].expected,
+            Some(Record {
+                event: Event::Sent {
+                    msg: ServerToClientMsg::Pong,
+                    ..
+                },
+                ..
+            })
+        ));
+        assert!(matches!(
+            divergences[0].actual,
+            Some(Record {
+                event: Event::Sent {
+                    msg: ServerToClientMsg::UserList { .. },
+                    ..
+                },
+                ..
+            })
+        ));
+    }
+
+    async fn http_get(address: SocketAddr, path: &str) -> String {
+        let mut stream = TcpStream::connect(address)
+            .await
+            .expect("cannot connect to metrics");
+        let request = format!("GET {path} HTTP/1.1\r\nHost: localhost\r\n\r\n");
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    async fn run_test<C, F, R>(opts: ServerOpts, func: C) -> R
+    where
         C: FnOnce(ClientSpawner) -> F,
         F: Future<Output = anyhow::Result<R>>,
     {
         let localset = LocalSet::new();
         let (port, ret) = localset
             .run_until(async {
-                // Start the server
-                let server = run_server(opts).await.expect("creating server failed");
-                let port = server.port;
-
-                let spawner = ClientSpawner { port };
-
-                // Spawn the server future
-                let server_fut = tokio::task::spawn_local(server.future);
+                let (spawner, server) = start_server(opts).await;
+                let port = spawner.port;
 
                 // Run the test
                 let ret = func(spawner).await.expect("test failed");
 
-                // Tell the server to shut down
-                server.tx.send(()).unwrap();
-
-                // Wait until it shuts down
-                server_fut.await.unwrap().unwrap();
-
+                server.stop().await;
                 (port, ret)
             })
             .await;
@@ -735,19 +1849,127 @@ mod tests {
         ret
     }
 
+    /// Runs a federation of servers with the given names, where every server links to all the
+    /// servers before it. The spawners are in the order of `names`.
+    async fn run_federation_test<C, F, R>(names: &[&str], func: C) -> R
+    where
+        C: FnOnce(Vec<ClientSpawner>) -> F,
+        F: Future<Output = anyhow::Result<R>>,
+    {
+        let localset = LocalSet::new();
+        localset
+            .run_until(async {
+                let mut spawners = vec![];
+                let mut servers = vec![];
+                let mut peers = vec![];
+                for name in names {
+                    let opts = ServerOpts {
+                        federation: Some(FederationConfig {
+                            name: name.to_string(),
+                            listen: Some(SocketAddr::from(([127, 0, 0, 1], 0))),
+                            peers: peers.clone(),
+                        }),
+                        ..opts(10)
+                    };
+                    let (spawner, server) = start_server(opts).await;
+                    peers.push(server.federation_address.expect("federation is disabled"));
+                    spawners.push(spawner);
+                    servers.push(server);
+                }
+
+                let ret = func(spawners).await.expect("test failed");
+
+                for server in servers {
+                    server.stop().await;
+                }
+                ret
+            })
+            .await
+    }
+
+    /// A server whose future was spawned, see [`start_server`].
+    struct SpawnedServer {
+        tx: oneshot::Sender<()>,
+        future: tokio::task::JoinHandle<anyhow::Result<()>>,
+        federation_address: Option<SocketAddr>,
+    }
+
+    impl SpawnedServer {
+        /// Tells the server to shut down and waits until it does.
+        async fn stop(self) {
+            self.tx.send(()).unwrap();
+            self.future.await.unwrap().unwrap();
+        }
+    }
+
+    /// Starts the server and spawns its future, it has to run inside a [`LocalSet`].
+    async fn start_server(opts: ServerOpts) -> (ClientSpawner, SpawnedServer) {
+        let tls = opts.tls.as_ref().map(|tls| tls_connector(&tls.certificate));
+        let server = run_server(opts).await.expect("creating server failed");
+        let unix = server.addresses.iter().find_map(|address| match address {
+            ListenAddr::Unix(path) => Some(path.clone()),
+            _ => None,
+        });
+        let websocket = server.addresses.iter().find_map(|address| match address {
+            ListenAddr::WebSocket(address) => Some(address.port()),
+            _ => None,
+        });
+
+        let spawner = ClientSpawner {
+            port: server.port(),
+            unix,
+            websocket,
+            tls,
+            metrics: server.metrics_address(),
+            state: server.state.clone(),
+        };
+        let federation_address = server.federation_address();
+        let server = SpawnedServer {
+            tx: server.tx,
+            future: tokio::task::spawn_local(server.future),
+            federation_address,
+        };
+        (spawner, server)
+    }
+
     struct Client {
-        writer: MessageWriter<ClientToServerMsg, OwnedWriteHalf>,
-        reader: MessageReader<ServerToClientMsg, OwnedReadHalf>,
+        writer: MessageWriter<ClientToServerMsg, WriteHalf<Box<dyn Stream>>>,
+        reader: MessageReader<ServerToClientMsg, ReadHalf<Box<dyn Stream>>>,
     }
 
     impl Client {
-        async fn join(&mut self, name: &str) {
+        /// Returns the resume token.
+        async fn join(&mut self, name: &str) -> String {
             self.send(ClientToServerMsg::Join {
                 name: name.to_string(),
             })
             .await;
-            let msg = self.recv().await;
-            assert!(matches!(msg, ServerToClientMsg::Welcome));
+            self.expect_welcome().await
+        }
+
+        /// Returns the new resume token.
+        async fn resume(&mut self, token: &str) -> String {
+            self.send(ClientToServerMsg::Resume {
+                token: token.to_string(),
+            })
+            .await;
+            self.expect_welcome().await
+        }
+
+        async fn resume_expect_error(mut self, token: &str) {
+            self.send(ClientToServerMsg::Resume {
+                token: token.to_string(),
+            })
+            .await;
+            self.expect_error("Invalid resume token").await;
+            self.check_closed().await;
+        }
+
+        async fn expect_welcome(&mut self) -> String {
+            match self.recv().await {
+                ServerToClientMsg::Welcome { token, .. } => token,
+                msg => panic!("Unexpected message {msg:?}"),
+            }
         }
 
         async fn ping(&mut self) {
@@ -756,20 +1978,6 @@ mod tests {
             assert!(matches!(msg, ServerToClientMsg::Pong));
         }
 
-        async fn list_users(&mut self) -> Vec<String> {
-            self.send(ClientToServerMsg::ListUsers).await;
-            let msg = self.recv().await;
-            match msg {
-                ServerToClientMsg::UserList { mut users } => {
-                    users.sort();
-                    users
-                }
-                msg => {
-                    panic!("Unexpected response {msg:?}");
-                }
-            }
-        }
-
         async fn dm(&mut self, to: &str, message: &str) {
             self.send(ClientToServerMsg::SendDM {
                 to: to.to_string(),
@@ -778,23 +1986,53 @@ mod tests {
             .await;
         }
 
-        async fn expect_message(&mut self, expected_from: &str, expected_message: &str) {
+        async fn ack(&mut self, id: u64) {
+            self.send(ClientToServerMsg::Ack { id }).await;
+        }
+
+        /// Returns the ID of the message.
+        async fn expect_message(&mut self, expected_from: &str, expected_message: &str) -> u64 {
             let msg = self.recv().await;
             match msg {
-                ServerToClientMsg::Message { from, message } => {
+                ServerToClientMsg::Message { id, from, message } => {
                     assert_eq!(from, expected_from);
                     assert_eq!(message, expected_message);
+                    id
                 }
                 msg => panic!("Unexpected message {msg:?}"),
             }
         }
 
-        async fn send(&mut self, msg: ClientToServerMsg) {
-            self.writer.send(msg).await.expect("cannot send message");
+        /// Returns the ID of the accepted message.
+        async fn expect_accepted(&mut self) -> u64 {
+            match self.recv().await {
+                ServerToClientMsg::Accepted { id } => id,
+                msg => panic!("Unexpected message {msg:?}"),
+            }
         }
 
-        async fn try_send(&mut self, msg: ClientToServerMsg) -> anyhow::Result<()> {
-            self.writer.send(msg).await
+        async fn expect_delivered(&mut self, expected_id: u64, expected_to: &str) {
+            match self.recv().await {
+                ServerToClientMsg::Delivered { id, to } => {
+                    assert_eq!(id, expected_id);
+                    assert_eq!(to, expected_to);
+                }
+                msg => panic!("Unexpected message {msg:?}"),
+            }
+        }
+
+        async fn expect_read(&mut self, expected_id: u64, expected_by: &str) {
+            match self.recv().await {
+                ServerToClientMsg::Read { id, by } => {
+                    assert_eq!(id, expected_id);
+                    assert_eq!(by, expected_by);
+                }
+                msg => panic!("Unexpected message {msg:?}"),
+            }
+        }
+
+        async fn send(&mut self, msg: ClientToServerMsg) {
+            self.writer.send(msg).await.expect("cannot send message");
         }
 
         async fn expect_error(&mut self, expected_error: &str) {
@@ -809,7 +2047,17 @@ mod tests {
             }
         }
 
+        /// Receives the next message, skipping presence notifications.
         async fn recv(&mut self) -> ServerToClientMsg {
+            loop {
+                match self.recv_any().await {
+                    ServerToClientMsg::UserJoined { .. } | ServerToClientMsg::UserLeft { .. } => {}
+                    msg => return msg,
+                }
+            }
+        }
+
+        async fn recv_any(&mut self) -> ServerToClientMsg {
             self.reader
                 .recv()
                 .await
@@ -817,27 +2065,144 @@ mod tests {
                 .expect("did not receive welcome message")
         }
 
-        async fn close(self) {
-            self.writer.into_inner().shutdown().await.unwrap();
+        async fn expect_joined(&mut self, expected_name: &str) {
+            match self.recv_any().await {
+                ServerToClientMsg::UserJoined { name } => assert_eq!(name, expected_name),
+                msg => panic!("Unexpected message {msg:?}"),
+            }
+        }
+
+        async fn expect_left(&mut self, expected_name: &str, expected_reason: LeaveReason) {
+            match self.recv_any().await {
+                ServerToClientMsg::UserLeft { name, reason } => {
+                    assert_eq!(name, expected_name);
+                    assert_eq!(reason, expected_reason);
+                }
+                msg => panic!("Unexpected message {msg:?}"),
+            }
+        }
+
+        async fn list_users(&mut self) -> Vec<String> {
+            self.send(ClientToServerMsg::ListUsers).await;
+            match self.recv().await {
+                ServerToClientMsg::UserList { users } => users,
+                msg => panic!("Unexpected message {msg:?}"),
+            }
+        }
+
+        /// Waits until the user of a linked server shows up in the user list.
+        async fn wait_for_user(&mut self, name: &str) {
+            for _ in 0..50 {
+                if self.list_users().await.iter().any(|user| user == name) {
+                    return;
+                }
+                sleep(100).await;
+            }
+            panic!("User {name} did not show up");
+        }
+
+        /// Checks that the next message is [`ServerToClientMsg::Pong`], i.e. that nothing else
+        /// was sent to the client before it.
+        async fn expect_only_pong(&mut self) {
+            self.send(ClientToServerMsg::Ping).await;
+            let msg = self.recv_any().await;
+            assert!(matches!(msg, ServerToClientMsg::Pong), "{msg:?}");
         }
 
         async fn check_closed(mut self) {
             assert!(matches!(self.reader.recv().await, None | Some(Err(_))));
         }
+
+        async fn close(self) {
+            self.writer.into_inner().shutdown().await.unwrap();
+        }
+
+        /// Drops the connection without closing it properly, as if the network went down.
+        /// The socket is closed while it still has unread data, which resets the connection.
+        async fn lose_connection(mut self) {
+            self.send(ClientToServerMsg::Ping).await;
+            sleep(100).await;
+            // Dropping the write half on its own would close the connection properly
+            let stream = self.reader.into_inner().unsplit(self.writer.into_inner());
+            drop(stream);
+        }
     }
 
-    #[derive(Copy, Clone)]
+    #[derive(Clone)]
     struct ClientSpawner {
         port: u16,
+        /// Path of the first Unix socket listener of the server, if there is one.
+        unix: Option<PathBuf>,
+        /// Port of the first WebSocket listener of the server, if there is one.
+        websocket: Option<u16>,
+        /// Connects to the server with TLS if it is set.
+        tls: Option<TlsConnector>,
+        /// Address of the metrics endpoint, if it is enabled.
+        metrics: Option<SocketAddr>,
+        state: SharedState,
     }
 
     impl ClientSpawner {
+        /// Same as [`RunningServer::stats`](crate::RunningServer::stats), which cannot be called
+        /// once the future of the server is spawned.
+        fn stats(&self) -> Stats {
+            self.state.borrow().stats()
+        }
+
         async fn client(&self) -> Client {
             let client = TcpStream::connect(("127.0.0.1", self.port))
                 .await
                 .expect("cannot connect to server");
+            self.connect(client).await
+        }
+
+        async fn unix_client(&self) -> Client {
+            let path = self
+                .unix
+                .as_ref()
+                .expect("server does not listen on a Unix socket");
+            let client = UnixStream::connect(path)
+                .await
+                .expect("cannot connect to server");
+            self.connect(client).await
+        }
 
-            let (rx, tx) = client.into_split();
+        async fn websocket_client(&self) -> Client {
+            let socket = self.raw_websocket().await;
+            Self::wrap(Box::new(WebSocketLines::new(socket)))
+        }
+
+        /// Connects a WebSocket client that sends and receives individual frames.
+        async fn raw_websocket(&self) -> WebSocketStream<Box<dyn Stream>> {
+            let port = self.websocket.expect("server does not listen on WebSocket");
+            let client = TcpStream::connect(("127.0.0.1", port))
+                .await
+                .expect("cannot connect to server");
+            let client = self.secure(client).await;
+            let (socket, _) = tokio_tungstenite::client_async("ws://localhost/", client)
+                .await
+                .expect("WebSocket handshake failed");
+            socket
+        }
+
+        async fn connect<S: Stream + 'static>(&self, client: S) -> Client {
+            Self::wrap(self.secure(client).await)
+        }
+
+        async fn secure<S: Stream + 'static>(&self, client: S) -> Box<dyn Stream> {
+            match &self.tls {
+                Some(connector) => Box::new(
+                    connector
+                        .connect(ServerName::try_from("localhost").unwrap(), client)
+                        .await
+                        .expect("TLS handshake failed"),
+                ),
+                None => Box::new(client),
+            }
+        }
+
+        fn wrap(client: Box<dyn Stream>) -> Client {
+            let (rx, tx) = tokio::io::split(client);
 
             let reader = MessageReader::<ServerToClientMsg, _>::new(rx);
             let writer = MessageWriter::<ClientToServerMsg, _>::new(tx);
@@ -849,7 +2214,251 @@ mod tests {
         tokio::time::sleep(Duration::from_millis(duration_ms)).await;
     }
 
+    /// Registers [`WordFilter`] and [`EchoBot`], in this order.
+    fn opts_with_plugins(max_clients: usize) -> ServerOpts {
+        ServerOpts {
+            plugins: vec![
+                Arc::new(|| Box::new(WordFilter)),
+                Arc::new(|| Box::new(EchoBot)),
+            ],
+            // Sessions are not kept, so that users leave right away
+            resume_grace: Duration::ZERO,
+            ..opts(max_clients)
+        }
+    }
+
+    /// Hides "darn" and drops messages with "spam".
+    struct WordFilter;
+
+    impl WordFilter {
+        fn check(&self, message: &str) -> Verdict {
+            if message.contains("spam") {
+                Verdict::Drop("Spam is not allowed".to_string())
+            } else if message.contains("darn") {
+                Verdict::Rewrite(message.replace("darn", "****"))
+            } else {
+                Verdict::Pass
+            }
+        }
+    }
+
+    impl Plugin for WordFilter {
+        fn on_dm(&mut self, _from: &str, _to: &str, message: &str, _ctx: &mut Context) -> Verdict {
+            self.check(message)
+        }
+
+        fn on_broadcast(&mut self, _from: &str, message: &str, _ctx: &mut Context) -> Verdict {
+            self.check(message)
+        }
+    }
+
+    /// Virtual user `echo`, which sends DMs back, greets users and answers `/weather`.
+    struct EchoBot;
+
+    impl Plugin for EchoBot {
+        fn users(&self) -> Vec<String> {
+            vec!["echo".to_string()]
+        }
+
+        fn on_join(&mut self, name: &str, ctx: &mut Context) {
+            ctx.send_dm("echo", name, &format!("Welcome {name}!"));
+        }
+
+        fn on_leave(&mut self, name: &str, ctx: &mut Context) {
+            ctx.broadcast("echo", &format!("Bye {name}"));
+        }
+
+        fn on_dm(&mut self, from: &str, to: &str, message: &str, ctx: &mut Context) -> Verdict {
+            if to == "echo" {
+                ctx.send_dm("echo", from, message);
+            }
+            Verdict::Pass
+        }
+
+        fn on_broadcast(&mut self, _from: &str, message: &str, ctx: &mut Context) -> Verdict {
+            if message == "/weather" {
+                ctx.broadcast("echo", "It is sunny");
+            }
+            Verdict::Pass
+        }
+    }
+
+    /// Virtual user `moderator`, which drops DMs with passwords and warns their authors.
+    struct Moderator;
+
+    impl Plugin for Moderator {
+        fn users(&self) -> Vec<String> {
+            vec!["moderator".to_string()]
+        }
+
+        fn on_dm(&mut self, from: &str, _to: &str, message: &str, ctx: &mut Context) -> Verdict {
+            if message.contains("password") {
+                ctx.send_dm("moderator", from, "Do not share your password");
+                Verdict::Drop("Passwords are not allowed".to_string())
+            } else {
+                Verdict::Pass
+            }
+        }
+    }
+
     fn opts(max_clients: usize) -> ServerOpts {
-        ServerOpts { max_clients }
+        ServerOpts {
+            listen: vec![ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 0)))],
+            max_clients,
+            history: None,
+            join_timeout: Duration::from_secs(2),
+            idle_timeout: Duration::from_secs(3),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            resume_grace: Duration::from_secs(2),
+            tls: None,
+            metrics: None,
+            federation: None,
+            plugins: vec![],
+            record: None,
+        }
     }
+
+    /// Listens on TCP/IP and on a Unix socket in `dir`.
+    fn opts_with_unix_socket(max_clients: usize, dir: &Path) -> ServerOpts {
+        let mut opts = opts(max_clients);
+        opts.listen.push(ListenAddr::Unix(dir.join("chat.sock")));
+        opts
+    }
+
+    /// Listens on TCP/IP and WebSocket.
+    fn opts_with_websocket(max_clients: usize) -> ServerOpts {
+        let mut opts = opts(max_clients);
+        opts.listen
+            .push(ListenAddr::WebSocket(SocketAddr::from(([127, 0, 0, 1], 0))));
+        opts
+    }
+
+    fn opts_with_history(max_clients: usize, history: &Path) -> ServerOpts {
+        ServerOpts {
+            history: Some(history.to_path_buf()),
+            ..opts(max_clients)
+        }
+    }
+
+    /// Generates a self-signed certificate for `localhost` into `dir`.
+    fn opts_with_tls(max_clients: usize, dir: &Path) -> ServerOpts {
+        let rcgen::CertifiedKey { cert, key_pair } =
+            rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let tls = TlsConfig {
+            certificate: dir.join("certificate.pem"),
+            key: dir.join("key.pem"),
+        };
+        std::fs::write(&tls.certificate, cert.pem()).unwrap();
+        std::fs::write(&tls.key, key_pair.serialize_pem()).unwrap();
+        ServerOpts {
+            tls: Some(tls),
+            ..opts(max_clients)
+        }
+    }
+
+    /// The certificate of the server is its own certificate authority.
+    fn tls_connector(certificate: &Path) -> TlsConnector {
+        let mut roots = RootCertStore::empty();
+        for certificate in CertificateDer::pem_file_iter(certificate).unwrap() {
+            roots.add(certificate.unwrap()).unwrap();
+        }
+        let config = ClientConfig::builder()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        TlsConnector::from(Arc::new(config))
+    }
+}
+
+#[cfg(test)]
+mod conformance {
+    use crate::net::ListenAddr;
+    use crate::reader::DEFAULT_MAX_MESSAGE_SIZE;
+    use crate::{run_server, ServerOpts};
+    use chat_conformance::{Capabilities, ChatServer, Framing, ServerConfig};
+    use std::net::SocketAddr;
+    use std::thread::JoinHandle;
+    use std::time::Duration;
+    use tokio::sync::oneshot;
+    use tokio::task::LocalSet;
+
+    const JOIN_TIMEOUT: Duration = Duration::from_secs(2);
+    const IDLE_TIMEOUT: Duration = Duration::from_secs(3);
+    const RESUME_GRACE: Duration = Duration::from_secs(2);
+
+    /// Runs the server inside a [`LocalSet`] on its own thread, so that the blocking clients of
+    /// the suite can talk to it.
+    struct TestServer {
+        port: u16,
+        tx: Option<oneshot::Sender<()>>,
+        thread: Option<JoinHandle<anyhow::Result<()>>>,
+    }
+
+    impl ChatServer for TestServer {
+        const FRAMING: Framing = Framing::NewlineDelimited;
+        const CAPABILITIES: Capabilities = Capabilities {
+            join_timeout: Some(JOIN_TIMEOUT),
+            idle_timeout: Some(IDLE_TIMEOUT),
+            history: true,
+        };
+
+        fn start(config: ServerConfig) -> anyhow::Result<Self> {
+            let opts = ServerOpts {
+                listen: vec![ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 0)))],
+                max_clients: config.max_clients,
+                history: config.history,
+                join_timeout: JOIN_TIMEOUT,
+                idle_timeout: IDLE_TIMEOUT,
+                max_message_size: config.max_message_size.unwrap_or(DEFAULT_MAX_MESSAGE_SIZE),
+                resume_grace: RESUME_GRACE,
+                tls: None,
+                metrics: None,
+                federation: None,
+                plugins: vec![],
+                record: None,
+            };
+            let (started_tx, started_rx) = std::sync::mpsc::channel();
+            let thread = std::thread::spawn(move || {
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?;
+                LocalSet::new().block_on(&runtime, async move {
+                    let server = run_server(opts).await?;
+                    let _ = started_tx.send((server.port(), server.tx));
+                    server.future.await
+                })
+            });
+            match started_rx.recv() {
+                Ok((port, tx)) => Ok(Self {
+                    port,
+                    tx: Some(tx),
+                    thread: Some(thread),
+                }),
+                // The server failed before it started listening
+                Err(_) => Err(thread
+                    .join()
+                    .expect("server thread panicked")
+                    .expect_err("server did not start")),
+            }
+        }
+
+        fn port(&self) -> u16 {
+            self.port
+        }
+    }
+
+    impl Drop for TestServer {
+        fn drop(&mut self) {
+            if let Some(tx) = self.tx.take() {
+                let _ = tx.send(());
+            }
+            if let Some(thread) = self.thread.take() {
+                thread
+                    .join()
+                    .expect("server thread panicked")
+                    .expect("server failed");
+            }
+        }
+    }
+
+    chat_conformance::conformance_tests!(TestServer);
 }
@@ -1,9 +1,17 @@
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
 pub enum ClientToServerMsg {
     /// This is the first message in the communication, which should be sent by the client.
     /// When some other client with the same name already exists, the server should respond
     /// with an error "Username already taken" and disconnect the new client.
     Join { name: String },
+    /// Can be sent instead of [ClientToServerMsg::Join] to continue the session of a user whose
+    /// connection was lost, using the token from the last [ServerToClientMsg::Welcome] it has
+    /// received. The server responds with [ServerToClientMsg::Welcome] (with a new token) and
+    /// then sends the messages that were sent to the user in the meantime.
+    /// Only a session whose connection was lost unexpectedly (an I/O error or the idle timeout)
+    /// can be resumed, and only within the resume grace period of the server. Otherwise the
+    /// server responds with an error "Invalid resume token" and disconnects the client.
+    Resume { token: String },
     /// This message checks that the connection is OK.
     /// The server should respond with [ServerToClientMsg::Pong].
     Ping,
@@ -12,24 +20,136 @@ pub enum ClientToServerMsg {
     ListUsers,
     /// Sends a direct message to the user with the given name (`to`).
     /// If the user does not exist, the server responds with an error "User <to> does not exist".
+    /// When the history is enabled, a user that has joined the server in the past but is not
+    /// connected right now also exists. The DM is then stored and delivered to them the next
+    /// time they join, right after [ServerToClientMsg::Welcome].
     /// If the client tries to send a message to themselves, the server responds with an error
     /// "Cannot send a DM to yourself".
+    /// An accepted DM is confirmed by [ServerToClientMsg::Accepted].
     SendDM { to: String, message: String },
     /// Sends a message to all currently connected users (except for the sender of the broadcast).
+    /// The broadcast is confirmed by [ServerToClientMsg::Accepted].
     Broadcast { message: String },
+    /// Send a request to return a page of past broadcasts and DMs sent or received by the sender,
+    /// with an ID larger than `since`, ordered by their ID.
+    /// At most `limit` messages (and never more than 100) are returned.
+    /// The server responds with [ServerToClientMsg::History].
+    /// If the history is not enabled, the server responds with an error "History is not enabled".
+    History { since: u64, limit: usize },
+    /// Confirms that the sender has read the message with the given ID, which was sent to it in
+    /// [ServerToClientMsg::Message]. The author of the message then receives
+    /// [ServerToClientMsg::Read]. Every message can be acknowledged only once, otherwise the
+    /// server responds with an error "Unknown message <id>".
+    Ack { id: u64 },
+    /// Starts sending [ServerToClientMsg::UserJoined] and [ServerToClientMsg::UserLeft] to the
+    /// client. Every client is subscribed right after it joins, so this is only needed after
+    /// [ClientToServerMsg::Unsubscribe]. The server does not respond to this message.
+    Subscribe,
+    /// Stops sending presence notifications to the client, e.g. to a bot that does not care
+    /// about them. The server does not respond to this message.
+    Unsubscribe,
 }
 
-#[derive(serde::Serialize, serde::Deserialize, Debug)]
+impl ClientToServerMsg {
+    /// Name of the message type, used in metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientToServerMsg::Join { .. } => "Join",
+            ClientToServerMsg::Resume { .. } => "Resume",
+            ClientToServerMsg::Ping => "Ping",
+            ClientToServerMsg::ListUsers => "ListUsers",
+            ClientToServerMsg::SendDM { .. } => "SendDM",
+            ClientToServerMsg::Broadcast { .. } => "Broadcast",
+            ClientToServerMsg::History { .. } => "History",
+            ClientToServerMsg::Ack { .. } => "Ack",
+            ClientToServerMsg::Subscribe => "Subscribe",
+            ClientToServerMsg::Unsubscribe => "Unsubscribe",
+        }
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
 pub enum ServerToClientMsg {
-    /// Response to [ClientToServerMsg::Join].
-    Welcome,
+    /// Response to [ClientToServerMsg::Join] and [ClientToServerMsg::Resume].
+    /// Announces the maximum size of a single message (in bytes) that the server accepts.
+    /// Larger messages are rejected with an error "Message too large".
+    /// The `token` can be used to resume the session with [ClientToServerMsg::Resume].
+    Welcome {
+        max_message_size: usize,
+        token: String,
+    },
     /// Response to [ClientToServerMsg::Ping].
     Pong,
     /// Response to [ClientToServerMsg::ListUsers].
     UserList { users: Vec<String> },
     /// This message is sent by the server to a client that should receive a message
     /// (that was sent either by [ClientToServerMsg::SendDM] or [ClientToServerMsg::Broadcast]).
-    Message { from: String, message: String },
+    Message {
+        id: u64,
+        from: String,
+        message: String,
+    },
+    /// Response to [ClientToServerMsg::SendDM] and [ClientToServerMsg::Broadcast].
+    /// The ID is unique and assigned by the server in increasing order. When the history is
+    /// enabled, it is the ID of the message in the history.
+    Accepted { id: u64 },
+    /// Tells the author of a message that the message was sent to the user `to`.
+    /// A DM for an offline user is delivered once the user joins again.
+    Delivered { id: u64, to: String },
+    /// Tells the author of a message that the user `by` has acknowledged it with
+    /// [ClientToServerMsg::Ack].
+    Read { id: u64, by: String },
+    /// Tells every other subscribed client that a user has joined the server.
+    UserJoined { name: String },
+    /// Tells every other subscribed client that a user which has joined the server before is no
+    /// longer connected. Clients that leave before joining (e.g. after "Timed out waiting for
+    /// Join") are never announced.
+    UserLeft { name: String, reason: LeaveReason },
+    /// Response to [ClientToServerMsg::History].
+    History { messages: Vec<HistoryMessage> },
     /// This message is returned by the server when an error occurs.
     Error(String),
 }
+
+impl ServerToClientMsg {
+    /// Name of the message type, used in metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ServerToClientMsg::Welcome { .. } => "Welcome",
+            ServerToClientMsg::Pong => "Pong",
+            ServerToClientMsg::UserList { .. } => "UserList",
+            ServerToClientMsg::Message { .. } => "Message",
+            ServerToClientMsg::Accepted { .. } => "Accepted",
+            ServerToClientMsg::Delivered { .. } => "Delivered",
+            ServerToClientMsg::Read { .. } => "Read",
+            ServerToClientMsg::UserJoined { .. } => "UserJoined",
+            ServerToClientMsg::UserLeft { .. } => "UserLeft",
+            ServerToClientMsg::History { .. } => "History",
+            ServerToClientMsg::Error(_) => "Error",
+        }
+    }
+}
+
+/// Why has a user left the server.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum LeaveReason {
+    /// The client has closed the connection (or the connection was broken).
+    Disconnected,
+    /// The client has not sent or received anything for too long ("Timeouted").
+    TimedOut,
+    /// The client was disconnected because it has sent an unexpected message.
+    Error,
+    /// The server is shutting down.
+    Shutdown,
+}
+
+/// A broadcast or a DM stored in the history of the server.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryMessage {
+    /// Unique ID of the message, IDs are assigned in increasing order starting from 1.
+    pub id: u64,
+    pub from: String,
+    /// Recipient of a DM, `None` for broadcasts.
+    pub to: Option<String>,
+    pub message: String,
+}
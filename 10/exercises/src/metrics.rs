@@ -0,0 +1,267 @@
+use crate::messages::{ClientToServerMsg, ServerToClientMsg};
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Errors that contain the name of a user or the ID of a message. They are counted under these
+/// templates, so that clients cannot create an unbounded number of error kinds.
+const ERROR_TEMPLATES: &[&str] = &["User <name> does not exist", "Unknown message <id>"];
+
+/// Counters of a running server, shared by all of its tasks.
+#[derive(Default)]
+pub struct Metrics {
+    messages_received: RefCell<BTreeMap<&'static str, u64>>,
+    messages_sent: RefCell<BTreeMap<&'static str, u64>>,
+    errors: RefCell<BTreeMap<String, u64>>,
+    bytes_received: Cell<u64>,
+    bytes_sent: Cell<u64>,
+    busy_time: Cell<Duration>,
+}
+
+impl Metrics {
+    pub fn message_received(&self, msg: &ClientToServerMsg) {
+        *self
+            .messages_received
+            .borrow_mut()
+            .entry(msg.kind())
+            .or_default() += 1;
+    }
+
+    pub fn message_sent(&self, msg: &ServerToClientMsg) {
+        *self
+            .messages_sent
+            .borrow_mut()
+            .entry(msg.kind())
+            .or_default() += 1;
+        if let ServerToClientMsg::Error(error) = msg {
+            *self
+                .errors
+                .borrow_mut()
+                .entry(error_kind(error))
+                .or_default() += 1;
+        }
+    }
+
+    /// Returns the current values of the counters. The gauges are not tracked here, so they
+    /// have to be passed in.
+    pub fn snapshot(&self, connected_clients: usize, joined_users: usize) -> Stats {
+        let to_owned = |counts: &RefCell<BTreeMap<&'static str, u64>>| {
+            counts
+                .borrow()
+                .iter()
+                .map(|(kind, count)| (kind.to_string(), *count))
+                .collect()
+        };
+        Stats {
+            connected_clients,
+            joined_users,
+            messages_received: to_owned(&self.messages_received),
+            messages_sent: to_owned(&self.messages_sent),
+            errors: self.errors.borrow().clone(),
+            bytes_received: self.bytes_received.get(),
+            bytes_sent: self.bytes_sent.get(),
+            busy_time: self.busy_time.get(),
+        }
+    }
+}
+
+fn error_kind(error: &str) -> String {
+    ERROR_TEMPLATES
+        .iter()
+        .find(|template| {
+            let (prefix, rest) = template.split_once('<').unwrap();
+            let (_, suffix) = rest.split_once('>').unwrap();
+            error.len() > prefix.len() + suffix.len()
+                && error.starts_with(prefix)
+                && error.ends_with(suffix)
+        })
+        .map_or(error, |template| template)
+        .to_string()
+}
+
+/// Snapshot of the metrics of a running server.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Stats {
+    /// Connected clients, including clients that have not joined yet.
+    pub connected_clients: usize,
+    pub joined_users: usize,
+    /// Messages received from clients, by their type.
+    pub messages_received: BTreeMap<String, u64>,
+    /// Messages sent to clients, by their type.
+    pub messages_sent: BTreeMap<String, u64>,
+    /// Errors sent to clients, by their message. Names of users and message IDs are replaced by
+    /// placeholders, e.g. "User <name> does not exist".
+    pub errors: BTreeMap<String, u64>,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+    /// Time that the tasks of the server have spent running, as opposed to waiting for I/O or
+    /// timers.
+    pub busy_time: Duration,
+}
+
+impl Stats {
+    /// Formats the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let mut metric = |name: &str, kind: &str, help: &str, samples: Vec<(String, String)>| {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            for (labels, value) in samples {
+                let _ = writeln!(out, "{name}{labels} {value}");
+            }
+        };
+        let single = |value: String| vec![(String::new(), value)];
+        let labelled = |label: &str, counts: &BTreeMap<String, u64>| {
+            counts
+                .iter()
+                .map(|(key, count)| {
+                    let key = key
+                        .replace('\\', "\\\\")
+                        .replace('"', "\\\"")
+                        .replace('\n', "\\n");
+                    (format!("{{{label}=\"{key}\"}}"), count.to_string())
+                })
+                .collect()
+        };
+
+        metric(
+            "chat_connected_clients",
+            "gauge",
+            "Connected clients, including clients that have not joined yet.",
+            single(self.connected_clients.to_string()),
+        );
+        metric(
+            "chat_joined_users",
+            "gauge",
+            "Users that have joined the server.",
+            single(self.joined_users.to_string()),
+        );
+        metric(
+            "chat_messages_received_total",
+            "counter",
+            "Messages received from clients.",
+            labelled("type", &self.messages_received),
+        );
+        metric(
+            "chat_messages_sent_total",
+            "counter",
+            "Messages sent to clients.",
+            labelled("type", &self.messages_sent),
+        );
+        metric(
+            "chat_errors_total",
+            "counter",
+            "Errors sent to clients.",
+            labelled("error", &self.errors),
+        );
+        metric(
+            "chat_received_bytes_total",
+            "counter",
+            "Bytes received from clients.",
+            single(self.bytes_received.to_string()),
+        );
+        metric(
+            "chat_sent_bytes_total",
+            "counter",
+            "Bytes sent to clients.",
+            single(self.bytes_sent.to_string()),
+        );
+        metric(
+            "chat_busy_seconds_total",
+            "counter",
+            "Time spent running the tasks of the server, as opposed to waiting.",
+            single(self.busy_time.as_secs_f64().to_string()),
+        );
+        out
+    }
+}
+
+/// Stream that counts the bytes read from and written to a client, including the overhead of
+/// TLS and WebSocket.
+pub struct Counted<S> {
+    stream: S,
+    metrics: Rc<Metrics>,
+}
+
+impl<S> Counted<S> {
+    pub fn new(stream: S, metrics: Rc<Metrics>) -> Self {
+        Self { stream, metrics }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.stream).poll_read(cx, buf);
+        let count = (buf.filled().len() - before) as u64;
+        this.metrics
+            .bytes_received
+            .set(this.metrics.bytes_received.get() + count);
+        result
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.stream).poll_write(cx, buf);
+        if let Poll::Ready(Ok(count)) = result {
+            this.metrics
+                .bytes_sent
+                .set(this.metrics.bytes_sent.get() + count as u64);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Future that adds the time spent polling the inner future to the busy time of the server.
+pub struct Timed<F> {
+    future: Pin<Box<F>>,
+    metrics: Rc<Metrics>,
+}
+
+impl<F: Future> Timed<F> {
+    pub fn new(future: F, metrics: Rc<Metrics>) -> Self {
+        Self {
+            future: Box::pin(future),
+            metrics,
+        }
+    }
+}
+
+impl<F: Future> Future for Timed<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let started = Instant::now();
+        let result = this.future.as_mut().poll(cx);
+        this.metrics
+            .busy_time
+            .set(this.metrics.busy_time.get() + started.elapsed());
+        result
+    }
+}
@@ -0,0 +1,164 @@
+use crate::client::ClientStream;
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::net::{TcpListener, UnixListener, UnixStream};
+
+/// How long does an accept loop wait after an accept fails for a reason that would most likely
+/// repeat right away.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Address on which the server listens for new clients.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    /// TCP/IP address. Use port 0 to let the operating system choose a free port.
+    Tcp(SocketAddr),
+    /// Path of a Unix domain socket. Who can connect to it is decided by the permissions of the
+    /// socket file and its directory.
+    Unix(PathBuf),
+    /// TCP/IP address on which clients talk WebSocket, e.g. from a browser.
+    WebSocket(SocketAddr),
+}
+
+/// How are the messages of a client delimited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    /// Every message is a single line.
+    Newline,
+    /// Every message is a single WebSocket text frame.
+    WebSocket,
+}
+
+impl Display for ListenAddr {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ListenAddr::Tcp(address) => write!(f, "{address}"),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+            ListenAddr::WebSocket(address) => write!(f, "ws://{address}"),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener),
+    WebSocket(TcpListener),
+}
+
+impl Listener {
+    /// A Unix socket file left behind by a server that has crashed is replaced.
+    pub async fn bind(address: &ListenAddr) -> std::io::Result<Self> {
+        match address {
+            ListenAddr::Tcp(address) => Ok(Listener::Tcp(TcpListener::bind(address).await?)),
+            ListenAddr::Unix(path) => match UnixListener::bind(path) {
+                Err(error)
+                    if error.kind() == ErrorKind::AddrInUse
+                        && UnixStream::connect(path).await.is_err() =>
+                {
+                    std::fs::remove_file(path)?;
+                    Ok(Listener::Unix(UnixListener::bind(path)?))
+                }
+                listener => Ok(Listener::Unix(listener?)),
+            },
+            ListenAddr::WebSocket(address) => {
+                Ok(Listener::WebSocket(TcpListener::bind(address).await?))
+            }
+        }
+    }
+
+    /// Returns the address on which the listener listens, including the port chosen by the
+    /// operating system.
+    pub fn local_addr(&self) -> std::io::Result<ListenAddr> {
+        match self {
+            Listener::Tcp(listener) => Ok(ListenAddr::Tcp(listener.local_addr()?)),
+            Listener::Unix(listener) => {
+                let address = listener.local_addr()?;
+                let path = address.as_pathname().ok_or_else(|| {
+                    std::io::Error::new(ErrorKind::InvalidInput, "Unnamed Unix socket")
+                })?;
+                Ok(ListenAddr::Unix(path.to_path_buf()))
+            }
+            Listener::WebSocket(listener) => Ok(ListenAddr::WebSocket(listener.local_addr()?)),
+        }
+    }
+
+    fn poll_accept(&self, cx: &mut Context<'_>) -> Poll<std::io::Result<Incoming>> {
+        match self {
+            Listener::Tcp(listener) => listener.poll_accept(cx).map_ok(|(stream, peer)| Incoming {
+                stream: Box::new(stream),
+                framing: Framing::Newline,
+                peer: peer.to_string(),
+            }),
+            Listener::Unix(listener) => listener.poll_accept(cx).map_ok(|(stream, _)| Incoming {
+                stream: Box::new(stream),
+                framing: Framing::Newline,
+                peer: "local client".to_string(),
+            }),
+            Listener::WebSocket(listener) => {
+                listener.poll_accept(cx).map_ok(|(stream, peer)| Incoming {
+                    stream: Box::new(stream),
+                    framing: Framing::WebSocket,
+                    peer: peer.to_string(),
+                })
+            }
+        }
+    }
+}
+
+/// A client that has just connected.
+pub struct Incoming {
+    /// The connection, before the TLS and WebSocket handshakes.
+    pub stream: ClientStream,
+    pub framing: Framing,
+    /// Description of the client for logging.
+    pub peer: String,
+}
+
+/// Waits until a client connects to any of the listeners.
+pub async fn accept(listeners: &[Listener]) -> std::io::Result<Incoming> {
+    std::future::poll_fn(|cx| {
+        listeners
+            .iter()
+            .find_map(|listener| match listener.poll_accept(cx) {
+                Poll::Ready(client) => Some(Poll::Ready(client)),
+                Poll::Pending => None,
+            })
+            .unwrap_or(Poll::Pending)
+    })
+    .await
+}
+
+/// Source of the clients of the server.
+pub(crate) trait Acceptor {
+    /// Waits until a client connects.
+    async fn accept(&mut self) -> std::io::Result<Incoming>;
+}
+
+impl Acceptor for Vec<Listener> {
+    async fn accept(&mut self) -> std::io::Result<Incoming> {
+        accept(self).await
+    }
+}
+
+/// Handles a failed accept. The connection might have been aborted by the client already, which
+/// is not an error of the server. Other errors (e.g. too many open files) would most likely
+/// happen again right away, so the accept loop waits a bit first.
+pub async fn accept_failed(error: std::io::Error) {
+    if !matches!(
+        error.kind(),
+        ErrorKind::Interrupted | ErrorKind::ConnectionAborted
+    ) {
+        log::warn!("Cannot accept a connection: {error}");
+        tokio::time::sleep(ACCEPT_BACKOFF).await;
+    }
+}
+
+/// Removes the socket file of a Unix socket listener, so that the server can be started again.
+pub fn remove_socket(address: &ListenAddr) {
+    if let ListenAddr::Unix(path) = address {
+        let _ = std::fs::remove_file(path);
+    }
+}
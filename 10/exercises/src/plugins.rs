@@ -0,0 +1,91 @@
+use std::sync::Arc;
+
+/// Creates a plugin when the server starts. Every server gets its own instance of the plugin,
+/// which lives on the thread of the server.
+pub type PluginFactory = Arc<dyn Fn() -> Box<dyn Plugin> + Send + Sync>;
+
+/// Extension of the server, e.g. a bot that answers commands or a filter of messages.
+///
+/// The hooks are called while the server handles an event of a user of this server, so they must
+/// not block. Messages sent by a plugin through its [`Context`] are delivered once the event is
+/// handled. They do not trigger the hooks of any plugin, so plugins cannot keep answering each
+/// other.
+///
+/// Plugins are called in the order of [`crate::ServerOpts::plugins`], a message rewritten by one
+/// plugin is passed to the next one.
+pub trait Plugin {
+    /// Virtual users of the plugin, asked once when the server starts. They are listed in
+    /// `ListUsers` and users can send DMs to them, but they are not visible to federated peers.
+    /// Clients cannot join with their names.
+    fn users(&self) -> Vec<String> {
+        vec![]
+    }
+
+    /// Called after a user has joined.
+    fn on_join(&mut self, _name: &str, _ctx: &mut Context) {}
+
+    /// Called after a user has left, or after its session expired if it could be resumed.
+    fn on_leave(&mut self, _name: &str, _ctx: &mut Context) {}
+
+    /// Called before a DM is routed, including DMs sent to virtual users.
+    fn on_dm(&mut self, _from: &str, _to: &str, _message: &str, _ctx: &mut Context) -> Verdict {
+        Verdict::Pass
+    }
+
+    /// Called before a broadcast is sent.
+    fn on_broadcast(&mut self, _from: &str, _message: &str, _ctx: &mut Context) -> Verdict {
+        Verdict::Pass
+    }
+}
+
+/// What should happen with a message that a plugin has seen.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Verdict {
+    /// The message continues unchanged.
+    Pass,
+    /// The message continues with this text instead.
+    Rewrite(String),
+    /// The message is not delivered, its sender receives an error with this reason.
+    Drop(String),
+}
+
+/// Messages that a plugin sends while handling an event.
+#[derive(Default)]
+pub struct Context {
+    actions: Vec<Action>,
+}
+
+pub(crate) enum Action {
+    Dm {
+        from: String,
+        to: String,
+        message: String,
+    },
+    Broadcast {
+        from: String,
+        message: String,
+    },
+}
+
+impl Context {
+    /// Sends a DM in the name of `from`, usually one of the virtual users of the plugin.
+    pub fn send_dm(&mut self, from: &str, to: &str, message: &str) {
+        self.actions.push(Action::Dm {
+            from: from.to_string(),
+            to: to.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    /// Sends a broadcast in the name of `from`, usually one of the virtual users of the plugin.
+    pub fn broadcast(&mut self, from: &str, message: &str) {
+        self.actions.push(Action::Broadcast {
+            from: from.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    pub(crate) fn into_actions(self) -> Vec<Action> {
+        self.actions
+    }
+}
@@ -1,42 +1,99 @@
 use serde::de::DeserializeOwned;
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
 use std::marker::PhantomData;
 use tokio::io::{AsyncRead, AsyncReadExt};
 
+/// Maximum size of a single message (in bytes), unless configured otherwise.
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+
+/// A message was larger than the maximum message size of the reader.
+///
+/// It is returned wrapped in an [`std::io::Error`] of kind [`ErrorKind::InvalidData`].
+/// The message is skipped, so the reader can still be used to read the following messages.
+#[derive(Debug)]
+pub struct MessageTooLarge;
+
+impl MessageTooLarge {
+    pub fn is_cause_of(error: &std::io::Error) -> bool {
+        error
+            .get_ref()
+            .is_some_and(|error| error.is::<MessageTooLarge>())
+    }
+}
+
+impl Display for MessageTooLarge {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Message too large")
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
 pub struct MessageReader<T, R> {
+    client: R,
     buffer: Vec<u8>,
     loaded: usize,
-    client: R,
+    max_message_size: usize,
+    /// The message that is being read is too large, drop its bytes until the next newline.
+    skipping: bool,
     _phantom: PhantomData<T>,
 }
 
 impl<T: DeserializeOwned, R: AsyncRead + Unpin> MessageReader<T, R> {
     pub fn new(client: R) -> Self {
+        Self::with_max_message_size(client, DEFAULT_MAX_MESSAGE_SIZE)
+    }
+
+    pub fn with_max_message_size(client: R, max_message_size: usize) -> Self {
         Self {
+            // The buffer grows on demand, up to the maximum message size
             buffer: vec![0; 1024],
             loaded: 0,
+            max_message_size,
+            skipping: false,
             client,
             _phantom: Default::default(),
         }
     }
 
+    pub fn into_inner(self) -> R {
+        self.client
+    }
+
     pub async fn recv(&mut self) -> Option<std::io::Result<T>> {
         loop {
             if let Some(position) = self.buffer[..self.loaded].iter().position(|c| *c == b'\n') {
-                let msg = &self.buffer[..position];
-                let msg: T = match serde_json::from_slice(msg) {
-                    Ok(msg) => msg,
-                    Err(error) => return Some(Err(error.into())),
+                let result = if self.skipping {
+                    None
+                } else if position > self.max_message_size {
+                    Some(Err(too_large()))
+                } else {
+                    Some(serde_json::from_slice(&self.buffer[..position]).map_err(|e| e.into()))
                 };
-                self.buffer.copy_within(position + 1.., 0);
-
+                self.skipping = false;
+                self.buffer.copy_within(position + 1..self.loaded, 0);
                 self.loaded -= position + 1;
-                return Some(Ok(msg));
+                match result {
+                    Some(result) => return Some(result),
+                    None => continue,
+                }
             }
 
-            assert!(self.loaded < self.buffer.len());
+            if self.skipping {
+                self.loaded = 0;
+            } else if self.loaded > self.max_message_size {
+                self.skipping = true;
+                self.loaded = 0;
+                return Some(Err(too_large()));
+            }
+
+            if self.loaded == self.buffer.len() {
+                self.buffer.resize(self.buffer.len() * 2, 0);
+            }
             let read_bytes = match self.client.read(&mut self.buffer[self.loaded..]).await {
                 Ok(b) => b,
-                Err(err) => return Some(Err(err.into())),
+                Err(err) => return Some(Err(err)),
             };
             if read_bytes == 0 {
                 break;
@@ -46,3 +103,7 @@ impl<T: DeserializeOwned, R: AsyncRead + Unpin> MessageReader<T, R> {
         None
     }
 }
+
+fn too_large() -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, MessageTooLarge)
+}
@@ -0,0 +1,346 @@
+use crate::client::ClientStream;
+use crate::messages::{ClientToServerMsg, ServerToClientMsg};
+use crate::net::{Acceptor, Framing, Incoming};
+use crate::trace::{Event, Record, Recorder};
+use crate::{start_server, RunningServer, ServerOpts};
+use std::cell::Cell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf, WriteHalf};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// A difference between the trace and the replay, in what the server has sent to a client or
+/// which of its timers have fired.
+#[derive(Debug)]
+pub struct Divergence {
+    pub client: u64,
+    /// `None` if the recorded server did not do anything more.
+    pub expected: Option<Record>,
+    /// `None` if the replayed server did not do anything more.
+    pub actual: Option<Record>,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let describe = |record: &Option<Record>| match record {
+            Some(Record { at, event }) => format!("{event:?} at {:.6} s", *at as f64 / 1e6),
+            None => "nothing".to_string(),
+        };
+        write!(
+            f,
+            "Client {}: expected {}, got {}",
+            self.client,
+            describe(&self.expected),
+            describe(&self.actual)
+        )
+    }
+}
+
+/// Feeds the connections and messages of a trace to a fresh server with the given options, at
+/// the same times as they were recorded. Then compares what the server has sent to every client
+/// and which timers have fired with the trace, and returns the first divergence of every
+/// connection.
+///
+/// The time of the runtime is paused during the replay and advanced only when the server is idle,
+/// so the replay is deterministic and does not wait for the timeouts. Messages that arrived at
+/// the same time are fed one by one, each after the server has handled the previous one.
+/// Clients connect in memory, so the listeners in `opts` are not used, and federation is not
+/// supported. Resume tokens in the trace are replaced by the tokens of the replayed server.
+///
+/// Has to be called in a [`tokio::task::LocalSet`] on a current-thread runtime whose time is not
+/// paused.
+pub async fn replay(records: &[Record], opts: ServerOpts) -> anyhow::Result<Vec<Divergence>> {
+    tokio::time::pause();
+    let result = feed(records, opts).await;
+    tokio::time::resume();
+    let actual = result?;
+    Ok(compare(records, &actual))
+}
+
+/// A client of the replayed server.
+struct ReplayedClient {
+    writer: WriteHalf<DuplexStream>,
+    lost: Rc<Cell<bool>>,
+}
+
+/// Returns the trace of the replayed server.
+async fn feed(records: &[Record], opts: ServerOpts) -> anyhow::Result<Vec<Record>> {
+    let opts = ServerOpts {
+        metrics: None,
+        federation: None,
+        record: None,
+        ..opts
+    };
+    let max_message_size = opts.max_message_size;
+    let (connections, connections_rx) = mpsc::unbounded_channel();
+    let server = start_server(opts, InMemoryClients(connections_rx), vec![]).await?;
+    let recorder = Rc::new(Recorder::in_memory(&server.state.borrow().opts));
+    server.state.borrow_mut().recorder = Some(recorder.clone());
+    let RunningServer { future, tx, .. } = server;
+    let server = tokio::task::spawn_local(future);
+
+    let start = Instant::now();
+    let mut last_input = start;
+    let mut clients = HashMap::new();
+    let mut stopped = false;
+    for record in records {
+        let mut at = start + Duration::from_micros(record.at);
+        if is_input(&record.event) {
+            at = at.max(last_input + Duration::from_micros(1));
+            last_input = at;
+        }
+        // The server handles everything that has happened before, including its timers
+        tokio::time::sleep_until(at).await;
+
+        match &record.event {
+            Event::Connected { client } => {
+                let (stream, server_stream) = tokio::io::duplex(max_message_size + 1024);
+                let lost = Rc::new(Cell::new(false));
+                let server_stream = ReplayedStream {
+                    stream: server_stream,
+                    lost: lost.clone(),
+                };
+                connections
+                    .send(Box::new(server_stream))
+                    .map_err(|_| anyhow::anyhow!("The replayed server has stopped"))?;
+                let (mut reader, writer) = tokio::io::split(stream);
+                tokio::task::spawn_local(async move {
+                    let _ = tokio::io::copy(&mut reader, &mut tokio::io::sink()).await;
+                });
+                clients.insert(*client, ReplayedClient { writer, lost });
+            }
+            Event::Received { client, msg } => {
+                let msg = match msg {
+                    ClientToServerMsg::Resume { token } => ClientToServerMsg::Resume {
+                        token: replayed_token(records, &recorder.records(), token),
+                    },
+                    msg => msg.clone(),
+                };
+                let mut line = serde_json::to_vec(&msg)?;
+                line.push(b'\n');
+                write(&mut clients, *client, &line).await;
+            }
+            Event::TooLarge { client } => {
+                let mut line = vec![b'x'; max_message_size + 1];
+                line.push(b'\n');
+                write(&mut clients, *client, &line).await;
+            }
+            Event::Closed { client } | Event::Lost { client, .. } => {
+                if let Some(mut replayed) = clients.remove(client) {
+                    replayed
+                        .lost
+                        .set(matches!(record.event, Event::Lost { .. }));
+                    let _ = replayed.writer.shutdown().await;
+                }
+            }
+            Event::Stopped => {
+                stopped = true;
+                break;
+            }
+            Event::Started(_) | Event::Sent { .. } | Event::Timer { .. } => {}
+        }
+    }
+
+    let mut actual = None;
+    if !stopped {
+        // Let the server finish what it has started, but ignore what the shutdown causes, because
+        // the recorded server was not stopped
+        tokio::time::sleep(Duration::from_micros(1)).await;
+        actual = Some(recorder.records());
+    }
+    let _ = tx.send(());
+    server.await??;
+    Ok(actual.unwrap_or_else(|| recorder.records()))
+}
+
+fn is_input(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::Connected { .. }
+            | Event::Received { .. }
+            | Event::TooLarge { .. }
+            | Event::Closed { .. }
+            | Event::Lost { .. }
+    )
+}
+
+/// Messages for a client that has already closed its connection are ignored, like the writes
+/// to a connection that the server has already closed.
+async fn write(clients: &mut HashMap<u64, ReplayedClient>, client: u64, data: &[u8]) {
+    if let Some(replayed) = clients.get_mut(&client) {
+        let _ = replayed.writer.write_all(data).await;
+    }
+}
+
+/// Finds the client that has received `token` in the trace, and returns the token that the same
+/// client has received from the replayed server.
+fn replayed_token(expected: &[Record], actual: &[Record], token: &str) -> String {
+    let welcome_token = |records: &[Record], client: u64| {
+        records.iter().find_map(|record| match &record.event {
+            Event::Sent {
+                client: c,
+                msg: ServerToClientMsg::Welcome { token, .. },
+            } if *c == client => Some(token.clone()),
+            _ => None,
+        })
+    };
+    let client = expected.iter().find_map(|record| match &record.event {
+        Event::Sent {
+            client,
+            msg: ServerToClientMsg::Welcome { token: t, .. },
+        } if t == token => Some(*client),
+        _ => None,
+    });
+    client
+        .and_then(|client| welcome_token(actual, client))
+        .unwrap_or_else(|| token.to_string())
+}
+
+/// Compares the messages sent to every client and the timers of every client.
+fn compare(expected: &[Record], actual: &[Record]) -> Vec<Divergence> {
+    let expected = outputs(expected);
+    let mut actual = outputs(actual);
+    let mut divergences = vec![];
+    for (client, expected) in expected {
+        let actual = actual.remove(&client).unwrap_or_default();
+        if let Some(divergence) = first_divergence(client, expected, actual) {
+            divergences.push(divergence);
+        }
+    }
+    for (client, actual) in actual {
+        if let Some(divergence) = first_divergence(client, vec![], actual) {
+            divergences.push(divergence);
+        }
+    }
+    divergences.sort_by_key(|divergence| divergence.client);
+    divergences
+}
+
+fn first_divergence(client: u64, expected: Vec<Record>, actual: Vec<Record>) -> Option<Divergence> {
+    let mut expected = expected.into_iter();
+    let mut actual = actual.into_iter();
+    loop {
+        match (expected.next(), actual.next()) {
+            (None, None) => return None,
+            (Some(e), Some(a)) if normalize(&e.event) == normalize(&a.event) => {}
+            (expected, actual) => {
+                return Some(Divergence {
+                    client,
+                    expected,
+                    actual,
+                })
+            }
+        }
+    }
+}
+
+/// Outputs of the server for every client.
+fn outputs(records: &[Record]) -> BTreeMap<u64, Vec<Record>> {
+    let mut outputs: BTreeMap<u64, Vec<Record>> = BTreeMap::new();
+    for record in records {
+        if let Event::Sent { client, .. } | Event::Timer { client, .. } = &record.event {
+            outputs.entry(*client).or_default().push(record.clone());
+        }
+    }
+    outputs
+}
+
+/// Removes the differences that are expected, the resume tokens are random and the order of
+/// users in a user list is not important.
+fn normalize(event: &Event) -> Event {
+    match event {
+        Event::Sent {
+            client,
+            msg: ServerToClientMsg::Welcome {
+                max_message_size, ..
+            },
+        } => Event::Sent {
+            client: *client,
+            msg: ServerToClientMsg::Welcome {
+                max_message_size: *max_message_size,
+                token: String::new(),
+            },
+        },
+        Event::Sent {
+            client,
+            msg: ServerToClientMsg::UserList { users },
+        } => {
+            let mut users = users.clone();
+            users.sort();
+            Event::Sent {
+                client: *client,
+                msg: ServerToClientMsg::UserList { users },
+            }
+        }
+        event => event.clone(),
+    }
+}
+
+/// Connections of the replayed clients, made within the process.
+struct InMemoryClients(mpsc::UnboundedReceiver<ClientStream>);
+
+impl Acceptor for InMemoryClients {
+    async fn accept(&mut self) -> std::io::Result<Incoming> {
+        match self.0.recv().await {
+            Some(stream) => Ok(Incoming {
+                stream,
+                framing: Framing::Newline,
+                peer: "in-memory client".to_string(),
+            }),
+            // The replay has finished, no more clients will connect
+            None => std::future::pending().await,
+        }
+    }
+}
+
+/// Server side of an in-memory connection, reading from it fails once the connection is lost.
+struct ReplayedStream {
+    stream: DuplexStream,
+    lost: Rc<Cell<bool>>,
+}
+
+impl AsyncRead for ReplayedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        if self.lost.get() {
+            return Poll::Ready(Err(std::io::Error::new(
+                ErrorKind::ConnectionReset,
+                "Connection lost",
+            )));
+        }
+        Pin::new(&mut self.stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for ReplayedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,629 @@
+use crate::federation::PeerMsg;
+use crate::history::History;
+use crate::messages::{HistoryMessage, LeaveReason, ServerToClientMsg};
+use crate::metrics::{Metrics, Stats};
+use crate::plugins::{Action, Context, Plugin, Verdict};
+use crate::trace::Recorder;
+use crate::ServerOpts;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::rc::Rc;
+use tokio::sync::mpsc::UnboundedSender;
+
+pub type SharedState = Rc<RefCell<ServerState>>;
+
+/// Channel used to send messages to the task that serves a given client.
+pub type ClientSender = UnboundedSender<ServerToClientMsg>;
+
+/// Maximum number of messages kept for a user whose connection was lost. The oldest messages are
+/// dropped when the backlog is full. With the history enabled, DMs are not kept in the backlog,
+/// they are stored as undelivered and returned by the history when the user comes back.
+pub const MAX_RESUME_BACKLOG: usize = 100;
+
+/// Maximum number of delivered messages that a user has not acknowledged yet. The oldest ones are
+/// forgotten when there are more, they cannot be acknowledged anymore.
+pub const MAX_UNACKED: usize = 1000;
+
+/// Where should a DM go.
+pub enum DmRoute {
+    /// The recipient has joined, the DM with the given ID was sent to it.
+    Online {
+        id: u64,
+    },
+    /// The recipient is offline or its connection was lost, the DM was stored in the history and
+    /// will be delivered when the recipient joins or resumes its session.
+    Stored {
+        id: u64,
+    },
+    UnknownUser,
+    /// A plugin has dropped the DM for this reason.
+    Dropped(String),
+}
+
+/// Where did a broadcast go.
+pub enum BroadcastRoute {
+    /// The broadcast with the given ID was sent to all users.
+    Sent { id: u64 },
+    /// A plugin has dropped the broadcast for this reason.
+    Dropped(String),
+}
+
+/// A federated server that is linked with this server.
+struct Peer {
+    /// Channel used to send messages to the task that serves the link.
+    sender: UnboundedSender<PeerMsg>,
+    /// Users that have joined the peer.
+    users: Vec<String>,
+}
+
+/// A client that has joined the server.
+struct User {
+    connection: Connection,
+    /// Does the client want to receive presence notifications.
+    subscribed: bool,
+    /// Token that resumes the session of the user after its connection is lost.
+    token: String,
+}
+
+impl User {
+    fn send(&mut self, msg: ServerToClientMsg) {
+        match &mut self.connection {
+            // The client might have disconnected in the meantime, which is not an error of the
+            // sender
+            Connection::Online(sender) => {
+                let _ = sender.send(msg);
+            }
+            Connection::Suspended(session) => {
+                if session.backlog.len() == MAX_RESUME_BACKLOG {
+                    session.backlog.pop_front();
+                }
+                session.backlog.push_back(msg);
+            }
+        }
+    }
+}
+
+enum Connection {
+    Online(ClientSender),
+    /// The connection was lost, but the user can still resume its session.
+    Suspended(Session),
+}
+
+/// What is kept from a user whose connection was lost, until the user resumes it or the
+/// resume grace period expires.
+pub struct Session {
+    /// Messages sent to the user since the connection was lost.
+    pub backlog: VecDeque<ServerToClientMsg>,
+    /// Messages delivered to the user that it has not acknowledged yet, indexed by their ID.
+    pub unacked: BTreeMap<u64, String>,
+    /// Why was the connection lost, the other users are told about it if the session expires.
+    reason: LeaveReason,
+}
+
+/// State shared by all tasks of the server.
+///
+/// Never hold a borrow of this state across an `await` point.
+pub struct ServerState {
+    pub opts: ServerOpts,
+    /// Number of connected clients, including clients that have not joined yet.
+    pub client_count: usize,
+    /// Clients that have joined, indexed by their username.
+    users: HashMap<String, User>,
+    history: Option<History>,
+    /// ID of the last message, used only when the history is disabled.
+    last_message_id: u64,
+    pub metrics: Rc<Metrics>,
+    /// Federated servers linked with this server, indexed by their name.
+    peers: HashMap<String, Peer>,
+    plugins: Vec<Box<dyn Plugin>>,
+    /// Virtual users of all plugins.
+    virtual_users: Vec<String>,
+    pub recorder: Option<Rc<Recorder>>,
+}
+
+impl ServerState {
+    pub fn new(opts: ServerOpts) -> anyhow::Result<Self> {
+        let history = opts.history.as_deref().map(History::open).transpose()?;
+        let plugins: Vec<Box<dyn Plugin>> = opts.plugins.iter().map(|factory| factory()).collect();
+        let virtual_users = plugins.iter().flat_map(|plugin| plugin.users()).collect();
+        let recorder = opts
+            .record
+            .as_deref()
+            .map(|path| Recorder::create(path, &opts))
+            .transpose()?
+            .map(Rc::new);
+        Ok(Self {
+            opts,
+            client_count: 0,
+            users: Default::default(),
+            history,
+            last_message_id: 0,
+            metrics: Default::default(),
+            peers: Default::default(),
+            plugins,
+            virtual_users,
+            recorder,
+        })
+    }
+
+    /// Returns `None` if the username is already taken, otherwise returns the resume token of the
+    /// user and the DMs that it has missed while it was offline. The other users are told that
+    /// the user has joined, and that it has left again if the join cannot be completed.
+    pub fn add_user(
+        &mut self,
+        name: &str,
+        sender: ClientSender,
+    ) -> anyhow::Result<Option<(String, Vec<HistoryMessage>)>> {
+        if self.users.contains_key(name) || self.is_virtual_user(name) {
+            return Ok(None);
+        }
+        announce(
+            &mut self.users,
+            name,
+            ServerToClientMsg::UserJoined {
+                name: name.to_string(),
+            },
+        );
+        let token = new_token();
+        self.users.insert(
+            name.to_string(),
+            User {
+                connection: Connection::Online(sender),
+                subscribed: true,
+                token: token.clone(),
+            },
+        );
+        self.publish_users();
+        let missed = self
+            .run_hooks(|plugin, ctx| plugin.on_join(name, ctx))
+            .and_then(|()| match &mut self.history {
+                Some(history) => history.user_joined(name),
+                None => Ok(vec![]),
+            });
+        match missed {
+            Ok(missed) => Ok(Some((token, missed))),
+            Err(error) => {
+                // The client is disconnected, so the name must not stay taken
+                self.remove_user(name, LeaveReason::Error);
+                Err(error)
+            }
+        }
+    }
+
+    /// Keeps the user after its connection was lost, so that it can resume the session with its
+    /// token. `pending` are the messages that were sent to the user but not delivered to it.
+    /// Returns the token of the session, or `None` if the user was already removed.
+    pub fn suspend_user(
+        &mut self,
+        name: &str,
+        pending: Vec<ServerToClientMsg>,
+        unacked: BTreeMap<u64, String>,
+        reason: LeaveReason,
+    ) -> Option<String> {
+        let user = self.users.get_mut(name)?;
+        user.connection = Connection::Suspended(Session {
+            backlog: VecDeque::new(),
+            unacked,
+            reason,
+        });
+        for msg in pending {
+            if let (ServerToClientMsg::Message { id, .. }, Some(history)) =
+                (&msg, &mut self.history)
+            {
+                match history.mark_undelivered(*id, name) {
+                    Ok(true) => continue,
+                    Ok(false) => {}
+                    Err(error) => log::warn!("Cannot store DM {id} as undelivered: {error}"),
+                }
+            }
+            user.send(msg);
+        }
+        Some(user.token.clone())
+    }
+
+    /// Resumes the suspended session with the given token. Returns `None` if there is no such
+    /// session, otherwise returns the name of the user, its new resume token and the session.
+    /// The DMs that the user has missed are moved from the history to the front of the backlog.
+    pub fn resume_user(
+        &mut self,
+        token: &str,
+        sender: ClientSender,
+    ) -> anyhow::Result<Option<(String, String, Session)>> {
+        let Some((name, user)) = self.users.iter_mut().find(|(_, user)| {
+            user.token == token && matches!(user.connection, Connection::Suspended(_))
+        }) else {
+            return Ok(None);
+        };
+        let missed = match &mut self.history {
+            Some(history) => history.user_joined(name)?,
+            None => vec![],
+        };
+        let Connection::Suspended(mut session) =
+            std::mem::replace(&mut user.connection, Connection::Online(sender))
+        else {
+            unreachable!("the user was suspended");
+        };
+        for msg in missed.into_iter().rev() {
+            session.backlog.push_front(ServerToClientMsg::Message {
+                id: msg.id,
+                from: msg.from,
+                message: msg.message,
+            });
+        }
+        user.token = new_token();
+        Ok(Some((name.clone(), user.token.clone(), session)))
+    }
+
+    /// Removes a user whose session was not resumed in time. Does nothing if the session was
+    /// resumed in the meantime.
+    pub fn expire_session(&mut self, name: &str, token: &str) {
+        let reason = match self.users.get(name) {
+            Some(User {
+                connection: Connection::Suspended(session),
+                token: user_token,
+                ..
+            }) if user_token == token => session.reason,
+            _ => return,
+        };
+        self.remove_user(name, reason);
+    }
+
+    /// Removes the user and tells the other users that it has left. Does nothing if the user was
+    /// already removed.
+    pub fn remove_user(&mut self, name: &str, reason: LeaveReason) {
+        if self.users.remove(name).is_some() {
+            announce(
+                &mut self.users,
+                name,
+                ServerToClientMsg::UserLeft {
+                    name: name.to_string(),
+                    reason,
+                },
+            );
+            self.publish_users();
+            if let Err(error) = self.run_hooks(|plugin, ctx| plugin.on_leave(name, ctx)) {
+                log::error!("Cannot send the messages of plugins: {error}");
+            }
+        }
+    }
+
+    /// Removes all users when the server shuts down. Every user is told that all the other users
+    /// have left before its task is stopped, so the tasks should send out the messages waiting
+    /// in their channels before disconnecting their clients.
+    pub fn remove_all_users(&mut self) {
+        let mut users = std::mem::take(&mut self.users);
+        let names: Vec<String> = users.keys().cloned().collect();
+        for name in names {
+            announce(
+                &mut users,
+                &name,
+                ServerToClientMsg::UserLeft {
+                    name: name.clone(),
+                    reason: LeaveReason::Shutdown,
+                },
+            );
+        }
+    }
+
+    pub fn set_subscribed(&mut self, name: &str, subscribed: bool) {
+        if let Some(user) = self.users.get_mut(name) {
+            user.subscribed = subscribed;
+        }
+    }
+
+    /// Users whose connection was lost are counted as joined until their session expires.
+    pub fn stats(&self) -> Stats {
+        self.metrics.snapshot(self.client_count, self.users.len())
+    }
+
+    /// Users whose connection was lost are included until their session expires.
+    pub fn usernames(&self) -> Vec<String> {
+        self.users.keys().cloned().collect()
+    }
+
+    /// Local users and virtual users of plugins, followed by the users of the linked peers,
+    /// qualified by the name of their server (`<user>@<server>`).
+    pub fn all_usernames(&self) -> Vec<String> {
+        let mut users = self.usernames();
+        users.extend(self.virtual_users.iter().cloned());
+        for (server, peer) in &self.peers {
+            users.extend(peer.users.iter().map(|user| format!("{user}@{server}")));
+        }
+        users
+    }
+
+    /// Name of this server in the federation, or `None` if federation is disabled.
+    pub fn server_name(&self) -> Option<&str> {
+        self.opts
+            .federation
+            .as_ref()
+            .map(|federation| federation.name.as_str())
+    }
+
+    /// Registers a new link with a peer and sends it the local users. Returns `false` if a peer
+    /// with the same name is already linked, or if it has the name of this server.
+    pub fn add_peer(&mut self, server: &str, sender: UnboundedSender<PeerMsg>) -> bool {
+        if self.peers.contains_key(server) || self.server_name() == Some(server) {
+            return false;
+        }
+        let _ = sender.send(PeerMsg::Users {
+            users: self.usernames(),
+        });
+        self.peers.insert(
+            server.to_string(),
+            Peer {
+                sender,
+                users: vec![],
+            },
+        );
+        true
+    }
+
+    pub fn remove_peer(&mut self, server: &str) {
+        self.peers.remove(server);
+    }
+
+    /// Handles a message received from a linked peer.
+    pub fn handle_peer_msg(&mut self, server: &str, msg: PeerMsg) -> anyhow::Result<()> {
+        match msg {
+            PeerMsg::Hello { .. } => anyhow::bail!("Server {server} sent Hello twice"),
+            PeerMsg::Users { users } => {
+                if let Some(peer) = self.peers.get_mut(server) {
+                    peer.users = users;
+                }
+            }
+            PeerMsg::Dm { from, to, message } => {
+                let from = format!("{from}@{server}");
+                if let DmRoute::UnknownUser = self.route_local_dm(&from, &to, &message)? {
+                    log::debug!("Dropping a DM from {from} to unknown user {to}");
+                }
+            }
+            PeerMsg::Broadcast { from, message } => {
+                self.broadcast_local(&format!("{from}@{server}"), &message)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells all linked peers about the current local users.
+    fn publish_users(&self) {
+        if self.peers.is_empty() {
+            return;
+        }
+        let users = self.usernames();
+        for peer in self.peers.values() {
+            let _ = peer.sender.send(PeerMsg::Users {
+                users: users.clone(),
+            });
+        }
+    }
+
+    /// Sends a notification to `user`, if it has joined.
+    pub fn notify(&mut self, user: &str, msg: ServerToClientMsg) {
+        if let Some(user) = self.users.get_mut(user) {
+            user.send(msg);
+        }
+    }
+
+    /// Lets the plugins check a DM from a user of this server, and routes it unless a plugin
+    /// drops it.
+    pub fn route_dm(&mut self, from: &str, to: &str, message: &str) -> anyhow::Result<DmRoute> {
+        let mut ctx = Context::default();
+        let message = match filter(&mut self.plugins, &mut ctx, message, |plugin, msg, ctx| {
+            plugin.on_dm(from, to, msg, ctx)
+        }) {
+            Ok(message) => message,
+            Err(reason) => {
+                // A plugin can answer a message that it drops, e.g. to tell the author why
+                self.apply_actions(ctx)?;
+                return Ok(DmRoute::Dropped(reason));
+            }
+        };
+        let route = self.send_dm(from, to, &message)?;
+        self.apply_actions(ctx)?;
+        Ok(route)
+    }
+
+    /// Decides where a DM should go, stores it in the history and sends it to the recipient if
+    /// it has joined. With federation enabled, recipients qualified by the name of a linked peer
+    /// (`<user>@<server>`) get the DM through the peer. Without it, `@` is a part of the name.
+    fn send_dm(&mut self, from: &str, to: &str, message: &str) -> anyhow::Result<DmRoute> {
+        let qualified = to.rsplit_once('@').filter(|_| self.server_name().is_some());
+        let Some((user, server)) = qualified else {
+            return self.route_local_dm(from, to, message);
+        };
+        if self.server_name() == Some(server) {
+            return self.route_local_dm(from, user, message);
+        }
+        let Some(peer) = self
+            .peers
+            .get(server)
+            .filter(|peer| peer.users.iter().any(|name| name == user))
+        else {
+            return Ok(DmRoute::UnknownUser);
+        };
+        let _ = peer.sender.send(PeerMsg::Dm {
+            from: from.to_string(),
+            to: user.to_string(),
+            message: message.to_string(),
+        });
+        let id = match &mut self.history {
+            Some(history) => history.record_dm(from, to, message, true)?,
+            None => self.next_message_id(),
+        };
+        Ok(DmRoute::Online { id })
+    }
+
+    /// Routes a DM to a user of this server. A DM to a virtual user only reaches its plugin
+    /// through [`Plugin::on_dm`].
+    fn route_local_dm(&mut self, from: &str, to: &str, message: &str) -> anyhow::Result<DmRoute> {
+        if self.is_virtual_user(to) {
+            let id = match &mut self.history {
+                Some(history) => history.record_dm(from, to, message, true)?,
+                None => self.next_message_id(),
+            };
+            return Ok(DmRoute::Online { id });
+        }
+        let user = self.users.get(to);
+        let online = user.is_some();
+        let route = match &mut self.history {
+            None if online => DmRoute::Online {
+                id: self.next_message_id(),
+            },
+            None => DmRoute::UnknownUser,
+            Some(history) if !online && !history.is_known_user(to) => DmRoute::UnknownUser,
+            Some(history) => {
+                // A DM for a suspended user is stored as well, so that it is not lost when the
+                // backlog of the user overflows or its session expires
+                let connected =
+                    user.is_some_and(|user| matches!(user.connection, Connection::Online(_)));
+                let id = history.record_dm(from, to, message, connected)?;
+                match connected {
+                    true => DmRoute::Online { id },
+                    false => DmRoute::Stored { id },
+                }
+            }
+        };
+        if let DmRoute::Online { id } = route {
+            self.notify(
+                to,
+                ServerToClientMsg::Message {
+                    id,
+                    from: from.to_string(),
+                    message: message.to_string(),
+                },
+            );
+        }
+        Ok(route)
+    }
+
+    /// Lets the plugins check a broadcast from a user of this server, and sends it unless a
+    /// plugin drops it.
+    pub fn broadcast(&mut self, from: &str, message: &str) -> anyhow::Result<BroadcastRoute> {
+        let mut ctx = Context::default();
+        let message = match filter(&mut self.plugins, &mut ctx, message, |plugin, msg, ctx| {
+            plugin.on_broadcast(from, msg, ctx)
+        }) {
+            Ok(message) => message,
+            Err(reason) => {
+                self.apply_actions(ctx)?;
+                return Ok(BroadcastRoute::Dropped(reason));
+            }
+        };
+        let id = self.send_broadcast(from, &message)?;
+        self.apply_actions(ctx)?;
+        Ok(BroadcastRoute::Sent { id })
+    }
+
+    /// Stores a broadcast in the history, sends it to all joined users except for `from` and to
+    /// all linked peers, and returns its ID.
+    fn send_broadcast(&mut self, from: &str, message: &str) -> anyhow::Result<u64> {
+        let id = self.broadcast_local(from, message)?;
+        for peer in self.peers.values() {
+            let _ = peer.sender.send(PeerMsg::Broadcast {
+                from: from.to_string(),
+                message: message.to_string(),
+            });
+        }
+        Ok(id)
+    }
+
+    /// Like [`ServerState::send_broadcast`], but only for the users of this server.
+    fn broadcast_local(&mut self, from: &str, message: &str) -> anyhow::Result<u64> {
+        let id = match &mut self.history {
+            Some(history) => history.record_broadcast(from, message)?,
+            None => self.next_message_id(),
+        };
+        for (_, user) in self.users.iter_mut().filter(|(user, _)| *user != from) {
+            user.send(ServerToClientMsg::Message {
+                id,
+                from: from.to_string(),
+                message: message.to_string(),
+            });
+        }
+        Ok(id)
+    }
+
+    fn is_virtual_user(&self, name: &str) -> bool {
+        self.virtual_users.iter().any(|user| user == name)
+    }
+
+    /// Calls a hook of every plugin, and then sends the messages of the plugins.
+    fn run_hooks(
+        &mut self,
+        mut hook: impl FnMut(&mut dyn Plugin, &mut Context),
+    ) -> anyhow::Result<()> {
+        let mut ctx = Context::default();
+        for plugin in &mut self.plugins {
+            hook(plugin.as_mut(), &mut ctx);
+        }
+        self.apply_actions(ctx)
+    }
+
+    /// Sends the messages of plugins, without calling the hooks of plugins again.
+    fn apply_actions(&mut self, ctx: Context) -> anyhow::Result<()> {
+        for action in ctx.into_actions() {
+            match action {
+                Action::Dm { from, to, message } => {
+                    if let DmRoute::UnknownUser = self.send_dm(&from, &to, &message)? {
+                        log::debug!("Dropping a DM from plugin user {from} to unknown user {to}");
+                    }
+                }
+                Action::Broadcast { from, message } => {
+                    self.send_broadcast(&from, &message)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn next_message_id(&mut self) -> u64 {
+        self.last_message_id += 1;
+        self.last_message_id
+    }
+
+    /// Returns `None` if the history is not enabled.
+    pub fn history_page(
+        &self,
+        user: &str,
+        since: u64,
+        limit: usize,
+    ) -> Option<Vec<HistoryMessage>> {
+        self.history
+            .as_ref()
+            .map(|history| history.page(user, since, limit))
+    }
+}
+
+/// Sends a presence notification about `name` to all other subscribed users.
+fn announce(users: &mut HashMap<String, User>, name: &str, msg: ServerToClientMsg) {
+    for (_, user) in users
+        .iter_mut()
+        .filter(|(user, User { subscribed, .. })| *subscribed && *user != name)
+    {
+        user.send(msg.clone());
+    }
+}
+
+/// Passes a message through a hook of every plugin. Returns the message rewritten by the
+/// plugins, or the reason why a plugin has dropped it.
+fn filter(
+    plugins: &mut [Box<dyn Plugin>],
+    ctx: &mut Context,
+    message: &str,
+    mut hook: impl FnMut(&mut dyn Plugin, &str, &mut Context) -> Verdict,
+) -> Result<String, String> {
+    let mut message = message.to_string();
+    for plugin in plugins {
+        match hook(plugin.as_mut(), &message, ctx) {
+            Verdict::Pass => {}
+            Verdict::Rewrite(rewritten) => message = rewritten,
+            Verdict::Drop(reason) => return Err(reason),
+        }
+    }
+    Ok(message)
+}
+
+fn new_token() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
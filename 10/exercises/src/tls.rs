@@ -0,0 +1,30 @@
+use crate::TlsConfig;
+use anyhow::Context;
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::ServerConfig;
+use tokio_rustls::TlsAcceptor;
+
+/// Loads the certificate chain and the private key from PEM files.
+pub fn acceptor(config: &TlsConfig) -> anyhow::Result<TlsAcceptor> {
+    let certificates = CertificateDer::pem_file_iter(&config.certificate)
+        .and_then(|certificates| certificates.collect::<Result<Vec<_>, _>>())
+        .with_context(|| {
+            format!(
+                "Cannot read certificate file {}",
+                config.certificate.display()
+            )
+        })?;
+    if certificates.is_empty() {
+        anyhow::bail!("No certificate found in {}", config.certificate.display());
+    }
+    let key = PrivateKeyDer::from_pem_file(&config.key)
+        .with_context(|| format!("Cannot read key file {}", config.key.display()))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certificates, key)
+        .context("Invalid TLS certificate or key")?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
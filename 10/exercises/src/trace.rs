@@ -0,0 +1,229 @@
+use crate::jsonl;
+use crate::messages::{ClientToServerMsg, ServerToClientMsg};
+use crate::net::ListenAddr;
+use crate::ServerOpts;
+use anyhow::Context;
+use std::cell::{Cell, RefCell};
+use std::fs::File;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// A single line of a trace.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Record {
+    /// Time since the recording started, in microseconds.
+    pub at: u64,
+    pub event: Event,
+}
+
+/// Something that has happened in a recorded server.
+///
+/// Connections are numbered from 0 in the order in which the server has accepted them, the
+/// events of a connection refer to it by this number (`client`). Links to federated peers are
+/// not recorded.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub enum Event {
+    /// The first event of every trace.
+    Started(Settings),
+    /// The server has accepted a connection, even if it rejects it because it is full.
+    Connected {
+        client: u64,
+    },
+    /// The server has read a message from the client.
+    Received {
+        client: u64,
+        msg: ClientToServerMsg,
+    },
+    /// The client has sent a message larger than the maximum message size.
+    TooLarge {
+        client: u64,
+    },
+    /// The client has closed the connection.
+    Closed {
+        client: u64,
+    },
+    /// Reading from the client has failed, e.g. because the connection was broken or the client
+    /// has sent something else than a message.
+    Lost {
+        client: u64,
+        error: String,
+    },
+    /// The server has sent a message to the client.
+    Sent {
+        client: u64,
+        msg: ServerToClientMsg,
+    },
+    Timer {
+        client: u64,
+        timer: Timer,
+    },
+    /// The server has started to shut down.
+    Stopped,
+}
+
+/// A timeout of a connection.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum Timer {
+    /// The client has not joined within the join timeout.
+    Join,
+    /// The client has not sent or received anything within the idle timeout.
+    Idle,
+    /// The session of the client was not resumed within the resume grace period after its
+    /// connection was lost.
+    ResumeGrace,
+}
+
+/// Options of the recorded server that affect how it behaves.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub max_clients: usize,
+    pub join_timeout: Duration,
+    pub idle_timeout: Duration,
+    pub max_message_size: usize,
+    pub resume_grace: Duration,
+    /// Was the history enabled.
+    pub history: bool,
+}
+
+impl Settings {
+    fn new(opts: &ServerOpts) -> Self {
+        Self {
+            max_clients: opts.max_clients,
+            join_timeout: opts.join_timeout,
+            idle_timeout: opts.idle_timeout,
+            max_message_size: opts.max_message_size,
+            resume_grace: opts.resume_grace,
+            history: opts.history.is_some(),
+        }
+    }
+
+    /// Options of a server that behaves like the recorded one, without TLS, metrics, federation
+    /// and plugins. If the recorded server had the history enabled, the replayed server needs a
+    /// `history` file with the history as it was when the recording started.
+    pub fn server_opts(&self, history: Option<PathBuf>) -> anyhow::Result<ServerOpts> {
+        let history = match (self.history, history) {
+            (true, None) => anyhow::bail!("The recorded server had the history enabled"),
+            (false, Some(_)) => anyhow::bail!("The recorded server had the history disabled"),
+            (_, history) => history,
+        };
+        Ok(ServerOpts {
+            listen: vec![ListenAddr::Tcp(SocketAddr::from(([127, 0, 0, 1], 0)))],
+            max_clients: self.max_clients,
+            history,
+            join_timeout: self.join_timeout,
+            idle_timeout: self.idle_timeout,
+            max_message_size: self.max_message_size,
+            resume_grace: self.resume_grace,
+            tls: None,
+            metrics: None,
+            federation: None,
+            plugins: vec![],
+            record: None,
+        })
+    }
+}
+
+enum Sink {
+    File(File),
+    /// Used by the replay, which compares the trace with the original one.
+    #[cfg(any(test, feature = "replay"))]
+    Memory(Vec<Record>),
+}
+
+/// Writes the events of a server to a trace.
+pub(crate) struct Recorder {
+    start: Instant,
+    sink: RefCell<Sink>,
+    next_client: Cell<u64>,
+}
+
+impl Recorder {
+    /// Replaces the file if it exists.
+    pub fn create(path: &Path, opts: &ServerOpts) -> anyhow::Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("Cannot create trace file {}", path.display()))?;
+        Ok(Self::new(Sink::File(file), opts))
+    }
+
+    #[cfg(any(test, feature = "replay"))]
+    pub fn in_memory(opts: &ServerOpts) -> Self {
+        Self::new(Sink::Memory(vec![]), opts)
+    }
+
+    fn new(sink: Sink, opts: &ServerOpts) -> Self {
+        let recorder = Self {
+            start: Instant::now(),
+            sink: RefCell::new(sink),
+            next_client: Cell::new(0),
+        };
+        recorder.record(Event::Started(Settings::new(opts)));
+        recorder
+    }
+
+    /// A trace that cannot be written does not stop the server.
+    pub fn record(&self, event: Event) {
+        let record = Record {
+            at: self.start.elapsed().as_micros() as u64,
+            event,
+        };
+        match &mut *self.sink.borrow_mut() {
+            Sink::File(file) => {
+                if let Err(error) = jsonl::append(file, &record) {
+                    log::warn!("Cannot write trace: {error}");
+                }
+            }
+            #[cfg(any(test, feature = "replay"))]
+            Sink::Memory(records) => records.push(record),
+        }
+    }
+
+    /// Returns the records of an in-memory trace.
+    #[cfg(any(test, feature = "replay"))]
+    pub fn records(&self) -> Vec<Record> {
+        match &*self.sink.borrow() {
+            Sink::File(_) => vec![],
+            Sink::Memory(records) => records.clone(),
+        }
+    }
+}
+
+/// Records the events of a single connection. Does nothing if the recording is disabled.
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionTrace {
+    recorder: Option<Rc<Recorder>>,
+    client: u64,
+}
+
+impl ConnectionTrace {
+    /// Records a new connection.
+    pub fn connected(recorder: Option<&Rc<Recorder>>) -> Self {
+        let Some(recorder) = recorder else {
+            return Self::default();
+        };
+        let client = recorder.next_client.get();
+        recorder.next_client.set(client + 1);
+        recorder.record(Event::Connected { client });
+        Self {
+            recorder: Some(recorder.clone()),
+            client,
+        }
+    }
+
+    /// The event is only created when the recording is enabled.
+    pub fn record(&self, event: impl FnOnce(u64) -> Event) {
+        if let Some(recorder) = &self.recorder {
+            recorder.record(event(self.client));
+        }
+    }
+}
+
+/// Reads a trace written by a server with [`ServerOpts::record`]. The last record is left out if
+/// the server has crashed or is still running and has not written it whole.
+pub fn read_trace(path: &Path) -> anyhow::Result<Vec<Record>> {
+    let file = File::open(path).with_context(|| format!("Cannot open trace {}", path.display()))?;
+    let (records, _) = jsonl::read(file, "trace")?;
+    Ok(records)
+}
@@ -0,0 +1,152 @@
+use crate::reader::MessageTooLarge;
+use futures_util::{ready, Sink, Stream};
+use std::io::ErrorKind;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::tungstenite::error::CapacityError;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+use tokio_tungstenite::tungstenite::{Error, Message};
+use tokio_tungstenite::WebSocketStream;
+
+/// Performs the server side of the WebSocket handshake. Frames and messages larger than
+/// `max_message_size` are rejected before they are buffered.
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    max_message_size: usize,
+) -> anyhow::Result<WebSocketLines<S>> {
+    let config = WebSocketConfig::default()
+        .max_message_size(Some(max_message_size))
+        .max_frame_size(Some(max_message_size));
+    Ok(WebSocketLines::new(
+        tokio_tungstenite::accept_async_with_config(stream, Some(config)).await?,
+    ))
+}
+
+/// Presents a WebSocket connection as a stream of newline-delimited messages, so that WebSocket
+/// clients can be served by the same code as the other clients.
+///
+/// Every text frame carries one message. Reading yields the content of each received frame
+/// followed by a newline, and every line written is sent as a separate text frame.
+///
+/// A frame that is too large is reported as [`MessageTooLarge`]. Its payload cannot be skipped,
+/// so reading ends after it.
+pub struct WebSocketLines<S> {
+    socket: WebSocketStream<S>,
+    /// Received bytes that were not read yet.
+    incoming: Vec<u8>,
+    read: usize,
+    /// Was a frame too large, so that nothing more can be read?
+    truncated: bool,
+    /// Written bytes that were not sent in a frame yet.
+    outgoing: Vec<u8>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> WebSocketLines<S> {
+    pub fn new(socket: WebSocketStream<S>) -> Self {
+        Self {
+            socket,
+            incoming: vec![],
+            read: 0,
+            truncated: false,
+            outgoing: vec![],
+        }
+    }
+
+    /// Sends every complete line of the written bytes as a text frame.
+    fn poll_send_lines(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while let Some(position) = self.outgoing.iter().position(|c| *c == b'\n') {
+            ready!(Pin::new(&mut self.socket).poll_ready(cx)).map_err(std::io::Error::other)?;
+            let line: Vec<u8> = self.outgoing.drain(..=position).take(position).collect();
+            let text = String::from_utf8(line)
+                .map_err(|error| std::io::Error::new(ErrorKind::InvalidData, error))?;
+            Pin::new(&mut self.socket)
+                .start_send(Message::text(text))
+                .map_err(std::io::Error::other)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WebSocketLines<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        while this.read == this.incoming.len() {
+            if this.truncated {
+                return Poll::Ready(Ok(()));
+            }
+            let message = match ready!(Pin::new(&mut this.socket).poll_next(cx)) {
+                Some(Ok(message)) => message,
+                Some(Err(Error::Capacity(CapacityError::MessageTooLong { .. }))) => {
+                    this.truncated = true;
+                    return Poll::Ready(Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        MessageTooLarge,
+                    )));
+                }
+                Some(Err(error)) => return Poll::Ready(Err(std::io::Error::other(error))),
+                None => return Poll::Ready(Ok(())),
+            };
+            match message {
+                // A newline is only allowed as whitespace between the tokens of a JSON
+                // document, so it can be replaced by a space
+                Message::Text(text) => {
+                    this.incoming = text
+                        .as_bytes()
+                        .iter()
+                        .map(|c| if *c == b'\n' { b' ' } else { *c })
+                        .chain([b'\n'])
+                        .collect();
+                    this.read = 0;
+                }
+                Message::Binary(_) => {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        ErrorKind::InvalidData,
+                        "Binary WebSocket frames are not supported",
+                    )));
+                }
+                Message::Close(_) => return Poll::Ready(Ok(())),
+                // Pings are answered by the WebSocket library
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+            }
+        }
+        let count = buf.remaining().min(this.incoming.len() - this.read);
+        buf.put_slice(&this.incoming[this.read..this.read + count]);
+        this.read += count;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WebSocketLines<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        // Send the previous lines first, so that the buffer does not grow without bounds
+        ready!(this.poll_send_lines(cx))?;
+        this.outgoing.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_lines(cx))?;
+        Pin::new(&mut this.socket)
+            .poll_flush(cx)
+            .map_err(std::io::Error::other)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_send_lines(cx))?;
+        Pin::new(&mut this.socket)
+            .poll_close(cx)
+            .map_err(std::io::Error::other)
+    }
+}
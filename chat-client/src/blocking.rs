@@ -0,0 +1,145 @@
+use crate::codec::{self, FrameBuffer};
+use crate::messages::{ClientToServerMsg, HistoryMessage, ServerToClientMsg};
+use crate::{classify, Error, Event, Expected, Framing, Incoming, Welcome};
+use std::collections::VecDeque;
+use std::io::{Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Connection to a chat server using blocking I/O.
+pub struct ChatClient {
+    stream: TcpStream,
+    framing: Framing,
+    frames: FrameBuffer,
+    /// Events received while waiting for a reply, returned by [`ChatClient::next_event`] first.
+    events: VecDeque<Event>,
+}
+
+impl ChatClient {
+    pub fn connect(address: impl ToSocketAddrs, framing: Framing) -> Result<Self, Error> {
+        let stream = TcpStream::connect(address)?;
+        Ok(Self {
+            stream,
+            framing,
+            frames: FrameBuffer::new(framing),
+            events: VecDeque::new(),
+        })
+    }
+
+    /// Limits how long the client waits for a message, see [`TcpStream::set_read_timeout`].
+    /// A read that times out returns [`Error::Io`], and it can be retried without losing data.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        Ok(self.stream.set_read_timeout(timeout)?)
+    }
+
+    /// Joins the server. A rejected `Join` (e.g. "Username already taken") returns
+    /// [`Error::Server`], after which the server closes the connection.
+    pub fn join(&mut self, name: &str) -> Result<Welcome, Error> {
+        let msg = ClientToServerMsg::Join {
+            name: name.to_string(),
+        };
+        self.request(msg, Expected::Welcome)
+            .map(crate::into_welcome)
+    }
+
+    pub fn send_dm(&mut self, to: &str, message: &str) -> Result<(), Error> {
+        self.send(ClientToServerMsg::SendDM {
+            to: to.to_string(),
+            message: message.to_string(),
+        })
+    }
+
+    pub fn broadcast(&mut self, message: &str) -> Result<(), Error> {
+        self.send(ClientToServerMsg::Broadcast {
+            message: message.to_string(),
+        })
+    }
+
+    /// Acknowledges a message received in [`Event::Message`].
+    pub fn ack(&mut self, id: u64) -> Result<(), Error> {
+        self.send(ClientToServerMsg::Ack { id })
+    }
+
+    /// Checks that the connection is OK.
+    pub fn ping(&mut self) -> Result<(), Error> {
+        self.request(ClientToServerMsg::Ping, Expected::Pong)
+            .map(|_| ())
+    }
+
+    pub fn list_users(&mut self) -> Result<Vec<String>, Error> {
+        self.request(ClientToServerMsg::ListUsers, Expected::UserList)
+            .map(crate::into_users)
+    }
+
+    /// Returns a page of the history with messages newer than `since`. Returns
+    /// [`Error::Server`] if the history of the server is disabled.
+    pub fn history(&mut self, since: u64, limit: usize) -> Result<Vec<HistoryMessage>, Error> {
+        self.request(
+            ClientToServerMsg::History { since, limit },
+            Expected::History,
+        )
+        .map(crate::into_history)
+    }
+
+    /// Returns the next event, waiting for it if necessary. Returns `None` once the server has
+    /// closed the connection.
+    pub fn next_event(&mut self) -> Result<Option<Event>, Error> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(Some(event));
+        }
+        match self.receive(None) {
+            Ok(Incoming::Event(event)) => Ok(Some(event)),
+            Ok(Incoming::Reply(_)) => unreachable!("no reply was expected"),
+            Err(Error::Closed) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Iterates over the events until the server closes the connection.
+    pub fn events(&mut self) -> impl Iterator<Item = Result<Event, Error>> + '_ {
+        std::iter::from_fn(|| self.next_event().transpose())
+    }
+
+    /// Sends any message, e.g. one that has no method in this client.
+    pub fn send(&mut self, msg: ClientToServerMsg) -> Result<(), Error> {
+        let frame = codec::encode(&msg, self.framing)?;
+        self.stream.write_all(&frame)?;
+        Ok(())
+    }
+
+    /// Closes the connection.
+    pub fn close(self) -> Result<(), Error> {
+        Ok(self.stream.shutdown(Shutdown::Both)?)
+    }
+
+    /// Sends a request and waits for its reply, keeping the events received in the meantime.
+    fn request(
+        &mut self,
+        msg: ClientToServerMsg,
+        expected: Expected,
+    ) -> Result<ServerToClientMsg, Error> {
+        self.send(msg)?;
+        loop {
+            match self.receive(Some(expected))? {
+                Incoming::Reply(reply) => return Ok(reply),
+                Incoming::Event(event) => self.events.push_back(event),
+            }
+        }
+    }
+
+    fn receive(&mut self, expected: Option<Expected>) -> Result<Incoming, Error> {
+        let mut chunk = [0; 4096];
+        loop {
+            if let Some(decoded) = self.frames.next() {
+                return classify(decoded?, expected);
+            }
+            match self.stream.read(&mut chunk)? {
+                0 if self.frames.is_empty() => return Err(Error::Closed),
+                0 => {
+                    return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+                }
+                read => self.frames.extend(&chunk[..read]),
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use crate::messages::{ClientToServerMsg, ServerToClientMsg};
+use crate::{Error, Framing};
+
+/// Largest frame accepted from a length-prefixed server, so that a corrupted length cannot make
+/// the client allocate gigabytes.
+const MAX_FRAME_SIZE: usize = 64 * 1024 * 1024;
+
+/// Something received from the server that could be decoded.
+pub(crate) enum Decoded {
+    Msg(ServerToClientMsg),
+    /// Valid JSON that is not a message known to this library.
+    Unknown(serde_json::Value),
+}
+
+pub(crate) fn encode(msg: &ClientToServerMsg, framing: Framing) -> Result<Vec<u8>, Error> {
+    let serialized = serde_json::to_vec(msg)?;
+    let mut frame = Vec::with_capacity(serialized.len() + 4);
+    match framing {
+        Framing::LengthPrefixed => {
+            frame.extend_from_slice(&(serialized.len() as u32).to_le_bytes());
+            frame.extend_from_slice(&serialized);
+        }
+        Framing::NewlineDelimited => {
+            frame.extend_from_slice(&serialized);
+            frame.push(b'\n');
+        }
+    }
+    Ok(frame)
+}
+
+/// Collects the bytes received from the server and splits them into frames.
+///
+/// The bytes are kept here between reads, so reading can be interrupted (e.g. by a timeout or by
+/// dropping a future) at any point without losing data.
+pub(crate) struct FrameBuffer {
+    framing: Framing,
+    buffer: Vec<u8>,
+}
+
+impl FrameBuffer {
+    pub(crate) fn new(framing: Framing) -> Self {
+        Self {
+            framing,
+            buffer: vec![],
+        }
+    }
+
+    pub(crate) fn extend(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Returns `false` if some bytes of an incomplete frame were received.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Removes the next complete frame from the buffer and decodes it.
+    pub(crate) fn next(&mut self) -> Option<Result<Decoded, Error>> {
+        let (start, end) = match self.framing {
+            Framing::LengthPrefixed => {
+                let size: [u8; 4] = self.buffer.get(..4)?.try_into().unwrap();
+                let size = u32::from_le_bytes(size) as usize;
+                if size > MAX_FRAME_SIZE {
+                    self.buffer.clear();
+                    return Some(Err(Error::Unexpected(format!(
+                        "Frame of {size} bytes is too large"
+                    ))));
+                }
+                if self.buffer.len() < 4 + size {
+                    return None;
+                }
+                (4, 4 + size)
+            }
+            Framing::NewlineDelimited => {
+                let position = self.buffer.iter().position(|c| *c == b'\n')?;
+                (0, position + 1)
+            }
+        };
+        let decoded = decode(&self.buffer[start..end]);
+        self.buffer.drain(..end);
+        Some(decoded)
+    }
+}
+
+fn decode(frame: &[u8]) -> Result<Decoded, Error> {
+    match serde_json::from_slice(frame) {
+        Ok(msg) => Ok(Decoded::Msg(msg)),
+        Err(error) => match serde_json::from_slice(frame) {
+            Ok(value) => Ok(Decoded::Unknown(value)),
+            Err(_) => Err(Error::Decode(error)),
+        },
+    }
+}
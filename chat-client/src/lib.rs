@@ -0,0 +1,453 @@
+//! Client library for the chat servers from weeks 08, 09 and 10.
+//!
+//! There is a blocking client in [`blocking`] and an asynchronous one in [`tokio`] (behind the
+//! `tokio` feature, enabled by default). Both have the same API:
+//!
+//! ```no_run
+//! use chat_client::blocking::ChatClient;
+//! use chat_client::{Event, Framing};
+//!
+//! # fn main() -> Result<(), chat_client::Error> {
+//! let mut client = ChatClient::connect("127.0.0.1:5000", Framing::NewlineDelimited)?;
+//! client.join("bot")?;
+//! println!("Users: {:?}", client.list_users()?);
+//! while let Some(event) = client.next_event()? {
+//!     if let Event::Message { from, message, .. } = event {
+//!         client.send_dm(&from, &format!("You said: {message}"))?;
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Requests with a reply ([`blocking::ChatClient::list_users`] etc.) wait for the reply. Messages
+//! that the server pushes in the meantime (e.g. DMs from other users) are kept and returned by
+//! `next_event` afterwards, in the order in which they were received.
+//!
+//! The servers do not say which request an error belongs to, so errors caused by `send_dm` and
+//! `broadcast` (e.g. "User <name> does not exist") are returned as [`Event::Error`].
+//!
+//! The client always uses JSON to encode the messages.
+
+use crate::messages::{HistoryMessage, ServerToClientMsg};
+use std::fmt::{Display, Formatter};
+
+/// The client using blocking I/O from `std::net`
+pub mod blocking;
+/// Encoding and framing of the messages
+mod codec;
+/// Messages of the chat protocol
+pub mod messages;
+/// The client using tokio
+#[cfg(feature = "tokio")]
+pub mod tokio;
+
+/// How are the messages delimited on the wire.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Framing {
+    /// Every message is prefixed by its size as a little-endian `u32` (week 08).
+    LengthPrefixed,
+    /// Every message is terminated by a newline (weeks 09 and 10).
+    NewlineDelimited,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(std::io::Error),
+    /// The server sent something that is not JSON.
+    Decode(serde_json::Error),
+    /// The server rejected a request, e.g. "Username already taken".
+    Server(String),
+    /// The server sent a message that does not fit the conversation, e.g. a reply to a request
+    /// that was not sent.
+    Unexpected(String),
+    /// The server closed the connection.
+    Closed,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "I/O error: {error}"),
+            Error::Decode(error) => write!(f, "Cannot decode message: {error}"),
+            Error::Server(error) => write!(f, "Server error: {error}"),
+            Error::Unexpected(msg) => write!(f, "Unexpected message: {msg}"),
+            Error::Closed => write!(f, "The server closed the connection"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(error) => Some(error),
+            Error::Decode(error) => Some(error),
+            Error::Server(_) | Error::Unexpected(_) | Error::Closed => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(error: std::io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(error: serde_json::Error) -> Self {
+        Error::Decode(error)
+    }
+}
+
+/// Response to `Join`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Welcome {
+    /// Maximum size of a single message (in bytes) that the server accepts.
+    pub max_message_size: usize,
+    /// Token that resumes the session, if the server supports resuming.
+    pub token: Option<String>,
+}
+
+/// A message pushed by the server, which is not a reply to a request.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// A DM or a broadcast from another user. The `id` is only sent by servers that send
+    /// receipts, which expect it to be acknowledged with `ack`.
+    Message {
+        id: Option<u64>,
+        from: String,
+        message: String,
+    },
+    /// An error, e.g. a DM could not be sent.
+    Error(String),
+    /// A DM or a broadcast sent by this client was accepted.
+    Accepted {
+        id: u64,
+    },
+    /// A DM or a broadcast sent by this client was delivered to `to`.
+    Delivered {
+        id: u64,
+        to: String,
+    },
+    /// A DM or a broadcast sent by this client was acknowledged by `by`.
+    Read {
+        id: u64,
+        by: String,
+    },
+    UserJoined {
+        name: String,
+    },
+    UserLeft {
+        name: String,
+        reason: String,
+    },
+    /// A message that this library does not know, e.g. a room message of the week08 server.
+    Unknown(serde_json::Value),
+}
+
+/// Reply that a request is waiting for.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Expected {
+    Welcome,
+    Pong,
+    UserList,
+    History,
+}
+
+/// What to do with a message received from the server.
+enum Incoming {
+    /// The reply the client was waiting for.
+    Reply(ServerToClientMsg),
+    Event(Event),
+}
+
+/// Sorts a decoded message into a reply to the request waiting for `expected`, or an event.
+fn classify(decoded: codec::Decoded, expected: Option<Expected>) -> Result<Incoming, Error> {
+    let msg = match decoded {
+        codec::Decoded::Msg(msg) => msg,
+        codec::Decoded::Unknown(value) => return Ok(Incoming::Event(Event::Unknown(value))),
+    };
+    let event = match msg {
+        ServerToClientMsg::Message { id, from, message } => Event::Message { id, from, message },
+        // Nothing else can be sent before `Welcome`, so the error belongs to `Join`
+        ServerToClientMsg::Error(error) if expected == Some(Expected::Welcome) => {
+            return Err(Error::Server(error))
+        }
+        ServerToClientMsg::Error(error)
+            if expected == Some(Expected::History) && error == "History is not enabled" =>
+        {
+            return Err(Error::Server(error))
+        }
+        ServerToClientMsg::Error(error) => Event::Error(error),
+        ServerToClientMsg::Accepted { id } => Event::Accepted { id },
+        ServerToClientMsg::Delivered { id, to } => Event::Delivered { id, to },
+        ServerToClientMsg::Read { id, by } => Event::Read { id, by },
+        ServerToClientMsg::UserJoined { name } => Event::UserJoined { name },
+        ServerToClientMsg::UserLeft { name, reason } => Event::UserLeft { name, reason },
+        reply => {
+            let kind = match reply {
+                ServerToClientMsg::Welcome { .. } => Expected::Welcome,
+                ServerToClientMsg::Pong => Expected::Pong,
+                ServerToClientMsg::UserList { .. } => Expected::UserList,
+                _ => Expected::History,
+            };
+            if expected != Some(kind) {
+                return Err(Error::Unexpected(format!("{reply:?}")));
+            }
+            return Ok(Incoming::Reply(reply));
+        }
+    };
+    Ok(Incoming::Event(event))
+}
+
+fn into_welcome(reply: ServerToClientMsg) -> Welcome {
+    match reply {
+        ServerToClientMsg::Welcome {
+            max_message_size,
+            token,
+        } => Welcome {
+            max_message_size,
+            token,
+        },
+        _ => unreachable!("the reply was classified as Welcome"),
+    }
+}
+
+fn into_users(reply: ServerToClientMsg) -> Vec<String> {
+    match reply {
+        ServerToClientMsg::UserList { users } => users,
+        _ => unreachable!("the reply was classified as UserList"),
+    }
+}
+
+fn into_history(reply: ServerToClientMsg) -> Vec<HistoryMessage> {
+    match reply {
+        ServerToClientMsg::History { messages } => messages,
+        _ => unreachable!("the reply was classified as History"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blocking::ChatClient;
+    use crate::messages::ClientToServerMsg;
+    use crate::{Error, Event, Framing, Welcome};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn reply_after_pushed_messages() {
+        let address = fake_server(Framing::NewlineDelimited, |mut server| {
+            server.expect(ClientToServerMsg::Join {
+                name: "Mia".to_string(),
+            });
+            server.send(r#"{"Welcome":{"max_message_size":100}}"#);
+            server.expect(ClientToServerMsg::ListUsers);
+            server.send(r#"{"Message":{"from":"Leo","message":"Hi"}}"#);
+            server.send(r#"{"Error":"User Eva does not exist"}"#);
+            server.send(r#"{"UserList":{"users":["Mia","Leo"]}}"#);
+        });
+        let mut client = ChatClient::connect(address, Framing::NewlineDelimited).unwrap();
+        assert_eq!(
+            client.join("Mia").unwrap(),
+            Welcome {
+                max_message_size: 100,
+                token: None
+            }
+        );
+        assert_eq!(client.list_users().unwrap(), vec!["Mia", "Leo"]);
+        let events: Vec<Event> = client.events().map(|event| event.unwrap()).collect();
+        assert_eq!(
+            events,
+            vec![
+                Event::Message {
+                    id: None,
+                    from: "Leo".to_string(),
+                    message: "Hi".to_string()
+                },
+                Event::Error("User Eva does not exist".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn join_rejected() {
+        let address = fake_server(Framing::NewlineDelimited, |mut server| {
+            server.read();
+            server.send(r#"{"Error":"Username already taken"}"#);
+        });
+        let mut client = ChatClient::connect(address, Framing::NewlineDelimited).unwrap();
+        assert!(matches!(
+            client.join("Mia"),
+            Err(Error::Server(error)) if error == "Username already taken"
+        ));
+    }
+
+    #[test]
+    fn length_prefixed_framing_with_receipts() {
+        let address = fake_server(Framing::LengthPrefixed, |mut server| {
+            server.read();
+            server.send(r#"{"Welcome":{"max_message_size":100,"token":"abc"}}"#);
+            server.expect(ClientToServerMsg::SendDM {
+                to: "Leo".to_string(),
+                message: "Hi".to_string(),
+            });
+            server.send(r#"{"Accepted":{"id":1}}"#);
+            server.send(r#"{"Message":{"id":2,"from":"Leo","message":"Hello"}}"#);
+            server.expect(ClientToServerMsg::Ack { id: 2 });
+        });
+        let mut client = ChatClient::connect(address, Framing::LengthPrefixed).unwrap();
+        assert_eq!(client.join("Mia").unwrap().token.as_deref(), Some("abc"));
+        client.send_dm("Leo", "Hi").unwrap();
+        assert_eq!(
+            client.next_event().unwrap(),
+            Some(Event::Accepted { id: 1 })
+        );
+        assert_eq!(
+            client.next_event().unwrap(),
+            Some(Event::Message {
+                id: Some(2),
+                from: "Leo".to_string(),
+                message: "Hello".to_string()
+            })
+        );
+        client.ack(2).unwrap();
+        assert_eq!(client.next_event().unwrap(), None);
+    }
+
+    #[test]
+    fn unknown_and_unexpected_messages() {
+        let address = fake_server(Framing::NewlineDelimited, |mut server| {
+            server.read();
+            server.send(r#"{"Welcome":{"max_message_size":100}}"#);
+            server.send(r#"{"RoomJoined":{"room":"rust"}}"#);
+            server.send(r#""Pong""#);
+            server.expect(ClientToServerMsg::History {
+                since: 0,
+                limit: 10,
+            });
+            server.send(r#"{"Error":"History is not enabled"}"#);
+            server.send("not json");
+        });
+        let mut client = ChatClient::connect(address, Framing::NewlineDelimited).unwrap();
+        client.join("Mia").unwrap();
+        assert_eq!(
+            client.next_event().unwrap(),
+            Some(Event::Unknown(
+                serde_json::json!({"RoomJoined": {"room": "rust"}})
+            ))
+        );
+        assert!(matches!(client.next_event(), Err(Error::Unexpected(_))));
+        assert!(matches!(
+            client.history(0, 10),
+            Err(Error::Server(error)) if error == "History is not enabled"
+        ));
+        assert!(matches!(client.next_event(), Err(Error::Decode(_))));
+    }
+
+    #[tokio::test]
+    async fn tokio_next_event_is_cancel_safe() {
+        let (resume_tx, resume_rx) = mpsc::channel();
+        let address = fake_server(Framing::NewlineDelimited, move |mut server| {
+            server.read();
+            server.send(r#"{"Welcome":{"max_message_size":100}}"#);
+            server.expect(ClientToServerMsg::Ping);
+            server.send(r#"{"UserJoined":{"name":"Leo"}}"#);
+            server.send(r#""Pong""#);
+            server.write(br#"{"Message":{"from":"Leo","#);
+            resume_rx.recv().unwrap();
+            server.write(b"\"message\":\"Hi\"}}\n");
+        });
+        let mut client = crate::tokio::ChatClient::connect(address, Framing::NewlineDelimited)
+            .await
+            .unwrap();
+        client.join("Mia").await.unwrap();
+        client.ping().await.unwrap();
+        assert_eq!(
+            client.next_event().await.unwrap(),
+            Some(Event::UserJoined {
+                name: "Leo".to_string()
+            })
+        );
+        let timeout = Duration::from_millis(100);
+        assert!(::tokio::time::timeout(timeout, client.next_event())
+            .await
+            .is_err());
+        resume_tx.send(()).unwrap();
+        assert_eq!(
+            client.next_event().await.unwrap(),
+            Some(Event::Message {
+                id: None,
+                from: "Leo".to_string(),
+                message: "Hi".to_string()
+            })
+        );
+        assert_eq!(client.next_event().await.unwrap(), None);
+    }
+
+    /// Server side of a connection, which follows a script.
+    struct FakeServer {
+        reader: BufReader<TcpStream>,
+        stream: TcpStream,
+        framing: Framing,
+    }
+
+    impl FakeServer {
+        fn read(&mut self) -> ClientToServerMsg {
+            let frame = match self.framing {
+                Framing::LengthPrefixed => {
+                    let mut size = [0; 4];
+                    self.reader.read_exact(&mut size).unwrap();
+                    let mut frame = vec![0; u32::from_le_bytes(size) as usize];
+                    self.reader.read_exact(&mut frame).unwrap();
+                    frame
+                }
+                Framing::NewlineDelimited => {
+                    let mut frame = vec![];
+                    self.reader.read_until(b'\n', &mut frame).unwrap();
+                    frame
+                }
+            };
+            serde_json::from_slice(&frame).unwrap()
+        }
+
+        fn expect(&mut self, expected: ClientToServerMsg) {
+            assert_eq!(self.read(), expected);
+        }
+
+        /// Sends a message as a complete frame, without checking that it is valid JSON.
+        fn send(&mut self, msg: &str) {
+            match self.framing {
+                Framing::LengthPrefixed => {
+                    self.write(&(msg.len() as u32).to_le_bytes());
+                    self.write(msg.as_bytes());
+                }
+                Framing::NewlineDelimited => self.write(format!("{msg}\n").as_bytes()),
+            }
+        }
+
+        fn write(&mut self, data: &[u8]) {
+            self.stream.write_all(data).unwrap();
+        }
+    }
+
+    /// Accepts a single client and runs the script, then closes the connection.
+    fn fake_server(
+        framing: Framing,
+        script: impl FnOnce(FakeServer) + Send + 'static,
+    ) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            script(FakeServer {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                stream,
+                framing,
+            });
+        });
+        address
+    }
+}
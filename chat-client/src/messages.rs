@@ -0,0 +1,80 @@
+//! Messages of the chat protocol, as sent on the wire.
+//!
+//! The servers of the individual weeks speak slightly different dialects of the protocol, so the
+//! fields that only some of them send are optional.
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub enum ClientToServerMsg {
+    Join {
+        name: String,
+    },
+    Ping,
+    ListUsers,
+    SendDM {
+        to: String,
+        message: String,
+    },
+    Broadcast {
+        message: String,
+    },
+    History {
+        since: u64,
+        limit: usize,
+    },
+    /// Only understood by servers that send receipts (week 10).
+    Ack {
+        id: u64,
+    },
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub enum ServerToClientMsg {
+    Welcome {
+        max_message_size: usize,
+        /// Token that resumes the session, sent only by servers that support resuming.
+        #[serde(default)]
+        token: Option<String>,
+    },
+    Pong,
+    UserList {
+        users: Vec<String>,
+    },
+    Message {
+        /// Sent only by servers that send receipts.
+        #[serde(default)]
+        id: Option<u64>,
+        from: String,
+        message: String,
+    },
+    History {
+        messages: Vec<HistoryMessage>,
+    },
+    Error(String),
+    Accepted {
+        id: u64,
+    },
+    Delivered {
+        id: u64,
+        to: String,
+    },
+    Read {
+        id: u64,
+        by: String,
+    },
+    UserJoined {
+        name: String,
+    },
+    UserLeft {
+        name: String,
+        reason: String,
+    },
+}
+
+/// A broadcast or a DM stored in the history of the server.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryMessage {
+    pub id: u64,
+    pub from: String,
+    pub to: Option<String>,
+    pub message: String,
+}
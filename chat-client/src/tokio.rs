@@ -0,0 +1,151 @@
+use crate::codec::{self, FrameBuffer};
+use crate::messages::{ClientToServerMsg, HistoryMessage, ServerToClientMsg};
+use crate::{classify, Error, Event, Expected, Framing, Incoming, Welcome};
+use ::tokio::io::{AsyncReadExt, AsyncWriteExt};
+use ::tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use ::tokio::net::{TcpStream, ToSocketAddrs};
+use std::collections::VecDeque;
+
+/// Connection to a chat server using tokio.
+///
+/// [`ChatClient::next_event`] is cancel safe, so it can wait in `tokio::select!` next to other
+/// futures. The other methods are not, a request that was cancelled might leave its reply
+/// behind, which is then returned as [`Error::Unexpected`].
+pub struct ChatClient {
+    reader: OwnedReadHalf,
+    writer: OwnedWriteHalf,
+    framing: Framing,
+    frames: FrameBuffer,
+    /// Events received while waiting for a reply, returned by [`ChatClient::next_event`] first.
+    events: VecDeque<Event>,
+}
+
+impl ChatClient {
+    pub async fn connect(address: impl ToSocketAddrs, framing: Framing) -> Result<Self, Error> {
+        let (reader, writer) = TcpStream::connect(address).await?.into_split();
+        Ok(Self {
+            reader,
+            writer,
+            framing,
+            frames: FrameBuffer::new(framing),
+            events: VecDeque::new(),
+        })
+    }
+
+    /// Joins the server. A rejected `Join` (e.g. "Username already taken") returns
+    /// [`Error::Server`], after which the server closes the connection.
+    pub async fn join(&mut self, name: &str) -> Result<Welcome, Error> {
+        let msg = ClientToServerMsg::Join {
+            name: name.to_string(),
+        };
+        self.request(msg, Expected::Welcome)
+            .await
+            .map(crate::into_welcome)
+    }
+
+    pub async fn send_dm(&mut self, to: &str, message: &str) -> Result<(), Error> {
+        self.send(ClientToServerMsg::SendDM {
+            to: to.to_string(),
+            message: message.to_string(),
+        })
+        .await
+    }
+
+    pub async fn broadcast(&mut self, message: &str) -> Result<(), Error> {
+        self.send(ClientToServerMsg::Broadcast {
+            message: message.to_string(),
+        })
+        .await
+    }
+
+    /// Acknowledges a message received in [`Event::Message`].
+    pub async fn ack(&mut self, id: u64) -> Result<(), Error> {
+        self.send(ClientToServerMsg::Ack { id }).await
+    }
+
+    /// Checks that the connection is OK.
+    pub async fn ping(&mut self) -> Result<(), Error> {
+        self.request(ClientToServerMsg::Ping, Expected::Pong)
+            .await
+            .map(|_| ())
+    }
+
+    pub async fn list_users(&mut self) -> Result<Vec<String>, Error> {
+        self.request(ClientToServerMsg::ListUsers, Expected::UserList)
+            .await
+            .map(crate::into_users)
+    }
+
+    /// Returns a page of the history with messages newer than `since`. Returns
+    /// [`Error::Server`] if the history of the server is disabled.
+    pub async fn history(
+        &mut self,
+        since: u64,
+        limit: usize,
+    ) -> Result<Vec<HistoryMessage>, Error> {
+        self.request(
+            ClientToServerMsg::History { since, limit },
+            Expected::History,
+        )
+        .await
+        .map(crate::into_history)
+    }
+
+    /// Returns the next event, waiting for it if necessary. Returns `None` once the server has
+    /// closed the connection.
+    pub async fn next_event(&mut self) -> Result<Option<Event>, Error> {
+        if let Some(event) = self.events.pop_front() {
+            return Ok(Some(event));
+        }
+        match self.receive(None).await {
+            Ok(Incoming::Event(event)) => Ok(Some(event)),
+            Ok(Incoming::Reply(_)) => unreachable!("no reply was expected"),
+            Err(Error::Closed) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Sends any message, e.g. one that has no method in this client.
+    pub async fn send(&mut self, msg: ClientToServerMsg) -> Result<(), Error> {
+        let frame = codec::encode(&msg, self.framing)?;
+        self.writer.write_all(&frame).await?;
+        Ok(())
+    }
+
+    /// Closes the connection.
+    pub async fn close(mut self) -> Result<(), Error> {
+        Ok(self.writer.shutdown().await?)
+    }
+
+    /// Sends a request and waits for its reply, keeping the events received in the meantime.
+    async fn request(
+        &mut self,
+        msg: ClientToServerMsg,
+        expected: Expected,
+    ) -> Result<ServerToClientMsg, Error> {
+        self.send(msg).await?;
+        loop {
+            match self.receive(Some(expected)).await? {
+                Incoming::Reply(reply) => return Ok(reply),
+                Incoming::Event(event) => self.events.push_back(event),
+            }
+        }
+    }
+
+    /// Cancel safe, the received bytes are kept in [`ChatClient::frames`].
+    async fn receive(&mut self, expected: Option<Expected>) -> Result<Incoming, Error> {
+        let mut chunk = [0; 4096];
+        loop {
+            if let Some(decoded) = self.frames.next() {
+                return classify(decoded?, expected);
+            }
+            match self.reader.read(&mut chunk).await? {
+                0 if self.frames.is_empty() => return Err(Error::Closed),
+                0 => {
+                    return Err(Error::Io(std::io::ErrorKind::UnexpectedEof.into()));
+                }
+                read => self.frames.extend(&chunk[..read]),
+            }
+        }
+    }
+}
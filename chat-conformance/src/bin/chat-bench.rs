@@ -0,0 +1,365 @@
+//! Load tester for the chat servers.
+//!
+//! Usage: `chat-bench [--framing length|newline] [--clients <count>] [--messages <count>]
+//! [--size <bytes>] <dm|broadcast|churn> <address>`
+//!
+//! Scenarios:
+//! - `dm`: every client sends `--messages` DMs to the next client, so that all clients send and
+//!   receive at the same time
+//! - `broadcast`: the first client broadcasts `--messages` messages, which fan out to all the
+//!   other clients
+//! - `churn`: every client connects, joins and leaves `--messages` times in a row
+//!
+//! The report contains the throughput and the p50/p99 latency. The latency of a message is the
+//! time between sending it and receiving it at its recipient, for `churn` it is the time between
+//! connecting and receiving `Welcome`.
+//!
+//! The week08 server uses the length-prefixed framing (with JSON, its default format), the week09
+//! and week10 servers use the newline framing. The server has to accept at least `--clients`
+//! clients at once.
+
+use chat_conformance::client::Client;
+use chat_conformance::messages::{ClientToServerMsg, ServerToClientMsg};
+use chat_conformance::Framing;
+use std::collections::BTreeMap;
+use std::process::ExitCode;
+use std::sync::mpsc::{self, Sender};
+use std::sync::Barrier;
+use std::thread::ScopedJoinHandle;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "Usage: chat-bench [--framing length|newline] [--clients <count>] \
+    [--messages <count>] [--size <bytes>] <dm|broadcast|churn> <address>";
+
+/// How long does a client wait for its next message before it considers the rest of its messages
+/// lost.
+const RECV_TIMEOUT: Duration = Duration::from_secs(10);
+/// The threads of the clients need little stack, so that thousands of them fit into memory.
+const STACK_SIZE: usize = 256 * 1024;
+
+struct Args {
+    scenario: Scenario,
+    address: String,
+    framing: Framing,
+    clients: usize,
+    messages: usize,
+    /// Size of the padding added to every message.
+    size: usize,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Scenario {
+    Dm,
+    Broadcast,
+    Churn,
+}
+
+/// What was measured by a scenario.
+#[derive(Default)]
+struct Report {
+    latencies: Vec<Duration>,
+    /// Number of messages (or joins) that were expected.
+    expected: usize,
+    /// Errors sent by the server and failed joins, by their message.
+    errors: BTreeMap<String, usize>,
+    elapsed: Duration,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.latencies.extend(other.latencies);
+        self.expected += other.expected;
+        for (error, count) in other.errors {
+            *self.errors.entry(error).or_default() += count;
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let args = match parse_args(std::env::args().skip(1)) {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("{error}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let report = match args.scenario {
+        Scenario::Dm | Scenario::Broadcast => run_messages(&args),
+        Scenario::Churn => run_churn(&args),
+    };
+    match report {
+        Ok(report) => {
+            print_report(&args, report);
+            ExitCode::SUCCESS
+        }
+        Err(error) => {
+            eprintln!("{error}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Runs the `dm` or the `broadcast` scenario.
+fn run_messages(args: &Args) -> anyhow::Result<Report> {
+    let clients = (0..args.clients)
+        .map(|id| join(args, &name(id)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let senders = match args.scenario {
+        Scenario::Dm => args.clients,
+        _ => 1,
+    };
+    let padding = "x".repeat(args.size);
+    let barrier = Barrier::new(senders);
+    let start = Instant::now();
+
+    let (report_tx, report_rx) = mpsc::channel();
+
+    let report = std::thread::scope(|s| {
+        for (id, client) in clients.iter().enumerate() {
+            let expected = match args.scenario {
+                Scenario::Dm => args.messages,
+                _ if id == 0 => 0,
+                _ => args.messages,
+            };
+            let client = client.try_clone().expect("cannot clone client");
+            let report_tx = report_tx.clone();
+            spawn(s, move || receive(client, expected, start, report_tx));
+        }
+
+        for (id, client) in clients.iter().take(senders).enumerate() {
+            let mut client = client.try_clone().expect("cannot clone client");
+            let (barrier, padding) = (&barrier, &padding);
+            let scenario = args.scenario;
+            let to = name((id + 1) % args.clients);
+            spawn(s, move || {
+                barrier.wait();
+                for _ in 0..args.messages {
+                    let message = format!("{} {padding}", start.elapsed().as_nanos());
+                    let msg = match scenario {
+                        Scenario::Dm => ClientToServerMsg::SendDM {
+                            to: to.clone(),
+                            message,
+                        },
+                        _ => ClientToServerMsg::Broadcast { message },
+                    };
+                    if client.try_send(msg).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+
+        let mut report = Report::default();
+        for received in report_rx.iter().take(clients.len()) {
+            report.merge(received);
+        }
+        report.elapsed = start.elapsed();
+        // Stop the threads that keep reading from the connections
+        for client in &clients {
+            client.shutdown();
+        }
+        report
+    });
+    Ok(report)
+}
+
+/// Receives `expected` messages sent by the other clients and sends out the report. Then keeps
+/// reading until the connection is closed, because a server might stop serving a client (and
+/// even the other clients) when the client does not read its receipts.
+fn receive(mut client: Client, expected: usize, start: Instant, report_tx: Sender<Report>) {
+    let mut report = Report {
+        expected,
+        ..Default::default()
+    };
+    let mut report_tx = Some(report_tx);
+    loop {
+        if report.latencies.len() == expected {
+            if let Some(report_tx) = report_tx.take() {
+                let _ = report_tx.send(std::mem::take(&mut report));
+            }
+        }
+        match client.read() {
+            Some(Ok(ServerToClientMsg::Message { message, .. })) => {
+                let sent = message
+                    .split(' ')
+                    .next()
+                    .and_then(|sent| sent.parse().ok())
+                    .map(Duration::from_nanos)
+                    .unwrap_or_default();
+                report.latencies.push(start.elapsed().saturating_sub(sent));
+            }
+            Some(Ok(ServerToClientMsg::Error(error))) => {
+                *report.errors.entry(error).or_default() += 1
+            }
+            Some(Ok(_)) => {}
+            // The connection was closed or the messages stopped coming, the rest are lost
+            Some(Err(_)) | None => break,
+        }
+    }
+    if let Some(report_tx) = report_tx {
+        let _ = report_tx.send(report);
+    }
+}
+
+/// Runs the `churn` scenario.
+fn run_churn(args: &Args) -> anyhow::Result<Report> {
+    let start = Instant::now();
+    let mut report = std::thread::scope(|s| {
+        let workers: Vec<ScopedJoinHandle<Report>> = (0..args.clients)
+            .map(|id| {
+                spawn(s, move || {
+                    let mut report = Report {
+                        expected: args.messages,
+                        ..Default::default()
+                    };
+                    for round in 0..args.messages {
+                        let connected = Instant::now();
+                        match join(args, &format!("{}-{round}", name(id))) {
+                            Ok(client) => {
+                                report.latencies.push(connected.elapsed());
+                                drop(client);
+                            }
+                            Err(error) => *report.errors.entry(error.to_string()).or_default() += 1,
+                        }
+                    }
+                    report
+                })
+            })
+            .collect();
+
+        let mut report = Report::default();
+        for worker in workers {
+            report.merge(worker.join().expect("client thread panicked"));
+        }
+        report
+    });
+    report.elapsed = start.elapsed();
+    Ok(report)
+}
+
+/// Connects a client and waits until it joins.
+fn join(args: &Args, name: &str) -> anyhow::Result<Client> {
+    let mut client = Client::connect_to(args.address.as_str(), args.framing)
+        .map_err(|error| anyhow::anyhow!("Cannot connect to {}: {error}", args.address))?;
+    client.set_read_timeout(Some(RECV_TIMEOUT))?;
+    // The server might tell us that it is full before we even send `Join`
+    let joined = client.try_send(ClientToServerMsg::Join {
+        name: name.to_string(),
+    });
+    match client.read() {
+        Some(Ok(ServerToClientMsg::Welcome { .. })) => Ok(client),
+        Some(Ok(ServerToClientMsg::Error(error))) => anyhow::bail!("{name} cannot join: {error}"),
+        Some(Ok(msg)) => anyhow::bail!("Unexpected response to Join: {msg:?}"),
+        Some(Err(error)) => Err(error),
+        None => {
+            joined?;
+            anyhow::bail!("The server closed the connection of {name}");
+        }
+    }
+}
+
+fn spawn<'scope, T: Send + 'scope>(
+    s: &'scope std::thread::Scope<'scope, '_>,
+    func: impl FnOnce() -> T + Send + 'scope,
+) -> ScopedJoinHandle<'scope, T> {
+    std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn_scoped(s, func)
+        .expect("cannot spawn client thread")
+}
+
+/// Users of a previous run might still be joined (e.g. while their sessions can be resumed), so
+/// every run uses different names.
+fn name(id: usize) -> String {
+    format!("bench-{}-{id}", std::process::id())
+}
+
+fn print_report(args: &Args, mut report: Report) {
+    let what = match args.scenario {
+        Scenario::Churn => "joins",
+        _ => "messages",
+    };
+    let seconds = report.elapsed.as_secs_f64();
+    println!(
+        "{:?} with {} clients: {} of {} {what} in {seconds:.2} s ({:.0} {what}/s)",
+        args.scenario,
+        args.clients,
+        report.latencies.len(),
+        report.expected,
+        report.latencies.len() as f64 / seconds,
+    );
+    report.latencies.sort();
+    if let Some(max) = report.latencies.last() {
+        println!(
+            "Latency: p50 {:.2} ms, p99 {:.2} ms, max {:.2} ms",
+            millis(percentile(&report.latencies, 0.5)),
+            millis(percentile(&report.latencies, 0.99)),
+            millis(*max),
+        );
+    }
+    for (error, count) in report.errors {
+        println!("Error {count}x: {error}");
+    }
+}
+
+/// `latencies` have to be sorted and non-empty.
+fn percentile(latencies: &[Duration], percentile: f64) -> Duration {
+    let index = ((latencies.len() - 1) as f64 * percentile).round() as usize;
+    latencies[index]
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+    let mut framing = Framing::NewlineDelimited;
+    let mut clients = 100;
+    let mut messages = 100;
+    let mut size = 32;
+    let mut positional = vec![];
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("Missing value of {name}"));
+        match arg.as_str() {
+            "--framing" => {
+                framing = match value("--framing")?.as_str() {
+                    "length" => Framing::LengthPrefixed,
+                    "newline" => Framing::NewlineDelimited,
+                    framing => return Err(format!("Unknown framing {framing}")),
+                }
+            }
+            "--clients" => clients = parse_number("--clients", &value("--clients")?)?,
+            "--messages" => messages = parse_number("--messages", &value("--messages")?)?,
+            "--size" => size = parse_number("--size", &value("--size")?)?,
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {arg}")),
+            _ => positional.push(arg),
+        }
+    }
+    let [scenario, address] = <[String; 2]>::try_from(positional)
+        .map_err(|_| "Expected a scenario and an address".to_string())?;
+    let scenario = match scenario.as_str() {
+        "dm" => Scenario::Dm,
+        "broadcast" => Scenario::Broadcast,
+        "churn" => Scenario::Churn,
+        _ => return Err(format!("Unknown scenario {scenario}")),
+    };
+    if clients < 2 && scenario != Scenario::Churn {
+        return Err(format!(
+            "The {scenario:?} scenario needs at least two clients"
+        ));
+    }
+    Ok(Args {
+        scenario,
+        address,
+        framing,
+        clients,
+        messages,
+        size,
+    })
+}
+
+fn parse_number(name: &str, value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{name} expects a number, got {value}"))
+}
@@ -0,0 +1,213 @@
+use crate::messages::{ClientToServerMsg, HistoryMessage, ServerToClientMsg};
+use crate::Framing;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{Shutdown, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Blocking chat client that speaks the framing of the server under test.
+pub struct Client {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+    framing: Framing,
+}
+
+impl Client {
+    pub fn connect(port: u16, framing: Framing) -> anyhow::Result<Self> {
+        Self::connect_to(("127.0.0.1", port), framing)
+    }
+
+    pub fn connect_to(address: impl ToSocketAddrs, framing: Framing) -> anyhow::Result<Self> {
+        let stream = TcpStream::connect(address)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Self {
+            stream,
+            reader,
+            framing,
+        })
+    }
+
+    /// Returns another client that uses the same connection, e.g. to read messages on a
+    /// different thread.
+    pub fn try_clone(&self) -> anyhow::Result<Self> {
+        let stream = self.stream.try_clone()?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Self {
+            stream,
+            reader,
+            framing: self.framing,
+        })
+    }
+
+    /// Makes [Client::read] fail if no message arrives within `timeout`. The timeout applies to
+    /// all clones of the client.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> anyhow::Result<()> {
+        self.stream.set_read_timeout(timeout)?;
+        Ok(())
+    }
+
+    #[track_caller]
+    pub fn join(&mut self, name: &str) {
+        self.send(ClientToServerMsg::Join {
+            name: name.to_string(),
+        });
+        let msg = self.recv();
+        assert!(matches!(msg, ServerToClientMsg::Welcome { .. }));
+    }
+
+    #[track_caller]
+    pub fn ping(&mut self) {
+        self.send(ClientToServerMsg::Ping);
+        let msg = self.recv();
+        assert!(matches!(msg, ServerToClientMsg::Pong));
+    }
+
+    #[track_caller]
+    pub fn list_users(&mut self) -> Vec<String> {
+        self.send(ClientToServerMsg::ListUsers);
+        let msg = self.recv();
+        match msg {
+            ServerToClientMsg::UserList { mut users } => {
+                users.sort();
+                users
+            }
+            msg => {
+                panic!("Unexpected response {msg:?}");
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn dm(&mut self, to: &str, message: &str) {
+        self.send(ClientToServerMsg::SendDM {
+            to: to.to_string(),
+            message: message.to_string(),
+        });
+    }
+
+    #[track_caller]
+    pub fn expect_message(&mut self, expected_from: &str, expected_message: &str) {
+        let msg = self.recv();
+        match msg {
+            ServerToClientMsg::Message { from, message } => {
+                assert_eq!(from, expected_from);
+                assert_eq!(message, expected_message);
+            }
+            msg => panic!("Unexpected message {msg:?}"),
+        }
+    }
+
+    #[track_caller]
+    pub fn history(&mut self, since: u64, limit: usize) -> Vec<HistoryMessage> {
+        self.send(ClientToServerMsg::History { since, limit });
+        match self.recv() {
+            ServerToClientMsg::History { messages } => messages,
+            msg => panic!("Unexpected response {msg:?}"),
+        }
+    }
+
+    #[track_caller]
+    pub fn send(&mut self, msg: ClientToServerMsg) {
+        self.try_send(msg).expect("cannot send message");
+    }
+
+    pub fn try_send(&mut self, msg: ClientToServerMsg) -> anyhow::Result<()> {
+        let serialized = serde_json::to_vec(&msg)?;
+        let mut frame = Vec::with_capacity(serialized.len() + 4);
+        match self.framing {
+            Framing::LengthPrefixed => {
+                frame.extend_from_slice(&(serialized.len() as u32).to_le_bytes());
+                frame.extend_from_slice(&serialized);
+            }
+            Framing::NewlineDelimited => {
+                frame.extend_from_slice(&serialized);
+                frame.push(b'\n');
+            }
+        }
+        self.stream.write_all(&frame)?;
+        Ok(())
+    }
+
+    #[track_caller]
+    pub fn expect_error(&mut self, expected_error: &str) {
+        let msg = self.recv();
+        match msg {
+            ServerToClientMsg::Error(error) => {
+                assert_eq!(error, expected_error);
+            }
+            msg => {
+                panic!("Unexpected response {msg:?}");
+            }
+        }
+    }
+
+    #[track_caller]
+    pub fn recv(&mut self) -> ServerToClientMsg {
+        self.read()
+            .expect("connection was closed")
+            .expect("cannot receive message")
+    }
+
+    /// Reads the next message, returns `None` if the server has closed the connection.
+    /// Receipts of the messages sent by this client and presence notifications are skipped.
+    pub fn read(&mut self) -> Option<anyhow::Result<ServerToClientMsg>> {
+        loop {
+            match self.read_frame()? {
+                Ok(
+                    ServerToClientMsg::Accepted { .. }
+                    | ServerToClientMsg::Delivered { .. }
+                    | ServerToClientMsg::Read { .. }
+                    | ServerToClientMsg::UserJoined { .. }
+                    | ServerToClientMsg::UserLeft { .. },
+                ) => continue,
+                msg => return Some(msg),
+            }
+        }
+    }
+
+    fn read_frame(&mut self) -> Option<anyhow::Result<ServerToClientMsg>> {
+        let frame = match self.framing {
+            Framing::LengthPrefixed => {
+                match self.reader.fill_buf() {
+                    Ok([]) => return None,
+                    Ok(_) => {}
+                    Err(error) => return Some(Err(error.into())),
+                }
+                let mut size = [0; 4];
+                if let Err(error) = self.reader.read_exact(&mut size) {
+                    return Some(Err(error.into()));
+                }
+                let mut frame = vec![0; u32::from_le_bytes(size) as usize];
+                if let Err(error) = self.reader.read_exact(&mut frame) {
+                    return Some(Err(error.into()));
+                }
+                frame
+            }
+            Framing::NewlineDelimited => {
+                let mut frame = vec![];
+                match self.reader.read_until(b'\n', &mut frame) {
+                    Ok(0) => return None,
+                    Ok(_) => frame,
+                    Err(error) => return Some(Err(error.into())),
+                }
+            }
+        };
+        Some(serde_json::from_slice(&frame).map_err(|error| error.into()))
+    }
+
+    /// Closes the connection of this client and all of its clones, e.g. to wake up a thread that
+    /// is reading from it. Unlike [Client::close], it does not fail if the connection is already
+    /// broken.
+    pub fn shutdown(&self) {
+        let _ = self.stream.shutdown(Shutdown::Both);
+    }
+
+    #[track_caller]
+    pub fn close(self) {
+        self.stream.shutdown(Shutdown::Both).unwrap();
+    }
+
+    #[track_caller]
+    pub fn check_closed(mut self) {
+        assert!(matches!(self.read(), None | Some(Err(_))));
+    }
+}
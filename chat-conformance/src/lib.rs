@@ -0,0 +1,205 @@
+//! Conformance test suite for the chat servers from weeks 08, 09 and 10.
+//!
+//! The suite only talks to a server over TCP/IP, so it can check any implementation of the chat
+//! protocol. An implementation registers itself by implementing [`ChatServer`] for its running
+//! server and then generates the tests with [`conformance_tests!`]:
+//!
+//! ```ignore
+//! #[cfg(test)]
+//! mod conformance {
+//!     impl chat_conformance::ChatServer for crate::RunningServer {
+//!         ...
+//!     }
+//!
+//!     chat_conformance::conformance_tests!(
+//!         crate::RunningServer;
+//!         join_timeout: true,
+//!         idle_timeout: false,
+//!         history: true,
+//!     );
+//! }
+//! ```
+//!
+//! The suite always uses JSON to encode the messages.
+
+use client::Client;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Client used by the suite
+pub mod client;
+/// Messages understood by every chat server
+pub mod messages;
+/// The individual conformance tests
+pub mod suite;
+
+/// How are the messages delimited on the wire.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Framing {
+    /// Every message is prefixed by its size as a little-endian `u32` (week 08).
+    LengthPrefixed,
+    /// Every message is terminated by a newline (weeks 09 and 10).
+    NewlineDelimited,
+}
+
+/// Optional behaviors of a server. Tests of an unsupported capability are generated as ignored
+/// tests by [`conformance_tests!`], and skipped with a note when they are run anyway.
+#[derive(Copy, Clone, Debug)]
+pub struct Capabilities {
+    /// The server disconnects clients that do not join within the given duration.
+    pub join_timeout: Option<Duration>,
+    /// The server disconnects clients that do not send or receive anything within the given
+    /// duration.
+    pub idle_timeout: Option<Duration>,
+    /// The server can store the history of broadcasts and DMs in a file.
+    pub history: bool,
+}
+
+/// Configuration of a server started by the suite.
+#[derive(Clone, Debug)]
+pub struct ServerConfig {
+    /// Maximum number of clients that can be connected to the server at once.
+    pub max_clients: usize,
+    /// File where the history is stored, `None` disables the history.
+    pub history: Option<PathBuf>,
+    /// Maximum size of a single message, `None` keeps the default of the server.
+    pub max_message_size: Option<usize>,
+}
+
+impl ServerConfig {
+    pub fn new(max_clients: usize) -> Self {
+        Self {
+            max_clients,
+            history: None,
+            max_message_size: None,
+        }
+    }
+
+    pub fn with_history(mut self, history: &Path) -> Self {
+        self.history = Some(history.to_path_buf());
+        self
+    }
+
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = Some(max_message_size);
+        self
+    }
+}
+
+/// Adapter between the suite and a chat server implementation.
+///
+/// Dropping the server must shut it down and disconnect all of its clients.
+pub trait ChatServer: Sized {
+    const FRAMING: Framing;
+    const CAPABILITIES: Capabilities;
+    /// How many messages are sent by the spamming tests.
+    const SPAM_COUNT: usize = 10_000;
+
+    /// Starts a server listening on `127.0.0.1` on a port chosen by the operating system.
+    fn start(config: ServerConfig) -> anyhow::Result<Self>;
+
+    fn port(&self) -> u16;
+
+    fn client(&self) -> Client {
+        Client::connect(self.port(), Self::FRAMING).expect("cannot connect to server")
+    }
+}
+
+/// Generates a `#[test]` for every test of the suite, running it against the given server type.
+///
+/// The optional capabilities of the server are listed after it (all of them are supported by
+/// default), and have to match [`ChatServer::CAPABILITIES`]. Tests of an unsupported capability
+/// are marked with `#[ignore]`, so that `cargo test` reports them as ignored.
+#[macro_export]
+macro_rules! conformance_tests {
+    ($server:ty) => {
+        $crate::conformance_tests!(
+            $server;
+            join_timeout: true,
+            idle_timeout: true,
+            history: true,
+        );
+    };
+    (
+        $server:ty;
+        join_timeout: $join_timeout:tt,
+        idle_timeout: $idle_timeout:tt,
+        history: $history:tt $(,)?
+    ) => {
+        const _: () = {
+            let capabilities = <$server as $crate::ChatServer>::CAPABILITIES;
+            assert!(
+                capabilities.join_timeout.is_some() == $join_timeout
+                    && capabilities.idle_timeout.is_some() == $idle_timeout
+                    && capabilities.history == $history,
+                "the capabilities do not match ChatServer::CAPABILITIES"
+            );
+        };
+        $crate::conformance_tests!(
+            @tests $server;
+            empty_server_shuts_down,
+            max_clients,
+            max_clients_after_client_leaves,
+            max_clients_herd,
+            list_users_before_join,
+            join_after_half_sec,
+            duplicated_join,
+            error_then_disconnect,
+            duplicated_username,
+            ping,
+            ping_before_join,
+            list_users_reconnect,
+            list_users_self,
+            list_users_ignore_not_joined_users,
+            list_users_after_error,
+            list_users,
+            dm_nonexistent_user,
+            dm_self,
+            dm_other,
+            dm_spam,
+            dm_spam_2,
+            broadcast_empty,
+            broadcast,
+            welcome_announces_max_message_size,
+            message_too_large,
+            message_too_large_before_join,
+            large_messages_within_limit,
+            drop_clients_on_shutdown,
+        );
+        $crate::conformance_tests!(@capability $server; $join_timeout; "a join timeout"; join_timeout);
+        $crate::conformance_tests!(
+            @capability $server; $idle_timeout; "an idle timeout";
+            message_timeout,
+            message_timeout_receiving_dms,
+        );
+        $crate::conformance_tests!(
+            @capability $server; $history; "the history";
+            history_disabled,
+            history_visibility,
+            history_paging,
+            history_survives_restart,
+            history_ignores_partial_record,
+            history_replays_missed_dms,
+        );
+    };
+    (@capability $server:ty; true; $capability:literal; $($test:ident),* $(,)?) => {
+        $crate::conformance_tests!(@tests $server; $($test),*);
+    };
+    (@capability $server:ty; false; $capability:literal; $($test:ident),* $(,)?) => {
+        $(
+            #[test]
+            #[ignore = concat!("the server does not support ", $capability)]
+            fn $test() {
+                $crate::suite::$test::<$server>();
+            }
+        )*
+    };
+    (@tests $server:ty; $($test:ident),* $(,)?) => {
+        $(
+            #[test]
+            fn $test() {
+                $crate::suite::$test::<$server>();
+            }
+        )*
+    };
+}
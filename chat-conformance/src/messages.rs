@@ -0,0 +1,61 @@
+//! The part of the chat protocol that is shared by all the chat server implementations.
+//! Implementations can support more messages (e.g. rooms), the suite just never sends them.
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub enum ClientToServerMsg {
+    Join { name: String },
+    Ping,
+    ListUsers,
+    SendDM { to: String, message: String },
+    Broadcast { message: String },
+    History { since: u64, limit: usize },
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Debug)]
+pub enum ServerToClientMsg {
+    Welcome {
+        max_message_size: usize,
+    },
+    Pong,
+    UserList {
+        users: Vec<String>,
+    },
+    Message {
+        from: String,
+        message: String,
+    },
+    History {
+        messages: Vec<HistoryMessage>,
+    },
+    Error(String),
+    /// Receipts of the messages sent by the client, which the suite does not check.
+    /// They are skipped by [crate::client::Client].
+    Accepted {
+        id: u64,
+    },
+    Delivered {
+        id: u64,
+        to: String,
+    },
+    Read {
+        id: u64,
+        by: String,
+    },
+    /// Presence notifications, which the suite does not check either.
+    UserJoined {
+        name: String,
+    },
+    UserLeft {
+        name: String,
+        reason: String,
+    },
+}
+
+/// A broadcast or a DM stored in the history of the server.
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, PartialEq)]
+pub struct HistoryMessage {
+    pub id: u64,
+    pub from: String,
+    pub to: Option<String>,
+    pub message: String,
+}
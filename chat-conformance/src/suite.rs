@@ -0,0 +1,879 @@
+//! Protocol-level tests shared by all the chat server implementations.
+//!
+//! Every test is a generic function that starts its own server through the [`ChatServer`]
+//! adapter. Tests of optional capabilities are skipped when the server does not support them, they
+//! pass and print that they were skipped (shown with `cargo test -- --show-output`).
+
+use crate::client::Client;
+use crate::messages::{ClientToServerMsg, HistoryMessage, ServerToClientMsg};
+use crate::{ChatServer, ServerConfig};
+use std::io::Write;
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Barrier, Mutex};
+use std::thread::spawn;
+use std::time::Duration;
+
+// If you're struggling with this test, comment it and implement the rest of the
+// functionality first.
+pub fn empty_server_shuts_down<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |_| Ok(()));
+}
+
+pub fn max_clients<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let _client = server.client();
+        let _client2 = server.client();
+
+        let mut client3 = server.client();
+        client3.expect_error("Server is full");
+        client3.check_closed();
+
+        Ok(())
+    });
+}
+
+pub fn max_clients_after_client_leaves<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let _client = server.client();
+        let client2 = server.client();
+        client2.close();
+
+        sleep(1000);
+
+        let mut client3 = server.client();
+        client3.join("Foo");
+
+        Ok(())
+    });
+}
+
+pub fn max_clients_herd<S: ChatServer>() {
+    let max_clients = 5;
+    run_test::<S>(ServerConfig::new(max_clients), |server| {
+        let thread_count = 50;
+
+        let port = server.port();
+        let barrier = Arc::new(Barrier::new(thread_count));
+
+        let errors = Arc::new(AtomicUsize::new(0));
+        let successes = Arc::new(AtomicUsize::new(0));
+
+        let joined_clients = Arc::new(Mutex::new(vec![]));
+        std::thread::scope(|s| {
+            for thread_id in 0..thread_count {
+                let barrier = barrier.clone();
+                let errors = errors.clone();
+                let successes = successes.clone();
+                let joined_clients = joined_clients.clone();
+                s.spawn(move || {
+                    barrier.wait();
+                    let mut client =
+                        Client::connect(port, S::FRAMING).expect("cannot connect to server");
+                    let _ = client.try_send(ClientToServerMsg::Join {
+                        name: format!("Thread {thread_id}"),
+                    });
+                    match client.recv() {
+                        ServerToClientMsg::Error(_) => {
+                            errors.fetch_add(1, Ordering::SeqCst);
+                        }
+                        ServerToClientMsg::Welcome { .. } => {
+                            successes.fetch_add(1, Ordering::SeqCst);
+                            // Make sure that the client doesn't disconnect
+                            joined_clients.lock().unwrap().push(client);
+                        }
+                        msg => {
+                            panic!("Unexpected message {msg:?}");
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(errors.load(Ordering::SeqCst), thread_count - max_clients);
+        assert_eq!(successes.load(Ordering::SeqCst), max_clients);
+
+        drop(joined_clients);
+
+        Ok(())
+    });
+}
+
+pub fn list_users_before_join<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut client = server.client();
+        client.send(ClientToServerMsg::ListUsers);
+        client.expect_error("Unexpected message received");
+
+        Ok(())
+    });
+}
+
+pub fn join_after_half_sec<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut client = server.client();
+        sleep(500);
+        client.join("Foo");
+        assert_eq!(client.list_users(), vec!["Foo".to_string()]);
+
+        Ok(())
+    });
+}
+
+pub fn join_timeout<S: ChatServer>() {
+    let Some(join_timeout) = S::CAPABILITIES.join_timeout else {
+        return skip("a join timeout");
+    };
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut client = server.client();
+        std::thread::sleep(join_timeout + Duration::from_secs(1));
+        if client
+            .try_send(ClientToServerMsg::Join {
+                name: "Bilbo".to_string(),
+            })
+            .is_ok()
+        {
+            client.expect_error("Timed out waiting for Join");
+        }
+
+        Ok(())
+    });
+}
+
+pub fn duplicated_join<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut client = server.client();
+        client.join("Foo");
+        client.send(ClientToServerMsg::Join {
+            name: "Bar".to_string(),
+        });
+        client.expect_error("Unexpected message received");
+
+        Ok(())
+    });
+}
+
+pub fn error_then_disconnect<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut client = server.client();
+        client.join("Foo");
+        client.send(ClientToServerMsg::Join {
+            name: "Bar".to_string(),
+        });
+        client.close();
+
+        let mut client2 = server.client();
+        client2.join("Bar");
+
+        Ok(())
+    });
+}
+
+pub fn duplicated_username<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut client = server.client();
+        client.join("Foo");
+
+        let mut client2 = server.client();
+        client2.send(ClientToServerMsg::Join {
+            name: "Foo".to_string(),
+        });
+        client2.expect_error("Username already taken");
+
+        Ok(())
+    });
+}
+
+pub fn ping<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut luca = server.client();
+        luca.join("Luca");
+        luca.ping();
+
+        Ok(())
+    });
+}
+
+pub fn ping_before_join<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut client = server.client();
+        client.send(ClientToServerMsg::Ping);
+        client.expect_error("Unexpected message received");
+
+        Ok(())
+    });
+}
+
+pub fn list_users_reconnect<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut client = server.client();
+        client.join("Foo");
+        client.close();
+
+        let mut client = server.client();
+        client.join("Foo");
+        assert_eq!(client.list_users(), vec!["Foo".to_string()]);
+
+        Ok(())
+    });
+}
+
+pub fn list_users_self<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut client = server.client();
+        client.join("Martin");
+        assert_eq!(client.list_users(), vec!["Martin".to_string()]);
+
+        Ok(())
+    });
+}
+
+pub fn list_users_ignore_not_joined_users<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let _client = server.client();
+        let mut client2 = server.client();
+        client2.join("Joe");
+        assert_eq!(client2.list_users(), vec!["Joe".to_string()]);
+
+        Ok(())
+    });
+}
+
+pub fn list_users_after_error<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut client = server.client();
+        client.join("Terrence");
+
+        let mut client2 = server.client();
+        client2.join("Joe");
+
+        client.send(ClientToServerMsg::Join {
+            name: "Barbara".to_string(),
+        });
+
+        sleep(1000);
+
+        assert_eq!(client2.list_users(), vec!["Joe".to_string()]);
+
+        Ok(())
+    });
+}
+
+pub fn list_users<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut client = server.client();
+        client.join("Terrence");
+
+        let mut client2 = server.client();
+        client2.join("Joe");
+        assert_eq!(
+            client2.list_users(),
+            vec!["Joe".to_string(), "Terrence".to_string()]
+        );
+        client2.close();
+
+        sleep(1000);
+
+        assert_eq!(client.list_users(), vec!["Terrence".to_string()]);
+
+        Ok(())
+    });
+}
+
+pub fn dm_nonexistent_user<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut client = server.client();
+        client.join("Mark");
+        client.dm("Fiona", "Hi");
+        client.expect_error("User Fiona does not exist");
+
+        Ok(())
+    });
+}
+
+pub fn dm_self<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut client = server.client();
+        client.join("Xal'atath");
+        client.dm("Xal'atath", "I'm so lonely :(");
+        client.expect_error("Cannot send a DM to yourself");
+
+        Ok(())
+    });
+}
+
+pub fn dm_other<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut terrence = server.client();
+        terrence.join("Terrence");
+
+        let mut joe = server.client();
+        joe.join("Joe");
+
+        terrence.dm("Joe", "How you doin'");
+        joe.expect_message("Terrence", "How you doin'");
+
+        Ok(())
+    });
+}
+
+pub fn dm_spam<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut diana = server.client();
+        diana.join("Diana");
+        drain_receipts(&diana);
+
+        let mut francesca = server.client();
+        francesca.join("Francesca");
+
+        let barrier = Arc::new(Barrier::new(2));
+        let barrier2 = barrier.clone();
+
+        let count = S::SPAM_COUNT;
+
+        // Let's say that someone is spamming you...
+        let t1 = spawn(move || {
+            barrier.wait();
+
+            for _ in 0..count {
+                diana.dm("Francesca", "Can I borrow your brush? Pleeeeeease :(((");
+            }
+        });
+
+        // ...so you get angry, and start spamming them back.
+        // But you make a critical *error*, because you're sending the message
+        // to the wrong account.
+        // Can your chat server handle that?
+        let t2 = spawn(move || {
+            // Sync the threads a little bit
+            barrier2.wait();
+
+            for _ in 0..count {
+                francesca.dm("Daina", "NO! Get your own!");
+                match francesca.recv() {
+                    ServerToClientMsg::Message { from, message } => {
+                        assert_eq!(from, "Diana");
+                        assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
+                    }
+                    ServerToClientMsg::Error(error) => {
+                        assert_eq!(error, "User Daina does not exist");
+                    }
+                    msg => panic!("Unexpected message {msg:?}"),
+                }
+            }
+            // Francesca should receive count * 2 messages, `count` from Diana and `count`
+            // error messages
+            for _ in 0..count {
+                match francesca.recv() {
+                    ServerToClientMsg::Message { from, message } => {
+                        assert_eq!(from, "Diana");
+                        assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
+                    }
+                    ServerToClientMsg::Error(error) => {
+                        assert_eq!(error, "User Daina does not exist");
+                    }
+                    msg => panic!("Unexpected message {msg:?}"),
+                }
+            }
+        });
+        t1.join().unwrap();
+        t2.join().unwrap();
+
+        Ok(())
+    });
+}
+
+pub fn dm_spam_2<S: ChatServer>() {
+    // Meanwhile, in a parallel universe...
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut diana = server.client();
+        diana.join("Diana");
+        drain_receipts(&diana);
+
+        let mut francesca = server.client();
+        francesca.join("Francesca");
+
+        let barrier = Arc::new(Barrier::new(2));
+        let barrier2 = barrier.clone();
+
+        let count = S::SPAM_COUNT;
+
+        // Let's say that someone is spamming you...
+        let t1 = spawn(move || {
+            barrier.wait();
+
+            for _ in 0..count {
+                diana.dm("Francesca", "Can I borrow your brush? Pleeeeeease :(((");
+            }
+        });
+
+        // ...so you get angry, and start spamming them back.
+        // But you make a critical *error*, because you push the wrong button and start
+        // sending pings to the server instead.
+        // Can your chat server handle that?
+        let t2 = spawn(move || {
+            // Sync the threads a little bit
+            barrier2.wait();
+
+            for _ in 0..count {
+                francesca.send(ClientToServerMsg::Ping);
+                match francesca.recv() {
+                    ServerToClientMsg::Message { from, message } => {
+                        assert_eq!(from, "Diana");
+                        assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
+                    }
+                    ServerToClientMsg::Pong => {}
+                    msg => panic!("Unexpected message {msg:?}"),
+                }
+            }
+            // Francesca should receive count * 2 messages, `count` from Diana and `count`
+            // pong messages
+            for _ in 0..count {
+                match francesca.recv() {
+                    ServerToClientMsg::Message { from, message } => {
+                        assert_eq!(from, "Diana");
+                        assert_eq!(message, "Can I borrow your brush? Pleeeeeease :(((");
+                    }
+                    ServerToClientMsg::Pong => {}
+                    msg => panic!("Unexpected message {msg:?}"),
+                }
+            }
+        });
+        t2.join().unwrap();
+        t1.join().unwrap();
+
+        Ok(())
+    });
+}
+
+pub fn broadcast_empty<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut ji = server.client();
+        ji.join("Ji");
+        ji.send(ClientToServerMsg::Broadcast {
+            message: "Haaaaaai!".to_string(),
+        });
+        ji.ping();
+
+        Ok(())
+    });
+}
+
+pub fn broadcast<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(10), |server| {
+        let mut niko = server.client();
+        niko.join("Niko");
+
+        let users: Vec<Client> = (0..5)
+            .map(|i| {
+                let mut client = server.client();
+                client.join(&format!("NPC {i}"));
+                client
+            })
+            .collect();
+
+        niko.send(ClientToServerMsg::Broadcast {
+            message: "Borrow this!".to_string(),
+        });
+        niko.ping();
+
+        for mut user in users {
+            user.expect_message("Niko", "Borrow this!");
+        }
+
+        Ok(())
+    });
+}
+
+pub fn message_timeout<S: ChatServer>() {
+    let Some(idle_timeout) = S::CAPABILITIES.idle_timeout else {
+        return skip("an idle timeout");
+    };
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut niko = server.client();
+        niko.join("Niko");
+
+        std::thread::sleep(idle_timeout / 3);
+        niko.ping();
+        std::thread::sleep(idle_timeout / 3);
+        niko.list_users();
+        std::thread::sleep(idle_timeout + Duration::from_secs(1));
+
+        niko.expect_error("Timeouted");
+        niko.check_closed();
+
+        Ok(())
+    });
+}
+
+// This test runs for roughly three times the idle timeout
+pub fn message_timeout_receiving_dms<S: ChatServer>() {
+    let Some(idle_timeout) = S::CAPABILITIES.idle_timeout else {
+        return skip("an idle timeout");
+    };
+    run_test::<S>(ServerConfig::new(10), |server| {
+        // Do not timeout user if he is receiving DMs
+        let mut niko = server.client();
+        niko.join("Niko");
+
+        let mut kobzol = server.client();
+        kobzol.join("Kobzol");
+
+        std::thread::sleep(idle_timeout / 2);
+        kobzol.dm("Niko", "Hi there!");
+        niko.recv();
+        std::thread::sleep(idle_timeout / 2);
+        kobzol.dm("Niko", "So, what you're up to?");
+        niko.recv();
+        std::thread::sleep(idle_timeout / 2);
+        kobzol.dm("Niko", "See you at RustWeek?");
+        niko.recv();
+        std::thread::sleep(idle_timeout / 2);
+        kobzol.send(ClientToServerMsg::Broadcast {
+            message: "Rust is really cool, you know".to_string(),
+        });
+        niko.recv();
+        std::thread::sleep(idle_timeout * 2 / 3);
+        kobzol.send(ClientToServerMsg::Broadcast {
+            message: "...anyone here?".to_string(),
+        });
+        niko.recv();
+        std::thread::sleep(idle_timeout * 2 / 3);
+
+        niko.ping();
+
+        Ok(())
+    });
+}
+
+pub fn history_disabled<S: ChatServer>() {
+    if !S::CAPABILITIES.history {
+        return skip("the history");
+    }
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut client = server.client();
+        client.join("Mia");
+        client.send(ClientToServerMsg::History {
+            since: 0,
+            limit: 10,
+        });
+        client.expect_error("History is not enabled");
+
+        Ok(())
+    });
+}
+
+pub fn history_visibility<S: ChatServer>() {
+    if !S::CAPABILITIES.history {
+        return skip("the history");
+    }
+    let dir = tempfile::tempdir().unwrap();
+    run_test::<S>(
+        ServerConfig::new(5).with_history(&dir.path().join("history")),
+        |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            let mut leo = server.client();
+            leo.join("Leo");
+            let mut zoe = server.client();
+            zoe.join("Zoe");
+
+            mia.send(ClientToServerMsg::Broadcast {
+                message: "Hi all".to_string(),
+            });
+            mia.dm("Leo", "Psst");
+            mia.ping();
+            leo.expect_message("Mia", "Hi all");
+            leo.expect_message("Mia", "Psst");
+            zoe.expect_message("Mia", "Hi all");
+
+            assert_eq!(
+                leo.history(0, 10),
+                vec![
+                    history_msg(1, "Mia", None, "Hi all"),
+                    history_msg(2, "Mia", Some("Leo"), "Psst")
+                ]
+            );
+            assert_eq!(mia.history(0, 10).len(), 2);
+            assert_eq!(
+                zoe.history(0, 10),
+                vec![history_msg(1, "Mia", None, "Hi all")]
+            );
+
+            Ok(())
+        },
+    );
+}
+
+pub fn history_paging<S: ChatServer>() {
+    if !S::CAPABILITIES.history {
+        return skip("the history");
+    }
+    let dir = tempfile::tempdir().unwrap();
+    run_test::<S>(
+        ServerConfig::new(2).with_history(&dir.path().join("history")),
+        |server| {
+            let mut mia = server.client();
+            mia.join("Mia");
+            for i in 0..5 {
+                mia.send(ClientToServerMsg::Broadcast {
+                    message: format!("{i}"),
+                });
+            }
+
+            let page = mia.history(0, 2);
+            assert_eq!(page.iter().map(|m| m.id).collect::<Vec<_>>(), vec![1, 2]);
+            let page = mia.history(2, 2);
+            assert_eq!(page.iter().map(|m| m.id).collect::<Vec<_>>(), vec![3, 4]);
+            let page = mia.history(4, 2);
+            assert_eq!(page, vec![history_msg(5, "Mia", None, "4")]);
+            assert!(mia.history(5, 2).is_empty());
+
+            Ok(())
+        },
+    );
+}
+
+pub fn history_survives_restart<S: ChatServer>() {
+    if !S::CAPABILITIES.history {
+        return skip("the history");
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("history");
+    run_test::<S>(ServerConfig::new(2).with_history(&path), |server| {
+        let mut mia = server.client();
+        mia.join("Mia");
+        mia.send(ClientToServerMsg::Broadcast {
+            message: "Before restart".to_string(),
+        });
+        mia.ping();
+
+        Ok(())
+    });
+    run_test::<S>(ServerConfig::new(2).with_history(&path), |server| {
+        let mut mia = server.client();
+        mia.join("Mia");
+        mia.send(ClientToServerMsg::Broadcast {
+            message: "After restart".to_string(),
+        });
+        assert_eq!(
+            mia.history(0, 10),
+            vec![
+                history_msg(1, "Mia", None, "Before restart"),
+                history_msg(2, "Mia", None, "After restart")
+            ]
+        );
+
+        Ok(())
+    });
+}
+
+pub fn history_ignores_partial_record<S: ChatServer>() {
+    if !S::CAPABILITIES.history {
+        return skip("the history");
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("history");
+    run_test::<S>(ServerConfig::new(2).with_history(&path), |server| {
+        let mut mia = server.client();
+        mia.join("Mia");
+        mia.send(ClientToServerMsg::Broadcast {
+            message: "Before crash".to_string(),
+        });
+        mia.ping();
+
+        Ok(())
+    });
+    // A server that crashed while writing a record leaves it without the newline
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&path)
+        .unwrap();
+    file.write_all(b"{\"Message\":{\"mess").unwrap();
+    drop(file);
+
+    run_test::<S>(ServerConfig::new(2).with_history(&path), |server| {
+        let mut mia = server.client();
+        mia.join("Mia");
+        mia.send(ClientToServerMsg::Broadcast {
+            message: "After crash".to_string(),
+        });
+        mia.ping();
+
+        Ok(())
+    });
+    // The record written after the partial one has to be readable as well
+    run_test::<S>(ServerConfig::new(2).with_history(&path), |server| {
+        let mut mia = server.client();
+        mia.join("Mia");
+        assert_eq!(
+            mia.history(0, 10),
+            vec![
+                history_msg(1, "Mia", None, "Before crash"),
+                history_msg(2, "Mia", None, "After crash")
+            ]
+        );
+
+        Ok(())
+    });
+}
+
+pub fn history_replays_missed_dms<S: ChatServer>() {
+    if !S::CAPABILITIES.history {
+        return skip("the history");
+    }
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("history");
+    run_test::<S>(ServerConfig::new(2).with_history(&path), |server| {
+        let mut leo = server.client();
+        leo.join("Leo");
+        leo.close();
+
+        sleep(1000);
+
+        let mut mia = server.client();
+        mia.join("Mia");
+        mia.dm("Leo", "While you were away");
+        // Leo was here before, so the DM is stored instead of failing
+        mia.ping();
+        // Someone who was never here still does not exist
+        mia.dm("Zoe", "Hi");
+        mia.expect_error("User Zoe does not exist");
+
+        Ok(())
+    });
+    run_test::<S>(ServerConfig::new(2).with_history(&path), |server| {
+        let mut leo = server.client();
+        leo.join("Leo");
+        leo.expect_message("Mia", "While you were away");
+        leo.close();
+
+        sleep(1000);
+
+        // The missed DMs are only delivered once
+        let mut leo = server.client();
+        leo.join("Leo");
+        leo.ping();
+
+        Ok(())
+    });
+}
+
+pub fn welcome_announces_max_message_size<S: ChatServer>() {
+    let config = ServerConfig::new(2).with_max_message_size(100);
+    run_test::<S>(config, |server| {
+        let mut client = server.client();
+        client.send(ClientToServerMsg::Join {
+            name: "Mia".to_string(),
+        });
+        assert!(matches!(
+            client.recv(),
+            ServerToClientMsg::Welcome {
+                max_message_size: 100
+            }
+        ));
+
+        Ok(())
+    });
+}
+
+pub fn message_too_large<S: ChatServer>() {
+    let config = ServerConfig::new(2).with_max_message_size(100);
+    run_test::<S>(config, |server| {
+        let mut mia = server.client();
+        mia.join("Mia");
+        let mut leo = server.client();
+        leo.join("Leo");
+
+        mia.dm("Leo", &"a".repeat(100));
+        mia.expect_error("Message too large");
+        mia.ping();
+
+        // Messages that fit are still delivered
+        mia.dm("Leo", "Hi");
+        leo.expect_message("Mia", "Hi");
+
+        Ok(())
+    });
+}
+
+pub fn message_too_large_before_join<S: ChatServer>() {
+    let config = ServerConfig::new(2).with_max_message_size(100);
+    run_test::<S>(config, |server| {
+        let mut client = server.client();
+        client.send(ClientToServerMsg::Join {
+            name: "a".repeat(100),
+        });
+        client.expect_error("Message too large");
+        client.join("Mia");
+        client.ping();
+
+        Ok(())
+    });
+}
+
+pub fn large_messages_within_limit<S: ChatServer>() {
+    run_test::<S>(ServerConfig::new(2), |server| {
+        let mut mia = server.client();
+        mia.join("Mia");
+        let mut leo = server.client();
+        leo.join("Leo");
+
+        let message = "a".repeat(10000);
+        mia.dm("Leo", &message);
+        leo.expect_message("Mia", &message);
+
+        Ok(())
+    });
+}
+
+// The server should correctly close client socket when it shuts down,
+// to avoid a situation where the clients would be stuck waiting for a message
+// for some indeterminate amount of time.
+pub fn drop_clients_on_shutdown<S: ChatServer>() {
+    let server = S::start(ServerConfig::new(2)).expect("creating server failed");
+
+    let mut client = server.client();
+    client.join("Bar");
+    let mut client2 = server.client();
+    client2.join("Foo");
+
+    drop(server);
+
+    assert!(client.read().is_none());
+    assert!(client2.read().is_none());
+}
+
+fn run_test<S: ChatServer>(config: ServerConfig, func: impl FnOnce(S) -> anyhow::Result<()>) {
+    let server = S::start(config).expect("creating server failed");
+    let port = server.port();
+    func(server).expect("test failed");
+
+    TcpStream::connect(("127.0.0.1", port)).expect_err("server is still alive");
+}
+
+/// Reads (and ignores) everything sent to a client that only sends messages, because servers
+/// that send receipts of the messages or presence notifications would get stuck if the client
+/// never read them.
+fn drain_receipts(client: &Client) {
+    let mut client = client.try_clone().expect("cannot clone client");
+    spawn(move || while let Some(Ok(_)) = client.read() {});
+}
+
+/// Called instead of a test that needs a capability which the server does not support, e.g.
+/// when an ignored test is run anyway.
+fn skip(capability: &str) {
+    eprintln!("Skipped, the server does not support {capability}");
+}
+
+fn sleep(duration_ms: u64) {
+    std::thread::sleep(Duration::from_millis(duration_ms));
+}
+
+fn history_msg(id: u64, from: &str, to: Option<&str>, message: &str) -> HistoryMessage {
+    HistoryMessage {
+        id,
+        from: from.to_string(),
+        to: to.map(|to| to.to_string()),
+        message: message.to_string(),
+    }
+}